@@ -9,20 +9,83 @@ RPI_EEPROM_DIGEST="/usr/share/rugix/rpi-eeprom/rpi-eeprom-digest"
 PI4_FIRMWARE="/usr/share/rugix/rpi-eeprom/firmware-2711"
 PI5_FIRMWARE="/usr/share/rugix/rpi-eeprom/firmware-2712"
 
+# Pinned default versions, such that images are reproducible.
+PI4_PIEEPROM_VERSION="2023-05-11"
+PI4_VL805_VERSION="000138c0"
+PI5_PIEEPROM_VERSION="2023-10-30"
+
+if [ "${RECIPE_PARAM_ENABLED}" != "true" ]; then
+    echo "Firmware disabled, skipping."
+    exit 0
+fi
+
 mkdir -p "${CONFIG_DIR}"
 
+# Create the digest of the firmware file, unless digests are disabled.
+make_digest() {
+    if [ "${RECIPE_PARAM_DIGEST}" = "true" ]; then
+        if [ ! -x "${RPI_EEPROM_DIGEST}" ]; then
+            echo "Error: '${RPI_EEPROM_DIGEST}' not found, set 'digest = false' to skip digests." >&2
+            exit 1
+        fi
+        ${RPI_EEPROM_DIGEST} -i "$1" -o "$2"
+    fi
+}
+
+# Find the firmware file with the given prefix and version, or the newest one for `latest`.
+find_firmware() {
+    local prefix="$1"
+    local version="$2"
+    local file
+    if [ ! -d "${FIRMWARE_DIR}" ]; then
+        echo "Error: Firmware directory '${FIRMWARE_DIR}' does not exist." >&2
+        echo "Set the 'firmware_dir' parameter or disable the firmware with 'enabled = false'." >&2
+        exit 1
+    fi
+    if [ "${version}" = "latest" ]; then
+        file=$(find "${FIRMWARE_DIR}" -maxdepth 1 -name "${prefix}-*.bin" | sort | tail -n 1)
+        if [ -z "${file}" ]; then
+            echo "Error: No firmware file '${prefix}-*.bin' found in '${FIRMWARE_DIR}'." >&2
+            exit 1
+        fi
+    else
+        file="${FIRMWARE_DIR}/${prefix}-${version}.bin"
+        if [ ! -f "${file}" ]; then
+            echo "Error: Firmware file '${file}' does not exist." >&2
+            exit 1
+        fi
+    fi
+    echo "${file}"
+}
+
+# Copy the recovery bootloader applying the update.
+copy_recovery() {
+    if [ ! -f "${FIRMWARE_DIR}/recovery.bin" ]; then
+        echo "Error: Recovery bootloader '${FIRMWARE_DIR}/recovery.bin' does not exist." >&2
+        exit 1
+    fi
+    cp -f "${FIRMWARE_DIR}/recovery.bin" "${CONFIG_DIR}/recovery.bin"
+}
+
 case "${RECIPE_PARAM_MODEL}" in
     "pi4")
-        cp -f "${PI4_FIRMWARE}/stable/pieeprom-2023-05-11.bin" "${CONFIG_DIR}/pieeprom.upd"
-        ${RPI_EEPROM_DIGEST} -i "${CONFIG_DIR}/pieeprom.upd" -o "${CONFIG_DIR}/pieeprom.sig"
-        cp -f "${PI4_FIRMWARE}/stable/vl805-000138c0.bin" "${CONFIG_DIR}/vl805.bin"
-        ${RPI_EEPROM_DIGEST} -i "${CONFIG_DIR}/vl805.bin" -o "${CONFIG_DIR}/vl805.sig"
-        cp -f "${PI4_FIRMWARE}/stable/recovery.bin" "${CONFIG_DIR}/recovery.bin"
+        FIRMWARE_DIR="${RECIPE_PARAM_FIRMWARE_DIR:-${PI4_FIRMWARE}}/${RECIPE_PARAM_CHANNEL}"
+        PIEEPROM=$(find_firmware pieeprom "${RECIPE_PARAM_PIEEPROM_VERSION:-${PI4_PIEEPROM_VERSION}}")
+        VL805=$(find_firmware vl805 "${RECIPE_PARAM_VL805_VERSION:-${PI4_VL805_VERSION}}")
+        echo "Including firmware '${PIEEPROM}' and '${VL805}'."
+        cp -f "${PIEEPROM}" "${CONFIG_DIR}/pieeprom.upd"
+        make_digest "${CONFIG_DIR}/pieeprom.upd" "${CONFIG_DIR}/pieeprom.sig"
+        cp -f "${VL805}" "${CONFIG_DIR}/vl805.bin"
+        make_digest "${CONFIG_DIR}/vl805.bin" "${CONFIG_DIR}/vl805.sig"
+        copy_recovery
         ;;
     "pi5")
-        cp -f "${PI5_FIRMWARE}/stable/pieeprom-2023-10-30.bin" "${CONFIG_DIR}/pieeprom.upd"
-        ${RPI_EEPROM_DIGEST} -i "${CONFIG_DIR}/pieeprom.upd" -o "${CONFIG_DIR}/pieeprom.sig"
-        cp -f "${PI5_FIRMWARE}/stable/recovery.bin" "${CONFIG_DIR}/recovery.bin"
+        FIRMWARE_DIR="${RECIPE_PARAM_FIRMWARE_DIR:-${PI5_FIRMWARE}}/${RECIPE_PARAM_CHANNEL}"
+        PIEEPROM=$(find_firmware pieeprom "${RECIPE_PARAM_PIEEPROM_VERSION:-${PI5_PIEEPROM_VERSION}}")
+        echo "Including firmware '${PIEEPROM}'."
+        cp -f "${PIEEPROM}" "${CONFIG_DIR}/pieeprom.upd"
+        make_digest "${CONFIG_DIR}/pieeprom.upd" "${CONFIG_DIR}/pieeprom.sig"
+        copy_recovery
         ;;
     *)
         echo "Error: Invalid Raspberry Pi model '${RECIPE_PARAM_MODEL}'."
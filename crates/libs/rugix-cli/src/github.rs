@@ -0,0 +1,107 @@
+//! Support for emitting GitHub Actions workflow commands.
+//!
+//! See [GitHub's documentation](https://docs.github.com/en/actions/using-workflows/workflow-commands-for-github-actions)
+//! for details on the format of workflow commands.
+
+use std::fmt;
+
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::Layer;
+
+/// Indicates whether we are running within GitHub Actions.
+pub fn is_github_actions() -> bool {
+    std::env::var_os("GITHUB_ACTIONS").is_some_and(|value| value == "true")
+}
+
+/// Emit an annotation with the given command, e.g., `warning` or `error`.
+pub fn annotate(command: &str, file: Option<&str>, message: &str) {
+    let line = format_annotation(command, file, message);
+    crate::suspend(|| println!("{line}"));
+}
+
+/// Format an annotation as a workflow command.
+fn format_annotation(command: &str, file: Option<&str>, message: &str) -> String {
+    let mut line = format!("::{command}");
+    if let Some(file) = file {
+        line.push_str(" file=");
+        line.push_str(&escape_property(file));
+    }
+    line.push_str("::");
+    line.push_str(&escape_data(message));
+    line
+}
+
+/// Escape the data of a workflow command.
+fn escape_data(data: &str) -> String {
+    data.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Escape a property value of a workflow command.
+fn escape_property(value: &str) -> String {
+    escape_data(value).replace(':', "%3A").replace(',', "%2C")
+}
+
+/// [`Layer`] emitting warnings and errors as GitHub Actions annotations.
+///
+/// Events may carry a `file` field to attach the annotation to a file.
+#[derive(Debug, Default)]
+pub struct GithubAnnotations(());
+
+impl<S: Subscriber> Layer<S> for GithubAnnotations {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let command = match *event.metadata().level() {
+            Level::ERROR => "error",
+            Level::WARN => "warning",
+            _ => return,
+        };
+        let mut visitor = EventVisitor::default();
+        event.record(&mut visitor);
+        annotate(command, visitor.file.as_deref(), &visitor.message);
+    }
+}
+
+/// Visitor extracting the message and file of an event.
+#[derive(Debug, Default)]
+pub(crate) struct EventVisitor {
+    pub message: String,
+    pub file: Option<String>,
+}
+
+impl Visit for EventVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = value.to_owned(),
+            "file" => self.file = Some(value.to_owned()),
+            _ => { /* ignore other fields */ }
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => self.message = format!("{value:?}"),
+            "file" => self.file = Some(format!("{value:?}")),
+            _ => { /* ignore other fields */ }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_annotation;
+
+    #[test]
+    fn test_format_annotation() {
+        assert_eq!(
+            format_annotation("warning", None, "50% done\nnext line"),
+            "::warning::50%25 done%0Anext line"
+        );
+        assert_eq!(
+            format_annotation("error", Some("recipes/a,b:c/recipe.toml"), "invalid"),
+            "::error file=recipes/a%2Cb%3Ac/recipe.toml::invalid"
+        );
+    }
+}
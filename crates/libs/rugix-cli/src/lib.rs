@@ -2,19 +2,25 @@
 
 use std::ops::Deref;
 use std::panic::AssertUnwindSafe;
-use std::sync::{Arc, LazyLock, Mutex, Weak};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock, Weak};
 use std::time::{Duration, Instant};
 use std::{fmt, io};
 
 use console::Term;
 use rugix_tasks::{is_canceled_payload, spawn_blocking};
-use style::{Style, Styled};
+use style::{Color, Style, Styled};
 use tracing::info;
+use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
 
 use crate::rate_limiter::RateLimiter;
 
+pub mod github;
 pub mod style;
+pub mod warnings;
 pub mod widgets;
 
 mod rate_limiter;
@@ -28,6 +34,24 @@ pub struct CliBuilder {
     start_drawing_thread: bool,
     /// Period for redrawing the status area.
     drawing_period: Duration,
+    /// Emit warnings and errors as GitHub Actions annotations.
+    github_annotations: bool,
+    /// Whether to use colors.
+    color: ColorChoice,
+    /// Maximal level of events to print.
+    level: LevelFilter,
+}
+
+/// Choice whether to use colors.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Use colors if the terminal supports them and `NO_COLOR` is not set.
+    #[default]
+    Auto,
+    /// Always use colors.
+    Always,
+    /// Never use colors.
+    Never,
 }
 
 impl CliBuilder {
@@ -37,19 +61,62 @@ impl CliBuilder {
             init_tracing: true,
             start_drawing_thread: true,
             drawing_period: Duration::from_millis(100),
+            github_annotations: false,
+            color: ColorChoice::Auto,
+            level: LevelFilter::INFO,
         }
     }
 
+    /// Set whether to use colors.
+    pub fn with_color(mut self, color: ColorChoice) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// Set the maximal level of events to print.
+    pub fn with_level(mut self, level: LevelFilter) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Emit warnings and errors as GitHub Actions annotations.
+    pub fn with_github_annotations(mut self, enabled: bool) -> Self {
+        self.github_annotations = enabled;
+        self
+    }
+
     /// Initialize the CLI.
+    ///
+    /// Colors are never used with GitHub Actions annotations, as the escape sequences
+    /// would end up in the annotations.
     pub fn init(self) {
+        let colors = !self.github_annotations
+            && match self.color {
+                ColorChoice::Auto => {
+                    std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+                        && TERMINAL.shared.term.features().colors_supported()
+                }
+                ColorChoice::Always => true,
+                ColorChoice::Never => false,
+            };
+        let _ = COLORS.set(colors);
         if self.init_tracing {
             let format = tracing_subscriber::fmt::format()
                 .without_time()
                 .with_target(false)
                 .compact();
-            tracing_subscriber::fmt()
+            let fmt_layer = tracing_subscriber::fmt::layer()
                 .with_writer(StderrWriter::new())
+                .with_ansi(colors)
                 .event_format(format)
+                .with_filter(self.level);
+            let github_layer = self
+                .github_annotations
+                .then(github::GithubAnnotations::default);
+            tracing_subscriber::registry()
+                .with(fmt_layer)
+                .with(github_layer)
+                .with(warnings::WarningCollector::default())
                 .init();
         }
         if self.start_drawing_thread {
@@ -65,6 +132,7 @@ impl CliBuilder {
         F: 'static + Send + FnOnce() -> Result<(), E>,
         E: Send + fmt::Debug,
     {
+        let github_annotations = self.github_annotations;
         self.init();
 
         #[derive(Debug)]
@@ -83,7 +151,18 @@ impl CliBuilder {
                     main_tx.send(TerminationReason::Success).ok();
                 }
                 Ok(Err(error)) => {
-                    cli_msg!("Error: {error:?}");
+                    if supports_colors() {
+                        cli_msg!(
+                            "{}Error:{} {error:?}",
+                            Color::Red.foreground_ansi_sequence(),
+                            style::RESET_ALL
+                        );
+                    } else {
+                        cli_msg!("Error: {error:?}");
+                    }
+                    if github_annotations {
+                        github::annotate("error", None, &format!("{error:?}"));
+                    }
                     main_tx.send(TerminationReason::Failed).ok();
                 }
                 Err(payload) => {
@@ -104,7 +183,8 @@ impl CliBuilder {
         }));
 
         ctrlc::set_handler(move || {
-            // Will abort the task by dropping it.
+            // Will abort the task by dropping it. Cancellation unwinds the stack of the
+            // task, thereby running destructors which, e.g., unmount filesystems.
             if main_task.take().is_some() {
                 info!("Shutting down...");
             }
@@ -136,6 +216,9 @@ static TERMINAL: LazyLock<TerminalRef> = LazyLock::new(|| TerminalRef {
     }),
 });
 
+/// Whether to use colors, determined when initializing the CLI.
+static COLORS: OnceLock<bool> = OnceLock::new();
+
 /// Add a status segment.
 pub fn add_status<S: 'static + StatusSegment + Send + Sync>(segment: S) -> StatusSegmentRef<S> {
     TERMINAL.add_status(segment)
@@ -240,8 +323,10 @@ impl TerminalRef {
     }
 
     /// Check whether the terminal supports colors.
+    ///
+    /// Respects the color choice the CLI has been initialized with.
     pub fn supports_colors(&self) -> bool {
-        self.shared.term.features().colors_supported()
+        *COLORS.get_or_init(|| self.shared.term.features().colors_supported())
     }
 
     /// Add a status segment to the terminal output.
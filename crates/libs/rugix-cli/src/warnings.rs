@@ -0,0 +1,32 @@
+//! Collection of warnings emitted during a run.
+
+use std::sync::Mutex;
+
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::Layer;
+
+use crate::github::EventVisitor;
+
+/// Warnings emitted so far.
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Get the warnings emitted so far.
+pub fn collected_warnings() -> Vec<String> {
+    WARNINGS.lock().unwrap().clone()
+}
+
+/// [`Layer`] collecting the messages of warnings.
+#[derive(Debug, Default)]
+pub struct WarningCollector(());
+
+impl<S: Subscriber> Layer<S> for WarningCollector {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if *event.metadata().level() != Level::WARN {
+            return;
+        }
+        let mut visitor = EventVisitor::default();
+        event.record(&mut visitor);
+        WARNINGS.lock().unwrap().push(visitor.message);
+    }
+}
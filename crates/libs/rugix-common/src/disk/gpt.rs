@@ -132,6 +132,10 @@ pub mod gpt_types {
     pub const EFI: PartitionType = PartitionType::Gpt(const_unwrap_result!(Guid::from_hex_str(
         "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"
     )));
+    /// Microsoft basic data GPT partition type.
+    pub const BASIC_DATA: PartitionType = PartitionType::Gpt(const_unwrap_result!(
+        Guid::from_hex_str("EBD0A0A2-B9E5-4433-87C0-68B6B72699C7")
+    ));
     /// Linux GPT partition type.
     pub const LINUX: PartitionType = PartitionType::Gpt(const_unwrap_result!(Guid::from_hex_str(
         "0FC63DAF-8483-4772-8E79-3D69D8477DE4"
@@ -27,48 +27,74 @@ reportify::new_whatever_type! {
     BootPatchError
 }
 
+/// Kernel command line used by Grub for the given root partition.
+pub fn grub_bootargs(root: &str) -> String {
+    format!("ro init=/usr/bin/rugix-ctrl root=PARTUUID={root}")
+}
+
 pub fn grub_patch_env(
     boot_dir: impl AsRef<Path>,
     root: impl AsRef<str>,
 ) -> Result<(), Report<BootPatchError>> {
     const RUGIX_BOOTARGS: &str = "rugpi_bootargs";
     let mut env = HashMap::new();
-    env.insert(
-        RUGIX_BOOTARGS.to_owned(),
-        format!(
-            "ro init=/usr/bin/rugix-ctrl root=PARTUUID={}",
-            root.as_ref()
-        ),
-    );
+    env.insert(RUGIX_BOOTARGS.to_owned(), grub_bootargs(root.as_ref()));
     let encoded = grub_envblk_encode(&env).whatever("unable to encode boot environment")?;
     std::fs::write(boot_dir.as_ref().join("boot.grubenv"), encoded.as_bytes())
         .whatever("unable to write grub environment file")?;
     Ok(())
 }
 
+/// Patches the given kernel command line to use the given root device and `rugix-ctrl`
+/// as init process.
+///
+/// The *extra* parameters are appended in the given order unless they are already
+/// present. Extra parameters cannot override the root device or init process.
+pub fn rpi_patch_cmdline(cmdline: &str, root: &str, extra: &[String]) -> String {
+    let mut parts = cmdline
+        .split_ascii_whitespace()
+        .filter(|part| {
+            !part.starts_with("root=")
+                && !part.starts_with("init=")
+                && !part.starts_with("panic")
+                && *part != "quiet"
+        })
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    for param in extra
+        .iter()
+        .flat_map(|param| param.split_ascii_whitespace())
+    {
+        if param.starts_with("root=") || param.starts_with("init=") {
+            continue;
+        }
+        if !parts.iter().any(|part| part == param) {
+            parts.push(param.to_owned());
+        }
+    }
+    parts.push("panic=60".to_owned());
+    parts.push(format!("root={root}"));
+    parts.push("init=/usr/bin/rugix-ctrl".to_owned());
+    parts.join(" ")
+}
+
 /// Patches `cmdline.txt` to use the given root device and `rugix-ctrl` as init process.
+///
+/// See [`rpi_patch_cmdline`] for how the *extra* parameters are handled.
 pub fn rpi_patch_boot(
     path: impl AsRef<Path>,
     root: impl AsRef<str>,
+    extra: &[String],
 ) -> Result<(), Report<BootPatchError>> {
-    fn _patch_cmdline(path: &Path, root: &str) -> Result<(), Report<BootPatchError>> {
+    fn _patch_cmdline(
+        path: &Path,
+        root: &str,
+        extra: &[String],
+    ) -> Result<(), Report<BootPatchError>> {
         let cmdline_path = path.join("cmdline.txt");
         let cmdline = fs::read_to_string(&cmdline_path)
             .whatever("unable to read `cmdline.txt` from boot partition")?;
-        let mut parts = cmdline
-            .split_ascii_whitespace()
-            .filter(|part| {
-                !part.starts_with("root=")
-                    && !part.starts_with("init=")
-                    && !part.starts_with("panic")
-                    && *part != "quiet"
-            })
-            .map(str::to_owned)
-            .collect::<Vec<_>>();
-        parts.push("panic=60".to_owned());
-        parts.push(format!("root={root}"));
-        parts.push("init=/usr/bin/rugix-ctrl".to_owned());
-        let cmdline_value = parts.join(" ");
+        let cmdline_value = rpi_patch_cmdline(&cmdline, root, extra);
         fs::write(&cmdline_path, &cmdline_value)
             .whatever("unable to write `cmdline.txt` to boot partition")?;
         let boot_env_path = path.join("boot.env");
@@ -82,7 +108,7 @@ pub fn rpi_patch_boot(
             .whatever("unable to save U-Boot environment")?;
         Ok(())
     }
-    _patch_cmdline(path.as_ref(), root.as_ref())
+    _patch_cmdline(path.as_ref(), root.as_ref(), extra)
 }
 
 /// Patches `config.txt` to not use `initramfs`.
@@ -100,6 +126,84 @@ pub fn rpi_patch_config(path: impl AsRef<Path>) -> io::Result<()> {
     _patch_config(path.as_ref())
 }
 
+/// Patches the given `fstab` to refer to partitions of the disk with the given MBR id.
+///
+/// The disk id of every MBR `PARTUUID=` device, i.e., `PARTUUID=<disk id>-<partition>`,
+/// referring to the original disk of the image is replaced by the given disk id while the
+/// partition number, the remainder of the entry, and comments are kept. The original
+/// disk is the one of the root filesystem or, if there is no such entry, of the first
+/// entry. Entries referring to other disks, e.g., a USB data drive, are kept as is and
+/// reported with a warning.
+pub fn patch_fstab(fstab: &str, disk_id: &str) -> String {
+    const PREFIX: &str = "PARTUUID=";
+    /// Disk id and mount point of the given MBR `PARTUUID=` entry.
+    fn mbr_entry(line: &str) -> Option<(&str, &str)> {
+        let uuid = line.strip_prefix(PREFIX)?;
+        let bytes = uuid.as_bytes();
+        let is_mbr = bytes.len() > 11
+            && bytes[..8].iter().all(u8::is_ascii_hexdigit)
+            && bytes[8] == b'-'
+            && bytes[9..11].iter().all(u8::is_ascii_hexdigit)
+            && bytes[11].is_ascii_whitespace();
+        is_mbr.then(|| {
+            (
+                &uuid[..8],
+                uuid[11..].split_whitespace().next().unwrap_or(""),
+            )
+        })
+    }
+    let entries = fstab
+        .lines()
+        .map(str::trim_start)
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(mbr_entry)
+        .collect::<Vec<_>>();
+    let original = entries
+        .iter()
+        .find(|(_, mount_point)| *mount_point == "/")
+        .or(entries.first())
+        .map(|(disk_id, _)| *disk_id);
+    let mut patched = String::with_capacity(fstab.len());
+    for line in fstab.split_inclusive('\n') {
+        let device = line.trim_start();
+        let entry = match device.starts_with('#') {
+            true => None,
+            false => mbr_entry(device),
+        };
+        match (entry, original) {
+            (Some((entry_disk_id, _)), Some(original))
+                if entry_disk_id.eq_ignore_ascii_case(original) =>
+            {
+                let indent = &line[..line.len() - device.len()];
+                patched.push_str(indent);
+                patched.push_str(PREFIX);
+                patched.push_str(disk_id);
+                patched.push_str(&device[PREFIX.len() + 8..]);
+            }
+            (Some(_), _) => {
+                tracing::warn!(
+                    "Not patching `fstab` entry referring to another disk: {}",
+                    device.trim_end()
+                );
+                patched.push_str(line);
+            }
+            (None, _) => {
+                patched.push_str(line);
+            }
+        }
+    }
+    patched
+}
+
+/// Patches the `fstab` at the given path to refer to partitions of the given MBR disk.
+///
+/// See [`patch_fstab`] for details.
+pub fn patch_fstab_file(path: impl AsRef<Path>, disk_id: &str) -> io::Result<()> {
+    let path = path.as_ref();
+    let fstab = fs::read_to_string(path)?;
+    fs::write(path, patch_fstab(&fstab, disk_id))
+}
+
 /// Runs a closure on drop.
 pub struct DropGuard<F: FnOnce()>(Option<F>);
 
@@ -122,3 +226,59 @@ impl<F: FnOnce()> Drop for DropGuard<F> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patch_cmdline_extra() {
+        let extra = [
+            "cgroup_enable=memory".to_owned(),
+            "cgroup_memory=1".to_owned(),
+            "console=tty1".to_owned(),
+            "cgroup_enable=memory".to_owned(),
+        ];
+        let cmdline = rpi_patch_cmdline(
+            "console=tty1 root=/dev/mmcblk0p2 quiet",
+            "LABEL=system",
+            &extra,
+        );
+        assert_eq!(
+            cmdline,
+            "console=tty1 cgroup_enable=memory cgroup_memory=1 panic=60 root=LABEL=system init=/usr/bin/rugix-ctrl"
+        );
+        // Patching again must not duplicate any parameters.
+        assert_eq!(rpi_patch_cmdline(&cmdline, "LABEL=system", &extra), cmdline);
+    }
+
+    #[test]
+    fn test_patch_fstab() {
+        let fstab = "proc            /proc           proc    defaults          0       0\n\
+            PARTUUID=e5d3a2f4-01  /boot/firmware  vfat    defaults          0       2\n\
+            \tPARTUUID=E5D3A2F4-02  /  ext4  defaults,noatime  0  1\n\
+            PARTUUID=e5d3a2f4-03 /config vfat ro 0 2\n\
+            # PARTUUID=e5d3a2f4-04 /data ext4 defaults 0 2\n\
+            PARTUUID=0f2c3d4e-5a6b-4c7d-8e9f-0a1b2c3d4e5f /efi vfat defaults 0 2\n\
+            PARTUUID=aabbccdd-01 /mnt/usb ext4 defaults,nofail 0 2";
+        assert_eq!(
+            patch_fstab(fstab, "1234abcd"),
+            "proc            /proc           proc    defaults          0       0\n\
+            PARTUUID=1234abcd-01  /boot/firmware  vfat    defaults          0       2\n\
+            \tPARTUUID=1234abcd-02  /  ext4  defaults,noatime  0  1\n\
+            PARTUUID=1234abcd-03 /config vfat ro 0 2\n\
+            # PARTUUID=e5d3a2f4-04 /data ext4 defaults 0 2\n\
+            PARTUUID=0f2c3d4e-5a6b-4c7d-8e9f-0a1b2c3d4e5f /efi vfat defaults 0 2\n\
+            PARTUUID=aabbccdd-01 /mnt/usb ext4 defaults,nofail 0 2"
+        );
+    }
+
+    #[test]
+    fn test_patch_cmdline_extra_no_override() {
+        let extra = ["root=/dev/sda1".to_owned(), "init=/bin/sh".to_owned()];
+        assert_eq!(
+            rpi_patch_cmdline("console=tty1", "LABEL=system", &extra),
+            "console=tty1 panic=60 root=LABEL=system init=/usr/bin/rugix-ctrl"
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
 
 use reportify::{Report, ResultExt};
@@ -11,6 +11,8 @@ reportify::new_whatever_type! {
 
 pub struct Mounted {
     path: PathBuf,
+    /// Indicates whether the mount point should be removed after unmounting.
+    remove_mount_point: bool,
 }
 
 impl Mounted {
@@ -24,7 +26,10 @@ impl Mounted {
             .whatever("unable to mount filesystem")
             .with_info(|_| format!("dev: {dev:?}"))
             .with_info(|_| format!("dst: {dst:?}"))?;
-        Ok(Mounted { path: dst.into() })
+        Ok(Mounted {
+            path: dst.into(),
+            remove_mount_point: false,
+        })
     }
 
     pub fn path(&self) -> &Path {
@@ -51,7 +56,10 @@ impl Mounted {
         .with_info(|_| format!("src: {src:?}"))
         .with_info(|_| format!("dst: {dst:?}"))
         .with_info(|_| format!("fstype: {fstype}"))?;
-        Ok(Mounted { path: dst.into() })
+        Ok(Mounted {
+            path: dst.into(),
+            remove_mount_point: false,
+        })
     }
 
     pub fn bind(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<Self, Report<MountError>> {
@@ -68,18 +76,101 @@ impl Mounted {
         .whatever("unable to bind mount")
         .with_info(|_| format!("src: {src:?}"))
         .with_info(|_| format!("dst: {dst:?}"))?;
-        Ok(Mounted { path: dst.into() })
+        Ok(Mounted {
+            path: dst.into(),
+            remove_mount_point: false,
+        })
+    }
+
+    /// Bind mount the file *src* to *dst*.
+    ///
+    /// If *dst* does not exist, an empty file is created as mount point, which is removed
+    /// again after unmounting.
+    pub fn bind_file(
+        src: impl AsRef<Path>,
+        dst: impl AsRef<Path>,
+    ) -> Result<Self, Report<MountError>> {
+        let dst = dst.as_ref();
+        if std::fs::symlink_metadata(dst).is_ok() {
+            return Self::bind(src, dst);
+        }
+        std::fs::write(dst, "")
+            .whatever("unable to create mount point")
+            .with_info(|_| format!("dst: {dst:?}"))?;
+        match Self::bind(src, dst) {
+            Ok(mut mounted) => {
+                mounted.remove_mount_point = true;
+                Ok(mounted)
+            }
+            Err(error) => {
+                std::fs::remove_file(dst).ok();
+                Err(error)
+            }
+        }
+    }
+
+    /// Mount an overlay filesystem with the given lower, upper, and work directory.
+    ///
+    /// The upper and work directory must reside on the same filesystem.
+    pub fn overlay(
+        lower: impl AsRef<Path>,
+        upper: impl AsRef<Path>,
+        work: impl AsRef<Path>,
+        dst: impl AsRef<Path>,
+    ) -> Result<Self, Report<MountError>> {
+        let dst = dst.as_ref();
+        let lower = lower.as_ref();
+        let upper = upper.as_ref();
+        let work = work.as_ref();
+        debug!("Mounting overlay of {lower:?} and {upper:?} to {dst:?}.");
+        let mut options = OsString::from("lowerdir=");
+        options.push(lower);
+        options.push(",upperdir=");
+        options.push(upper);
+        options.push(",workdir=");
+        options.push(work);
+        nix::mount::mount(
+            Some("overlay"),
+            dst,
+            Some("overlay"),
+            nix::mount::MsFlags::empty(),
+            Some(options.as_os_str()),
+        )
+        .whatever("unable to mount overlay filesystem")
+        .with_info(|_| format!("lower: {lower:?}"))
+        .with_info(|_| format!("upper: {upper:?}"))
+        .with_info(|_| format!("dst: {dst:?}"))?;
+        Ok(Mounted {
+            path: dst.into(),
+            remove_mount_point: false,
+        })
     }
 }
 
 impl Drop for Mounted {
     fn drop(&mut self) {
         if let Err(error) = nix::mount::umount(&self.path) {
-            eprintln!("Error unmounting {:?}: {:?}", self.path, error)
+            // The filesystem may still be busy, e.g., because a process spawned in a
+            // chroot is still running. Detach it lazily such that it is at least gone
+            // from the mount namespace and unmounted once it is no longer in use.
+            debug!("Unmounting {:?} failed ({error}), detaching.", self.path);
+            if let Err(error) = nix::mount::umount2(&self.path, nix::mount::MntFlags::MNT_DETACH) {
+                eprintln!("Error unmounting {:?}: {:?}", self.path, error)
+            }
+        }
+        if self.remove_mount_point {
+            if let Err(error) = std::fs::remove_file(&self.path) {
+                eprintln!("Error removing mount point {:?}: {:?}", self.path, error)
+            }
         }
     }
 }
 
+/// Stack of mounted filesystems which are unmounted in reverse order.
+///
+/// Mounts are unmounted in LIFO order when [`MountStack::unmount_all`] is called or when
+/// the stack is dropped. As dropping also happens during unwinding, this ensures that
+/// nested mounts are cleaned up properly when a task panics or is canceled.
 pub struct MountStack(Vec<Mounted>);
 
 impl MountStack {
@@ -95,6 +186,7 @@ impl MountStack {
         self.0.push(mounted);
     }
 
+    /// Unmount all filesystems in reverse order of mounting.
     pub fn unmount_all(&mut self) {
         while let Some(top) = self.0.pop() {
             drop(top);
@@ -102,6 +194,12 @@ impl MountStack {
     }
 }
 
+impl Default for MountStack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Drop for MountStack {
     fn drop(&mut self) {
         self.unmount_all()
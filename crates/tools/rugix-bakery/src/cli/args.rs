@@ -2,10 +2,18 @@
 
 use std::path::PathBuf;
 
-use clap::Parser;
+use byte_calc::NumBytes;
+use clap::{Parser, ValueEnum};
+use tracing::level_filters::LevelFilter;
+use url::Url;
 
+use crate::config::images::{Filesystem, PartitionTableType};
 use crate::config::systems::Architecture;
-use crate::oven::BundleOpts;
+use crate::oven::{
+    parse_partition_table, parse_root_fs, BundleOpts, ParallelOpts, ResumeOpts, SystemOpts,
+};
+use crate::project::lint::Severity;
+use crate::utils::compression::Compression;
 
 /// Command line arguments.
 #[derive(Debug, Parser)]
@@ -14,11 +22,131 @@ pub struct Args {
     /// Path to the `rugix-bakery.toml` configuration file.
     #[clap(long)]
     pub config: Option<PathBuf>,
+    /// Only reload recipes and layers of repositories which have changed.
+    #[clap(long)]
+    pub only_changed_repos: bool,
+    /// Profile of the configuration to apply.
+    #[clap(long)]
+    pub profile: Option<String>,
+    /// Treat configuration warnings as errors.
+    #[clap(long)]
+    pub strict: bool,
+    /// Register static QEMU emulators with `binfmt_misc` for foreign architectures.
+    #[clap(long)]
+    pub register_emulators: bool,
+    /// Reject unknown keys in configuration files.
+    #[clap(long)]
+    pub validate_schema: bool,
+    /// Fail if a layer applies more than the given number of recipes.
+    #[clap(long)]
+    pub max_recipes: Option<usize>,
+    /// Directory to cache downloaded files and layers in (defaults to `.rugix/cache`).
+    #[clap(long)]
+    pub cache_dir: Option<PathBuf>,
+    /// Directory to create temporary directories in (defaults to `$RUGIX_WORK_DIR` or
+    /// the system's temporary directory).
+    #[clap(long)]
+    pub work_dir: Option<PathBuf>,
+    /// Reuse and store layers built from identical inputs in the cache directory.
+    #[clap(long)]
+    pub layer_cache: bool,
+    /// Output format for warnings and errors.
+    ///
+    /// Defaults to `github` when running within GitHub Actions.
+    #[clap(long)]
+    pub output: Option<OutputFormat>,
+    /// Whether to use colors for the console output.
+    #[clap(long, default_value = "auto")]
+    pub color: ColorMode,
+    /// Write a JSON summary of the build to the given path.
+    #[clap(long)]
+    pub summary: Option<PathBuf>,
+    /// Write the durations of all recipe steps as JSON to the given path.
+    ///
+    /// Applies to the `bake`, `test`, and `run` commands.
+    #[clap(long)]
+    pub timings_json: Option<PathBuf>,
+    /// Print more details, e.g., the commands run by the steps of recipes.
+    #[clap(short, long, global = true)]
+    pub verbose: bool,
+    /// Only print warnings and errors.
+    #[clap(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
     /// The command to execute.
     #[clap(subcommand)]
     pub cmd: Command,
 }
 
+impl Args {
+    /// The maximal level of log messages to print.
+    pub fn log_level(&self) -> LevelFilter {
+        if self.quiet {
+            LevelFilter::WARN
+        } else if self.verbose {
+            LevelFilter::DEBUG
+        } else {
+            LevelFilter::INFO
+        }
+    }
+
+    /// The output format for warnings and errors.
+    pub fn output_format(&self) -> OutputFormat {
+        self.output.unwrap_or_else(|| {
+            if rugix_cli::github::is_github_actions() {
+                OutputFormat::Github
+            } else {
+                OutputFormat::Console
+            }
+        })
+    }
+
+    /// Whether to use colors for the console output.
+    ///
+    /// Colors are disabled when printing JSON, as the escape sequences would otherwise
+    /// end up in the JSON.
+    pub fn color_choice(&self) -> rugix_cli::ColorChoice {
+        let json = match &self.cmd {
+            Command::LintRecipes(cmd) => cmd.json,
+            _ => false,
+        };
+        if json {
+            rugix_cli::ColorChoice::Never
+        } else {
+            self.color.into()
+        }
+    }
+}
+
+/// Output format for warnings and errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable console output.
+    Console,
+    /// Additionally emit GitHub Actions workflow commands.
+    Github,
+}
+
+/// Whether to use colors for the console output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ColorMode {
+    /// Use colors if the terminal supports them and `NO_COLOR` is not set.
+    Auto,
+    /// Always use colors.
+    Always,
+    /// Never use colors.
+    Never,
+}
+
+impl From<ColorMode> for rugix_cli::ColorChoice {
+    fn from(mode: ColorMode) -> Self {
+        match mode {
+            ColorMode::Auto => rugix_cli::ColorChoice::Auto,
+            ColorMode::Always => rugix_cli::ColorChoice::Always,
+            ColorMode::Never => rugix_cli::ColorChoice::Never,
+        }
+    }
+}
+
 /// Commands of the CLI.
 #[derive(Debug, Parser)]
 pub enum Command {
@@ -36,10 +164,106 @@ pub enum Command {
     Pull,
     /// Initialize the project from a template.
     Init(InitCommand),
+    /// Scaffold a new repository with example recipes and layers.
+    InitRepository(InitRepositoryCommand),
     /// Spawn a shell in the Rugix Bakery Docker container.
     Shell,
     /// Run Rugix Bundler.
     Bundler(BundlerCommand),
+    /// Check whether the host prerequisites are available.
+    Doctor,
+    /// Check recipes for problems and lint their shell scripts with `shellcheck`.
+    #[clap(alias = "lint")]
+    LintRecipes(LintRecipesCommand),
+    /// Grow an existing image and its system partition.
+    Resize(ResizeCommand),
+    /// Verify that an image matches the layout of its system.
+    Verify(VerifyCommand),
+    /// Remove intermediate build state and detach leftover loop devices.
+    Clean(CleanCommand),
+    /// Inspect the configuration of the project.
+    #[clap(subcommand)]
+    Config(ConfigCommand),
+    /// Print the recipes of a layer and their dependencies as a graph.
+    Graph(GraphCommand),
+}
+
+/// The `graph` command.
+#[derive(Debug, Parser)]
+pub struct GraphCommand {
+    /// The architecture to resolve the recipes for.
+    #[clap(long)]
+    pub arch: Architecture,
+    /// The name of the layer.
+    pub layer: String,
+    /// Output format.
+    #[clap(long, default_value = "dot")]
+    pub format: GraphFormat,
+}
+
+/// Output format of the `graph` command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GraphFormat {
+    /// Graphviz DOT.
+    Dot,
+    /// JSON for tooling.
+    Json,
+}
+
+/// The `config` command.
+#[derive(Debug, Parser)]
+pub enum ConfigCommand {
+    /// Validate the configuration files of the project without building anything.
+    Check,
+}
+
+/// The `clean` command.
+#[derive(Debug, Parser)]
+pub struct CleanCommand {
+    /// Only list what would be removed and detached.
+    #[clap(long)]
+    pub dry_run: bool,
+    /// Also remove the cache and the checkouts of external repositories.
+    #[clap(long)]
+    pub all: bool,
+}
+
+/// The `verify` command.
+#[derive(Debug, Parser)]
+pub struct VerifyCommand {
+    /// The name of the system the image has been baked from.
+    pub system: String,
+    /// Path to the image to verify.
+    pub image: PathBuf,
+    /// Partition table the image has been baked with (`mbr` or `gpt`).
+    #[clap(long, value_parser = parse_partition_table)]
+    pub partition_table: Option<PartitionTableType>,
+    /// Root filesystem the image has been baked with (`ext4`, `f2fs`, or `btrfs`).
+    #[clap(long, value_parser = parse_root_fs)]
+    pub root_fs: Option<Filesystem>,
+}
+
+/// The `resize` command.
+#[derive(Debug, Parser)]
+pub struct ResizeCommand {
+    /// Path to the image to grow.
+    pub image: PathBuf,
+    /// New size of the image (e.g., `8GiB`).
+    pub size: NumBytes,
+}
+
+/// The `lint-recipes` command.
+#[derive(Debug, Parser)]
+pub struct LintRecipesCommand {
+    /// Also lint recipes of external repositories.
+    #[clap(long)]
+    pub all: bool,
+    /// Minimal severity of findings that cause the lint to fail.
+    #[clap(long, default_value = "error")]
+    pub fail_on: Severity,
+    /// Output the findings as JSON.
+    #[clap(long)]
+    pub json: bool,
 }
 
 /// The `list` command.
@@ -47,6 +271,39 @@ pub enum Command {
 pub enum ListCommand {
     /// List available images.
     Systems,
+    /// List loaded repositories.
+    Repositories {
+        /// Output format.
+        #[clap(long, default_value = "text")]
+        format: ListFormat,
+    },
+    /// List available recipes with their parameters.
+    Recipes {
+        /// Only list the recipes of the given repository (e.g., `core`).
+        #[clap(long)]
+        repository: Option<String>,
+        /// Show whether the recipes are applied or excluded by the given layer.
+        #[clap(long, requires = "arch")]
+        layer: Option<String>,
+        /// The architecture to resolve the recipes of the layer for.
+        #[clap(long, requires = "layer")]
+        arch: Option<Architecture>,
+        /// Only list the recipes applied by the layer.
+        #[clap(long, requires = "layer")]
+        default_only: bool,
+        /// Output format.
+        #[clap(long, default_value = "text")]
+        format: ListFormat,
+    },
+}
+
+/// Output format of the `list` command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ListFormat {
+    /// Human-readable text.
+    Text,
+    /// JSON for tooling.
+    Json,
 }
 
 /// The `bake` command.
@@ -58,19 +315,108 @@ pub enum BakeCommand {
         system: String,
         /// The output path for the resulting files.
         output: Option<PathBuf>,
+        /// Overwrite an existing output without asking.
+        #[clap(long)]
+        force: bool,
+        /// Print the kernel command line of the image and exit without baking it.
+        #[clap(long)]
+        print_cmdline: bool,
+        /// Compress the image with the given format.
+        #[clap(long)]
+        compress: Option<Compression>,
+        /// Compression level passed to the compressor (e.g., `9` for `xz -9`).
+        #[clap(long, requires = "compress")]
+        compress_level: Option<u32>,
+        /// Keep the uncompressed image next to the compressed one.
+        #[clap(long, requires = "compress")]
+        keep_uncompressed: bool,
+        /// Write the image manifest to the given path (defaults to next to the image).
+        #[clap(long)]
+        manifest: Option<PathBuf>,
+        #[clap(flatten)]
+        system_opts: SystemOpts,
     },
     /// Bake a layer.
     Layer {
         /// The architecture to bake the layer for.
         #[clap(long)]
         arch: Architecture,
+        /// The CPU variant of the architecture exposed to recipes.
+        #[clap(long)]
+        arch_variant: Option<String>,
         /// The name of the layer to bake.
         layer: String,
+        #[clap(flatten)]
+        resume: ResumeOpts,
+        /// Directory to copy over the root filesystem after applying all recipes.
+        ///
+        /// May be given multiple times, later overlays take precedence.
+        #[clap(long = "overlay-dir")]
+        overlay_dirs: Vec<PathBuf>,
+        /// Directory to additionally write the output of each recipe step to.
+        #[clap(long)]
+        log_dir: Option<PathBuf>,
+        /// Replace identical files with hardlinks before packing the layer.
+        #[clap(long)]
+        dedup_files: bool,
+        /// Fail if the layer contains setuid or setgid files not on the given allowlist
+        /// (one absolute path per line).
+        #[clap(long)]
+        audit_setuid: Option<PathBuf>,
+        #[clap(flatten)]
+        parallel: ParallelOpts,
+        /// Previous layer archive to additionally create a delta archive against.
+        ///
+        /// The delta archive is written to `system.delta.tar` next to the layer.
+        #[clap(long)]
+        from: Option<PathBuf>,
+        /// Only print the recipes which would be applied or, with `--only`, the changes
+        /// the given recipe would make.
+        #[clap(long)]
+        dry_run: bool,
+        /// Recipe to preview with `--dry-run`.
+        #[clap(long, requires = "dry_run")]
+        only: Option<String>,
+        /// Directory to additionally store the layer in, named by its content hash.
+        #[clap(long)]
+        dest_hashed: Option<PathBuf>,
+        /// Environment file with additional variables for recipe steps (`KEY=value` lines).
+        #[clap(long)]
+        env_file: Option<PathBuf>,
+        /// TOML or JSON file with recipe parameters overriding those of the layer.
+        #[clap(long)]
+        params_file: Option<PathBuf>,
+        /// APT mirror to install packages from instead of the configured sources.
+        #[clap(long)]
+        mirror: Option<Url>,
+        /// Host of the APT sources served by the mirror (defaults to the Debian and
+        /// Raspberry Pi OS hosts).
+        #[clap(long = "mirror-host", requires = "mirror")]
+        mirror_hosts: Vec<String>,
+        /// Number of retries of package installations failing due to network problems
+        /// (defaults to 2).
+        #[clap(long)]
+        apt_retries: Option<u32>,
+        /// Only extract and apply the recipes to the given subtree of the root filesystem.
+        ///
+        /// Recipes must not have `packages` or `install` steps.
+        #[clap(long, conflicts_with = "keep_work_dir")]
+        subtree: Option<PathBuf>,
+        /// Write a SHA-256 checksum file next to the layer archive.
+        ///
+        /// The checksum is verified before the layer is extracted to bake an image.
+        #[clap(long)]
+        checksum: bool,
     },
     /// Bake a bundle.
     Bundle {
         system: String,
         output: Option<PathBuf>,
+        /// Overwrite an existing output without asking.
+        #[clap(long)]
+        force: bool,
+        #[clap(flatten)]
+        system_opts: SystemOpts,
         /// Disable compression of the bundle.
         #[clap(flatten)]
         opts: BundleOpts,
@@ -106,6 +452,16 @@ pub struct InitCommand {
     pub template: Option<String>,
 }
 
+/// The `init-repository` command.
+#[derive(Debug, Parser)]
+pub struct InitRepositoryCommand {
+    /// Directory to create the repository in.
+    pub dir: PathBuf,
+    /// Initialize the repository even if the directory is not empty.
+    #[clap(long)]
+    pub force: bool,
+}
+
 /// The `bundler` command.
 #[derive(Debug, Parser)]
 pub struct BundlerCommand {
@@ -1,8 +1,16 @@
 pub mod run_bake;
 pub mod run_bundler;
+pub mod run_clean;
+pub mod run_config;
+pub mod run_doctor;
+pub mod run_graph;
 pub mod run_init;
+pub mod run_init_repository;
+pub mod run_lint_recipes;
 pub mod run_list;
 pub mod run_pull;
+pub mod run_resize;
 pub mod run_run;
 pub mod run_shell;
 pub mod run_test;
+pub mod run_verify;
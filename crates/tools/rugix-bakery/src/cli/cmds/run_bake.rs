@@ -1,43 +1,299 @@
 //! The `bake` command.
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
-use reportify::ResultExt;
+use colored::Colorize;
+use reportify::{bail, ResultExt};
 
+use crate::cli::summary::{self, BuildSummary};
 use crate::cli::{args, load_project};
+use crate::config::systems::Architecture;
+use crate::oven::customize::{load_params_file, load_step_env, ParameterOverrides, RecipeJob};
 use crate::oven::LayerBakery;
+use crate::project::recipes::StepKind;
+use crate::project::repositories::ProjectRepositories;
+use crate::project::ProjectRef;
+use crate::utils::caching::store_hashed;
+use crate::utils::compression::compress_file;
+use crate::utils::tree_diff::TreeDiff;
 use crate::{oven, BakeryResult};
 
 /// Run the `bake` command.
 pub fn run(args: &args::Args, cmd: &args::BakeCommand) -> BakeryResult<()> {
-    let project = load_project(args)?;
+    let start = Instant::now();
+    let project = load_project(args);
+    let stats = project
+        .as_ref()
+        .map(|project| project.stats().clone())
+        .unwrap_or_default();
+    let result = project.and_then(|project| bake(&project, cmd));
+    if let Ok(artifacts) = &result {
+        for artifact in artifacts {
+            rugix_cli::cli_msg!("{} {}", "Baked".green().bold(), artifact.display());
+        }
+    }
+    if let Some(summary_path) = &args.summary {
+        BuildSummary::new(&result, start.elapsed(), &stats).write(summary_path)?;
+    }
+    if let Some(timings_path) = &args.timings_json {
+        summary::write_timings(timings_path, &stats)?;
+    }
+    result.map(|_| ())
+}
+
+/// Bake the requested artifact and return the paths of the produced artifacts.
+fn bake(project: &ProjectRef, cmd: &args::BakeCommand) -> BakeryResult<Vec<PathBuf>> {
+    let mut artifacts = Vec::new();
     match cmd {
-        args::BakeCommand::Image { system, output } => {
-            let system_path = Path::new("build").join(system);
-            oven::bake_system(&project, system, &system_path)?;
+        args::BakeCommand::Image {
+            system,
+            output,
+            force,
+            print_cmdline,
+            compress,
+            compress_level,
+            keep_uncompressed,
+            manifest,
+            system_opts,
+        } => {
+            if *print_cmdline {
+                let cmdline = oven::preview_cmdline(project, system, system_opts)?;
+                rugix_cli::suspend(|| println!("{cmdline}"));
+                return Ok(artifacts);
+            }
             if let Some(output) = output {
-                if let Some(parent) = output.parent() {
-                    std::fs::create_dir_all(parent).ok();
+                match compress {
+                    Some(compression) => {
+                        check_overwrite(&compression.compressed_path(output), *force)?;
+                        if *keep_uncompressed {
+                            check_overwrite(output, *force)?;
+                        }
+                    }
+                    None => check_overwrite(output, *force)?,
                 }
-                std::fs::copy(system_path.join("system.img"), output)
-                    .whatever("error copying image")?;
+            }
+            let system_path = Path::new("build").join(system);
+            let layer_path = oven::bake_system(project, system, &system_path, system_opts)?;
+            let image_path = system_path.join("system.img");
+            if let Some(parent) = output.as_ref().and_then(|output| output.parent()) {
+                std::fs::create_dir_all(parent).ok();
+            }
+            let manifest_path = manifest.clone().unwrap_or_else(|| {
+                let produced = output.as_deref().unwrap_or(&image_path);
+                match compress {
+                    Some(compression) if !*keep_uncompressed => {
+                        manifest_sidecar(&compression.compressed_path(produced))
+                    }
+                    _ => manifest_sidecar(produced),
+                }
+            });
+            // The manifest must be written before the uncompressed image is removed.
+            oven::write_image_manifest(
+                project,
+                system,
+                &image_path,
+                &layer_path,
+                &manifest_path,
+                system_opts,
+            )?;
+            if let Some(compression) = compress {
+                let compressed =
+                    compression.compressed_path(output.as_deref().unwrap_or(&image_path));
+                compress_file(&image_path, &compressed, *compression, *compress_level)?;
+                artifacts.push(compressed);
+            }
+            if compress.is_none() || *keep_uncompressed {
+                artifacts.push(image_path.clone());
+                if let Some(output) = output {
+                    std::fs::copy(&image_path, output).whatever("error copying image")?;
+                    artifacts.push(output.clone());
+                }
+            } else if output.is_none() {
+                std::fs::remove_file(&image_path)
+                    .whatever("unable to remove uncompressed image")?;
             }
         }
-        args::BakeCommand::Layer { layer, arch } => {
-            LayerBakery::new(&project, *arch).bake_root(layer)?;
+        args::BakeCommand::Layer {
+            layer,
+            arch,
+            arch_variant,
+            resume,
+            overlay_dirs,
+            log_dir,
+            dedup_files,
+            audit_setuid,
+            parallel,
+            from,
+            dry_run,
+            only,
+            dest_hashed,
+            env_file,
+            params_file,
+            mirror,
+            mirror_hosts,
+            apt_retries,
+            subtree,
+            checksum,
+        } => {
+            let env = match env_file {
+                Some(env_file) => load_step_env(env_file)?,
+                None => Vec::new(),
+            };
+            let parameters = match params_file {
+                Some(params_file) => load_params_file(params_file)?,
+                None => ParameterOverrides::new(),
+            };
+            if let (true, Some(recipe)) = (*dry_run, only) {
+                let diff = LayerBakery::new(project, *arch)
+                    .with_arch_variant(arch_variant.clone())
+                    .with_log_dir(log_dir.clone())
+                    .with_env(env)
+                    .with_parameters(parameters)
+                    .with_apt_mirror(mirror.clone())
+                    .with_apt_mirror_hosts(mirror_hosts.clone())
+                    .with_apt_retries(*apt_retries)
+                    .preview_recipe(layer, recipe)?;
+                rugix_cli::suspend(|| print_tree_diff(&diff));
+                return Ok(artifacts);
+            }
+            if *dry_run {
+                let jobs = LayerBakery::new(project, *arch)
+                    .with_parameters(parameters)
+                    .plan_recipes(layer)?;
+                let repositories = project.repositories()?;
+                rugix_cli::suspend(|| print_recipe_plan(&repositories, *arch, &jobs));
+                return Ok(artifacts);
+            }
+            let layer_path = LayerBakery::new(project, *arch)
+                .with_arch_variant(arch_variant.clone())
+                .with_resume(resume.clone())
+                .with_overlay_dirs(overlay_dirs.clone())
+                .with_log_dir(log_dir.clone())
+                .with_dedup_files(*dedup_files)
+                .with_audit_setuid(audit_setuid.clone())
+                .with_parallel_recipes(parallel.max_jobs())
+                .with_env(env)
+                .with_parameters(parameters)
+                .with_apt_mirror(mirror.clone())
+                .with_apt_mirror_hosts(mirror_hosts.clone())
+                .with_apt_retries(*apt_retries)
+                .with_subtree(subtree.clone())
+                .with_checksum(*checksum)
+                .bake_root(layer)?;
+            if let Some(from) = from {
+                let delta_path = layer_path.with_file_name("system.delta.tar");
+                oven::delta::make_delta(from, &layer_path, &delta_path)?;
+                artifacts.push(delta_path);
+            }
+            if let Some(dest_hashed) = dest_hashed {
+                artifacts.push(store_hashed(&layer_path, dest_hashed)?);
+            }
+            artifacts.push(layer_path);
         }
         args::BakeCommand::Bundle {
             system,
             output,
+            force,
+            system_opts,
             opts,
         } => {
+            if let Some(output) = output {
+                check_overwrite(output, *force)?;
+            }
             let system_path = Path::new("build").join(system);
-            oven::bake_system(&project, system, &system_path)?;
+            oven::bake_system(project, system, &system_path, system_opts)?;
             let output = output
                 .clone()
                 .unwrap_or_else(|| system_path.join("system.rugixb"));
-            oven::bake_bundle(&project, system, &system_path, &output, opts)?;
+            oven::bake_bundle(project, system, &system_path, &output, system_opts, opts)?;
+            artifacts.push(output);
+        }
+    }
+    Ok(artifacts)
+}
+
+/// Path of the manifest sidecar of the given image, i.e., `<image>.manifest.json`.
+fn manifest_sidecar(image: &Path) -> PathBuf {
+    let mut path = image.as_os_str().to_owned();
+    path.push(".manifest.json");
+    PathBuf::from(path)
+}
+
+/// Check whether the given output may be overwritten.
+///
+/// Existing outputs are only overwritten with *force* or after confirmation by the user.
+fn check_overwrite(output: &Path, force: bool) -> BakeryResult<()> {
+    if force || !output.exists() {
+        return Ok(());
+    }
+    if rugix_cli::is_attended() {
+        let confirmed = rugix_cli::suspend(|| {
+            eprint!("Output {output:?} already exists. Overwrite? [y/N] ");
+            let mut answer = String::new();
+            std::io::stdin().read_line(&mut answer).ok();
+            matches!(answer.trim(), "y" | "Y" | "yes")
+        });
+        if confirmed {
+            return Ok(());
+        }
+    }
+    bail!("output {output:?} already exists, use `--force` to overwrite it");
+}
+
+/// Print the recipes in the order they are applied.
+///
+/// For each recipe, the repository, phase, priority, resolved parameters, and steps are
+/// shown.
+/// Steps which do not apply to the given architecture are marked as skipped.
+fn print_recipe_plan(repositories: &ProjectRepositories, arch: Architecture, jobs: &[RecipeJob]) {
+    for (idx, job) in jobs.iter().enumerate() {
+        let recipe = &job.recipe;
+        println!(
+            "{}. {} (repository: {}, phase: {}, priority: {})",
+            idx + 1,
+            recipe.name,
+            repositories.repositories[recipe.repository]
+                .source
+                .id
+                .as_str(),
+            recipe.phase().name(),
+            recipe.config.priority.unwrap_or_default()
+        );
+        let mut parameters = job.parameters.iter().collect::<Vec<_>>();
+        parameters.sort();
+        for (name, value) in parameters {
+            println!("   parameter {name} = {value:?}");
+        }
+        for step in &recipe.steps {
+            let kind = match &step.kind {
+                StepKind::Packages { packages, .. } => {
+                    format!("packages: {}", packages.join(" "))
+                }
+                StepKind::Install => "install".to_owned(),
+                StepKind::Run => "run".to_owned(),
+                StepKind::Copy { .. } => "copy".to_owned(),
+            };
+            if step.applies_to(arch) {
+                println!("   step {} ({kind})", step.filename);
+            } else {
+                println!("   step {} ({kind}, skipped: arch)", step.filename);
+            }
+        }
+    }
+}
+
+/// Print the changes of a recipe with one path per line.
+///
+/// Each path is prefixed with `A` (added), `M` (modified), or `D` (removed).
+fn print_tree_diff(diff: &TreeDiff) {
+    for (status, paths) in [
+        ("A", &diff.added),
+        ("M", &diff.modified),
+        ("D", &diff.removed),
+    ] {
+        for path in paths {
+            println!("{status} /{}", path.display());
         }
     }
-    Ok(())
 }
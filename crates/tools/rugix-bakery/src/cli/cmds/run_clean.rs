@@ -0,0 +1,276 @@
+//! The `clean` command.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use reportify::ResultExt;
+use tracing::{info, warn};
+use xscript::{read_str, run, Run};
+
+use crate::cli::{args, current_dir};
+use crate::utils::temp;
+use crate::BakeryResult;
+
+/// Paths in the `.rugix` directory of the project which are removed by default.
+///
+/// These paths only contain intermediate build state, which is recreated as needed.
+const STATE_PATHS: &[&str] = &[
+    "layers",
+    "preview",
+    "library",
+    "vm-image.img",
+    "vm-stdout.log",
+    "vm-stderr.log",
+    "test.log",
+];
+
+/// Run the `clean` command.
+pub fn run(args: &args::Args, cmd: &args::CleanCommand) -> BakeryResult<()> {
+    // Loop devices and mount points are reported with canonical paths.
+    let project_dir =
+        fs::canonicalize(current_dir()?).whatever("unable to canonicalize project directory")?;
+    let rugix_dir = project_dir.join(".rugix");
+    let mut paths = STATE_PATHS
+        .iter()
+        .map(|path| rugix_dir.join(path))
+        .collect::<Vec<_>>();
+    if cmd.all {
+        match &args.cache_dir {
+            Some(cache_dir) => {
+                let cache_dir = project_dir.join(cache_dir);
+                let cache_dir = fs::canonicalize(&cache_dir).unwrap_or(cache_dir);
+                // The cache directory may be shared with other projects.
+                if cache_dir.starts_with(&project_dir) {
+                    paths.push(cache_dir);
+                } else {
+                    warn!("Not removing cache directory {cache_dir:?} outside of the project.");
+                }
+            }
+            None => paths.push(rugix_dir.join("cache")),
+        }
+        paths.push(rugix_dir.join("repositories"));
+    }
+    // Temporary directories of interrupted builds are only removed if they have been
+    // created in the project, as other projects may share the work directory.
+    let work_dir = project_dir.join(temp::work_dir());
+    if work_dir.starts_with(&project_dir) {
+        paths.extend(temp_dirs(&work_dir)?);
+    }
+    // Interrupted builds may leave filesystems mounted in kept work directories and
+    // temporary directories, which must be unmounted before removing them.
+    let mount_points = fs::read_to_string("/proc/self/mountinfo")
+        .map(|mountinfo| parse_mount_points(&mountinfo))
+        .unwrap_or_default();
+    for mount_point in mount_points {
+        if !paths.iter().any(|path| mount_point.starts_with(path)) {
+            continue;
+        }
+        if cmd.dry_run {
+            info!("Would unmount {mount_point:?}.");
+        } else {
+            info!("Unmounting {mount_point:?}.");
+            run!(["umount", "--lazy", &mount_point]).whatever("unable to unmount filesystem")?;
+        }
+    }
+    // Detach loop devices first, as they may keep files in the project busy.
+    let loop_devices = read_str!([
+        "losetup",
+        "--list",
+        "--noheadings",
+        "--raw",
+        "--output",
+        "NAME,BACK-FILE"
+    ])
+    .map(|output| parse_loop_devices(&output))
+    .unwrap_or_default();
+    for (device, back_file) in loop_devices {
+        if !back_file.starts_with(&project_dir) {
+            continue;
+        }
+        if cmd.dry_run {
+            info!("Would detach {device} ({back_file:?}).");
+        } else {
+            info!("Detaching {device} ({back_file:?}).");
+            run!(["losetup", "-d", &device]).whatever("unable to detach loop device")?;
+        }
+    }
+    for path in paths {
+        if fs::symlink_metadata(&path).is_err() {
+            continue;
+        }
+        if cmd.dry_run {
+            info!("Would remove {path:?}.");
+        } else {
+            info!("Removing {path:?}.");
+            remove_path(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Temporary directories of Rugix Bakery in the given work directory.
+fn temp_dirs(work_dir: &Path) -> BakeryResult<Vec<PathBuf>> {
+    let Ok(entries) = fs::read_dir(work_dir) else {
+        return Ok(Vec::new());
+    };
+    let mut temp_dirs = Vec::new();
+    for entry in entries {
+        let entry = entry.whatever("unable to read work directory entry")?;
+        let is_temp_dir = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.starts_with(temp::TEMP_DIR_PREFIX));
+        if is_temp_dir && entry.path().is_dir() {
+            temp_dirs.push(entry.path());
+        }
+    }
+    Ok(temp_dirs)
+}
+
+/// Parse the mount points from `/proc/self/mountinfo`.
+///
+/// The mount points are returned with nested mount points first, such that they can be
+/// unmounted in order. Whitespace and backslashes are escaped as octal `\NNN` sequences.
+fn parse_mount_points(mountinfo: &str) -> Vec<PathBuf> {
+    let mut mount_points = mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(|mount_point| PathBuf::from(unescape_octal(mount_point)))
+        .collect::<Vec<_>>();
+    mount_points.sort_by_key(|mount_point| std::cmp::Reverse(mount_point.components().count()));
+    mount_points
+}
+
+/// Unescape `\NNN` sequences in `/proc/self/mountinfo`.
+fn unescape_octal(value: &str) -> String {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'\\' {
+            let escaped = tail
+                .get(..3)
+                .and_then(|octal| std::str::from_utf8(octal).ok())
+                .and_then(|octal| u8::from_str_radix(octal, 8).ok());
+            if let Some(escaped) = escaped {
+                bytes.push(escaped);
+                rest = &tail[3..];
+                continue;
+            }
+        }
+        bytes.push(byte);
+        rest = tail;
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Remove the given file or directory.
+fn remove_path(path: &Path) -> BakeryResult<()> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+    .whatever("unable to remove path")
+    .with_info(|_| format!("path: {path:?}"))
+}
+
+/// Parse the raw output of `losetup --list` into loop devices and their backing files.
+///
+/// In raw mode, `losetup` escapes whitespace in paths with `\x20`-style sequences.
+/// Backing files which have been deleted are marked with ` (deleted)`.
+fn parse_loop_devices(output: &str) -> Vec<(String, PathBuf)> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (device, back_file) = line.split_once(' ')?;
+            let back_file = back_file.strip_suffix(" (deleted)").unwrap_or(back_file);
+            if back_file.is_empty() {
+                return None;
+            }
+            Some((device.to_owned(), PathBuf::from(unescape_raw(back_file))))
+        })
+        .collect()
+}
+
+/// Unescape `\xHH` sequences in the raw output of `losetup`.
+fn unescape_raw(value: &str) -> String {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'\\' && tail.first() == Some(&b'x') {
+            let escaped = tail
+                .get(1..3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            if let Some(escaped) = escaped {
+                bytes.push(escaped);
+                rest = &tail[3..];
+                continue;
+            }
+        }
+        bytes.push(byte);
+        rest = tail;
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{parse_loop_devices, parse_mount_points, temp_dirs};
+
+    #[test]
+    fn test_parse_loop_devices() {
+        let devices = parse_loop_devices(
+            "/dev/loop0 /home/user/project/build/system.img\n\
+            /dev/loop1 /home/user/my\\x20project/system.img (deleted)\n\
+            /dev/loop2 \n",
+        );
+        assert_eq!(
+            devices,
+            [
+                (
+                    "/dev/loop0".to_owned(),
+                    PathBuf::from("/home/user/project/build/system.img")
+                ),
+                (
+                    "/dev/loop1".to_owned(),
+                    PathBuf::from("/home/user/my project/system.img")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_mount_points() {
+        let mount_points = parse_mount_points(
+            "22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw\n\
+            81 22 0:5 / /home/user/my\\040project/.rugix/layers/base/work/root/dev rw - devtmpfs udev rw\n\
+            82 81 0:6 / /home/user/my\\040project/.rugix/layers/base/work/root/dev/pts rw - devpts devpts rw\n",
+        );
+        assert_eq!(
+            mount_points,
+            [
+                PathBuf::from("/home/user/my project/.rugix/layers/base/work/root/dev/pts"),
+                PathBuf::from("/home/user/my project/.rugix/layers/base/work/root/dev"),
+                PathBuf::from("/"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_temp_dirs() {
+        let work_dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(work_dir.path().join("rugix-a1b2c3")).unwrap();
+        std::fs::create_dir(work_dir.path().join("other")).unwrap();
+        std::fs::write(work_dir.path().join("rugix-file"), "").unwrap();
+        assert_eq!(
+            temp_dirs(work_dir.path()).unwrap(),
+            [work_dir.path().join("rugix-a1b2c3")]
+        );
+        assert!(temp_dirs(&work_dir.path().join("missing"))
+            .unwrap()
+            .is_empty());
+    }
+}
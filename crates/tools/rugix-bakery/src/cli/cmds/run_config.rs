@@ -0,0 +1,52 @@
+//! The `config` command.
+
+use reportify::bail;
+use tracing::info;
+
+use crate::cli::{args, project_loader};
+use crate::BakeryResult;
+
+/// Run the `config` command.
+pub fn run(args: &args::Args, cmd: &args::ConfigCommand) -> BakeryResult<()> {
+    match cmd {
+        args::ConfigCommand::Check => check(args),
+    }
+}
+
+/// Validate the project, layer, and recipe configurations.
+///
+/// Unknown keys are always rejected, independently of `--validate-schema`.
+fn check(args: &args::Args) -> BakeryResult<()> {
+    let project = project_loader(args)?.with_validate_schema(true).load()?;
+    let library = project.library()?;
+    let root_repository = library.repositories.root_repository;
+    let mut systems = project
+        .config()
+        .systems
+        .iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    systems.sort_by_key(|(name, _)| *name);
+    let mut missing = Vec::new();
+    for (name, system) in &systems {
+        if library
+            .lookup_layer(root_repository, &system.layer)?
+            .is_none()
+        {
+            missing.push(format!(
+                "system `{name}` uses unknown layer `{}`",
+                system.layer
+            ));
+        }
+    }
+    if !missing.is_empty() {
+        bail!("invalid configuration: {}", missing.join(", "));
+    }
+    info!(
+        "Configuration is valid ({} systems, {} layers, {} recipes).",
+        systems.len(),
+        library.layers.len(),
+        library.recipes.len()
+    );
+    Ok(())
+}
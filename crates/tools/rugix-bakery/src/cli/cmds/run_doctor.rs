@@ -0,0 +1,201 @@
+//! The `doctor` command.
+
+use std::path::Path;
+use std::process::Command;
+
+use colored::Colorize;
+
+use reportify::bail;
+
+use crate::utils::which::find_binary;
+use crate::BakeryResult;
+
+/// Host tool required by Rugix Bakery.
+struct Tool {
+    /// Name of the binary.
+    name: &'static str,
+    /// Argument for obtaining the version of the tool, if supported.
+    version_arg: Option<&'static str>,
+    /// Indicates whether the tool is essential.
+    essential: bool,
+    /// Hint on how to install the tool.
+    hint: &'static str,
+}
+
+/// Tools used by Rugix Bakery.
+const TOOLS: &[Tool] = &[
+    Tool {
+        name: "tar",
+        version_arg: Some("--version"),
+        essential: true,
+        hint: "install the `tar` package",
+    },
+    Tool {
+        name: "xz",
+        version_arg: Some("--version"),
+        essential: true,
+        hint: "install the `xz-utils` package",
+    },
+    Tool {
+        name: "gzip",
+        version_arg: Some("--version"),
+        essential: true,
+        hint: "install the `gzip` package",
+    },
+    Tool {
+        name: "losetup",
+        version_arg: Some("--version"),
+        essential: true,
+        hint: "install the `mount` package",
+    },
+    Tool {
+        name: "mount",
+        version_arg: Some("--version"),
+        essential: true,
+        hint: "install the `mount` package",
+    },
+    Tool {
+        name: "chroot",
+        version_arg: Some("--version"),
+        essential: true,
+        hint: "install the `coreutils` package",
+    },
+    Tool {
+        name: "mkfs.ext4",
+        version_arg: Some("-V"),
+        essential: true,
+        hint: "install the `e2fsprogs` package",
+    },
+    Tool {
+        name: "mkfs.vfat",
+        version_arg: Some("--help"),
+        essential: true,
+        hint: "install the `dosfstools` package",
+    },
+    Tool {
+        name: "mcopy",
+        version_arg: Some("--version"),
+        essential: true,
+        hint: "install the `mtools` package",
+    },
+    Tool {
+        name: "git",
+        version_arg: Some("--version"),
+        essential: false,
+        hint: "install the `git` package (required for Git repositories)",
+    },
+    Tool {
+        name: "mmdebstrap",
+        version_arg: Some("--version"),
+        essential: false,
+        hint: "install the `mmdebstrap` package (required for bootstrapping layers)",
+    },
+    Tool {
+        name: "mkfs.f2fs",
+        version_arg: Some("-V"),
+        essential: false,
+        hint: "install the `f2fs-tools` package (required for F2FS filesystems)",
+    },
+    Tool {
+        name: "sload.f2fs",
+        version_arg: Some("-V"),
+        essential: false,
+        hint: "install the `f2fs-tools` package (required for F2FS filesystems)",
+    },
+    Tool {
+        name: "mkfs.btrfs",
+        version_arg: Some("--version"),
+        essential: false,
+        hint: "install the `btrfs-progs` package (required for Btrfs filesystems)",
+    },
+    Tool {
+        name: "zstd",
+        version_arg: Some("--version"),
+        essential: false,
+        hint: "install the `zstd` package (required for compressing images with zstd)",
+    },
+    Tool {
+        name: "shellcheck",
+        version_arg: Some("--version"),
+        essential: false,
+        hint: "install the `shellcheck` package (required for linting recipes)",
+    },
+    Tool {
+        name: "runuser",
+        version_arg: Some("--version"),
+        essential: false,
+        hint: "install the `util-linux` package (required for recipes with a `user`)",
+    },
+];
+
+/// Data paths used by Rugix Bakery.
+const DATA_PATHS: &[(&str, &str)] = &[
+    (
+        "/usr/share/rugix/repositories/core",
+        "core recipe repository",
+    ),
+    ("/usr/share/rugix/boot", "boot flow files"),
+    ("/usr/share/rugix/pi/firmware", "Raspberry Pi firmware"),
+    ("/usr/share/rugix/templates", "project templates"),
+];
+
+/// Run the `doctor` command.
+pub fn run() -> BakeryResult<()> {
+    let mut missing_essential = 0;
+    rugix_cli::suspend(|| {
+        eprintln!("{}\n", "Host Tools:".bold());
+        for tool in TOOLS {
+            match find_binary(tool.name) {
+                Some(path) => {
+                    let version = tool
+                        .version_arg
+                        .and_then(|arg| read_version(&path, arg))
+                        .unwrap_or_default();
+                    eprintln!("  {} {} {}", "✓".green(), tool.name, version.bright_black());
+                }
+                None => {
+                    if tool.essential {
+                        missing_essential += 1;
+                        eprintln!("  {} {}", "✗".red(), tool.name);
+                    } else {
+                        eprintln!("  {} {}", "!".yellow(), tool.name);
+                    }
+                    eprintln!("    {}", tool.hint.bright_black());
+                }
+            }
+        }
+        eprintln!("\n{}\n", "Data Paths:".bold());
+        for (path, description) in DATA_PATHS {
+            if Path::new(path).exists() {
+                eprintln!("  {} {path}", "✓".green());
+            } else {
+                missing_essential += 1;
+                eprintln!("  {} {path}", "✗".red());
+                eprintln!(
+                    "    {}",
+                    format!("{description} missing, use the Rugix Bakery Docker image")
+                        .bright_black()
+                );
+            }
+        }
+    });
+    if missing_essential > 0 {
+        bail!("{missing_essential} essential prerequisite(s) missing");
+    }
+    Ok(())
+}
+
+/// Read the first line of the version information of a binary.
+fn read_version(path: &Path, arg: &str) -> Option<String> {
+    let output = Command::new(path).arg(arg).output().ok()?;
+    // Some tools print their version information on stderr.
+    let output = if output.stdout.is_empty() {
+        output.stderr
+    } else {
+        output.stdout
+    };
+    String::from_utf8_lossy(&output)
+        .lines()
+        .next()
+        .map(|line| line.trim().to_owned())
+}
@@ -0,0 +1,205 @@
+//! The `graph` command.
+
+use std::fmt::Write;
+
+use serde::Serialize;
+
+use crate::cli::{args, load_project};
+use crate::oven::customize::RecipeGraph;
+use crate::oven::LayerBakery;
+use crate::project::library::{Library, RecipeIdx};
+use crate::BakeryResult;
+
+/// Run the `graph` command.
+pub fn run(args: &args::Args, cmd: &args::GraphCommand) -> BakeryResult<()> {
+    let project = load_project(args)?;
+    let graph = LayerBakery::new(&project, cmd.arch).recipe_graph(&cmd.layer)?;
+    let library = project.library()?;
+    let graph = GraphInfo::new(&library, &graph);
+    let output = match cmd.format {
+        args::GraphFormat::Dot => graph.to_dot(),
+        args::GraphFormat::Json => serde_json::to_string_pretty(&graph).unwrap(),
+    };
+    rugix_cli::suspend(|| println!("{output}"));
+    Ok(())
+}
+
+/// Recipe graph of a layer.
+#[derive(Debug, Serialize)]
+struct GraphInfo {
+    /// Recipes of the graph sorted by their id.
+    nodes: Vec<NodeInfo>,
+    /// Dependencies between the recipes.
+    edges: Vec<EdgeInfo>,
+}
+
+/// Recipe of the graph.
+#[derive(Debug, Serialize)]
+struct NodeInfo {
+    /// Unique id of the recipe, i.e., `<repository>/<name>`.
+    id: String,
+    /// Name of the recipe.
+    name: String,
+    /// Id of the repository of the recipe.
+    repository: String,
+    /// Indicates whether the recipe is listed in the layer configuration.
+    root: bool,
+    /// Indicates whether the recipe is excluded by the layer configuration.
+    excluded: bool,
+}
+
+/// Dependency of a recipe on another recipe.
+#[derive(Debug, Serialize)]
+struct EdgeInfo {
+    /// Id of the depending recipe.
+    from: String,
+    /// Id of the dependency.
+    to: String,
+    /// Indicates whether the dependency is excluded and therefore not applied.
+    excluded: bool,
+}
+
+impl GraphInfo {
+    /// Collect the nodes and edges of the given recipe graph.
+    fn new(library: &Library, graph: &RecipeGraph) -> Self {
+        let id = |idx: RecipeIdx| {
+            let recipe = &library.recipes[idx];
+            let repository = &library.repositories.repositories[recipe.repository];
+            format!("{}/{}", repository.source.id.as_short_str(), recipe.name)
+        };
+        let mut nodes = graph
+            .dependencies
+            .keys()
+            .chain(&graph.excluded)
+            .map(|idx| {
+                let recipe = &library.recipes[*idx];
+                let repository = &library.repositories.repositories[recipe.repository];
+                NodeInfo {
+                    id: id(*idx),
+                    name: recipe.name.to_string(),
+                    repository: repository.source.id.as_short_str().to_owned(),
+                    root: graph.roots.contains(idx),
+                    excluded: graph.excluded.contains(idx),
+                }
+            })
+            .collect::<Vec<_>>();
+        nodes.sort_by(|a, b| a.id.cmp(&b.id));
+        let mut edges = graph
+            .dependencies
+            .iter()
+            .flat_map(|(recipe, dependencies)| {
+                dependencies
+                    .iter()
+                    .map(|dependency| (*recipe, *dependency, false))
+            })
+            .chain(
+                graph
+                    .excluded_dependencies
+                    .iter()
+                    .map(|(recipe, dependency)| (*recipe, *dependency, true)),
+            )
+            .map(|(recipe, dependency, excluded)| EdgeInfo {
+                from: id(recipe),
+                to: id(dependency),
+                excluded,
+            })
+            .collect::<Vec<_>>();
+        edges.sort_by(|a, b| (&a.from, &a.to).cmp(&(&b.from, &b.to)));
+        Self { nodes, edges }
+    }
+
+    /// Render the graph in Graphviz DOT format.
+    ///
+    /// Recipes listed in the layer configuration are drawn bold, excluded recipes and
+    /// dependencies on them dashed.
+    fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph recipes {\n    rankdir=LR;\n    node [shape=box];\n");
+        for node in &self.nodes {
+            let mut attrs = vec![format!(
+                "label={}",
+                dot_string(&format!("{}\n{}", node.name, node.repository))
+            )];
+            if node.root {
+                attrs.push("style=bold".to_owned());
+            }
+            if node.excluded {
+                attrs.push("style=dashed".to_owned());
+                attrs.push("color=gray".to_owned());
+            }
+            writeln!(dot, "    {} [{}];", dot_string(&node.id), attrs.join(", ")).unwrap();
+        }
+        for edge in &self.edges {
+            let attrs = if edge.excluded {
+                " [style=dashed, color=gray]"
+            } else {
+                ""
+            };
+            writeln!(
+                dot,
+                "    {} -> {}{attrs};",
+                dot_string(&edge.from),
+                dot_string(&edge.to)
+            )
+            .unwrap();
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Quote the given string as a DOT identifier.
+fn dot_string(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dot_string, EdgeInfo, GraphInfo, NodeInfo};
+
+    #[test]
+    fn test_to_dot() {
+        let node = |name: &str, root, excluded| NodeInfo {
+            id: format!("core/{name}"),
+            name: name.to_owned(),
+            repository: "core".to_owned(),
+            root,
+            excluded,
+        };
+        let graph = GraphInfo {
+            nodes: vec![
+                node("app", true, false),
+                node("base", false, false),
+                node("docs", false, true),
+            ],
+            edges: vec![
+                EdgeInfo {
+                    from: "core/app".to_owned(),
+                    to: "core/base".to_owned(),
+                    excluded: false,
+                },
+                EdgeInfo {
+                    from: "core/app".to_owned(),
+                    to: "core/docs".to_owned(),
+                    excluded: true,
+                },
+            ],
+        };
+        let dot = graph.to_dot();
+        assert!(dot.starts_with("digraph recipes {\n"));
+        assert!(dot.contains("\"core/app\" [label=\"app\\ncore\", style=bold];"));
+        assert!(dot.contains("\"core/docs\" [label=\"docs\\ncore\", style=dashed, color=gray];"));
+        assert!(dot.contains("\"core/app\" -> \"core/base\";"));
+        assert!(dot.contains("\"core/app\" -> \"core/docs\" [style=dashed, color=gray];"));
+        assert_eq!(dot_string("say \"hi\""), "\"say \\\"hi\\\"\"");
+    }
+}
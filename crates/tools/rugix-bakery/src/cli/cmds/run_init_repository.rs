@@ -0,0 +1,81 @@
+//! The `init-repository` command.
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use indoc::{formatdoc, indoc};
+use reportify::{bail, ResultExt};
+use tracing::info;
+
+use crate::cli::args;
+use crate::BakeryResult;
+
+/// Run the `init-repository` command.
+pub fn run(cmd: &args::InitRepositoryCommand) -> BakeryResult<()> {
+    let dir = &cmd.dir;
+    let is_empty = match fs::read_dir(dir) {
+        Ok(mut entries) => entries.next().is_none(),
+        Err(_) => !dir.exists(),
+    };
+    if !is_empty && !cmd.force {
+        bail!("directory {dir:?} is not empty, use `--force` to initialize it anyway");
+    }
+    fs::create_dir_all(dir).whatever_with(|_| format!("unable to create directory {dir:?}"))?;
+    let name = dir
+        .canonicalize()
+        .whatever("unable to canonicalize repository path")?
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "example".to_owned());
+    info!("Initializing repository `{name}` in {dir:?}.");
+    write_file(
+        &dir.join("rugix-repository.toml"),
+        &formatdoc! {r#"
+            name = "{name}"
+            description = "Recipes and layers of the `{name}` repository."
+        "#},
+    )?;
+    write_file(
+        &dir.join("recipes/hello/recipe.toml"),
+        indoc! {r#"
+            description = "example recipe writing a greeting to `/etc/hello`"
+
+            [parameters]
+            greeting = { default = "Hello, Rugix!" }
+        "#},
+    )?;
+    let install_script = dir.join("recipes/hello/steps/00-install.sh");
+    write_file(
+        &install_script,
+        indoc! {r#"
+            #!/bin/bash
+
+            set -euo pipefail
+
+            echo "${RECIPE_PARAM_GREETING}" > /etc/hello
+        "#},
+    )?;
+    fs::set_permissions(&install_script, fs::Permissions::from_mode(0o755))
+        .whatever("unable to make install script executable")?;
+    write_file(
+        &dir.join("layers/hello.toml"),
+        indoc! {r#"
+            parent = "core/debian-bookworm"
+
+            recipes = [
+                "hello",
+            ]
+        "#},
+    )?;
+    Ok(())
+}
+
+/// Write a file of the repository skeleton, creating parent directories as necessary.
+fn write_file(path: &Path, content: &str) -> BakeryResult<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .whatever_with(|_| format!("unable to create directory {parent:?}"))?;
+    }
+    fs::write(path, content).whatever_with(|_| format!("unable to write {path:?}"))
+}
@@ -0,0 +1,144 @@
+//! The `lint-recipes` command.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use reportify::{bail, ResultExt};
+use serde::Deserialize;
+use tracing::{error, info, warn};
+
+use crate::cli::{args, load_project};
+use crate::project::library::Library;
+use crate::project::lint::{lint_library, Finding, Severity};
+use crate::project::recipes::StepKind;
+use crate::utils::which::find_binary;
+use crate::BakeryResult;
+
+/// Run the `lint-recipes` command.
+pub fn run(args: &args::Args, cmd: &args::LintRecipesCommand) -> BakeryResult<()> {
+    let project = load_project(args)?;
+    let library = project.library()?;
+    let mut findings = lint_library(&library, cmd.all);
+    match find_binary("shellcheck") {
+        Some(shellcheck) => findings.extend(shellcheck_library(&library, &shellcheck, cmd.all)?),
+        None => warn!("Skipping lint of recipe scripts, `shellcheck` not found."),
+    }
+    findings.sort_by(|a, b| (&a.repository, &a.recipe).cmp(&(&b.repository, &b.recipe)));
+    if cmd.json {
+        let json = serde_json::to_string_pretty(&findings).unwrap();
+        rugix_cli::suspend(|| println!("{json}"));
+    } else {
+        for finding in &findings {
+            let message = format!(
+                "{}: recipe `{}`: {}",
+                finding.repository, finding.recipe, finding.message
+            );
+            if finding.severity >= cmd.fail_on {
+                error!("{message}");
+            } else {
+                warn!("{message}");
+            }
+        }
+        info!("Lint reported {} finding(s).", findings.len());
+    }
+    let failures = findings
+        .iter()
+        .filter(|finding| finding.severity >= cmd.fail_on)
+        .count();
+    if failures > 0 {
+        bail!(
+            "{failures} finding(s) at or above severity `{}`",
+            cmd.fail_on
+        );
+    }
+    Ok(())
+}
+
+/// Lint the shell scripts of the `install` and `run` steps of the recipes with
+/// `shellcheck`.
+///
+/// Only the recipes of the root repository are checked, unless *all* is set.
+fn shellcheck_library(
+    library: &Library,
+    shellcheck: &Path,
+    all: bool,
+) -> BakeryResult<Vec<Finding>> {
+    let repositories = &library.repositories;
+    let mut findings = Vec::new();
+    for (_, recipe) in library.recipes.iter() {
+        if !all && recipe.repository != repositories.root_repository {
+            continue;
+        }
+        for step in &recipe.steps {
+            if !matches!(step.kind, StepKind::Install | StepKind::Run) {
+                continue;
+            }
+            let script = recipe.path.join("steps").join(&step.filename);
+            if !is_shell_script(&script)? {
+                continue;
+            }
+            let output = Command::new(shellcheck)
+                .arg("--format=json1")
+                .arg(&script)
+                .output()
+                .whatever("unable to run `shellcheck`")?;
+            let report = serde_json::from_slice::<ShellcheckReport>(&output.stdout)
+                .whatever("unable to parse `shellcheck` output")
+                .with_info(|_| format!("script: {script:?}"))?;
+            findings.extend(report.comments.into_iter().map(|comment| {
+                Finding {
+                    severity: comment.level,
+                    repository: repositories.repositories[recipe.repository]
+                        .source
+                        .id
+                        .as_str()
+                        .to_owned(),
+                    recipe: recipe.name.to_string(),
+                    message: format!(
+                        "{}:{}:{}: SC{}: {}",
+                        script.display(),
+                        comment.line,
+                        comment.column,
+                        comment.code,
+                        comment.message
+                    ),
+                }
+            }));
+        }
+    }
+    Ok(findings)
+}
+
+/// Check whether the script at the given path is a shell script based on its shebang.
+fn is_shell_script(path: &Path) -> BakeryResult<bool> {
+    let content = fs::read(path).whatever("unable to read step script")?;
+    let first_line = content.split(|b| *b == b'\n').next().unwrap_or_default();
+    let Some(shebang) = first_line.strip_prefix(b"#!") else {
+        return Ok(false);
+    };
+    let shebang = String::from_utf8_lossy(shebang);
+    let mut parts = shebang.split_whitespace();
+    let mut interpreter = parts.next().unwrap_or_default();
+    if interpreter.ends_with("/env") {
+        interpreter = parts.next().unwrap_or_default();
+    }
+    let interpreter = interpreter.rsplit('/').next().unwrap_or_default();
+    Ok(matches!(interpreter, "sh" | "bash" | "dash" | "ksh"))
+}
+
+/// Output of `shellcheck --format=json1`.
+#[derive(Debug, Deserialize)]
+struct ShellcheckReport {
+    comments: Vec<ShellcheckComment>,
+}
+
+/// Finding reported by `shellcheck`.
+#[derive(Debug, Deserialize)]
+struct ShellcheckComment {
+    line: u64,
+    column: u64,
+    level: Severity,
+    code: u64,
+    message: String,
+}
@@ -1,6 +1,18 @@
 //! The `list` command.
 
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+use colored::Colorize;
+use reportify::bail;
+use serde::Serialize;
+
 use crate::cli::{args, load_project};
+use crate::config::recipes::ParameterType;
+use crate::config::repositories::SourceConfig;
+use crate::oven::customize::RecipeGraph;
+use crate::oven::LayerBakery;
+use crate::project::repositories::{ProjectRepositories, RepositoryIdx};
+use crate::project::ProjectRef;
 use crate::BakeryResult;
 
 /// Run the `list` command.
@@ -19,6 +31,283 @@ pub fn run(args: &args::Args, cmd: &args::ListCommand) -> BakeryResult<()> {
                 }
             });
         }
+        args::ListCommand::Repositories { format } => {
+            let repositories = repository_infos(&project)?;
+            rugix_cli::suspend(|| match format {
+                args::ListFormat::Text => {
+                    for info in &repositories {
+                        let mut kind = Vec::new();
+                        if info.is_root {
+                            kind.push("root");
+                        }
+                        if info.is_core {
+                            kind.push("core");
+                        }
+                        println!(
+                            "{} {} {}",
+                            info.id.blue(),
+                            info.aliases.join(", "),
+                            kind.join(", ").bright_black(),
+                        );
+                        println!("  {}", format!("source {}", info.source).bright_black());
+                        println!(
+                            "  {}",
+                            format!("{} recipes, {} layers", info.recipes, info.layers)
+                                .bright_black()
+                        );
+                    }
+                }
+                args::ListFormat::Json => {
+                    println!("{}", serde_json::to_string_pretty(&repositories).unwrap());
+                }
+            });
+        }
+        args::ListCommand::Recipes {
+            repository,
+            layer,
+            arch,
+            default_only,
+            format,
+        } => {
+            let graph = match (layer, arch) {
+                (Some(layer), Some(arch)) => {
+                    Some(LayerBakery::new(&project, *arch).recipe_graph(layer)?)
+                }
+                _ => None,
+            };
+            let mut recipes = recipe_infos(&project, repository.as_deref(), graph.as_ref())?;
+            if *default_only {
+                recipes.retain(|info| info.status == Some("default"));
+            }
+            rugix_cli::suspend(|| match format {
+                args::ListFormat::Text => {
+                    for info in &recipes {
+                        println!(
+                            "{} {}",
+                            info.name.blue(),
+                            info.description.as_deref().unwrap_or_default()
+                        );
+                        let mut details = vec![
+                            format!("phase {}", info.phase),
+                            format!("priority {}", info.priority),
+                        ];
+                        if let Some(version) = &info.version {
+                            details.push(format!("version {version}"));
+                        }
+                        if let Some(status) = info.status {
+                            details.push(status.to_owned());
+                        }
+                        println!("  {}", details.join(", ").bright_black());
+                        for parameter in &info.parameters {
+                            let value = match (&parameter.default, parameter.required) {
+                                (Some(default), _) => format!(" = {default:?}"),
+                                (None, true) => " (required)".to_owned(),
+                                (None, false) => " (optional)".to_owned(),
+                            };
+                            println!(
+                                "  {}",
+                                format!("parameter {}: {}{value}", parameter.name, parameter.ty)
+                                    .bright_black()
+                            );
+                        }
+                    }
+                }
+                args::ListFormat::Json => {
+                    println!("{}", serde_json::to_string_pretty(&recipes).unwrap());
+                }
+            });
+        }
     }
     Ok(())
 }
+
+/// Information about a loaded repository.
+#[derive(Debug, Serialize)]
+struct RepositoryInfo {
+    /// Id of the repository's source.
+    id: String,
+    /// Names under which the repository is used by other repositories.
+    aliases: Vec<String>,
+    /// Source of the repository.
+    source: String,
+    /// Indicates whether the repository is the root repository.
+    is_root: bool,
+    /// Indicates whether the repository is the core repository.
+    is_core: bool,
+    /// Number of recipes provided by the repository.
+    recipes: usize,
+    /// Number of layers provided by the repository.
+    layers: usize,
+}
+
+/// Collect information about the repositories of the project.
+fn repository_infos(project: &ProjectRef) -> BakeryResult<Vec<RepositoryInfo>> {
+    let repositories = project.repositories()?;
+    let library = project.library()?;
+    let mut infos = Vec::new();
+    for (idx, repository) in repositories.iter() {
+        let mut aliases = BTreeSet::new();
+        if idx == repositories.root_repository {
+            aliases.insert("root".to_owned());
+        }
+        if idx == repositories.core_repository {
+            aliases.insert("core".to_owned());
+        }
+        for (_, other) in repositories.iter() {
+            for (name, used) in &other.repositories {
+                if *used == idx {
+                    aliases.insert(name.clone());
+                }
+            }
+        }
+        let source = match &repository.source.config {
+            SourceConfig::Path(config) => format!("path ./{}", config.path),
+            SourceConfig::Git(config) => format!("git {}", config.url),
+        };
+        infos.push(RepositoryInfo {
+            id: repository.source.id.as_short_str().to_owned(),
+            aliases: aliases.into_iter().collect(),
+            source,
+            is_root: idx == repositories.root_repository,
+            is_core: idx == repositories.core_repository,
+            recipes: library.recipe_tables[idx].len(),
+            layers: library.layer_tables[idx].len(),
+        });
+    }
+    Ok(infos)
+}
+
+/// Information about a recipe.
+#[derive(Debug, Serialize)]
+struct RecipeInfo {
+    /// Name of the recipe as referenced from the project.
+    name: String,
+    /// Path of the repository providing the recipe (`root` for the project).
+    repository: String,
+    /// Description of the recipe.
+    description: Option<String>,
+    /// Version of the recipe.
+    version: Option<String>,
+    /// Phase in which the recipe is applied.
+    phase: &'static str,
+    /// Priority of the recipe.
+    priority: i64,
+    /// Parameters of the recipe.
+    parameters: Vec<ParameterInfo>,
+    /// Status of the recipe in the given layer (`default` if it is applied by the layer
+    /// and `excluded` if it is excluded by the layer).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<&'static str>,
+}
+
+/// Information about a parameter of a recipe.
+#[derive(Debug, Serialize)]
+struct ParameterInfo {
+    /// Name of the parameter.
+    name: String,
+    /// Type of the parameter's value.
+    #[serde(rename = "type")]
+    ty: &'static str,
+    /// Default value of the parameter.
+    default: Option<String>,
+    /// Indicates whether a value must be provided.
+    required: bool,
+    /// Help message of the parameter.
+    help: Option<String>,
+}
+
+/// Collect information about the recipes of the project, optionally only of the given
+/// repository.
+///
+/// If a recipe *graph* of a layer is given, the status of the recipes in the layer is
+/// included.
+fn recipe_infos(
+    project: &ProjectRef,
+    repository: Option<&str>,
+    graph: Option<&RecipeGraph>,
+) -> BakeryResult<Vec<RecipeInfo>> {
+    let repositories = project.repositories()?;
+    let library = project.library()?;
+    let paths = repository_paths(&repositories);
+    if let Some(repository) = repository {
+        if !paths.values().any(|path| path == repository) {
+            bail!("unknown repository `{repository}`");
+        }
+    }
+    let mut infos = Vec::new();
+    for (idx, recipe) in library.recipes.iter() {
+        // Repositories which cannot be reached from the project are not listed.
+        let Some(path) = paths.get(&recipe.repository) else {
+            continue;
+        };
+        if repository.is_some_and(|repository| repository != path) {
+            continue;
+        }
+        let name = if recipe.repository == repositories.root_repository {
+            recipe.name.to_string()
+        } else {
+            format!("{path}/{}", recipe.name)
+        };
+        let mut parameters = recipe
+            .config
+            .parameters
+            .iter()
+            .flatten()
+            .map(|(name, def)| ParameterInfo {
+                name: name.clone(),
+                ty: def.ty.map_or("string", ParameterType::name),
+                default: def.default.as_ref().map(ToString::to_string),
+                required: def.required.unwrap_or(def.default.is_none()),
+                help: def.help.clone(),
+            })
+            .collect::<Vec<_>>();
+        parameters.sort_by(|a, b| a.name.cmp(&b.name));
+        infos.push(RecipeInfo {
+            name,
+            repository: path.clone(),
+            description: recipe.config.description.clone(),
+            version: recipe.config.version.clone(),
+            phase: recipe.phase().name(),
+            priority: recipe.config.priority.unwrap_or_default(),
+            parameters,
+            status: graph.and_then(|graph| {
+                if graph.excluded.contains(&idx) {
+                    Some("excluded")
+                } else if graph.dependencies.contains_key(&idx) {
+                    Some("default")
+                } else {
+                    None
+                }
+            }),
+        });
+    }
+    infos.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(infos)
+}
+
+/// Shortest paths by which the repositories can be referenced from the project.
+///
+/// The root repository has the path `root`.
+fn repository_paths(repositories: &ProjectRepositories) -> HashMap<RepositoryIdx, String> {
+    let mut paths = HashMap::from([
+        (repositories.root_repository, "root".to_owned()),
+        (repositories.core_repository, "core".to_owned()),
+    ]);
+    let mut queue = VecDeque::from([repositories.root_repository]);
+    while let Some(idx) = queue.pop_front() {
+        let mut used = repositories[idx].repositories.iter().collect::<Vec<_>>();
+        used.sort();
+        for (name, used) in used {
+            if paths.contains_key(used) {
+                continue;
+            }
+            let path = match idx == repositories.root_repository {
+                true => name.clone(),
+                false => format!("{}/{name}", paths[&idx]),
+            };
+            paths.insert(*used, path);
+            queue.push_back(*used);
+        }
+    }
+    paths
+}
@@ -0,0 +1,10 @@
+//! The `resize` command.
+
+use crate::cli::args;
+use crate::oven::resize::resize_image;
+use crate::BakeryResult;
+
+/// Run the `resize` command.
+pub fn run(cmd: &args::ResizeCommand) -> BakeryResult<()> {
+    resize_image(&cmd.image, cmd.size)
+}
@@ -5,12 +5,12 @@ use std::time::Duration;
 
 use reportify::ResultExt;
 use rugix_tasks::block_on;
-use tempfile::TempDir;
 use tracing::info;
 
-use crate::cli::{args, load_project};
+use crate::cli::{args, load_project, summary};
 use crate::config::tests::SystemConfig;
 use crate::tester::qemu;
+use crate::utils::temp;
 use crate::{oven, BakeryResult};
 
 /// Run the `run` command.
@@ -18,11 +18,16 @@ pub fn run(args: &args::Args, cmd: &args::RunCommand) -> BakeryResult<()> {
     let project = load_project(args)?;
 
     let output = Path::new("build").join(&cmd.system);
-    oven::bake_system(&project, &cmd.system, &output).whatever("error baking image")?;
+    let baked = oven::bake_system(&project, &cmd.system, &output, &oven::SystemOpts::default());
+    // The VM runs until it is interrupted, so the timings are written after baking.
+    if let Some(timings_path) = &args.timings_json {
+        summary::write_timings(timings_path, project.stats())?;
+    }
+    baked.whatever("error baking image")?;
 
     let image_path = output.join("system.img");
 
-    let tempdir = TempDir::new().whatever("unable to create temporary directory")?;
+    let tempdir = temp::temp_dir()?;
 
     let temp_img = tempdir.path().join("system.img");
 
@@ -5,12 +5,22 @@ use std::fs;
 
 use reportify::ResultExt;
 
-use crate::cli::{args, load_project};
+use crate::cli::{args, load_project, summary};
+use crate::project::ProjectRef;
 use crate::{tester, BakeryResult};
 
 /// Run the `test` command.
 pub fn run(args: &args::Args, cmd: &args::TestCommand) -> BakeryResult<()> {
     let project = load_project(args)?;
+    let result = run_workflows(&project, cmd);
+    if let Some(timings_path) = &args.timings_json {
+        summary::write_timings(timings_path, project.stats())?;
+    }
+    result
+}
+
+/// Run the requested test workflows of the project.
+fn run_workflows(project: &ProjectRef, cmd: &args::TestCommand) -> BakeryResult<()> {
     let mut workflows = Vec::new();
     if cmd.workflows.is_empty() {
         let mut read_dir = fs::read_dir(project.dir().join("tests"))
@@ -34,7 +44,7 @@ pub fn run(args: &args::Args, cmd: &args::TestCommand) -> BakeryResult<()> {
         }
     };
     for workflow in &workflows {
-        tester::main(&project, &workflow)?;
+        tester::main(project, &workflow)?;
         rugix_cli::force_redraw();
     }
     Ok(())
@@ -0,0 +1,18 @@
+//! The `verify` command.
+
+use crate::cli::{args, load_project};
+use crate::oven::verify::verify_image;
+use crate::oven::SystemOpts;
+use crate::BakeryResult;
+
+/// Run the `verify` command.
+pub fn run(args: &args::Args, cmd: &args::VerifyCommand) -> BakeryResult<()> {
+    let project = load_project(args)?;
+    let config = project.config().resolve_system_config(&cmd.system)?;
+    let opts = SystemOpts {
+        partition_table: cmd.partition_table,
+        root_fs: cmd.root_fs,
+        ..SystemOpts::default()
+    };
+    verify_image(config, &cmd.image, &opts)
+}
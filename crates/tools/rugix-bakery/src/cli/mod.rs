@@ -5,6 +5,7 @@ use std::path::PathBuf;
 use reportify::ResultExt;
 
 use crate::project::{ProjectLoader, ProjectRef};
+use crate::utils::temp;
 use crate::BakeryResult;
 
 mod cmds;
@@ -12,9 +13,13 @@ mod cmds;
 pub mod args;
 
 pub(crate) mod status;
+pub(crate) mod summary;
 
 /// Run Rugix Bakery with the provided command line arguments.
 pub fn run(args: args::Args) -> BakeryResult<()> {
+    if let Some(work_dir) = &args.work_dir {
+        temp::set_work_dir(work_dir);
+    }
     match &args.cmd {
         args::Command::Bake(cmd) => cmds::run_bake::run(&args, cmd),
         args::Command::Test(cmd) => cmds::run_test::run(&args, cmd),
@@ -22,8 +27,16 @@ pub fn run(args: args::Args) -> BakeryResult<()> {
         args::Command::List(cmd) => cmds::run_list::run(&args, cmd),
         args::Command::Pull => cmds::run_pull::run(&args),
         args::Command::Init(cmd) => cmds::run_init::run(cmd),
+        args::Command::InitRepository(cmd) => cmds::run_init_repository::run(cmd),
         args::Command::Shell => cmds::run_shell::run(),
         args::Command::Bundler(cmd) => cmds::run_bundler::run(cmd),
+        args::Command::Doctor => cmds::run_doctor::run(),
+        args::Command::LintRecipes(cmd) => cmds::run_lint_recipes::run(&args, cmd),
+        args::Command::Resize(cmd) => cmds::run_resize::run(cmd),
+        args::Command::Verify(cmd) => cmds::run_verify::run(&args, cmd),
+        args::Command::Clean(cmd) => cmds::run_clean::run(&args, cmd),
+        args::Command::Config(cmd) => cmds::run_config::run(&args, cmd),
+        args::Command::Graph(cmd) => cmds::run_graph::run(&args, cmd),
     }
 }
 
@@ -34,7 +47,19 @@ fn current_dir() -> BakeryResult<PathBuf> {
 
 /// Load the project from the current working directory.
 fn load_project(args: &args::Args) -> BakeryResult<ProjectRef> {
-    ProjectLoader::current_dir()?
+    project_loader(args)?.load()
+}
+
+/// Loader for the project in the current working directory configured by the arguments.
+fn project_loader(args: &args::Args) -> BakeryResult<ProjectLoader> {
+    Ok(ProjectLoader::current_dir()?
         .with_config_file(args.config.as_deref())
-        .load()
+        .with_library_cache(args.only_changed_repos)
+        .with_strict(args.strict)
+        .with_register_emulators(args.register_emulators)
+        .with_validate_schema(args.validate_schema)
+        .with_max_recipes(args.max_recipes)
+        .with_cache_dir(args.cache_dir.as_deref())
+        .with_layer_cache(args.layer_cache)
+        .with_profile(args.profile.as_deref()))
 }
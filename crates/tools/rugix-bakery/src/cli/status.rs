@@ -1,8 +1,10 @@
 use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use rugix_cli::style::Stylize;
-use rugix_cli::widgets::{Heading, Text, Widget};
-use rugix_cli::{StatusSegment, VisualHeight};
+use rugix_cli::widgets::{Heading, ProgressBar, Text, Widget};
+use rugix_cli::{StatusSegment, StatusSegmentRef, VisualHeight};
 
 #[derive(Debug)]
 pub struct CliLog {
@@ -49,3 +51,54 @@ impl StatusSegment for CliLog {
             .draw(ctx);
     }
 }
+
+#[derive(Debug)]
+pub struct CliProgress {
+    title: String,
+    position: AtomicU64,
+    length: u64,
+}
+
+impl CliProgress {
+    pub fn new(title: String, length: u64) -> Self {
+        Self {
+            title,
+            position: AtomicU64::new(0),
+            length: length.max(1),
+        }
+    }
+
+    pub fn inc(&self, delta: u64) {
+        self.position.fetch_add(delta, Ordering::Relaxed);
+    }
+
+    pub fn set_position(&self, position: u64) {
+        self.position.store(position, Ordering::Relaxed);
+    }
+}
+
+/// Create a callback showing the progress of packing or extracting the given archive.
+///
+/// The progress bar is added once progress is reported and removed with the callback.
+pub fn archive_progress(verb: &str, archive: &Path) -> impl FnMut(u64, u64) {
+    let name = archive
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let title = format!("{verb} {name}");
+    let mut status = None::<StatusSegmentRef<CliProgress>>;
+    move |position, length| {
+        status
+            .get_or_insert_with(|| rugix_cli::add_status(CliProgress::new(title.clone(), length)))
+            .set_position(position);
+    }
+}
+
+impl StatusSegment for CliProgress {
+    fn draw(&self, ctx: &mut rugix_cli::DrawCtx) {
+        Heading::new(&self.title).draw(ctx);
+        // The length may be an estimate, so we never draw more than 100%.
+        let position = self.position.load(Ordering::Relaxed).min(self.length);
+        ProgressBar::new(position, self.length).draw(ctx);
+    }
+}
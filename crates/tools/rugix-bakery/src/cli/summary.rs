@@ -0,0 +1,96 @@
+//! Machine-readable summary of a build.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use reportify::ResultExt;
+use serde::Serialize;
+
+use crate::project::stats::BuildStats;
+use crate::BakeryResult;
+
+/// Summary of a build.
+#[derive(Debug, Serialize)]
+pub struct BuildSummary {
+    /// Overall status of the build.
+    pub status: BuildStatus,
+    /// Error message, if the build failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Total duration of the build in seconds.
+    pub duration_secs: f64,
+    /// Number of recipes which have been applied.
+    pub recipes_applied: usize,
+    /// Artifacts produced by the build.
+    pub artifacts: Vec<BuildArtifact>,
+    /// Warnings emitted during the build.
+    pub warnings: Vec<String>,
+}
+
+/// Overall status of a build.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BuildStatus {
+    Success,
+    Failure,
+}
+
+/// Artifact produced by a build.
+#[derive(Debug, Serialize)]
+pub struct BuildArtifact {
+    /// Path of the artifact.
+    pub path: PathBuf,
+    /// Size of the artifact in bytes.
+    pub size: u64,
+}
+
+impl BuildSummary {
+    /// Create a summary from the result and the statistics of a build.
+    pub fn new<E: std::fmt::Debug>(
+        result: &Result<Vec<PathBuf>, E>,
+        duration: Duration,
+        stats: &BuildStats,
+    ) -> Self {
+        let (status, error, artifacts) = match result {
+            Ok(paths) => (
+                BuildStatus::Success,
+                None,
+                paths
+                    .iter()
+                    .map(|path| BuildArtifact {
+                        path: path.clone(),
+                        size: std::fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+                    })
+                    .collect(),
+            ),
+            Err(error) => (BuildStatus::Failure, Some(format!("{error:?}")), Vec::new()),
+        };
+        Self {
+            status,
+            error,
+            duration_secs: duration.as_secs_f64(),
+            recipes_applied: stats.recipes_applied(),
+            artifacts,
+            warnings: rugix_cli::warnings::collected_warnings(),
+        }
+    }
+
+    /// Write the summary as JSON to the given path.
+    pub fn write(&self, path: &Path) -> BakeryResult<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).ok();
+        }
+        let json = serde_json::to_string_pretty(self).whatever("unable to serialize summary")?;
+        std::fs::write(path, json).whatever("unable to write summary")
+    }
+}
+
+/// Write the durations of all recipe steps run during the build as JSON to the given path.
+pub fn write_timings(path: &Path, stats: &BuildStats) -> BakeryResult<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).ok();
+    }
+    let json =
+        serde_json::to_string_pretty(&stats.timings()).whatever("unable to serialize timings")?;
+    std::fs::write(path, json).whatever("unable to write timings")
+}
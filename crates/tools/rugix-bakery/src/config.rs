@@ -7,13 +7,13 @@ use std::{fmt, fs};
 use images::{Filesystem, PartitionTableType};
 use projects::ProjectConfig;
 use rugix_tasks::check_canceled;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use reportify::{whatever, ResultExt};
+use reportify::{bail, whatever, ResultExt};
 
 use crate::BakeryResult;
 
-use self::recipes::ParameterValue;
+use self::recipes::{ParameterType, ParameterValue, RecipeDependencies, RecipePhase};
 use self::systems::{Architecture, SystemConfig};
 
 sidex::include_bundle! {
@@ -31,6 +31,10 @@ pub mod errors {
     #[derive(Debug, Error)]
     #[error("invalid architecture")]
     pub struct InvalidArchitectureError;
+
+    #[derive(Debug, Error)]
+    #[error("invalid architecture family")]
+    pub struct InvalidArchitectureFamilyError;
 }
 
 impl Architecture {
@@ -43,6 +47,48 @@ impl Architecture {
             Architecture::Arm => "arm",
         }
     }
+
+    /// Family of the architecture, if any.
+    pub fn family(self) -> Option<ArchitectureFamily> {
+        match self {
+            Architecture::Amd64 => None,
+            Architecture::Arm64 | Architecture::Armv7 | Architecture::Armhf | Architecture::Arm => {
+                Some(ArchitectureFamily::Arm)
+            }
+        }
+    }
+}
+
+/// Family of related architectures which may share layer configurations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ArchitectureFamily {
+    /// All 32-bit and 64-bit ARM architectures.
+    Arm,
+}
+
+impl ArchitectureFamily {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ArchitectureFamily::Arm => "arm-family",
+        }
+    }
+}
+
+impl fmt::Display for ArchitectureFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ArchitectureFamily {
+    type Err = errors::InvalidArchitectureFamilyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "arm-family" => Ok(Self::Arm),
+            _ => Err(errors::InvalidArchitectureFamilyError),
+        }
+    }
 }
 
 impl fmt::Display for Architecture {
@@ -66,6 +112,8 @@ impl FromStr for Architecture {
     }
 }
 
+/// Values are formatted as they are passed to recipes, i.e., the items of lists are
+/// separated by newlines.
 impl fmt::Display for ParameterValue {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -73,10 +121,27 @@ impl fmt::Display for ParameterValue {
             ParameterValue::Boolean(value) => value.fmt(f),
             ParameterValue::Integer(value) => value.fmt(f),
             ParameterValue::Float(value) => value.fmt(f),
+            ParameterValue::List(values) => values.join("\n").fmt(f),
         }
     }
 }
 
+impl RecipeDependencies {
+    /// Iterator over the names of the dependencies and their version constraints.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        let (names, constrained) = match self {
+            RecipeDependencies::Names(names) => (names.as_slice(), None),
+            RecipeDependencies::Constrained(constrained) => (&[][..], Some(constrained)),
+        };
+        names.iter().map(|name| (name.as_str(), None)).chain(
+            constrained
+                .into_iter()
+                .flatten()
+                .map(|(name, constraint)| (name.as_str(), Some(constraint.as_str()))),
+        )
+    }
+}
+
 impl ProjectConfig {
     /// Retrieve the configuration of the image with the provided name.
     pub fn get_system_config(&self, name: &str) -> Option<&SystemConfig> {
@@ -96,6 +161,41 @@ impl Filesystem {
         match self {
             Filesystem::Ext4 => "ext4",
             Filesystem::Fat32 => "fat32",
+            Filesystem::F2fs => "f2fs",
+            Filesystem::Btrfs => "btrfs",
+        }
+    }
+
+    /// Option of the filesystem's `mkfs` tool for setting the label.
+    pub fn label_option(&self) -> &'static str {
+        match self {
+            Filesystem::Ext4 | Filesystem::Btrfs => "-L",
+            Filesystem::Fat32 => "-n",
+            Filesystem::F2fs => "-l",
+        }
+    }
+}
+
+impl ParameterType {
+    /// Name of the parameter type.
+    pub fn name(self) -> &'static str {
+        match self {
+            ParameterType::String => "string",
+            ParameterType::Integer => "integer",
+            ParameterType::Boolean => "boolean",
+            ParameterType::Enum => "enum",
+            ParameterType::List => "list",
+        }
+    }
+}
+
+impl RecipePhase {
+    /// Name of the phase.
+    pub fn name(self) -> &'static str {
+        match self {
+            RecipePhase::Pre => "pre",
+            RecipePhase::Main => "main",
+            RecipePhase::Post => "post",
         }
     }
 }
@@ -118,12 +218,239 @@ where
     toml::from_str(&config).whatever("unable to parse configuration file")
 }
 
+/// Parse a configuration of type `T` from the provided string rejecting unknown keys.
+pub fn parse_config_strict<T>(config: &str) -> BakeryResult<T>
+where
+    T: 'static + for<'de> Deserialize<'de> + Serialize,
+{
+    let config = toml::from_str(config).whatever("unable to parse configuration file")?;
+    deserialize_strict(config)
+}
+
+/// Deserialize a configuration of type `T` rejecting unknown keys.
+///
+/// By default, unknown keys are ignored such that configurations remain compatible
+/// with older versions. This is used to catch typos in configuration files. Keys which
+/// do not survive a roundtrip through `T` are unknown.
+fn deserialize_strict<T>(config: toml::Value) -> BakeryResult<T>
+where
+    T: for<'de> Deserialize<'de> + Serialize,
+{
+    let parsed = T::deserialize(config.clone()).whatever("unable to parse configuration file")?;
+    let known = toml::Value::try_from(&parsed).whatever("unable to serialize configuration")?;
+    let mut unknown = Vec::new();
+    collect_unknown_keys(&config, &known, "", &mut unknown);
+    if !unknown.is_empty() {
+        bail!("unknown configuration keys: {}", unknown.join(", "));
+    }
+    Ok(parsed)
+}
+
+/// Collect the paths of the keys of *config* which are missing in *known*.
+fn collect_unknown_keys(
+    config: &toml::Value,
+    known: &toml::Value,
+    path: &str,
+    unknown: &mut Vec<String>,
+) {
+    match (config, known) {
+        (toml::Value::Table(config), toml::Value::Table(known)) => {
+            for (key, value) in config {
+                let key_path = match path.is_empty() {
+                    true => key.clone(),
+                    false => format!("{path}.{key}"),
+                };
+                match known.get(key) {
+                    Some(known) => collect_unknown_keys(value, known, &key_path, unknown),
+                    None => unknown.push(key_path),
+                }
+            }
+        }
+        (toml::Value::Array(config), toml::Value::Array(known)) => {
+            for (idx, (value, known)) in config.iter().zip(known).enumerate() {
+                collect_unknown_keys(value, known, &format!("{path}[{idx}]"), unknown);
+            }
+        }
+        _ => { /* values without keys */ }
+    }
+}
+
 /// Load a configuration of type `T` from the provided path.
 pub fn load_config<T>(path: &Path) -> BakeryResult<T>
 where
     T: 'static + for<'de> Deserialize<'de>,
 {
     check_canceled();
-    parse_config(&fs::read_to_string(path).whatever("unable to read configuration file")?)
-        .with_info(|_| format!("loading configuration from {path:?}"))
+    let config = fs::read_to_string(path).whatever("unable to read configuration file")?;
+    parse_config(&config).with_info(|_| format!("loading configuration from {path:?}"))
+}
+
+/// Load a configuration of type `T` from the provided path.
+///
+/// If *validate_schema* is set, unknown keys are rejected.
+pub fn load_config_with<T>(path: &Path, validate_schema: bool) -> BakeryResult<T>
+where
+    T: 'static + for<'de> Deserialize<'de> + Serialize,
+{
+    if !validate_schema {
+        return load_config(path);
+    }
+    check_canceled();
+    let config = fs::read_to_string(path).whatever("unable to read configuration file")?;
+    parse_config_strict(&config).with_info(|_| format!("loading configuration from {path:?}"))
+}
+
+/// Load the project configuration from the provided path applying the given profile.
+///
+/// The tables of the profile are merged recursively into the base configuration while
+/// all other values, including lists, are replaced. Profiles are partial configurations,
+/// so they are removed before the configuration is deserialized, whether a profile is
+/// applied or not. If *validate_schema* is set, unknown keys are rejected.
+pub fn load_project_config(
+    path: &Path,
+    profile: Option<&str>,
+    validate_schema: bool,
+) -> BakeryResult<ProjectConfig> {
+    check_canceled();
+    let config = fs::read_to_string(path).whatever("unable to read configuration file")?;
+    parse_project_config(&config, profile, validate_schema).with_info(|_| match profile {
+        Some(profile) => format!("applying profile `{profile}` to {path:?}"),
+        None => format!("loading configuration from {path:?}"),
+    })
+}
+
+/// Parse the project configuration from the provided string applying the given profile.
+fn parse_project_config(
+    config: &str,
+    profile: Option<&str>,
+    validate_schema: bool,
+) -> BakeryResult<ProjectConfig> {
+    let mut config = config
+        .parse::<toml::Table>()
+        .whatever("unable to parse configuration file")?;
+    let mut profiles = match config.remove("profiles") {
+        Some(toml::Value::Table(profiles)) => profiles,
+        Some(_) => bail!("`profiles` must be a table"),
+        None => toml::Table::new(),
+    };
+    if let Some(profile) = profile {
+        let Some(toml::Value::Table(overrides)) = profiles.remove(profile) else {
+            let mut available = profiles.keys().map(String::as_str).collect::<Vec<_>>();
+            available.sort();
+            bail!(
+                "unknown profile `{profile}`, available profiles: {}",
+                if available.is_empty() {
+                    "none".to_owned()
+                } else {
+                    available.join(", ")
+                }
+            );
+        };
+        merge_tables(&mut config, overrides);
+    }
+    let config = toml::Value::Table(config);
+    if validate_schema {
+        deserialize_strict(config)
+    } else {
+        ProjectConfig::deserialize(config).whatever("unable to parse configuration file")
+    }
+}
+
+/// Recursively merge the `overrides` into the `base` table.
+fn merge_tables(base: &mut toml::Table, overrides: toml::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overrides)) => {
+                merge_tables(base, overrides);
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::layers::LayerConfig;
+    use super::recipes::ParameterValue;
+    use super::{merge_tables, parse_config, parse_config_strict, parse_project_config};
+
+    #[test]
+    fn test_display_parameter_value() {
+        let list = ParameterValue::List(vec!["curl".to_owned(), "vim".to_owned()]);
+        assert_eq!(list.to_string(), "curl\nvim");
+        assert_eq!(ParameterValue::Integer(8080).to_string(), "8080");
+    }
+
+    #[test]
+    fn test_parse_config_strict() {
+        let typos = [
+            "recipies = [\"hello-world\"]",
+            "[bootstrap]\nsuite = \"bookworm\"\nvarient = \"minbase\"",
+        ];
+        for config in typos {
+            assert!(parse_config::<LayerConfig>(config).is_ok());
+            assert!(parse_config_strict::<LayerConfig>(config).is_err());
+        }
+        assert!(parse_config_strict::<LayerConfig>("recipes = [\"hello-world\"]").is_ok());
+        let config = "recipes = [\"ssh\"]\n[parameters.ssh]\nport = 22\nkeys = [\"a\", \"b\"]";
+        assert!(parse_config_strict::<LayerConfig>(config).is_ok());
+        let error = parse_config_strict::<LayerConfig>(typos[1]).unwrap_err();
+        assert!(format!("{error:?}").contains("bootstrap.varient"));
+    }
+
+    #[test]
+    fn test_merge_tables() {
+        let mut base = r#"
+            [systems.customized]
+            layer = "customized"
+            architecture = "arm64"
+
+            [repositories]
+            extra = { git = "https://example.com/extra.git", tag = "v1", dir = "extra" }
+        "#
+        .parse::<toml::Table>()
+        .unwrap();
+        let overrides = r#"
+            [systems.customized]
+            layer = "customized-dev"
+
+            [repositories]
+            extra = { git = "https://example.com/extra.git", tag = "v2" }
+        "#
+        .parse::<toml::Table>()
+        .unwrap();
+        merge_tables(&mut base, overrides);
+        let system = &base["systems"]["customized"];
+        assert_eq!(system["layer"].as_str(), Some("customized-dev"));
+        assert_eq!(system["architecture"].as_str(), Some("arm64"));
+        let extra = &base["repositories"]["extra"];
+        assert_eq!(extra["tag"].as_str(), Some("v2"));
+        // Tables are merged, so the directory is still present.
+        assert_eq!(extra["dir"].as_str(), Some("extra"));
+    }
+
+    #[test]
+    fn test_parse_project_config_partial_profile() {
+        let config = r#"
+            [systems.customized]
+            layer = "customized"
+            architecture = "arm64"
+
+            [profiles.dev.systems.customized]
+            layer = "customized-dev"
+        "#;
+        for validate_schema in [false, true] {
+            let base = parse_project_config(config, None, validate_schema).unwrap();
+            let system = base.get_system_config("customized").unwrap();
+            assert_eq!(system.layer, "customized");
+            let dev = parse_project_config(config, Some("dev"), validate_schema).unwrap();
+            let system = dev.get_system_config("customized").unwrap();
+            assert_eq!(system.layer, "customized-dev");
+            assert_eq!(system.architecture.as_str(), "arm64");
+            assert!(dev.profiles.is_none());
+        }
+        assert!(parse_project_config(config, Some("prod"), false).is_err());
+    }
 }
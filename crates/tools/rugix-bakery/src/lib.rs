@@ -0,0 +1,26 @@
+//! Rugix Bakery, a flexible build system for bespoke Linux distributions.
+//!
+//! Besides the `rugix-bakery` executable, this crate can be used as a library to bake
+//! layers and images programmatically. A project is loaded with
+//! [`ProjectLoader`][project::ProjectLoader]. Layers are then baked with
+//! [`LayerBakery`][oven::LayerBakery] and systems with [`bake_system`][oven::bake_system].
+//! To apply the recipes of a layer to a root filesystem directly, use
+//! [`customize`][oven::customize::customize]. The CLI in [`cli`] is a thin wrapper around
+//! these functions.
+
+use reportify::Report;
+
+pub mod cli;
+pub mod config;
+pub mod oven;
+pub mod project;
+pub mod tester;
+pub mod utils;
+
+reportify::new_whatever_type! {
+    /// Error running Rugix Bakery.
+    BakeryError
+}
+
+/// [`Result`] with [`Report<BakeryError>`] as error type.
+pub type BakeryResult<T> = Result<T, Report<BakeryError>>;
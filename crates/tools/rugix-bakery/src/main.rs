@@ -2,24 +2,17 @@
 
 use clap::Parser;
 
-use reportify::Report;
-
-pub mod cli;
-pub mod config;
-pub mod oven;
-pub mod project;
-pub mod tester;
-pub mod utils;
-
-reportify::new_whatever_type! {
-    /// Error running Rugix Bakery.
-    BakeryError
-}
-
-/// [`Result`] with [`Report<BakeryError>`] as error type.
-pub type BakeryResult<T> = Result<T, Report<BakeryError>>;
+use rugix_bakery::cli;
 
 /// Entrypoint of the executable.
 pub fn main() {
-    rugix_cli::CliBuilder::new().run(|| cli::run(cli::args::Args::parse()))
+    let args = cli::args::Args::parse();
+    rugix_cli::CliBuilder::new()
+        .with_github_annotations(args.output_format() == cli::args::OutputFormat::Github)
+        .with_color(args.color_choice())
+        .with_level(args.log_level())
+        .run(move || {
+            colored::control::set_override(rugix_cli::supports_colors());
+            cli::run(args)
+        })
 }
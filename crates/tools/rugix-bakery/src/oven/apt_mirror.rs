@@ -0,0 +1,187 @@
+//! Rewriting of APT sources to use a mirror.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use regex::{Captures, Regex};
+use reportify::{bail, ResultExt};
+use tracing::info;
+use url::Url;
+
+use crate::BakeryResult;
+
+/// Hosts of the APT sources served by the mirror, if no hosts are configured.
+pub const DEFAULT_MIRROR_HOSTS: &[&str] = &[
+    "deb.debian.org",
+    "security.debian.org",
+    "archive.raspberrypi.com",
+    "archive.raspberrypi.org",
+    "raspbian.raspberrypi.com",
+    "raspbian.raspberrypi.org",
+];
+
+/// Original APT sources of a system whose sources have been rewritten to use a mirror.
+#[derive(Debug)]
+#[must_use]
+pub struct AptSources {
+    /// Root directory of the system.
+    root_dir: PathBuf,
+    /// Paths and original contents of the rewritten files.
+    originals: Vec<(PathBuf, String)>,
+}
+
+impl AptSources {
+    /// Rewrite the APT sources of the system with the given root directory to use the
+    /// given mirror.
+    ///
+    /// Only sources of the given hosts are rewritten, defaulting to
+    /// [`DEFAULT_MIRROR_HOSTS`] if no hosts are given.
+    pub fn use_mirror(root_dir: &Path, mirror: &Url, hosts: &[String]) -> BakeryResult<Self> {
+        if !matches!(mirror.scheme(), "http" | "https") {
+            bail!("mirror must be an HTTP or HTTPS URL, found `{mirror}`");
+        }
+        let hosts = if hosts.is_empty() {
+            DEFAULT_MIRROR_HOSTS.to_vec()
+        } else {
+            hosts.iter().map(String::as_str).collect()
+        };
+        let mut files = vec![root_dir.join("etc/apt/sources.list")];
+        let sources_dir = root_dir.join("etc/apt/sources.list.d");
+        if sources_dir.is_dir() {
+            for entry in fs::read_dir(&sources_dir).whatever("unable to read APT sources")? {
+                let path = entry.whatever("unable to read APT sources")?.path();
+                if matches!(
+                    path.extension().and_then(|ext| ext.to_str()),
+                    Some("list" | "sources")
+                ) {
+                    files.push(path);
+                }
+            }
+        }
+        let mut originals = Vec::new();
+        for path in files {
+            if !path.is_file() {
+                continue;
+            }
+            let contents = fs::read_to_string(&path)
+                .whatever("unable to read APT sources")
+                .with_info(|_| format!("path: {path:?}"))?;
+            let rewritten = rewrite_sources(&contents, mirror, &hosts);
+            if rewritten != contents {
+                fs::write(&path, rewritten)
+                    .whatever("unable to write APT sources")
+                    .with_info(|_| format!("path: {path:?}"))?;
+                originals.push((path, contents));
+            }
+        }
+        if !originals.is_empty() {
+            info!("Using APT mirror `{mirror}`.");
+        }
+        Ok(Self {
+            root_dir: root_dir.to_path_buf(),
+            originals,
+        })
+    }
+
+    /// Indicates whether any sources have been rewritten.
+    pub fn is_rewritten(&self) -> bool {
+        !self.originals.is_empty()
+    }
+
+    /// Restore the original sources.
+    ///
+    /// If the package lists have been updated from the mirror, they are removed, as they
+    /// do not match the original sources.
+    pub fn restore(self, lists_updated: bool) -> BakeryResult<()> {
+        for (path, contents) in self.originals {
+            fs::write(&path, contents)
+                .whatever("unable to restore APT sources")
+                .with_info(|_| format!("path: {path:?}"))?;
+        }
+        if lists_updated {
+            remove_package_lists(&self.root_dir)?;
+        }
+        Ok(())
+    }
+}
+
+/// Remove the package lists of the system with the given root directory.
+fn remove_package_lists(root_dir: &Path) -> BakeryResult<()> {
+    let lists_dir = root_dir.join("var/lib/apt/lists");
+    if !lists_dir.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(&lists_dir).whatever("unable to read package lists")? {
+        let path = entry.whatever("unable to read package lists")?.path();
+        if path.is_file() && path.file_name() != Some("lock".as_ref()) {
+            fs::remove_file(&path)
+                .whatever("unable to remove package list")
+                .with_info(|_| format!("path: {path:?}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Rewrite the HTTP and HTTPS URIs of the given APT sources to use the given mirror.
+///
+/// Supports both, the one-line and the deb822 format. The scheme, host, and port of each
+/// URI of one of the given hosts are replaced by the mirror while the path is appended to
+/// the path of the mirror. URIs of other hosts are kept.
+pub fn rewrite_sources(contents: &str, mirror: &Url, hosts: &[&str]) -> String {
+    let mirror = mirror.as_str().trim_end_matches('/');
+    let uri_regex = Regex::new(r"https?://\S+").unwrap();
+    let mut rewritten = String::with_capacity(contents.len());
+    for line in contents.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        let is_source = ["deb ", "deb\t", "deb-src ", "deb-src\t", "URIs:"]
+            .iter()
+            .any(|prefix| trimmed.starts_with(prefix));
+        if !is_source {
+            rewritten.push_str(line);
+            continue;
+        }
+        let line = uri_regex.replace_all(line, |captures: &Captures| {
+            let uri = &captures[0];
+            match Url::parse(uri) {
+                Ok(uri) if uri.host_str().is_some_and(|host| hosts.contains(&host)) => {
+                    format!("{mirror}{}", uri.path())
+                }
+                _ => uri.to_owned(),
+            }
+        });
+        rewritten.push_str(&line);
+    }
+    rewritten
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::{rewrite_sources, DEFAULT_MIRROR_HOSTS};
+
+    #[test]
+    fn test_rewrite_sources() {
+        let mirror = Url::parse("https://mirror.example.com/repos/").unwrap();
+        let one_line = "# Comment with http://deb.debian.org/debian\n\
+            deb http://deb.debian.org/debian bookworm main\n\
+            deb [arch=arm64] http://security.debian.org/debian-security bookworm-security main\n\
+            deb https://download.docker.com/linux/debian bookworm stable\n";
+        assert_eq!(
+            rewrite_sources(one_line, &mirror, DEFAULT_MIRROR_HOSTS),
+            "# Comment with http://deb.debian.org/debian\n\
+            deb https://mirror.example.com/repos/debian bookworm main\n\
+            deb [arch=arm64] https://mirror.example.com/repos/debian-security bookworm-security main\n\
+            deb https://download.docker.com/linux/debian bookworm stable\n"
+        );
+        let deb822 = "Types: deb\nURIs: http://deb.debian.org/debian\nSuites: bookworm\n";
+        assert_eq!(
+            rewrite_sources(deb822, &mirror, DEFAULT_MIRROR_HOSTS),
+            "Types: deb\nURIs: https://mirror.example.com/repos/debian\nSuites: bookworm\n"
+        );
+        assert_eq!(
+            rewrite_sources(deb822, &mirror, &["security.debian.org"]),
+            deb822
+        );
+    }
+}
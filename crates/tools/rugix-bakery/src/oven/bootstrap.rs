@@ -0,0 +1,64 @@
+//! Bootstrapping of root filesystems from scratch.
+
+use std::fs;
+use std::path::Path;
+
+use reportify::{bail, ResultExt};
+use tracing::info;
+use xscript::{cmd, ParentEnv, Run};
+
+use crate::cli::status::archive_progress;
+use crate::config::layers::BootstrapConfig;
+use crate::config::systems::Architecture;
+use crate::utils::archive::pack_tar;
+use crate::utils::temp;
+use crate::BakeryResult;
+
+/// Default mirror to bootstrap from.
+const DEFAULT_MIRROR: &str = "http://deb.debian.org/debian";
+
+/// Bootstrap a Debian root filesystem and package it as a layer.
+pub fn bootstrap(
+    config: &BootstrapConfig,
+    arch: Architecture,
+    layer_path: &Path,
+) -> BakeryResult<()> {
+    let debian_arch = match arch {
+        Architecture::Amd64 => "amd64",
+        Architecture::Arm64 => "arm64",
+        Architecture::Armv7 => "armhf",
+        Architecture::Arm => "armel",
+        Architecture::Armhf => {
+            bail!("Debian does not support `armhf` (ARMv6 with hard-float)");
+        }
+    };
+    if let Some(parent) = layer_path.parent() {
+        fs::create_dir_all(parent).whatever("unable to create layer path")?;
+    }
+    let temp_dir = temp::temp_dir()?;
+    let temp_dir_path = temp_dir.path();
+    let system_dir = temp_dir_path.join("roots/system");
+    fs::create_dir_all(&system_dir).whatever("unable to create system directory")?;
+    info!(
+        "Bootstrapping Debian `{}` for `{debian_arch}`.",
+        config.suite
+    );
+    let mut mmdebstrap = cmd!("mmdebstrap", format!("--architectures={debian_arch}"));
+    if let Some(variant) = &config.variant {
+        mmdebstrap.add_arg(format!("--variant={variant}"));
+    }
+    mmdebstrap
+        .add_arg(&config.suite)
+        .add_arg(&system_dir)
+        .add_arg(config.mirror.as_deref().unwrap_or(DEFAULT_MIRROR));
+    ParentEnv
+        .run(mmdebstrap)
+        .whatever("unable to bootstrap root filesystem")?;
+    pack_tar(
+        temp_dir_path,
+        &layer_path,
+        &mut archive_progress("Packing", &layer_path),
+    )
+    .whatever("unable to create layer tar file")?;
+    Ok(())
+}
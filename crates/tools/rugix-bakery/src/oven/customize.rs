@@ -1,71 +1,416 @@
 //! Applies a set of recipes to a system.
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::ffi::OsString;
 use std::fs;
-use std::io::{Read, Write};
+use std::hash::Hash;
+use std::io::{self, Read, Write};
+use std::num::NonZeroUsize;
 use std::ops::Deref;
-use std::path::Path;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use reportify::{bail, ResultExt};
+use byte_calc::NumBytes;
+use reportify::{bail, whatever, ResultExt};
 use rugix_cli::StatusSegmentRef;
 use rugix_common::mount::{MountStack, Mounted};
-use tempfile::tempdir;
-use tracing::{error, info};
-use xscript::{cmd, run, vars, Cmd, ParentEnv, Run};
+use tracing::{debug, error, info, warn};
+use url::Url;
+use xscript::{cmd, run, vars, Cmd, Run};
 
-use crate::cli::status::CliLog;
+use crate::cli::status::{archive_progress, CliLog, CliProgress};
 use crate::config::layers::LayerConfig;
+use crate::config::load_config;
+use crate::config::recipes::ParameterValue;
 use crate::config::systems::Architecture;
+use crate::oven::apt_mirror::AptSources;
+use crate::oven::emulation::setup_emulation;
 use crate::oven::layer::LayerContext;
+use crate::oven::retry;
 use crate::project::layers::Layer;
-use crate::project::library::Library;
-use crate::project::recipes::{PackageManager, Recipe, StepKind};
+use crate::project::library::{Library, RecipeIdx};
+use crate::project::recipes::{
+    CopyFile, PackageManager, Recipe, RecipeStep, StepKind, PRESET_PARAMETER,
+};
 use crate::project::repositories::RepositoryIdx;
+use crate::project::stats::{BuildStats, StepTiming};
 use crate::project::ProjectRef;
-use crate::utils::caching::{mtime, mtime_recursive};
+use crate::utils::archive::{extract_tar, pack_tar};
+use crate::utils::caching::{
+    checkpoint_keys, checksum_path, file_digest, hash_recursive, mtime, mtime_recursive,
+    recipes_cache_key, store_copy, write_checksum, Hasher,
+};
+use crate::utils::dedup::dedup_files;
+use crate::utils::env_file::load_env_file;
+use crate::utils::overlay::{find_conflict, prepare_merge};
+use crate::utils::temp;
+use crate::utils::tree_diff::{diff_upper, TreeDiff};
 use crate::BakeryResult;
 
 struct Logger {
+    layer_name: String,
     cli_log: StatusSegmentRef<CliLog>,
-    state: Mutex<LoggerState>,
-}
-
-struct LoggerState {
-    log_file: fs::File,
-    line_buffer: Vec<u8>,
+    /// Directory for the log files of individual steps.
+    step_log_dir: Option<PathBuf>,
+    /// Progress of applying the recipes of the layer.
+    progress: RecipeProgress,
+    /// Durations of the steps run so far.
+    timings: Mutex<Vec<StepTiming>>,
+    /// Statistics of the builds of the project.
+    stats: Arc<BuildStats>,
+    /// Indicates whether lines of output are prefixed with the name of the recipe.
+    prefix_lines: AtomicBool,
+    log_file: Mutex<fs::File>,
 }
 
 impl Logger {
-    pub fn new(layer_name: &str, layer_path: &Path) -> BakeryResult<Self> {
+    pub fn new(
+        stats: &Arc<BuildStats>,
+        layer_name: &str,
+        layer_path: &Path,
+        log_dir: Option<&Path>,
+        jobs: &[RecipeJob],
+    ) -> BakeryResult<Self> {
         let log_file = fs::File::create(layer_path.join("build.log"))
             .whatever("error creating layer log file")?;
         Ok(Self {
+            layer_name: layer_name.to_owned(),
             cli_log: rugix_cli::add_status(CliLog::new(format!("Layer: {layer_name}"))),
-            state: Mutex::new(LoggerState {
-                log_file,
-                line_buffer: Vec::new(),
-            }),
+            step_log_dir: log_dir.map(|log_dir| log_dir.join(layer_name)),
+            progress: RecipeProgress::new(layer_name, jobs),
+            timings: Mutex::new(Vec::new()),
+            stats: stats.clone(),
+            prefix_lines: AtomicBool::new(false),
+            log_file: Mutex::new(log_file),
+        })
+    }
+
+    /// Start logging the output of the given step.
+    ///
+    /// The output is additionally written to a dedicated log file if a log directory has
+    /// been configured.
+    pub fn start_step(&self, recipe: &str, step: &str) -> BakeryResult<StepLogger<'_>> {
+        let prefix = self
+            .prefix_lines
+            .load(Ordering::Relaxed)
+            .then(|| format!("[{recipe}] "));
+        let log_file = match &self.step_log_dir {
+            Some(step_log_dir) => {
+                let recipe_log_dir = step_log_dir.join(recipe);
+                fs::create_dir_all(&recipe_log_dir).whatever("unable to create log directory")?;
+                let step_log_file = fs::File::create(recipe_log_dir.join(format!("{step}.log")))
+                    .whatever("unable to create step log file")?;
+                Some(Mutex::new(step_log_file))
+            }
+            None => None,
+        };
+        Ok(StepLogger {
+            logger: self,
+            prefix,
+            line_buffer: Mutex::new(Vec::new()),
+            log_file,
         })
     }
 
+    /// Set whether lines of output are prefixed with the name of the recipe.
+    ///
+    /// This applies to steps started afterwards and is used while recipes are applied
+    /// concurrently such that their output can be told apart.
+    pub fn set_prefix_lines(&self, prefix_lines: bool) {
+        self.prefix_lines.store(prefix_lines, Ordering::Relaxed);
+    }
+
+    /// Record the duration of the given step.
+    pub fn record_step(&self, recipe: &str, step: &str, duration: Duration) {
+        self.timings.lock().unwrap().push(StepTiming {
+            layer: self.layer_name.clone(),
+            recipe: recipe.to_owned(),
+            step: step.to_owned(),
+            duration_secs: duration.as_secs_f64(),
+        });
+    }
+
+    /// Print the durations of the recipes and their steps, slowest first.
+    ///
+    /// The durations are also added to the statistics of the builds of the project.
+    pub fn report_timings(&self) {
+        let timings = std::mem::take(&mut *self.timings.lock().unwrap());
+        if timings.is_empty() {
+            return;
+        }
+        info!("Recipe timings of layer `{}`:", self.layer_name);
+        for (recipe, duration, steps) in summarize_timings(&timings) {
+            info!("  {duration:>8.1}s  {recipe}");
+            for (step, duration) in steps {
+                info!("    {duration:>8.1}s  {step}");
+            }
+        }
+        self.stats.add_timings(timings);
+    }
+
+    /// Record that the given number of recipes has been applied.
+    pub fn record_applied(&self, count: usize) {
+        self.stats.add_recipes_applied(count);
+    }
+
+    /// Write a complete line of output.
+    fn write_line(&self, line: String) {
+        let mut log_file = self.log_file.lock().unwrap();
+        let _ = writeln!(log_file, "{line}");
+        self.cli_log.push_line(line);
+    }
+}
+
+/// Logger for the output of an individual step.
+///
+/// Steps of recipes applied in parallel each have their own step logger.
+struct StepLogger<'l> {
+    logger: &'l Logger,
+    /// Prefix of the lines written to the layer log.
+    prefix: Option<String>,
+    /// Incomplete line of output.
+    line_buffer: Mutex<Vec<u8>>,
+    /// Log file of the step.
+    log_file: Option<Mutex<fs::File>>,
+}
+
+impl StepLogger<'_> {
     pub fn write(&self, bytes: &[u8]) {
-        let mut state = self.state.lock().unwrap();
-        let _ = state.log_file.write_all(&bytes);
+        if let Some(log_file) = &self.log_file {
+            let _ = log_file.lock().unwrap().write_all(bytes);
+        }
+        let mut line_buffer = self.line_buffer.lock().unwrap();
         for b in bytes {
             if *b == b'\n' {
-                self.cli_log
-                    .push_line(String::from_utf8_lossy(&state.line_buffer).into_owned());
-                state.line_buffer.clear();
+                self.write_line(&line_buffer);
+                line_buffer.clear();
             } else {
-                state.line_buffer.push(*b);
+                line_buffer.push(*b);
             }
         }
     }
+
+    fn write_line(&self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        match &self.prefix {
+            Some(prefix) => self.logger.write_line(format!("{prefix}{line}")),
+            None => self.logger.write_line(line.into_owned()),
+        }
+    }
+}
+
+impl Drop for StepLogger<'_> {
+    fn drop(&mut self) {
+        let line_buffer = self.line_buffer.get_mut().unwrap();
+        if !line_buffer.is_empty() {
+            let line = std::mem::take(line_buffer);
+            self.write_line(&line);
+        }
+    }
+}
+
+/// Progress of applying the recipes of a layer.
+///
+/// Recipes contribute to the progress according to their weight.
+struct RecipeProgress {
+    /// Weights of the recipes.
+    weights: Vec<u64>,
+    /// Sum of the weights of all recipes.
+    total_weight: u64,
+    /// Sum of the weights of the recipes applied so far.
+    applied_weight: AtomicU64,
+    /// Time at which the progress has been created.
+    started: Instant,
+    /// Progress bar of the layer.
+    cli_progress: StatusSegmentRef<CliProgress>,
+}
+
+impl RecipeProgress {
+    pub fn new(layer_name: &str, jobs: &[RecipeJob]) -> Self {
+        let weights = jobs
+            .iter()
+            .map(|job| u64::from(job.recipe.config.weight.unwrap_or(1)))
+            .collect::<Vec<_>>();
+        let total_weight = weights.iter().sum();
+        Self {
+            weights,
+            total_weight,
+            applied_weight: AtomicU64::new(0),
+            started: Instant::now(),
+            cli_progress: rugix_cli::add_status(CliProgress::new(
+                format!("Recipes: {layer_name}"),
+                total_weight,
+            )),
+        }
+    }
+
+    /// Log that the recipe of the given job is being applied.
+    ///
+    /// Once some recipes have been applied, an estimate of the remaining time is included.
+    pub fn start(&self, idx: usize, job: &RecipeJob) {
+        let recipe = &job.recipe;
+        let applied_weight = self.applied_weight.load(Ordering::Relaxed);
+        let percent = (applied_weight * 100)
+            .checked_div(self.total_weight)
+            .unwrap_or(100);
+        let eta = if applied_weight > 0 {
+            let remaining = self.started.elapsed().as_secs_f64()
+                * (self.total_weight - applied_weight) as f64
+                / applied_weight as f64;
+            format!(", ETA {}", format_duration(remaining as u64))
+        } else {
+            String::new()
+        };
+        info!(
+            "[{:>2}/{}] ({percent:>2}%{eta}) {} {:?}",
+            idx + 1,
+            self.weights.len(),
+            recipe
+                .config
+                .description
+                .as_deref()
+                .unwrap_or(recipe.name.deref()),
+            &job.parameters,
+        );
+    }
+
+    /// Record that the recipe of the given job has been applied.
+    pub fn finish(&self, idx: usize) {
+        self.applied_weight
+            .fetch_add(self.weights[idx], Ordering::Relaxed);
+        self.cli_progress.inc(self.weights[idx]);
+    }
+}
+
+/// Format the given number of seconds in a human-readable way, e.g., `3m 05s`.
+fn format_duration(secs: u64) -> String {
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, secs) => format!("{secs}s"),
+        (0, mins, secs) => format!("{mins}m {secs:02}s"),
+        (hours, mins, _) => format!("{hours}h {mins:02}m"),
+    }
+}
+
+/// Group the given step durations by recipe, sorting recipes and steps by duration.
+///
+/// Returns the name, total duration, and step durations of each recipe, slowest first.
+fn summarize_timings(timings: &[StepTiming]) -> Vec<(&str, f64, Vec<(&str, f64)>)> {
+    let mut recipes = Vec::<(&str, f64, Vec<(&str, f64)>)>::new();
+    for timing in timings {
+        let idx = match recipes
+            .iter()
+            .position(|(name, _, _)| *name == timing.recipe)
+        {
+            Some(idx) => idx,
+            None => {
+                recipes.push((timing.recipe.as_str(), 0.0, Vec::new()));
+                recipes.len() - 1
+            }
+        };
+        let (_, total, steps) = &mut recipes[idx];
+        *total += timing.duration_secs;
+        steps.push((timing.step.as_str(), timing.duration_secs));
+    }
+    for (_, _, steps) in &mut recipes {
+        steps.sort_by(|a, b| b.1.total_cmp(&a.1));
+    }
+    recipes.sort_by(|a, b| b.1.total_cmp(&a.1));
+    recipes
+}
+
+/// Options for customizing a layer.
+#[derive(Debug, Clone, Default)]
+pub struct CustomizeOpts {
+    /// CPU variant of the architecture exposed to recipes.
+    pub arch_variant: Option<String>,
+    /// Keep the work directory instead of using a temporary directory.
+    pub keep_work_dir: bool,
+    /// Recipe to continue from, assuming earlier recipes have been applied to the kept
+    /// work directory.
+    pub continue_from: Option<String>,
+    /// Directories copied over the root filesystem after applying all recipes.
+    ///
+    /// Overlays are applied in order such that later overlays take precedence.
+    pub overlay_dirs: Vec<PathBuf>,
+    /// Directory to additionally write the output of each step to.
+    pub log_dir: Option<PathBuf>,
+    /// Replace identical files with hardlinks before packing the layer.
+    pub dedup_files: bool,
+    /// Maximal number of independent recipes to apply concurrently.
+    ///
+    /// If not set, recipes are applied sequentially.
+    pub parallel_recipes: Option<NonZeroUsize>,
+    /// Additional environment variables for recipe steps.
+    pub env: Vec<(String, String)>,
+    /// Mirror to rewrite the APT sources to while applying recipes.
+    pub apt_mirror: Option<Url>,
+    /// Hosts of the APT sources served by the mirror.
+    pub apt_mirror_hosts: Vec<String>,
+    /// Number of retries of package manager operations failing due to network problems.
+    pub apt_retries: u32,
+    /// Subtree of the root filesystem to apply the recipes to.
+    ///
+    /// Only the subtree is extracted from the parent layer and spliced back afterwards.
+    pub subtree: Option<PathBuf>,
+    /// Write a SHA-256 checksum file next to the layer archive.
+    pub checksum: bool,
+    /// Recipe parameters overriding the parameters of the layer configuration.
+    pub parameters: ParameterOverrides,
+    /// Snapshot the layer after each recipe and resume from the latest snapshot whose
+    /// inputs are unchanged.
+    pub incremental: bool,
 }
 
+/// Recipe parameters by recipe name, overriding the parameters of layer configurations.
+pub type ParameterOverrides = HashMap<String, HashMap<String, ParameterValue>>;
+
+/// Load recipe parameters from the given TOML or JSON file.
+///
+/// The file has the same structure as the `parameters` section of layer configurations.
+/// Files with a `.json` extension are parsed as JSON, all other files as TOML.
+pub fn load_params_file(path: &Path) -> BakeryResult<ParameterOverrides> {
+    if path
+        .extension()
+        .is_some_and(|extension| extension == "json")
+    {
+        let contents = fs::read_to_string(path)
+            .whatever("unable to read parameters file")
+            .with_info(|_| format!("path: {path:?}"))?;
+        serde_json::from_str(&contents)
+            .whatever("unable to parse parameters file")
+            .with_info(|_| format!("path: {path:?}"))
+    } else {
+        load_config(path)
+    }
+}
+
+/// Load additional environment variables for recipe steps from the given file.
+///
+/// Variables set by Rugix Bakery itself take precedence. Such variables are ignored with
+/// a warning.
+pub fn load_step_env(path: &Path) -> BakeryResult<Vec<(String, String)>> {
+    let mut env = load_env_file(path)?;
+    env.retain(|(name, _)| {
+        let reserved = name.starts_with("RUGIX_")
+            || name.starts_with("RECIPE_")
+            || name == "DEBIAN_FRONTEND"
+            || name == "LAYER_REBUILD_IF_CHANGED";
+        if reserved {
+            warn!("Ignoring variable `{name}` of environment file, it is set by Rugix Bakery.");
+        }
+        !reserved
+    });
+    Ok(env)
+}
+
+/// Bake the given layer by applying its recipes and pack it into the archive *target*.
+///
+/// The root filesystem is extracted from the parent layer archive *src*, if given.
+/// Intermediate build state is kept in the layer directory *layer_path* of the project.
 pub fn customize(
     project: &ProjectRef,
     arch: Architecture,
@@ -73,14 +418,47 @@ pub fn customize(
     src: Option<&Path>,
     target: &Path,
     layer_path: &Path,
+    opts: &CustomizeOpts,
 ) -> BakeryResult<()> {
     let library = project.library()?;
     // Collect the recipes to apply.
-    let config = layer.config(arch).unwrap();
-    let jobs = recipe_schedule(layer.repo, config, &library)?;
+    let Some(config) = layer.config(arch) else {
+        bail!("no layer configuration for architecture `{arch}`");
+    };
+    let mut jobs = recipe_schedule(
+        layer.repo,
+        config,
+        &library,
+        project.is_strict(),
+        project.max_recipes(),
+        &opts.parameters,
+    )?;
     if jobs.is_empty() {
         bail!("layer must have recipes")
     }
+    let build = LayerBuild {
+        project,
+        arch,
+        layer,
+        layer_path,
+        opts,
+        checkpoints: None,
+    };
+    let work_dir = project.dir().join(layer_path).join("work");
+    if let Some(continue_from) = &opts.continue_from {
+        let recipe = &library.recipes[library.try_lookup(layer.repo, continue_from)?];
+        let Some(position) = jobs.iter().position(|job| Arc::ptr_eq(&job.recipe, recipe)) else {
+            bail!("recipe `{continue_from}` is not part of the layer");
+        };
+        if !work_dir.exists() {
+            bail!("no work directory to continue from, use `--keep-work-dir` first");
+        }
+        info!("Continuing from recipe `{continue_from}`.");
+        jobs.drain(..position);
+        apply_all(&build, &jobs, &work_dir)?;
+        pack(&work_dir, target)?;
+        return update_checksum(target, opts);
+    }
     let mut last_modified = jobs
         .iter()
         .map(|job| job.recipe.modified)
@@ -90,19 +468,30 @@ pub fn customize(
     if let Some(src) = src {
         last_modified = last_modified.max(mtime(src).whatever("unable to determine mtime")?);
     }
+    for overlay_dir in &opts.overlay_dirs {
+        let modified = mtime_recursive(&project.dir().join(overlay_dir))
+            .whatever("unable to determine mtime of overlay directory")
+            .with_info(|_| format!("overlay: {overlay_dir:?}"))?;
+        last_modified = last_modified.max(modified);
+    }
     let mut force_run = false;
-    let used_files = project
+    let mut used_files = Vec::new();
+    let used_files_path = project
         .dir()
         .join(layer_path.join("rebuild-if-changed.txt"));
-    if used_files.exists() {
-        for line in std::fs::read_to_string(used_files)
+    if used_files_path.exists() {
+        for line in std::fs::read_to_string(used_files_path)
             .whatever("unable to read used files")?
             .lines()
         {
             if let Ok(modified) = mtime_recursive(&project.dir().join(line)) {
-                last_modified = last_modified.max(modified)
+                last_modified = last_modified.max(modified);
+                used_files.push(project.dir().join(line));
             } else {
-                error!("error determining modification time for {line}");
+                error!(
+                    file = line,
+                    "error determining modification time for {line}"
+                );
                 force_run = true;
             }
         }
@@ -111,42 +500,609 @@ pub fn customize(
         && last_modified < mtime(target).whatever("unable to read `mtime` of target")?
         && !force_run
     {
+        if opts.checksum && !checksum_path(target).exists() {
+            write_checksum(target)?;
+        }
         return Ok(());
     }
-    let bundle_dir = tempdir().whatever("unable to create temporary directory")?;
-    let bundle_dir = bundle_dir.path();
-    if let Some(src) = src {
-        info!("Extracting layer.");
-        run!(["tar", "-x", "-f", &src, "-C", bundle_dir]).whatever("unable to extract layer")?;
+    let cache_entry = match project.layer_cache_dir() {
+        Some(cache_dir) if !opts.keep_work_dir && !force_run => {
+            let key = layer_cache_key(project, arch, src, &jobs, &used_files, opts)?;
+            Some(cache_dir.join(format!("{key}.tar")))
+        }
+        _ => None,
+    };
+    if let Some(cache_entry) = &cache_entry {
+        if cache_entry.exists() {
+            info!("Reusing cached layer with identical inputs.");
+            fs::copy(cache_entry, target).whatever("unable to copy cached layer")?;
+            return update_checksum(target, opts);
+        }
+    }
+    let temp_dir;
+    let bundle_dir = if opts.keep_work_dir {
+        if work_dir.exists() {
+            fs::remove_dir_all(&work_dir).whatever("unable to remove work directory")?;
+        }
+        fs::create_dir_all(&work_dir).whatever("unable to create work directory")?;
+        work_dir.as_path()
+    } else {
+        temp_dir = temp::temp_dir()?;
+        temp_dir.path()
+    };
+    if let Some(subtree) = &opts.subtree {
+        if opts.incremental {
+            bail!("incremental builds are not supported for subtrees");
+        }
+        let Some(src) = src else {
+            bail!("subtrees are only supported for layers with a parent");
+        };
+        extract_subtree(&jobs, src, subtree, bundle_dir)?;
+        apply_all(&build, &jobs, bundle_dir)?;
+        splice_subtree(src, subtree, bundle_dir, target)?;
     } else {
-        info!("Creating empty layer.");
-        std::fs::create_dir_all(&bundle_dir).whatever("unable ot create layer directory")?;
+        let mut checkpoints = None;
+        let mut restored = 0;
+        if opts.incremental {
+            let incremental =
+                Checkpoints::new(project, arch, layer_path, src, &jobs, &used_files, opts)?;
+            restored = incremental.restore(bundle_dir)?;
+            checkpoints = Some(incremental.skip(restored));
+        }
+        if restored > 0 {
+            info!(
+                "Skipping {restored} unchanged recipes, resuming after recipe `{}`.",
+                jobs[restored - 1].recipe.name
+            );
+        } else if let Some(src) = src {
+            info!("Extracting layer.");
+            extract_tar(src, bundle_dir, &mut archive_progress("Extracting", src))
+                .whatever("unable to extract layer")?;
+        } else {
+            info!("Creating empty layer.");
+            std::fs::create_dir_all(&bundle_dir).whatever("unable ot create layer directory")?;
+        }
+        let build = LayerBuild {
+            checkpoints: checkpoints.as_ref(),
+            ..build
+        };
+        apply_all(&build, &jobs[restored..], bundle_dir)?;
+        pack(bundle_dir, target)?;
+    }
+    update_checksum(target, opts)?;
+    if let Some(cache_entry) = &cache_entry {
+        store_copy(target, cache_entry)?;
+    }
+    Ok(())
+}
+
+/// Write the checksum file of the layer archive, if enabled, or remove a stale one.
+fn update_checksum(target: &Path, opts: &CustomizeOpts) -> BakeryResult<()> {
+    if opts.checksum {
+        return write_checksum(target);
+    }
+    let checksum_path = checksum_path(target);
+    if checksum_path.exists() {
+        fs::remove_file(&checksum_path).whatever("unable to remove stale checksum file")?;
     }
+    Ok(())
+}
+
+/// Snapshots of the bundle directory after applying each recipe of a layer.
+///
+/// Snapshots are stored in the `checkpoints` directory of the layer and named by a key
+/// covering all inputs of the recipes applied so far, such that a snapshot is only
+/// restored if none of these inputs has changed.
+struct Checkpoints {
+    /// Directory the snapshots are stored in.
+    dir: PathBuf,
+    /// Keys of the snapshots after each of the remaining recipes.
+    keys: Vec<String>,
+}
+
+impl Checkpoints {
+    /// Compute the keys of the snapshots and remove stale snapshots.
+    fn new(
+        project: &ProjectRef,
+        arch: Architecture,
+        layer_path: &Path,
+        src: Option<&Path>,
+        jobs: &[RecipeJob],
+        used_files: &[PathBuf],
+        opts: &CustomizeOpts,
+    ) -> BakeryResult<Self> {
+        if opts.parallel_recipes.is_some() {
+            bail!("incremental builds are not supported with parallel recipes");
+        }
+        if opts.apt_mirror.is_some() {
+            bail!("incremental builds are not supported with an APT mirror");
+        }
+        let mut settings = vec![("arch", arch.as_str().as_bytes().to_vec())];
+        if let Some(arch_variant) = &opts.arch_variant {
+            settings.push(("arch_variant", arch_variant.as_bytes().to_vec()));
+        }
+        for (name, value) in &opts.env {
+            settings.push(("env", format!("{name}={value}").into_bytes()));
+        }
+        for used_file in used_files {
+            settings.push(("used_file", used_file_digest(used_file)?.into_bytes()));
+        }
+        let settings = settings
+            .iter()
+            .map(|(tag, value)| (*tag, value.as_slice()))
+            .collect::<Vec<_>>();
+        let keys = checkpoint_keys(src, &recipe_keys(jobs)?, &settings)?;
+        let dir = project.dir().join(layer_path).join("checkpoints");
+        fs::create_dir_all(&dir).whatever("unable to create checkpoints directory")?;
+        for entry in fs::read_dir(&dir).whatever("unable to read checkpoints directory")? {
+            let entry = entry.whatever("unable to read checkpoints directory")?;
+            let is_current = keys
+                .iter()
+                .any(|key| entry.file_name().to_str() == Some(&format!("{key}.tar")));
+            if !is_current {
+                fs::remove_file(entry.path()).whatever("unable to remove stale checkpoint")?;
+            }
+        }
+        Ok(Self { dir, keys })
+    }
+
+    /// Path of the snapshot after the recipe with the given index.
+    fn path(&self, idx: usize) -> PathBuf {
+        self.dir.join(format!("{}.tar", self.keys[idx]))
+    }
+
+    /// Extract the latest snapshot into the given bundle directory, if there is one.
+    ///
+    /// Returns the number of recipes which have been applied to the snapshot.
+    fn restore(&self, bundle_dir: &Path) -> BakeryResult<usize> {
+        let Some(idx) = (0..self.keys.len())
+            .rev()
+            .find(|idx| self.path(*idx).exists())
+        else {
+            return Ok(0);
+        };
+        info!("Extracting checkpoint.");
+        let path = self.path(idx);
+        extract_tar(
+            &path,
+            bundle_dir,
+            &mut archive_progress("Extracting", &path),
+        )
+        .whatever("unable to extract checkpoint")?;
+        Ok(idx + 1)
+    }
+
+    /// Drop the keys of the given number of already applied recipes.
+    fn skip(mut self, applied: usize) -> Self {
+        self.keys.drain(..applied);
+        self
+    }
+
+    /// Snapshot the given bundle directory after the recipe with the given index.
+    fn save(&self, idx: usize, bundle_dir: &Path) -> BakeryResult<()> {
+        let path = self.path(idx);
+        let partial = path.with_extension("tar.partial");
+        pack_tar(
+            bundle_dir,
+            &partial,
+            &mut archive_progress("Packing", &partial),
+        )
+        .whatever("unable to create checkpoint")?;
+        fs::rename(&partial, &path).whatever("unable to rename checkpoint")?;
+        Ok(())
+    }
+}
+
+/// Compute the keys of the given recipe jobs for content-based caching.
+///
+/// The key of a job covers the name, contents, and resolved parameters of the recipe.
+fn recipe_keys(jobs: &[RecipeJob]) -> BakeryResult<Vec<String>> {
+    let mut keys = Vec::with_capacity(jobs.len());
+    for job in jobs {
+        let mut hasher = Hasher::new();
+        hasher.push("recipe", job.recipe.name.as_bytes());
+        hasher.push("fingerprint", job.recipe.fingerprint()?.as_str());
+        let mut parameters = job.parameters.iter().collect::<Vec<_>>();
+        parameters.sort();
+        for (name, value) in parameters {
+            hasher.push("parameter", format!("{name}={value}"));
+        }
+        keys.push(hasher.finalize());
+    }
+    Ok(keys)
+}
+
+/// Compute the digest of a file or directory used by the recipes of a layer.
+fn used_file_digest(used_file: &Path) -> BakeryResult<String> {
+    let mut hasher = Hasher::new();
+    if used_file.is_dir() {
+        hash_recursive(&mut hasher, used_file)
+    } else {
+        file_digest(used_file).map(|digest| hasher.push("file", digest))
+    }
+    .whatever("unable to hash used file")
+    .with_info(|_| format!("file: {used_file:?}"))?;
+    Ok(hasher.finalize())
+}
+
+/// Compute the key of the layer in the layer cache.
+///
+/// Besides the source layer and the recipes, the key covers all options and files which
+/// influence the resulting layer.
+fn layer_cache_key(
+    project: &ProjectRef,
+    arch: Architecture,
+    src: Option<&Path>,
+    jobs: &[RecipeJob],
+    used_files: &[PathBuf],
+    opts: &CustomizeOpts,
+) -> BakeryResult<String> {
+    let mut settings = vec![("arch", arch.as_str().as_bytes().to_vec())];
+    if let Some(arch_variant) = &opts.arch_variant {
+        settings.push(("arch_variant", arch_variant.as_bytes().to_vec()));
+    }
+    for (name, value) in &opts.env {
+        settings.push(("env", format!("{name}={value}").into_bytes()));
+    }
+    for overlay_dir in &opts.overlay_dirs {
+        let mut hasher = Hasher::new();
+        hash_recursive(&mut hasher, &project.dir().join(overlay_dir))
+            .whatever("unable to hash overlay directory")
+            .with_info(|_| format!("overlay: {overlay_dir:?}"))?;
+        settings.push(("overlay", hasher.finalize().into_bytes()));
+    }
+    for used_file in used_files {
+        settings.push(("used_file", used_file_digest(used_file)?.into_bytes()));
+    }
+    if opts.dedup_files {
+        settings.push(("dedup_files", b"true".to_vec()));
+    }
+    if let Some(subtree) = &opts.subtree {
+        settings.push(("subtree", subtree.as_os_str().as_encoded_bytes().to_vec()));
+    }
+    if let Some(apt_mirror) = &opts.apt_mirror {
+        settings.push(("apt_mirror", apt_mirror.as_str().as_bytes().to_vec()));
+        for host in &opts.apt_mirror_hosts {
+            settings.push(("apt_mirror_host", host.as_bytes().to_vec()));
+        }
+    }
+    settings.push(("apt_retries", opts.apt_retries.to_string().into_bytes()));
+    let settings = settings
+        .iter()
+        .map(|(tag, value)| (*tag, value.as_slice()))
+        .collect::<Vec<_>>();
+    recipes_cache_key(src, &recipe_keys(jobs)?, &settings)
+}
+
+/// Layer being built by [`customize`].
+struct LayerBuild<'b> {
+    project: &'b ProjectRef,
+    arch: Architecture,
+    layer: &'b Layer,
+    /// Layer directory of the project for intermediate build state.
+    layer_path: &'b Path,
+    opts: &'b CustomizeOpts,
+    /// Checkpoints to snapshot the bundle directory to after each recipe, if any.
+    checkpoints: Option<&'b Checkpoints>,
+}
+
+/// Apply the recipes, overlays, and deduplication to the given bundle directory.
+fn apply_all(build: &LayerBuild, jobs: &[RecipeJob], bundle_dir: &Path) -> BakeryResult<()> {
+    let LayerBuild {
+        project,
+        arch,
+        layer,
+        layer_path,
+        opts,
+        checkpoints,
+    } = *build;
+    let root_dir = create_layer_roots(bundle_dir);
+    let apt_sources = use_apt_mirror(&root_dir, opts)?;
     let layer_ctx = LayerContext {
         project: project.clone(),
+        arch_variant: opts.arch_variant.clone(),
+        env: opts.env.clone(),
         build_dir: bundle_dir.to_path_buf(),
         output_dir: layer_path.to_path_buf(),
+        update_package_lists: Arc::new(AtomicBool::new(
+            apt_sources.as_ref().is_some_and(AptSources::is_rewritten),
+        )),
+        apt_retries: opts.apt_retries,
+    };
+    let logger = Logger::new(
+        project.stats(),
+        &layer.name,
+        layer_path,
+        opts.log_dir.as_deref(),
+        jobs,
+    )?;
+    let applied = if let Some(max_jobs) = opts.parallel_recipes {
+        let scratch_dir = project.dir().join(layer_path).join("parallel");
+        apply_recipes_parallel(
+            &layer_ctx,
+            &logger,
+            arch,
+            layer,
+            jobs,
+            &root_dir,
+            &scratch_dir,
+            max_jobs.get(),
+        )
+    } else {
+        apply_recipes(
+            &layer_ctx,
+            &logger,
+            project,
+            arch,
+            jobs,
+            &root_dir,
+            checkpoints,
+        )
     };
-    let root_dir = bundle_dir.join("roots/system");
-    std::fs::create_dir_all(&root_dir).ok();
-    let logger = Logger::new(&layer.name, layer_path)?;
-    apply_recipes(&layer_ctx, &logger, project, arch, &jobs, &root_dir)?;
+    logger.report_timings();
+    if let Some(apt_sources) = apt_sources {
+        restore_apt_sources(&layer_ctx, apt_sources)?;
+    }
+    applied?;
+    logger.record_applied(jobs.len());
+    apply_overlays(project, &opts.overlay_dirs, &root_dir)?;
+    if opts.dedup_files {
+        info!("Deduplicating files.");
+        let stats = dedup_files(&root_dir).whatever("unable to deduplicate files")?;
+        info!(
+            "Replaced {} duplicate files with hardlinks, saving {}.",
+            stats.files,
+            NumBytes::from_raw(stats.bytes)
+        );
+    }
+    Ok(())
+}
+
+/// Pack the given bundle directory into the target archive.
+fn pack(bundle_dir: &Path, target: &Path) -> BakeryResult<()> {
     info!("packing system files");
-    run!(["tar", "-c", "-f", &target, "-C", bundle_dir, "."])
+    pack_tar(bundle_dir, target, &mut archive_progress("Packing", target))
         .whatever("unable to package system files")?;
     Ok(())
 }
 
-struct RecipeJob {
-    recipe: Arc<Recipe>,
-    parameters: HashMap<String, String>,
+/// Extract the given subtree of the root filesystem of the source layer.
+///
+/// Fails if any of the recipes has steps running in the system, as those may require
+/// the entire root filesystem.
+fn extract_subtree(
+    jobs: &[RecipeJob],
+    src: &Path,
+    subtree: &Path,
+    bundle_dir: &Path,
+) -> BakeryResult<()> {
+    for job in jobs {
+        if job.recipe.steps.iter().any(|step| step.kind.needs_chroot()) {
+            bail!(
+                "recipe `{}` has `packages` or `install` steps, which are not supported for subtrees",
+                job.recipe.name
+            );
+        }
+    }
+    info!("Extracting subtree {subtree:?} of layer.");
+    run!([
+        "tar",
+        "-x",
+        "-f",
+        src,
+        "-C",
+        bundle_dir,
+        subtree_member(subtree)
+    ])
+    .whatever("unable to extract subtree of layer")
+    .with_info(|_| format!("subtree: {subtree:?}"))?;
+    Ok(())
 }
 
-fn recipe_schedule(
+/// Replace the given subtree of the source layer with the subtree of the bundle directory.
+fn splice_subtree(
+    src: &Path,
+    subtree: &Path,
+    bundle_dir: &Path,
+    target: &Path,
+) -> BakeryResult<()> {
+    info!("Splicing subtree into layer.");
+    let member = subtree_member(subtree);
+    let partial = target.with_extension("tar.partial");
+    fs::copy(src, &partial).whatever("unable to copy parent layer")?;
+    run!(["tar", "--delete", "-f", &partial, &member])
+        .whatever("unable to remove subtree from layer")?;
+    run!(["tar", "-r", "-f", &partial, "-C", bundle_dir, &member])
+        .whatever("unable to add subtree to layer")?;
+    fs::rename(&partial, target).whatever("unable to rename layer")?;
+    Ok(())
+}
+
+/// Name of the archive member of the given subtree of the root filesystem.
+fn subtree_member(subtree: &Path) -> PathBuf {
+    Path::new("./roots/system").join(subtree.strip_prefix("/").unwrap_or(subtree))
+}
+
+/// Preview the changes the given recipe makes to the root filesystem of the layer.
+///
+/// The recipes scheduled before the given recipe are applied to a temporary copy of the
+/// layer. The given recipe is then applied to an overlay filesystem on top of it, such
+/// that its changes can be reported without packing the layer. The directories of the
+/// overlay are kept in the layer directory *layer_path* of the project.
+pub fn preview_recipe(
+    project: &ProjectRef,
+    arch: Architecture,
+    layer: &Layer,
+    src: Option<&Path>,
+    layer_path: &Path,
+    recipe_name: &str,
+    opts: &CustomizeOpts,
+) -> BakeryResult<TreeDiff> {
+    let library = project.library()?;
+    let Some(config) = layer.config(arch) else {
+        bail!("no layer configuration for architecture `{arch}`");
+    };
+    let jobs = recipe_schedule(
+        layer.repo,
+        config,
+        &library,
+        project.is_strict(),
+        project.max_recipes(),
+        &opts.parameters,
+    )?;
+    let recipe = &library.recipes[library.try_lookup(layer.repo, recipe_name)?];
+    let Some(position) = jobs.iter().position(|job| Arc::ptr_eq(&job.recipe, recipe)) else {
+        bail!("recipe `{recipe_name}` is not part of the layer");
+    };
+    let bundle_dir = temp::temp_dir()?;
+    let bundle_dir = bundle_dir.path();
+    if let Some(src) = src {
+        info!("Extracting layer.");
+        extract_tar(src, bundle_dir, &mut archive_progress("Extracting", src))
+            .whatever("unable to extract layer")?;
+    }
+    let root_dir = create_layer_roots(bundle_dir);
+    let apt_sources = use_apt_mirror(&root_dir, opts)?;
+    let layer_ctx = LayerContext {
+        project: project.clone(),
+        arch_variant: opts.arch_variant.clone(),
+        env: opts.env.clone(),
+        build_dir: bundle_dir.to_path_buf(),
+        output_dir: layer_path.to_path_buf(),
+        update_package_lists: Arc::new(AtomicBool::new(
+            apt_sources.as_ref().is_some_and(AptSources::is_rewritten),
+        )),
+        apt_retries: opts.apt_retries,
+    };
+    let logger = Logger::new(
+        project.stats(),
+        &layer.name,
+        layer_path,
+        opts.log_dir.as_deref(),
+        &jobs,
+    )?;
+    let job_dir = project.dir().join(layer_path).join("preview");
+    if job_dir.exists() {
+        fs::remove_dir_all(&job_dir).whatever("unable to remove preview directory")?;
+    }
+    let applied = apply_recipes(
+        &layer_ctx,
+        &logger,
+        project,
+        arch,
+        &jobs[..position],
+        &root_dir,
+        None,
+    )
+    .and_then(|_| {
+        logger.progress.start(position, &jobs[position]);
+        apply_recipe_isolated(
+            &layer_ctx,
+            &logger,
+            project,
+            arch,
+            &jobs[position],
+            &root_dir,
+            &job_dir,
+        )
+    });
+    if let Some(apt_sources) = apt_sources {
+        // The package lists are only updated within the overlay, which is discarded.
+        apt_sources.restore(false)?;
+    }
+    let diff = applied.and_then(|_| {
+        diff_upper(&root_dir, &job_dir.join("upper")).whatever("unable to compute changes")
+    });
+    fs::remove_dir_all(&job_dir).ok();
+    diff
+}
+
+/// Compute the recipes the layer applies in the order they are applied.
+pub fn plan_recipes(
+    project: &ProjectRef,
+    arch: Architecture,
+    layer: &Layer,
+    parameters: &ParameterOverrides,
+) -> BakeryResult<Vec<RecipeJob>> {
+    let library = project.library()?;
+    let Some(config) = layer.config(arch) else {
+        bail!("no layer configuration for architecture `{arch}`");
+    };
+    recipe_schedule(
+        layer.repo,
+        config,
+        &library,
+        project.is_strict(),
+        project.max_recipes(),
+        parameters,
+    )
+}
+
+/// Rewrite the APT sources of the system to use the configured mirror, if any.
+fn use_apt_mirror(root_dir: &Path, opts: &CustomizeOpts) -> BakeryResult<Option<AptSources>> {
+    opts.apt_mirror
+        .as_ref()
+        .map(|mirror| AptSources::use_mirror(root_dir, mirror, &opts.apt_mirror_hosts))
+        .transpose()
+}
+
+/// Restore the original APT sources of the system.
+fn restore_apt_sources(layer_ctx: &LayerContext, apt_sources: AptSources) -> BakeryResult<()> {
+    // The flag is reset once the package lists have been updated from the mirror.
+    let lists_updated =
+        apt_sources.is_rewritten() && !layer_ctx.update_package_lists.load(Ordering::Relaxed);
+    apt_sources.restore(lists_updated)
+}
+
+/// Copy the given overlay directories over the root filesystem.
+///
+/// Modes and ownership are preserved and later overlays take precedence.
+fn apply_overlays(
+    project: &ProjectRef,
+    overlay_dirs: &[PathBuf],
+    root_dir: &Path,
+) -> BakeryResult<()> {
+    for overlay_dir in overlay_dirs {
+        let overlay_dir = project.dir().join(overlay_dir);
+        if !overlay_dir.is_dir() {
+            bail!("overlay directory {overlay_dir:?} does not exist");
+        }
+        info!("Applying overlay {overlay_dir:?}.");
+        let overlay_contents = overlay_dir.join(".");
+        run!(["cp", "-a", "--", &overlay_contents, root_dir])
+            .whatever("unable to apply overlay directory")
+            .with_info(|_| format!("overlay: {overlay_dir:?}"))?;
+    }
+    Ok(())
+}
+
+/// Recipe scheduled to be applied with its resolved parameters.
+pub struct RecipeJob {
+    pub recipe: Arc<Recipe>,
+    pub parameters: HashMap<String, String>,
+}
+
+/// Recipes of a layer with their resolved dependencies.
+#[derive(Debug, Clone)]
+pub struct RecipeGraph {
+    /// Recipes listed in the layer configuration, except for excluded ones.
+    pub roots: Vec<RecipeIdx>,
+    /// Recipes excluded by the layer configuration.
+    pub excluded: HashSet<RecipeIdx>,
+    /// Resolved dependencies of each recipe applied by the layer.
+    pub dependencies: HashMap<RecipeIdx, Vec<RecipeIdx>>,
+    /// Dependencies on excluded recipes, which are not applied.
+    pub excluded_dependencies: Vec<(RecipeIdx, RecipeIdx)>,
+}
+
+/// Resolve the recipes of the given layer and their dependencies.
+///
+/// Dependencies on excluded recipes are recorded but not followed.
+fn resolve_recipe_graph(
     repo: RepositoryIdx,
     layer: &LayerConfig,
     library: &Library,
-) -> BakeryResult<Vec<RecipeJob>> {
+) -> BakeryResult<RecipeGraph> {
     let mut stack = layer
         .recipes
         .as_deref()
@@ -154,44 +1110,142 @@ fn recipe_schedule(
         .iter()
         .map(|name| library.try_lookup(repo, name))
         .collect::<BakeryResult<Vec<_>>>()?;
+    let excluded = layer
+        .exclude
+        .as_deref()
+        .unwrap_or_default()
+        .iter()
+        .map(|name| library.try_lookup(repo, name.deref()))
+        .collect::<BakeryResult<HashSet<_>>>()?;
+    // Excluded recipes are never traversed, so their dependencies are not pulled in.
+    stack.retain(|idx| !excluded.contains(idx));
+    let roots = stack.clone();
     let mut enabled = stack.iter().cloned().collect::<HashSet<_>>();
+    let mut dependencies = HashMap::new();
+    let mut excluded_dependencies = Vec::new();
     while let Some(idx) = stack.pop() {
         let recipe = &library.recipes[idx];
-        for name in recipe.config.dependencies.as_deref().unwrap_or_default() {
+        let mut resolved = Vec::new();
+        for (name, constraint) in recipe.config.dependencies.iter().flat_map(|d| d.iter()) {
             let dependency = library.try_lookup(recipe.repository, name)?;
+            if excluded.contains(&dependency) {
+                excluded_dependencies.push((idx, dependency));
+                continue;
+            }
+            if let Some(constraint) = constraint {
+                library.recipes[dependency]
+                    .check_version(constraint)
+                    .with_info(|_| format!("dependency of recipe `{}`", recipe.name))?;
+            }
+            resolved.push(dependency);
             if enabled.insert(dependency) {
                 stack.push(dependency);
             }
         }
+        dependencies.insert(idx, resolved);
     }
-    for excluded in layer.exclude.as_deref().unwrap_or_default() {
-        let excluded = library.try_lookup(repo, excluded.deref())?;
-        enabled.remove(&excluded);
+    Ok(RecipeGraph {
+        roots,
+        excluded,
+        dependencies,
+        excluded_dependencies,
+    })
+}
+
+/// Resolve the recipes the given layer applies and their dependencies.
+pub fn recipe_graph(
+    project: &ProjectRef,
+    arch: Architecture,
+    layer: &Layer,
+) -> BakeryResult<RecipeGraph> {
+    let library = project.library()?;
+    let Some(config) = layer.config(arch) else {
+        bail!("no layer configuration for architecture `{arch}`");
+    };
+    resolve_recipe_graph(layer.repo, config, &library)
+}
+
+/// Compute the recipes to apply for the given layer.
+///
+/// The given *overrides* take precedence over the parameters of the layer configuration.
+/// Fails if parameters are provided for recipes which are not part of the layer or if
+/// more than *max_recipes* recipes would be applied.
+fn recipe_schedule(
+    repo: RepositoryIdx,
+    layer: &LayerConfig,
+    library: &Library,
+    strict: bool,
+    max_recipes: Option<usize>,
+    overrides: &ParameterOverrides,
+) -> BakeryResult<Vec<RecipeJob>> {
+    let graph = resolve_recipe_graph(repo, layer, library)?;
+    if !graph.excluded_dependencies.is_empty() {
+        let mut excluded_dependencies = graph
+            .excluded_dependencies
+            .iter()
+            .map(|(recipe, dependency)| {
+                format!(
+                    "`{}` depends on `{}`",
+                    library.recipes[*recipe].name, library.recipes[*dependency].name
+                )
+            })
+            .collect::<Vec<_>>();
+        excluded_dependencies.sort();
+        let excluded_dependencies = excluded_dependencies.join(", ");
+        if strict {
+            bail!("recipes depend on excluded recipes: {excluded_dependencies}");
+        }
+        warn!("Recipes depend on excluded recipes: {excluded_dependencies}.");
     }
-    let parameters = layer
-        .parameters
-        .as_ref()
-        .map(|parameters| {
-            parameters
+    let enabled = graph.dependencies.keys().copied().collect::<HashSet<_>>();
+    if let Some(cycle) = find_cycle(&graph.roots, &graph.dependencies) {
+        let cycle = cycle
+            .iter()
+            .map(|idx| library.recipes[*idx].name.to_string())
+            .collect::<Vec<_>>()
+            .join(" -> ");
+        bail!("recipe cycle detected: {cycle}");
+    }
+    if let Some(max_recipes) = max_recipes {
+        if enabled.len() > max_recipes {
+            bail!(
+                "layer applies {} recipes, exceeding the limit of {max_recipes}",
+                enabled.len()
+            );
+        }
+    }
+    let mut layer_parameters = layer.parameters.clone().unwrap_or_default();
+    for (name, values) in overrides {
+        layer_parameters.entry(name.clone()).or_default().extend(
+            values
                 .iter()
-                .map(|(name, parameters)| {
-                    let recipe = library.try_lookup(repo, name.deref())?;
-                    if !enabled.contains(&recipe) {
-                        bail!("recipe with name {name} is not part of the layer");
-                    }
-                    Ok((recipe, parameters))
-                })
-                .collect::<BakeryResult<HashMap<_, _>>>()
-        })
-        .transpose()?
-        .unwrap_or_default();
+                .map(|(name, value)| (name.clone(), value.clone())),
+        );
+    }
+    let mut parameters = HashMap::new();
+    let mut unused = Vec::new();
+    for (name, recipe_parameters) in &layer_parameters {
+        let recipe = library.try_lookup(repo, name.deref())?;
+        let recipe_parameters = LayerParameters::resolve(layer, recipe_parameters)
+            .with_info(|_| format!("parameters of recipe `{name}`"))?;
+        if enabled.contains(&recipe) {
+            parameters.insert(recipe, recipe_parameters);
+        } else {
+            unused.push(name.as_str());
+        }
+    }
+    if !unused.is_empty() {
+        unused.sort();
+        let unused = unused.join(", ");
+        bail!("parameters provided for recipes which are not part of the layer: {unused}");
+    }
     let mut recipes = enabled
         .into_iter()
         .map(|idx| {
             let recipe = library.recipes[idx].clone();
             let recipe_params = parameters.get(&idx);
             if let Some(params) = recipe_params {
-                for param_name in params.keys() {
+                for param_name in params.explicit_names() {
                     if !recipe
                         .config
                         .parameters
@@ -206,30 +1260,157 @@ fn recipe_schedule(
                     }
                 }
             }
-            let mut parameters = HashMap::new();
-            if let Some(p) = &recipe.config.parameters {
-                for (name, def) in p {
-                    if let Some(params) = recipe_params {
-                        if let Some(value) = params.get(name) {
-                            parameters.insert(name.to_owned(), value.to_string());
-                            continue;
-                        }
-                    }
-                    if let Some(default) = &def.default {
-                        parameters.insert(name.to_owned(), default.to_string());
-                        continue;
-                    }
-                    bail!("unable to find value for parameter `{name}`");
-                }
-            }
+            let parameters = recipe_parameters(&recipe, recipe_params)?;
             Ok(RecipeJob { recipe, parameters })
         })
         .collect::<Result<Vec<_>, _>>()?;
-    recipes.sort_by_key(|job| -job.recipe.config.priority.unwrap_or_default());
+    recipes.sort_by_key(|job| job.recipe.schedule_key());
+    check_recipe_steps(&recipes, strict)?;
     Ok(recipes)
 }
 
-fn run_cmd(logger: &Logger, cmd: Cmd<OsString>) -> BakeryResult<()> {
+/// Check the steps of the given recipes for common mistakes.
+///
+/// Problems are reported as warnings or, if *strict* is set, as an error.
+fn check_recipe_steps(jobs: &[RecipeJob], strict: bool) -> BakeryResult<()> {
+    let mut problems = jobs
+        .iter()
+        .flat_map(|job| job.recipe.check_steps())
+        .collect::<Vec<_>>();
+    if problems.is_empty() {
+        return Ok(());
+    }
+    problems.sort();
+    if strict {
+        bail!("invalid recipe steps: {}", problems.join(", "));
+    }
+    for problem in problems {
+        warn!("{problem}");
+    }
+    Ok(())
+}
+
+/// Compute the values of the parameters of the given recipe exposed to its steps.
+///
+/// Provided values take precedence over default values. Optional parameters without a
+/// value are omitted while required parameters without a value are an error.
+fn recipe_parameters(
+    recipe: &Recipe,
+    values: Option<&LayerParameters>,
+) -> BakeryResult<HashMap<String, String>> {
+    let mut parameters = HashMap::new();
+    let Some(defs) = &recipe.config.parameters else {
+        return Ok(parameters);
+    };
+    for (name, def) in defs {
+        if let Some(value) = values.and_then(|values| values.get(name)) {
+            let value = recipe.parameter_value(name, value)?;
+            parameters.insert(name.to_owned(), value);
+            continue;
+        }
+        if let Some(default) = &def.default {
+            let default = recipe.parameter_value(name, default)?;
+            parameters.insert(name.to_owned(), default);
+            continue;
+        }
+        if !def.required.unwrap_or(true) {
+            continue;
+        }
+        match &def.help {
+            Some(help) => bail!(
+                "missing value for parameter `{name}` of recipe `{}`: {help}",
+                recipe.name
+            ),
+            None => bail!(
+                "missing value for parameter `{name}` of recipe `{}`",
+                recipe.name
+            ),
+        }
+    }
+    Ok(parameters)
+}
+
+/// Parameter values provided by a layer for a recipe.
+struct LayerParameters<'c> {
+    /// Explicitly provided values.
+    explicit: &'c HashMap<String, ParameterValue>,
+    /// Values of the referenced preset, if any.
+    preset: Option<&'c HashMap<String, ParameterValue>>,
+}
+
+impl<'c> LayerParameters<'c> {
+    /// Resolve the preset referenced by the given values.
+    fn resolve(
+        layer: &'c LayerConfig,
+        explicit: &'c HashMap<String, ParameterValue>,
+    ) -> BakeryResult<Self> {
+        let preset = match explicit.get(PRESET_PARAMETER) {
+            Some(ParameterValue::String(name)) => {
+                match layer.presets.as_ref().and_then(|presets| presets.get(name)) {
+                    Some(preset) => Some(preset),
+                    None => bail!("unknown preset `{name}`"),
+                }
+            }
+            Some(value) => bail!("preset must be given by its name, found `{value}`"),
+            None => None,
+        };
+        Ok(Self { explicit, preset })
+    }
+
+    /// Names of the explicitly provided parameters.
+    fn explicit_names(&self) -> impl Iterator<Item = &'c String> {
+        self.explicit
+            .keys()
+            .filter(|name| *name != PRESET_PARAMETER)
+    }
+
+    /// Value of the given parameter, where explicit values override the preset.
+    ///
+    /// Preset values are only used for parameters the recipe actually defines, as
+    /// presets are typically shared among multiple recipes.
+    fn get(&self, name: &str) -> Option<&'c ParameterValue> {
+        if name == PRESET_PARAMETER {
+            return None;
+        }
+        self.explicit
+            .get(name)
+            .or_else(|| self.preset.and_then(|preset| preset.get(name)))
+    }
+}
+
+fn run_cmd(logger: &StepLogger, cmd: Cmd<OsString>) -> BakeryResult<()> {
+    run_cmd_with_output(logger, &cmd, None)
+}
+
+/// Run a command of the package manager, retrying it if it fails due to a network
+/// problem (see [`retry::is_transient_failure`]).
+fn run_package_cmd(
+    layer_ctx: &LayerContext,
+    logger: &StepLogger,
+    what: &str,
+    cmd: Cmd<OsString>,
+) -> BakeryResult<()> {
+    retry::with_retries(what, layer_ctx.apt_retries, || {
+        let output = Mutex::new(Vec::new());
+        let result = run_cmd_with_output(logger, &cmd, Some(&output));
+        let transient = result.is_err()
+            && retry::is_transient_failure(&String::from_utf8_lossy(&output.into_inner().unwrap()));
+        (result, transient)
+    })
+}
+
+/// Maximal number of bytes of the output kept by [`run_cmd_with_output`].
+const OUTPUT_TAIL_SIZE: usize = 64 * 1024;
+
+/// Run a command writing its output to the given logger.
+///
+/// If provided, the tail of the output is additionally collected in `output`.
+fn run_cmd_with_output(
+    logger: &StepLogger,
+    cmd: &Cmd<OsString>,
+    output: Option<&Mutex<Vec<u8>>>,
+) -> BakeryResult<()> {
+    debug!("running {cmd}");
     let mut command = Command::new(cmd.prog());
     command.args(cmd.args());
     if let Some(vars) = cmd.vars() {
@@ -254,19 +1435,25 @@ fn run_cmd(logger: &Logger, cmd: Cmd<OsString>) -> BakeryResult<()> {
     let stdout = child.stdout.take().unwrap();
     let stderr = child.stderr.take().unwrap();
 
-    fn copy_log<R: Read>(logger: &Logger, mut reader: R) {
+    fn copy_log<R: Read>(logger: &StepLogger, output: Option<&Mutex<Vec<u8>>>, mut reader: R) {
         let mut buffer = vec![0; 8192];
         while let Ok(read) = reader.read(&mut buffer) {
             if read == 0 {
                 break;
             }
             logger.write(&buffer[..read]);
+            if let Some(output) = output {
+                let mut output = output.lock().unwrap();
+                output.extend_from_slice(&buffer[..read]);
+                let excess = output.len().saturating_sub(OUTPUT_TAIL_SIZE);
+                output.drain(..excess);
+            }
         }
     }
 
     let status = std::thread::scope(|scope| {
-        scope.spawn(|| copy_log(logger, stdout));
-        scope.spawn(|| copy_log(logger, stderr));
+        scope.spawn(|| copy_log(logger, output, stdout));
+        scope.spawn(|| copy_log(logger, output, stderr));
         child.wait()
     });
 
@@ -284,162 +1471,1049 @@ fn apply_recipes(
     arch: Architecture,
     jobs: &[RecipeJob],
     root_dir_path: &Path,
+    checkpoints: Option<&Checkpoints>,
 ) -> BakeryResult<()> {
     let mut mount_stack = MountStack::new();
+    for (idx, job) in jobs.iter().enumerate() {
+        logger.progress.start(idx, job);
+        apply_recipe(
+            layer_ctx,
+            logger,
+            project,
+            arch,
+            job,
+            root_dir_path,
+            &mut mount_stack,
+        )?;
+        logger.progress.finish(idx);
+        if let Some(checkpoints) = checkpoints {
+            // The mounts must not end up in the snapshot. They are set up again by the
+            // next recipe needing them.
+            mount_stack.unmount_all();
+            checkpoints.save(idx, &layer_ctx.build_dir)?;
+        }
+    }
+    Ok(())
+}
 
-    fn mount_all(
-        project: &ProjectRef,
-        root_dir_path: &Path,
-        stack: &mut MountStack,
-    ) -> BakeryResult<()> {
-        stack.push(
-            Mounted::bind("/dev", root_dir_path.join("dev")).whatever("unable to mount /dev")?,
-        );
-        stack.push(
-            Mounted::bind("/dev/pts", root_dir_path.join("dev/pts"))
-                .whatever("unable to mount /dev/pts")?,
-        );
-        stack.push(
-            Mounted::bind("/sys", root_dir_path.join("sys")).whatever("unable to mount /sys")?,
-        );
-        stack.push(
-            Mounted::mount_fs("proc", "proc", root_dir_path.join("proc"))
-                .whatever("unable to mount /proc")?,
-        );
-        stack.push(
-            Mounted::mount_fs("tmpfs", "tmpfs", root_dir_path.join("run"))
-                .whatever("unable to mount /run")?,
-        );
-        stack.push(
-            Mounted::mount_fs("tmpfs", "tmpfs", root_dir_path.join("tmp"))
-                .whatever("unable to mount /tmp")?,
-        );
-
-        let project_dir = root_dir_path.join("run/rugix/bakery/project");
-        fs::create_dir_all(&project_dir).whatever("unable to create project directory")?;
+/// Apply the recipes running independent recipes concurrently.
+///
+/// The recipes are partitioned into waves of recipes which are independent of each other
+/// based on the resolved dependencies of the layer's recipes (see [`recipe_waves`]).
+/// Within a wave, up to `max_jobs` recipes are applied concurrently, each to its own
+/// overlay filesystems on top of the layer bundle (see [`apply_recipe_isolated`]). After all recipes of a wave have been applied, the changes
+/// are merged into the layer bundle. If multiple recipes of a wave modify the same path,
+/// their changes cannot be merged and the recipes of the wave are applied again,
+/// sequentially.
+fn apply_recipes_parallel(
+    layer_ctx: &LayerContext,
+    logger: &Logger,
+    arch: Architecture,
+    layer: &Layer,
+    jobs: &[RecipeJob],
+    root_dir_path: &Path,
+    scratch_dir: &Path,
+    max_jobs: usize,
+) -> BakeryResult<()> {
+    if scratch_dir.exists() {
+        fs::remove_dir_all(scratch_dir).whatever("unable to remove scratch directory")?;
+    }
+    let project = &layer_ctx.project;
+    let library = project.library()?;
+    let graph = recipe_graph(project, arch, layer)?;
+    let position = |idx: RecipeIdx| {
+        jobs.iter()
+            .position(|job| Arc::ptr_eq(&job.recipe, &library.recipes[idx]))
+    };
+    let mut phases = Vec::with_capacity(jobs.len());
+    let mut dependencies = vec![Vec::new(); jobs.len()];
+    let mut exclusive = Vec::with_capacity(jobs.len());
+    for (idx, recipe_dependencies) in &graph.dependencies {
+        // Recipes which are not scheduled have already been applied.
+        if let Some(job_idx) = position(*idx) {
+            dependencies[job_idx] = recipe_dependencies
+                .iter()
+                .filter_map(|dependency| position(*dependency))
+                .collect();
+        }
+    }
+    for job in jobs {
+        let recipe = &job.recipe;
+        phases.push(recipe.schedule_key().0);
+        // Steps running within the system may mutate shared state, e.g., the state of
+        // the package manager, which cannot be merged. Only recipes whose steps run
+        // exclusively on the host are applied concurrently.
+        exclusive.push(recipe.steps.iter().any(|step| step.kind.needs_chroot()));
+    }
+    let mut mount_stack = MountStack::new();
+    for wave in recipe_waves(&phases, &dependencies, &exclusive) {
+        if let [idx] = wave[..] {
+            logger.progress.start(idx, &jobs[idx]);
+            apply_recipe(
+                layer_ctx,
+                logger,
+                project,
+                arch,
+                &jobs[idx],
+                root_dir_path,
+                &mut mount_stack,
+            )?;
+            logger.progress.finish(idx);
+            continue;
+        }
+        // The root filesystem serves as the lower directory of the overlays.
+        mount_stack.unmount_all();
+        logger.set_prefix_lines(true);
+        let next = AtomicUsize::new(0);
+        let result = std::thread::scope(|scope| {
+            let workers = (0..max_jobs.min(wave.len()))
+                .map(|_| {
+                    scope.spawn(|| loop {
+                        let Some(&idx) = wave.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                            return Ok(());
+                        };
+                        logger.progress.start(idx, &jobs[idx]);
+                        let result = apply_recipe_isolated(
+                            layer_ctx,
+                            logger,
+                            project,
+                            arch,
+                            &jobs[idx],
+                            &scratch_dir.join(idx.to_string()),
+                        );
+                        if result.is_err() {
+                            // Do not start any further recipes.
+                            next.store(wave.len(), Ordering::Relaxed);
+                            return result;
+                        }
+                        logger.progress.finish(idx);
+                    })
+                })
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap())
+                .collect::<BakeryResult<()>>()
+        });
+        logger.set_prefix_lines(false);
+        result?;
+        let mut conflict = None;
+        for dir in ISOLATED_DIRS {
+            let uppers = wave
+                .iter()
+                .map(|idx| scratch_dir.join(idx.to_string()).join("upper").join(dir))
+                .collect::<Vec<_>>();
+            conflict = find_conflict(&uppers)
+                .whatever("unable to check changes of recipes")?
+                .map(|(path, first, second)| (Path::new(dir).join(path), first, second));
+            if conflict.is_some() {
+                break;
+            }
+        }
+        if let Some((path, first, second)) = conflict {
+            warn!(
+                "Recipes `{}` and `{}` both modify {path:?}, applying them sequentially.",
+                jobs[wave[first]].recipe.name, jobs[wave[second]].recipe.name,
+            );
+            for idx in &wave {
+                fs::remove_dir_all(scratch_dir.join(idx.to_string()))
+                    .whatever("unable to remove recipe directory")?;
+            }
+            for idx in wave {
+                // The progress has already been accounted for, so it is not finished.
+                logger.progress.start(idx, &jobs[idx]);
+                apply_recipe(
+                    layer_ctx,
+                    logger,
+                    project,
+                    arch,
+                    &jobs[idx],
+                    root_dir_path,
+                    &mut mount_stack,
+                )?;
+            }
+            continue;
+        }
+        for idx in wave {
+            let job_dir = scratch_dir.join(idx.to_string());
+            for dir in ISOLATED_DIRS {
+                let upper_dir = job_dir.join("upper").join(dir);
+                let lower_dir = layer_ctx.build_dir.join(dir);
+                prepare_merge(&upper_dir, &lower_dir)
+                    .whatever("unable to merge changes of recipe")
+                    .with_info(|_| format!("recipe: {}", jobs[idx].recipe.name))?;
+                let upper_contents = upper_dir.join(".");
+                run!(["cp", "-a", "--", &upper_contents, &lower_dir])
+                    .whatever("unable to merge changes of recipe")
+                    .with_info(|_| format!("recipe: {}", jobs[idx].recipe.name))?;
+            }
+            fs::remove_dir_all(&job_dir).whatever("unable to remove recipe directory")?;
+        }
+    }
+    fs::remove_dir_all(scratch_dir).ok();
+    Ok(())
+}
 
-        let resolved_resolv = root_dir_path.join("run/systemd/resolve/stub-resolv.conf");
-        fs::create_dir_all(resolved_resolv.parent().unwrap())
-            .whatever("unable to create `systemd/resolve` directory")?;
-        let resolv_conf =
-            fs::read("/etc/resolv.conf").whatever("unable to read `/etc/resolv.conf")?;
-        fs::write(resolved_resolv, resolv_conf).whatever("unable to write `resolv.conf`")?;
+/// Directories of the layer bundle recipes write to when applied concurrently.
+const ISOLATED_DIRS: [&str; 4] = ["roots/system", "roots/boot", "roots/config", "artifacts"];
 
-        stack.push(
-            Mounted::bind(project.dir(), &project_dir)
-                .whatever("unable to bind mount project directory")?,
+/// Apply a recipe to overlay filesystems on top of the layer bundle.
+///
+/// The recipe is applied to the `bundle` directory of the given job directory, where each
+/// of the [`ISOLATED_DIRS`] is an overlay filesystem on top of the respective directory
+/// of the layer bundle. The changes of the recipe are kept in the `upper` directory of
+/// the job directory.
+fn apply_recipe_isolated(
+    layer_ctx: &LayerContext,
+    logger: &Logger,
+    project: &ProjectRef,
+    arch: Architecture,
+    job: &RecipeJob,
+    job_dir: &Path,
+) -> BakeryResult<()> {
+    let mut overlays = Vec::with_capacity(ISOLATED_DIRS.len());
+    for dir in ISOLATED_DIRS {
+        let lower_dir = layer_ctx.build_dir.join(dir);
+        let upper_dir = job_dir.join("upper").join(dir);
+        let work_dir = job_dir.join("work").join(dir);
+        let merged_dir = job_dir.join("bundle").join(dir);
+        for dir in [&lower_dir, &upper_dir, &work_dir, &merged_dir] {
+            fs::create_dir_all(dir).whatever("unable to create overlay directory")?;
+        }
+        overlays.push(
+            Mounted::overlay(&lower_dir, &upper_dir, &work_dir, &merged_dir)
+                .whatever("unable to mount overlay for recipe")
+                .with_info(|_| format!("recipe: {}", job.recipe.name))?,
         );
+    }
+    let job_ctx = LayerContext {
+        build_dir: job_dir.join("bundle"),
+        ..layer_ctx.clone()
+    };
+    let mut mount_stack = MountStack::new();
+    apply_recipe(
+        &job_ctx,
+        logger,
+        project,
+        arch,
+        job,
+        &job_ctx.build_dir.join("roots/system"),
+        &mut mount_stack,
+    )
+}
 
-        Ok(())
+/// Partition the scheduled recipes into waves of recipes which can be applied concurrently.
+///
+/// The waves are built from the dependencies between the recipes: A recipe joins the first
+/// wave after all of its dependencies have been applied. Recipes of different phases never
+/// share a wave and, as in the sequential schedule, dependencies on recipes of later phases
+/// are not waited for. Exclusive recipes always form a wave of their own. Among the recipes
+/// whose dependencies have been applied, waves follow the order of the schedule.
+fn recipe_waves<K: Eq>(
+    phases: &[K],
+    dependencies: &[Vec<usize>],
+    exclusive: &[bool],
+) -> Vec<Vec<usize>> {
+    let mut applied = vec![false; phases.len()];
+    let mut waves = Vec::new();
+    let mut phase_start = 0;
+    while phase_start < phases.len() {
+        let phase_end = (phase_start..phases.len())
+            .find(|idx| phases[*idx] != phases[phase_start])
+            .unwrap_or(phases.len());
+        loop {
+            let mut ready = (phase_start..phase_end)
+                .filter(|idx| {
+                    !applied[*idx]
+                        && dependencies[*idx]
+                            .iter()
+                            .all(|dependency| applied[*dependency] || *dependency >= phase_end)
+                })
+                .peekable();
+            let wave = match ready.peek().copied() {
+                Some(idx) if exclusive[idx] => vec![idx],
+                Some(_) => ready.take_while(|idx| !exclusive[*idx]).collect(),
+                None => {
+                    // Cycles are rejected when scheduling the recipes, however, we make
+                    // sure that all recipes are applied nevertheless.
+                    match (phase_start..phase_end).find(|idx| !applied[*idx]) {
+                        Some(idx) => vec![idx],
+                        None => break,
+                    }
+                }
+            };
+            for idx in &wave {
+                applied[*idx] = true;
+            }
+            waves.push(wave);
+        }
+        phase_start = phase_end;
+    }
+    waves
+}
+
+/// Find a cycle in the given dependency graph reachable from the given roots.
+///
+/// The cycle is returned as a path starting and ending with the same node.
+fn find_cycle<T: Copy + Eq + Hash>(roots: &[T], graph: &HashMap<T, Vec<T>>) -> Option<Vec<T>> {
+    fn visit<T: Copy + Eq + Hash>(
+        node: T,
+        graph: &HashMap<T, Vec<T>>,
+        done: &mut HashSet<T>,
+        path: &mut Vec<T>,
+    ) -> Option<Vec<T>> {
+        if let Some(start) = path.iter().position(|visiting| *visiting == node) {
+            let mut cycle = path[start..].to_vec();
+            cycle.push(node);
+            return Some(cycle);
+        }
+        if done.contains(&node) {
+            return None;
+        }
+        path.push(node);
+        for dependency in graph.get(&node).into_iter().flatten() {
+            if let Some(cycle) = visit(*dependency, graph, done, path) {
+                return Some(cycle);
+            }
+        }
+        path.pop();
+        done.insert(node);
+        None
     }
+    let mut done = HashSet::new();
+    roots
+        .iter()
+        .find_map(|root| visit(*root, graph, &mut done, &mut Vec::new()))
+}
+
+/// Mount the system directories and the project directory into the root filesystem.
+///
+/// If necessary, an emulator for the architecture of the system is set up as well.
+fn mount_all(
+    project: &ProjectRef,
+    arch: Architecture,
+    root_dir_path: &Path,
+    stack: &mut MountStack,
+) -> BakeryResult<()> {
+    stack.push(Mounted::bind("/dev", root_dir_path.join("dev")).whatever("unable to mount /dev")?);
+    stack.push(
+        Mounted::bind("/dev/pts", root_dir_path.join("dev/pts"))
+            .whatever("unable to mount /dev/pts")?,
+    );
+    stack.push(Mounted::bind("/sys", root_dir_path.join("sys")).whatever("unable to mount /sys")?);
+    stack.push(
+        Mounted::mount_fs("proc", "proc", root_dir_path.join("proc"))
+            .whatever("unable to mount /proc")?,
+    );
+    stack.push(
+        Mounted::mount_fs("tmpfs", "tmpfs", root_dir_path.join("run"))
+            .whatever("unable to mount /run")?,
+    );
+    stack.push(
+        Mounted::mount_fs("tmpfs", "tmpfs", root_dir_path.join("tmp"))
+            .whatever("unable to mount /tmp")?,
+    );
 
     let project_dir = root_dir_path.join("run/rugix/bakery/project");
+    fs::create_dir_all(&project_dir).whatever("unable to create project directory")?;
 
-    for (idx, job) in jobs.iter().enumerate() {
-        let recipe = &job.recipe;
-        info!(
-            "[{:>2}/{}] {} {:?}",
-            idx + 1,
-            jobs.len(),
-            recipe
-                .config
-                .description
-                .as_deref()
-                .unwrap_or(recipe.name.deref()),
-            &job.parameters,
+    let resolved_resolv = root_dir_path.join("run/systemd/resolve/stub-resolv.conf");
+    fs::create_dir_all(resolved_resolv.parent().unwrap())
+        .whatever("unable to create `systemd/resolve` directory")?;
+    let resolv_conf = fs::read("/etc/resolv.conf").whatever("unable to read `/etc/resolv.conf")?;
+    fs::write(resolved_resolv, resolv_conf).whatever("unable to write `resolv.conf`")?;
+
+    stack.push(
+        Mounted::bind(project.dir(), &project_dir)
+            .whatever("unable to bind mount project directory")?,
+    );
+
+    setup_emulation(arch, root_dir_path, project.register_emulators(), stack)?;
+
+    Ok(())
+}
+
+/// Apply a single recipe to the given root filesystem.
+///
+/// The system directories are mounted on demand and kept on the given mount stack.
+fn apply_recipe(
+    layer_ctx: &LayerContext,
+    logger: &Logger,
+    project: &ProjectRef,
+    arch: Architecture,
+    job: &RecipeJob,
+    root_dir_path: &Path,
+    mount_stack: &mut MountStack,
+) -> BakeryResult<()> {
+    let recipe = &job.recipe;
+    let library = project.library()?;
+    let repository = library.repositories.repositories[recipe.repository]
+        .source
+        .id
+        .as_str();
+    for step in &recipe.steps {
+        if !step.applies_to(arch) {
+            info!("    - {} (skipped: arch)", step.filename);
+            continue;
+        }
+        info!("    - {}", step.filename);
+        let step_logger = logger.start_step(&recipe.name, &step.filename)?;
+        let started = Instant::now();
+        let result = apply_step(
+            layer_ctx,
+            &step_logger,
+            arch,
+            job,
+            step,
+            root_dir_path,
+            mount_stack,
         );
+        // Failing steps are recorded as well, so that their timings are reported.
+        logger.record_step(&recipe.name, &step.filename, started.elapsed());
+        result.with_info(|_| {
+            format!(
+                "step `{}` ({}) of recipe `{}` from repository `{repository}` failed",
+                step.filename,
+                step.kind.name(),
+                recipe.name,
+            )
+        })?;
+    }
+    Ok(())
+}
 
-        for step in &recipe.steps {
-            info!("    - {}", step.filename);
-            match &step.kind {
-                StepKind::Packages { packages, manager } => {
-                    if mount_stack.is_empty() {
-                        mount_all(project, root_dir_path, &mut mount_stack)?;
-                    }
-                    let chroot_manager = if root_dir_path.join("usr/bin/apt-get").exists() {
-                        PackageManager::Apt
-                    } else if root_dir_path.join("sbin/apk").exists() {
-                        PackageManager::Apk
-                    } else {
-                        bail!("unable to determine package manager")
-                    };
-                    let manager = manager.unwrap_or(chroot_manager);
-                    if manager == chroot_manager {
-                        let mut cmd = match manager {
-                            PackageManager::Apt => {
-                                cmd!("chroot", root_dir_path, "apt-get", "install", "-y")
-                            }
-                            PackageManager::Apk => {
-                                cmd!("chroot", root_dir_path, "apk", "add", "--no-interactive")
-                            }
-                        };
-                        cmd.extend_args(packages);
-                        ParentEnv
-                            .run(cmd.with_vars(vars! {
-                                DEBIAN_FRONTEND = "noninteractive"
-                            }))
-                            .whatever("unable to install packages")?;
-                    }
-                }
-                StepKind::Install => {
-                    if mount_stack.is_empty() {
-                        mount_all(project, root_dir_path, &mut mount_stack)?;
+/// Create the directories of the roots of the layer and return the root filesystem.
+///
+/// Besides the root filesystem, recipes can stage files for the boot and config
+/// partitions in `roots/boot` and `roots/config`, respectively. These are copied into the
+/// partitions when baking an image.
+fn create_layer_roots(bundle_dir: &Path) -> PathBuf {
+    for root in ["system", "boot", "config"] {
+        std::fs::create_dir_all(bundle_dir.join("roots").join(root)).ok();
+    }
+    bundle_dir.join("roots/system")
+}
+
+/// Apply a single step of a recipe.
+fn apply_step(
+    layer_ctx: &LayerContext,
+    step_logger: &StepLogger<'_>,
+    arch: Architecture,
+    job: &RecipeJob,
+    step: &RecipeStep,
+    root_dir_path: &Path,
+    mount_stack: &mut MountStack,
+) -> BakeryResult<()> {
+    let recipe = &job.recipe;
+    let project_dir = root_dir_path.join("run/rugix/bakery/project");
+    match &step.kind {
+        StepKind::Packages {
+            packages,
+            manager,
+            recommends,
+            options,
+        } => {
+            if mount_stack.is_empty() {
+                mount_all(&layer_ctx.project, arch, root_dir_path, mount_stack)?;
+            }
+            let chroot_manager = if root_dir_path.join("usr/bin/apt-get").exists() {
+                PackageManager::Apt
+            } else if root_dir_path.join("sbin/apk").exists() {
+                PackageManager::Apk
+            } else {
+                bail!("unable to determine package manager")
+            };
+            let manager = manager.unwrap_or(chroot_manager);
+            if manager == PackageManager::Apt
+                && layer_ctx
+                    .update_package_lists
+                    .swap(false, Ordering::Relaxed)
+            {
+                info!("Updating package lists from mirror.");
+                let cmd = cmd!("chroot", root_dir_path, "apt-get", "update");
+                run_package_cmd(
+                    layer_ctx,
+                    step_logger,
+                    "Updating package lists",
+                    cmd.with_vars(vars! {
+                        DEBIAN_FRONTEND = "noninteractive"
+                    }),
+                )
+                .with_info(|_| "unable to update package lists")?;
+            }
+            if manager == chroot_manager {
+                let mut cmd = match manager {
+                    PackageManager::Apt => {
+                        let mut cmd = cmd!("chroot", root_dir_path, "apt-get", "install", "-y");
+                        if !recommends {
+                            cmd.add_arg("--no-install-recommends");
+                        }
+                        cmd
                     }
-                    let bakery_recipe_path = root_dir_path.join("run/rugix/bakery/recipe");
-                    fs::create_dir_all(&bakery_recipe_path)
-                        .whatever("unable to create recipe directory")?;
-                    let _mounted_recipe = Mounted::bind(&recipe.path, &bakery_recipe_path)
-                        .whatever("unable to bind mount recipe")?;
-                    let chroot_layer_dir = root_dir_path.join("run/rugix/bakery/bundle/");
-                    fs::create_dir_all(&chroot_layer_dir)
-                        .whatever("unable to create layer bundle directory")?;
-                    let _mounted_layer_dir = Mounted::bind(&layer_ctx.build_dir, &chroot_layer_dir)
-                        .whatever("unable to bind mount layer bundle")?;
-                    let script = format!("/run/rugix/bakery/recipe/steps/{}", step.filename);
-                    let mut vars = vars! {
-                        DEBIAN_FRONTEND = "noninteractive",
-                        RUGIX_LAYER_DIR = "/run/rugix/bakery/bundle/",
-                        RUGIX_ARTIFACTS_DIR = "/run/rugix/bakery/bundle/artifacts",
-                        RUGIX_ROOT_DIR = "/",
-                        RUGIX_PROJECT_DIR = "/run/rugix/bakery/project/",
-                        RUGIX_ARCH = arch.as_str(),
-                        LAYER_REBUILD_IF_CHANGED = Path::new("/run/rugix/bakery/project").join(&layer_ctx.output_dir).join("rebuild-if-changed.txt"),
-                        RECIPE_DIR = "/run/rugix/bakery/recipe/",
-                        RECIPE_STEP_PATH = &script,
-                    };
-                    for (name, value) in &job.parameters {
-                        vars.set(format!("RECIPE_PARAM_{}", name.to_uppercase()), value);
+                    PackageManager::Apk => {
+                        cmd!("chroot", root_dir_path, "apk", "add", "--no-interactive")
                     }
-                    run_cmd(
-                        logger,
-                        Cmd::new("chroot")
-                            .add_arg(root_dir_path)
-                            .add_arg(&script)
-                            .clone()
-                            .with_vars(vars),
-                    )?;
-                }
-                StepKind::Run => {
-                    let script = recipe.path.join("steps").join(&step.filename);
-                    let mut vars = vars! {
-                        DEBIAN_FRONTEND = "noninteractive",
-                        RUGIX_LAYER_DIR = &layer_ctx.build_dir,
-                        RUGIX_ARTIFACTS_DIR = layer_ctx.build_dir.join("artifacts"),
-                        RUGIX_ROOT_DIR = root_dir_path,
-                        RUGIX_PROJECT_DIR = &project_dir,
-                        RUGIX_ARCH = arch.as_str(),
-                        LAYER_REBUILD_IF_CHANGED = project_dir.join(&layer_ctx.output_dir).join("rebuild-if-changed.txt"),
-                        RECIPE_DIR = &recipe.path,
-                        RECIPE_STEP_PATH = &script,
-                    };
-                    for (name, value) in &job.parameters {
-                        vars.set(format!("RECIPE_PARAM_{}", name.to_uppercase()), value);
-                    }
-                    run_cmd(logger, Cmd::new(&script).with_vars(vars))?;
-                }
+                };
+                cmd.extend_args(options);
+                cmd.extend_args(expand_packages(packages, &job.parameters)?);
+                run_package_cmd(
+                    layer_ctx,
+                    step_logger,
+                    "Installing packages",
+                    cmd.with_vars(vars! {
+                        DEBIAN_FRONTEND = "noninteractive"
+                    }),
+                )
+                .with_info(|_| "unable to install packages")?;
+            }
+        }
+        StepKind::Install => {
+            if mount_stack.is_empty() {
+                mount_all(&layer_ctx.project, arch, root_dir_path, mount_stack)?;
+            }
+            let bakery_recipe_path = root_dir_path.join("run/rugix/bakery/recipe");
+            fs::create_dir_all(&bakery_recipe_path)
+                .whatever("unable to create recipe directory")?;
+            let _mounted_recipe = Mounted::bind(&recipe.path, &bakery_recipe_path)
+                .whatever("unable to bind mount recipe")?;
+            let chroot_layer_dir = root_dir_path.join("run/rugix/bakery/bundle/");
+            fs::create_dir_all(&chroot_layer_dir)
+                .whatever("unable to create layer bundle directory")?;
+            let _mounted_layer_dir = Mounted::bind(&layer_ctx.build_dir, &chroot_layer_dir)
+                .whatever("unable to bind mount layer bundle")?;
+            let script = format!("/run/rugix/bakery/recipe/steps/{}", step.filename);
+            let mut vars = vars! {
+                DEBIAN_FRONTEND = "noninteractive",
+                RUGIX_LAYER_DIR = "/run/rugix/bakery/bundle/",
+                RUGIX_ARTIFACTS_DIR = "/run/rugix/bakery/bundle/artifacts",
+                RUGIX_BOOT_DIR = "/run/rugix/bakery/bundle/roots/boot",
+                RUGIX_CONFIG_DIR = "/run/rugix/bakery/bundle/roots/config",
+                RUGIX_ROOT_DIR = "/",
+                RUGIX_PROJECT_DIR = "/run/rugix/bakery/project/",
+                RUGIX_ARCH = arch.as_str(),
+                LAYER_REBUILD_IF_CHANGED = Path::new("/run/rugix/bakery/project").join(&layer_ctx.output_dir).join("rebuild-if-changed.txt"),
+                RECIPE_DIR = "/run/rugix/bakery/recipe/",
+                RECIPE_STEP_PATH = &script,
+            };
+            for (name, value) in &layer_ctx.env {
+                vars.set(name, value);
+            }
+            if let Some(arch_variant) = &layer_ctx.arch_variant {
+                vars.set("RUGIX_ARCH_VARIANT", arch_variant);
+            }
+            for (name, value) in &job.parameters {
+                vars.set(format!("RECIPE_PARAM_{}", name.to_uppercase()), value);
+            }
+            let mut cmd = Cmd::new("chroot");
+            if let Some(user) = &recipe.config.user {
+                check_system_user(root_dir_path, user)?;
+                cmd.add_arg(format!("--userspec={user}"));
+            }
+            cmd.add_arg(root_dir_path);
+            for arg in step_interpreter(recipe, step).iter().flatten() {
+                cmd.add_arg(arg);
+            }
+            cmd.add_arg(&script);
+            run_cmd(step_logger, cmd.with_vars(vars))?;
+        }
+        StepKind::Run => {
+            let script = recipe.path.join("steps").join(&step.filename);
+            let mut vars = vars! {
+                DEBIAN_FRONTEND = "noninteractive",
+                RUGIX_LAYER_DIR = &layer_ctx.build_dir,
+                RUGIX_ARTIFACTS_DIR = layer_ctx.build_dir.join("artifacts"),
+                RUGIX_BOOT_DIR = layer_ctx.build_dir.join("roots/boot"),
+                RUGIX_CONFIG_DIR = layer_ctx.build_dir.join("roots/config"),
+                RUGIX_ROOT_DIR = root_dir_path,
+                RUGIX_PROJECT_DIR = &project_dir,
+                RUGIX_ARCH = arch.as_str(),
+                LAYER_REBUILD_IF_CHANGED = project_dir.join(&layer_ctx.output_dir).join("rebuild-if-changed.txt"),
+                RECIPE_DIR = &recipe.path,
+                RECIPE_STEP_PATH = &script,
+            };
+            for (name, value) in &layer_ctx.env {
+                vars.set(name, value);
+            }
+            if let Some(arch_variant) = &layer_ctx.arch_variant {
+                vars.set("RUGIX_ARCH_VARIANT", arch_variant);
+            }
+            for (name, value) in &job.parameters {
+                vars.set(format!("RECIPE_PARAM_{}", name.to_uppercase()), value);
+            }
+            let mut argv = step_interpreter(recipe, step)
+                .into_iter()
+                .flatten()
+                .map(OsString::from)
+                .collect::<Vec<_>>();
+            argv.push(script.as_os_str().to_owned());
+            let mut cmd = match &recipe.config.user {
+                Some(user) => Cmd::new("runuser")
+                    .add_arg("-u")
+                    .add_arg(user)
+                    .add_arg("--")
+                    .add_arg(&argv[0])
+                    .clone(),
+                None => Cmd::new(&argv[0]),
+            };
+            for arg in &argv[1..] {
+                cmd.add_arg(arg);
+            }
+            run_cmd(step_logger, cmd.with_vars(vars))?;
+        }
+        StepKind::Copy { files } => {
+            copy_recipe_files(&recipe.path, files, root_dir_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Expand references to recipe parameters in the packages of a `packages` step.
+///
+/// A package given as `${RECIPE_PARAM_<NAME>}` is replaced by the value of the respective
+/// parameter. Values of list parameters are newline-separated and each element becomes a
+/// package of its own. As packages are passed to the package manager as separate
+/// arguments without involving a shell, no further escaping is necessary.
+fn expand_packages(
+    packages: &[String],
+    parameters: &HashMap<String, String>,
+) -> BakeryResult<Vec<String>> {
+    let mut expanded = Vec::with_capacity(packages.len());
+    for package in packages {
+        let Some(var) = package
+            .strip_prefix("${RECIPE_PARAM_")
+            .and_then(|var| var.strip_suffix('}'))
+        else {
+            expanded.push(package.clone());
+            continue;
+        };
+        let Some(value) = parameters
+            .iter()
+            .find_map(|(name, value)| (name.to_uppercase() == var).then_some(value))
+        else {
+            bail!("packages reference parameter `{var}` without a value");
+        };
+        expanded.extend(
+            value
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_owned),
+        );
+    }
+    Ok(expanded)
+}
+
+/// Interpreter to run the script of the given step with, if it is not executable.
+fn step_interpreter(recipe: &Recipe, step: &RecipeStep) -> Option<Vec<String>> {
+    let interpreter = recipe.step_interpreter(step)?;
+    warn!(
+        "Step `{}` of recipe `{}` is not executable, running it with `{}`.",
+        step.filename,
+        recipe.name,
+        interpreter.join(" ")
+    );
+    Some(interpreter)
+}
+
+/// Copy the given files of a recipe into the root filesystem.
+fn copy_recipe_files(
+    recipe_dir: &Path,
+    files: &[CopyFile],
+    root_dir_path: &Path,
+) -> BakeryResult<()> {
+    for file in files {
+        let src = recipe_dir.join(&file.src);
+        if !src.exists() {
+            bail!("source {src:?} of copy step does not exist");
+        }
+        let owner = resolve_owner(root_dir_path, &file.owner)?;
+        copy_entry(&src, root_dir_path, &file.dest, owner, file.mode)
+            .whatever("unable to copy files into the system")
+            .with_info(|_| format!("src: {src:?}"))
+            .with_info(|_| format!("dest: {:?}", file.dest))?;
+    }
+    Ok(())
+}
+
+/// Recursively copy the given entry to the path *dest* within the given root directory,
+/// setting the given ownership and file mode.
+///
+/// Directories are merged with existing directories, whose modes and ownership are kept.
+/// Symlinks of the destination are resolved within the root directory (see
+/// [`resolve_in_root`]). Existing symlinks at the destination of files are replaced
+/// instead of followed.
+fn copy_entry(
+    src: &Path,
+    root: &Path,
+    dest: &Path,
+    owner: (u32, u32),
+    mode: Option<u32>,
+) -> io::Result<()> {
+    let (uid, gid) = owner;
+    let metadata = fs::symlink_metadata(src)?;
+    if metadata.is_dir() {
+        let dest_path = resolve_in_root(root, dest)?;
+        if fs::symlink_metadata(&dest_path).is_err() {
+            fs::create_dir_all(&dest_path)?;
+            fs::set_permissions(&dest_path, metadata.permissions())?;
+            std::os::unix::fs::chown(&dest_path, Some(uid), Some(gid))?;
+        }
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            copy_entry(
+                &entry.path(),
+                root,
+                &dest.join(entry.file_name()),
+                owner,
+                mode,
+            )?;
+        }
+        return Ok(());
+    }
+    let Some(file_name) = dest.file_name() else {
+        return Err(io::Error::other(format!("invalid destination {dest:?}")));
+    };
+    let parent = resolve_in_root(root, dest.parent().unwrap_or(Path::new("")))?;
+    fs::create_dir_all(&parent)?;
+    let dest_path = parent.join(file_name);
+    if fs::symlink_metadata(&dest_path).is_ok_and(|existing| existing.is_symlink()) {
+        fs::remove_file(&dest_path)?;
+    }
+    if metadata.is_symlink() {
+        std::os::unix::fs::symlink(fs::read_link(src)?, &dest_path)?;
+        std::os::unix::fs::lchown(&dest_path, Some(uid), Some(gid))?;
+    } else {
+        fs::copy(src, &dest_path)?;
+        if let Some(mode) = mode {
+            fs::set_permissions(&dest_path, fs::Permissions::from_mode(mode))?;
+        }
+        std::os::unix::fs::chown(&dest_path, Some(uid), Some(gid))?;
+    }
+    Ok(())
+}
+
+/// Maximal number of symlinks followed when resolving a path.
+const MAX_SYMLINKS: usize = 40;
+
+/// Resolve the given path within the given root directory, following symlinks as if the
+/// root directory were the root of the filesystem.
+///
+/// Absolute symlinks are resolved relative to the root directory and `..` never leaves
+/// it, so the resolved path is always within the root directory. Components which do
+/// not exist are kept as is.
+fn resolve_in_root(root: &Path, path: &Path) -> io::Result<PathBuf> {
+    let mut resolved = PathBuf::new();
+    let mut pending = path
+        .components()
+        .map(|component| component.as_os_str().to_owned())
+        .collect::<VecDeque<_>>();
+    let mut followed = 0;
+    while let Some(component) = pending.pop_front() {
+        if component == "/" {
+            resolved = PathBuf::new();
+        } else if component == ".." {
+            resolved.pop();
+        } else if component != "." {
+            let candidate = root.join(&resolved).join(&component);
+            if !fs::symlink_metadata(&candidate).is_ok_and(|metadata| metadata.is_symlink()) {
+                resolved.push(component);
+                continue;
+            }
+            followed += 1;
+            if followed > MAX_SYMLINKS {
+                return Err(io::Error::other(format!(
+                    "too many levels of symlinks resolving {path:?}"
+                )));
+            }
+            let target = fs::read_link(&candidate)?;
+            for component in target.components().rev() {
+                pending.push_front(component.as_os_str().to_owned());
             }
         }
     }
+    Ok(root.join(resolved))
+}
+
+/// Resolve an owner `user:group` to the ids of the user and group in the given system.
+///
+/// Numeric ids are used as is.
+fn resolve_owner(root_dir_path: &Path, owner: &str) -> BakeryResult<(u32, u32)> {
+    let Some((user, group)) = owner.split_once(':') else {
+        bail!("owner `{owner}` must be of the form `user:group`");
+    };
+    let resolve = |name: &str, database: &str| -> BakeryResult<u32> {
+        if let Ok(id) = name.parse() {
+            return Ok(id);
+        }
+        let entries = fs::read_to_string(root_dir_path.join("etc").join(database))
+            .whatever_with(|_| format!("unable to read `/etc/{database}` of the system"))?;
+        entries
+            .lines()
+            .map(|line| line.split(':').collect::<Vec<_>>())
+            .find(|fields| fields.first() == Some(&name))
+            .and_then(|fields| fields.get(2)?.parse().ok())
+            .ok_or_else(|| whatever!("unknown `{name}` in `/etc/{database}` of the system"))
+    };
+    Ok((resolve(user, "passwd")?, resolve(group, "group")?))
+}
 
+/// Check whether the given user exists in the system being built.
+fn check_system_user(root_dir_path: &Path, user: &str) -> BakeryResult<()> {
+    let passwd = fs::read_to_string(root_dir_path.join("etc/passwd"))
+        .whatever("unable to read `/etc/passwd` of the system")?;
+    if !passwd
+        .lines()
+        .any(|line| line.split(':').next() == Some(user))
+    {
+        bail!("user `{user}` does not exist in the system");
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use super::{
+        copy_entry, expand_packages, find_cycle, format_duration, load_params_file, recipe_keys,
+        recipe_parameters, recipe_waves, resolve_in_root, summarize_timings, LayerParameters,
+        RecipeJob,
+    };
+    use crate::config::layers::LayerConfig;
+    use crate::config::recipes::ParameterValue;
+    use crate::project::recipes::RecipeLoader;
+    use crate::project::repositories::RepositoryIdx;
+    use crate::project::stats::StepTiming;
+    use crate::utils::idx_vec::Idx;
+
+    #[test]
+    fn test_recipe_keys() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let loader = RecipeLoader::new(RepositoryIdx::from_usize(0));
+        let job = |name: &str, value: &str| {
+            let dir = temp_dir.path().join(name);
+            std::fs::create_dir_all(dir.join("steps")).unwrap();
+            std::fs::write(dir.join("recipe.toml"), "").unwrap();
+            std::fs::write(dir.join("steps/00-packages"), "curl").unwrap();
+            RecipeJob {
+                recipe: Arc::new(loader.load(&dir).unwrap()),
+                parameters: HashMap::from([("value".to_owned(), value.to_owned())]),
+            }
+        };
+        let keys = recipe_keys(&[job("first", "a"), job("second", "a")]).unwrap();
+        // The recipes have the same contents and parameters but different names.
+        assert_ne!(keys[0], keys[1]);
+        let changed = recipe_keys(&[job("first", "a"), job("second", "b")]).unwrap();
+        assert_eq!(keys[0], changed[0]);
+        assert_ne!(keys[1], changed[1]);
+    }
+
+    #[test]
+    fn test_recipe_waves() {
+        let phases = [0, 1, 1, 1, 1, 1, 2];
+        let dependencies = [vec![], vec![6], vec![0], vec![4], vec![], vec![], vec![]];
+        let exclusive = [false, false, false, false, false, true, false];
+        assert_eq!(
+            recipe_waves(&phases, &dependencies, &exclusive),
+            vec![vec![0], vec![1, 2, 4], vec![3], vec![5], vec![6]]
+        );
+    }
+
+    #[test]
+    fn test_expand_packages() {
+        let parameters = HashMap::from([
+            ("packages".to_owned(), "curl\nvim".to_owned()),
+            ("editor".to_owned(), "nano".to_owned()),
+        ]);
+        let packages =
+            ["git", "${RECIPE_PARAM_PACKAGES}", "${RECIPE_PARAM_EDITOR}"].map(str::to_owned);
+        assert_eq!(
+            expand_packages(&packages, &parameters).unwrap(),
+            vec!["git", "curl", "vim", "nano"]
+        );
+        let packages = ["${RECIPE_PARAM_MISSING}".to_owned()];
+        assert!(expand_packages(&packages, &parameters).is_err());
+    }
+
+    #[test]
+    fn test_find_cycle() {
+        let graph = HashMap::from([(0, vec![1, 2]), (1, vec![2]), (2, vec![]), (3, vec![0])]);
+        assert_eq!(find_cycle(&[3, 0], &graph), None);
+        let graph = HashMap::from([(0, vec![1]), (1, vec![2]), (2, vec![3]), (3, vec![1])]);
+        assert_eq!(find_cycle(&[0], &graph), Some(vec![1, 2, 3, 1]));
+        let graph = HashMap::from([(0, vec![0])]);
+        assert_eq!(find_cycle(&[0], &graph), Some(vec![0, 0]));
+    }
+
+    #[test]
+    fn test_summarize_timings() {
+        let timing = |recipe: &str, step: &str, duration_secs| StepTiming {
+            layer: "customized".to_owned(),
+            recipe: recipe.to_owned(),
+            step: step.to_owned(),
+            duration_secs,
+        };
+        let timings = [
+            timing("setup-apt", "00-packages", 1.0),
+            timing("nginx", "00-packages", 20.0),
+            timing("nginx", "10-configure.sh", 30.0),
+            timing("setup-apt", "10-configure.sh", 2.0),
+        ];
+        assert_eq!(
+            summarize_timings(&timings),
+            vec![
+                (
+                    "nginx",
+                    50.0,
+                    vec![("10-configure.sh", 30.0), ("00-packages", 20.0)]
+                ),
+                (
+                    "setup-apt",
+                    3.0,
+                    vec![("10-configure.sh", 2.0), ("00-packages", 1.0)]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(42), "42s");
+        assert_eq!(format_duration(185), "3m 05s");
+        assert_eq!(format_duration(7260), "2h 01m");
+    }
+
+    fn string_values(values: &[(&str, &str)]) -> HashMap<String, ParameterValue> {
+        values
+            .iter()
+            .map(|(name, value)| {
+                (
+                    (*name).to_owned(),
+                    ParameterValue::String((*value).to_owned()),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_layer_parameters_preset() {
+        let presets = HashMap::from([(
+            "common".to_owned(),
+            string_values(&[("mirror", "http://mirror.local"), ("timezone", "UTC")]),
+        )]);
+        let layer = LayerConfig::new().with_presets(Some(presets));
+        let explicit = string_values(&[("preset", "common"), ("timezone", "Europe/Berlin")]);
+        let parameters = LayerParameters::resolve(&layer, &explicit).unwrap();
+        assert_eq!(
+            parameters.get("mirror").unwrap().to_string(),
+            "http://mirror.local"
+        );
+        assert_eq!(
+            parameters.get("timezone").unwrap().to_string(),
+            "Europe/Berlin"
+        );
+        assert!(parameters.get("locale").is_none());
+        assert!(parameters.get("preset").is_none());
+        assert_eq!(
+            parameters.explicit_names().collect::<Vec<_>>(),
+            vec!["timezone"]
+        );
+        let unknown = string_values(&[("preset", "other")]);
+        assert!(LayerParameters::resolve(&layer, &unknown).is_err());
+    }
+
+    #[test]
+    fn test_recipe_parameters() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            temp_dir.path().join("recipe.toml"),
+            indoc::indoc! {r#"
+                [parameters.hostname]
+                help = "Set the hostname of the device."
+                [parameters.timezone]
+                default = "UTC"
+                [parameters.proxy]
+                required = false
+            "#},
+        )
+        .unwrap();
+        let recipe = RecipeLoader::new(RepositoryIdx::from_usize(0))
+            .load(temp_dir.path())
+            .unwrap();
+        let layer = LayerConfig::new();
+        let explicit = string_values(&[("hostname", "rugix")]);
+        let values = LayerParameters::resolve(&layer, &explicit).unwrap();
+        let parameters = recipe_parameters(&recipe, Some(&values)).unwrap();
+        assert_eq!(parameters["hostname"], "rugix");
+        assert_eq!(parameters["timezone"], "UTC");
+        // Optional parameters without a value are omitted.
+        assert!(!parameters.contains_key("proxy"));
+        // Required parameters without a value are an error mentioning the help message.
+        let error = recipe_parameters(&recipe, None).unwrap_err();
+        assert!(format!("{error:?}").contains("Set the hostname of the device."));
+    }
+
+    #[test]
+    fn test_load_params_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml_path = dir.path().join("params.toml");
+        std::fs::write(&toml_path, "[nginx]\nport = 8080\nhost = \"example.com\"\n").unwrap();
+        let json_path = dir.path().join("params.json");
+        std::fs::write(
+            &json_path,
+            r#"{"nginx": {"port": 8080, "host": "example.com"}}"#,
+        )
+        .unwrap();
+        for path in [toml_path, json_path] {
+            let parameters = load_params_file(&path).unwrap();
+            let nginx = &parameters["nginx"];
+            assert_eq!(nginx["port"].to_string(), "8080");
+            assert_eq!(nginx["host"].to_string(), "example.com");
+        }
+    }
+
+    #[test]
+    fn test_resolve_in_root() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        std::fs::create_dir_all(root.join("usr/lib")).unwrap();
+        std::fs::create_dir_all(root.join("var")).unwrap();
+        std::os::unix::fs::symlink("usr/lib", root.join("lib")).unwrap();
+        std::os::unix::fs::symlink("/run", root.join("var/run")).unwrap();
+        std::os::unix::fs::symlink("../../..", root.join("var/escape")).unwrap();
+        std::os::unix::fs::symlink("loop", root.join("loop")).unwrap();
+        let resolve = |path: &str| resolve_in_root(root, Path::new(path)).unwrap();
+        assert_eq!(resolve("lib/firmware"), root.join("usr/lib/firmware"));
+        assert_eq!(resolve("var/run/app"), root.join("run/app"));
+        assert_eq!(resolve("var/escape/etc"), root.join("etc"));
+        assert_eq!(resolve("../etc"), root.join("etc"));
+        assert!(resolve_in_root(root, Path::new("loop/etc")).is_err());
+    }
+
+    #[test]
+    fn test_copy_entry_symlinked_parent() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        let root = temp_dir.path().join("root");
+        std::fs::create_dir_all(src.join("conf.d")).unwrap();
+        std::fs::write(src.join("conf.d/app.conf"), "app").unwrap();
+        std::fs::create_dir_all(root.join("etc")).unwrap();
+        std::os::unix::fs::symlink("/", root.join("etc/host")).unwrap();
+        let metadata = std::fs::metadata(&root).unwrap();
+        let owner = (metadata.uid(), metadata.gid());
+        copy_entry(&src, &root, Path::new("etc/host/app"), owner, None).unwrap();
+        assert!(root.join("app/conf.d/app.conf").is_file());
+        copy_entry(
+            &src.join("conf.d/app.conf"),
+            &root,
+            Path::new("etc/host/tmp/app.conf"),
+            owner,
+            None,
+        )
+        .unwrap();
+        assert!(root.join("tmp/app.conf").is_file());
+    }
+}
@@ -0,0 +1,80 @@
+//! Delta archives containing only the files changed relative to a previous build.
+
+use std::fs;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use reportify::ResultExt;
+use tracing::info;
+use xscript::{run, Run};
+
+use crate::cli::status::archive_progress;
+use crate::utils::archive::extract_tar;
+use crate::utils::temp;
+use crate::utils::tree_diff::diff_trees;
+use crate::BakeryResult;
+
+/// Name of the manifest listing the removed paths within a delta archive.
+pub const REMOVED_MANIFEST: &str = "rugix-delta-removed";
+
+/// Create a delta archive of the layer archive `next` relative to the layer archive `prev`.
+///
+/// The delta archive contains all entries which have been added or modified as well as
+/// a manifest, [`REMOVED_MANIFEST`], listing the removed paths separated by NUL bytes.
+pub fn make_delta(prev: &Path, next: &Path, output: &Path) -> BakeryResult<()> {
+    let temp_dir = temp::temp_dir()?;
+    let prev_dir = temp_dir.path().join("prev");
+    let next_dir = temp_dir.path().join("next");
+    let manifest_dir = temp_dir.path().join("manifest");
+    for dir in [&prev_dir, &next_dir, &manifest_dir] {
+        fs::create_dir_all(dir).whatever("unable to create temporary directory")?;
+    }
+    info!("Extracting previous layer {prev:?}.");
+    extract_tar(prev, &prev_dir, &mut archive_progress("Extracting", prev))
+        .whatever("unable to extract previous layer")?;
+    extract_tar(next, &next_dir, &mut archive_progress("Extracting", next))
+        .whatever("unable to extract layer")?;
+    let diff = diff_trees(&prev_dir, &next_dir).whatever("unable to compare layers")?;
+    info!(
+        "Delta: {} added, {} modified, {} removed.",
+        diff.added.len(),
+        diff.modified.len(),
+        diff.removed.len()
+    );
+    let files_list = temp_dir.path().join("files");
+    write_nul_separated(&files_list, diff.added.iter().chain(&diff.modified))?;
+    write_nul_separated(&manifest_dir.join(REMOVED_MANIFEST), &diff.removed)?;
+    run!([
+        "tar",
+        "-c",
+        "-f",
+        output,
+        "-C",
+        &manifest_dir,
+        REMOVED_MANIFEST,
+        "-C",
+        &next_dir,
+        "--no-recursion",
+        "--null",
+        "-T",
+        &files_list,
+    ])
+    .whatever("unable to create delta archive")?;
+    Ok(())
+}
+
+/// Write the given relative paths, prefixed with `./`, separated by NUL bytes.
+fn write_nul_separated<'p>(
+    path: &Path,
+    paths: impl IntoIterator<Item = &'p PathBuf>,
+) -> BakeryResult<()> {
+    let mut file = fs::File::create(path).whatever("unable to create file list")?;
+    for entry in paths {
+        file.write_all(b"./")
+            .and_then(|_| file.write_all(entry.as_os_str().as_bytes()))
+            .and_then(|_| file.write_all(b"\0"))
+            .whatever("unable to write file list")?;
+    }
+    Ok(())
+}
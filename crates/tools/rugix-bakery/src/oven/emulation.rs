@@ -0,0 +1,215 @@
+//! Emulation of foreign architectures with QEMU user mode.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use reportify::{bail, ResultExt};
+use rugix_common::mount::{MountStack, Mounted};
+use tracing::info;
+use xscript::{run, Run};
+
+use crate::config::systems::Architecture;
+use crate::utils::which::find_binary;
+use crate::BakeryResult;
+
+/// Directory of the `binfmt_misc` filesystem.
+const BINFMT_MISC_DIR: &str = "/proc/sys/fs/binfmt_misc";
+
+/// Architecture of the host.
+pub fn host_architecture() -> Option<Architecture> {
+    match std::env::consts::ARCH {
+        "x86_64" => Some(Architecture::Amd64),
+        "aarch64" => Some(Architecture::Arm64),
+        "arm" => Some(Architecture::Armv7),
+        _ => None,
+    }
+}
+
+/// Indicates whether binaries of the given architecture run natively on the host.
+///
+/// We assume that 64-bit ARM hosts can execute 32-bit ARM binaries.
+fn runs_natively(host: Architecture, arch: Architecture) -> bool {
+    host == arch
+        || (host == Architecture::Arm64
+            && matches!(
+                arch,
+                Architecture::Armv7 | Architecture::Armhf | Architecture::Arm
+            ))
+}
+
+/// Name of QEMU's user mode emulator for the given architecture.
+fn qemu_name(arch: Architecture) -> &'static str {
+    match arch {
+        Architecture::Amd64 => "x86_64",
+        Architecture::Arm64 => "aarch64",
+        Architecture::Armv7 | Architecture::Armhf | Architecture::Arm => "arm",
+    }
+}
+
+/// ELF header magic and mask identifying binaries of the given architecture.
+///
+/// Taken from QEMU's `qemu-binfmt-conf.sh`.
+fn elf_magic(arch: Architecture) -> (&'static str, &'static str) {
+    match arch {
+        Architecture::Amd64 => (
+            r"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x3e\x00",
+            r"\xff\xff\xff\xff\xff\xfe\xfe\x00\xff\xff\xff\xff\xff\xff\xff\xff\xfe\xff\xff\xff",
+        ),
+        Architecture::Arm64 => (
+            r"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\xb7\x00",
+            r"\xff\xff\xff\xff\xff\xff\xff\x00\xff\xff\xff\xff\xff\xff\xff\xff\xfe\xff\xff\xff",
+        ),
+        Architecture::Armv7 | Architecture::Armhf | Architecture::Arm => (
+            r"\x7fELF\x01\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x28\x00",
+            r"\xff\xff\xff\xff\xff\xff\xff\x00\xff\xff\xff\xff\xff\xff\xff\xff\xfe\xff\xff\xff",
+        ),
+    }
+}
+
+/// Registration of an emulator with `binfmt_misc`.
+#[derive(Debug, PartialEq, Eq)]
+struct BinfmtEntry {
+    /// Indicates whether the registration is enabled.
+    enabled: bool,
+    /// Path of the interpreter.
+    interpreter: PathBuf,
+    /// Indicates whether the interpreter is opened when registering it (`F` flag).
+    fix_binary: bool,
+}
+
+impl BinfmtEntry {
+    /// Parse a registration as shown in `/proc/sys/fs/binfmt_misc/<name>`.
+    fn parse(contents: &str) -> Option<Self> {
+        let mut enabled = false;
+        let mut interpreter = None;
+        let mut fix_binary = false;
+        for line in contents.lines() {
+            if line == "enabled" {
+                enabled = true;
+            } else if let Some(path) = line.strip_prefix("interpreter ") {
+                interpreter = Some(PathBuf::from(path));
+            } else if let Some(flags) = line.strip_prefix("flags: ") {
+                fix_binary = flags.contains('F');
+            }
+        }
+        Some(Self {
+            enabled,
+            interpreter: interpreter?,
+            fix_binary,
+        })
+    }
+}
+
+/// Set up the emulation of the given architecture for the given root filesystem.
+///
+/// Nothing is done, if binaries of the architecture run natively on the host. If an
+/// emulator is registered with `binfmt_misc` without the `F` flag, it is bind mounted into
+/// the root filesystem and kept on the given mount stack. If no emulator is registered
+/// and *register* is set, the static QEMU emulator of the host is registered with the `F`
+/// flag, such that it does not need to exist in the root filesystem. As registrations
+/// affect the entire host, they are kept.
+pub fn setup_emulation(
+    arch: Architecture,
+    root_dir_path: &Path,
+    register: bool,
+    stack: &mut MountStack,
+) -> BakeryResult<()> {
+    if host_architecture().is_some_and(|host| runs_natively(host, arch)) {
+        return Ok(());
+    }
+    let binfmt_dir = Path::new(BINFMT_MISC_DIR);
+    if !binfmt_dir.join("register").exists() {
+        run!(["mount", "-t", "binfmt_misc", "binfmt_misc", binfmt_dir])
+            .whatever("unable to mount `binfmt_misc`, which is required for emulation")?;
+    }
+    let name = format!("qemu-{}", qemu_name(arch));
+    let entry = fs::read_to_string(binfmt_dir.join(&name))
+        .ok()
+        .and_then(|contents| BinfmtEntry::parse(&contents));
+    match entry {
+        Some(entry) if entry.enabled && entry.fix_binary => Ok(()),
+        Some(entry) if entry.enabled => {
+            if !entry.interpreter.is_file() {
+                bail!(
+                    "emulator {:?} registered for `{arch}` does not exist",
+                    entry.interpreter
+                );
+            }
+            let target = root_dir_path.join(
+                entry
+                    .interpreter
+                    .strip_prefix("/")
+                    .unwrap_or(&entry.interpreter),
+            );
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent).whatever("unable to create emulator directory")?;
+            }
+            stack.push(
+                Mounted::bind_file(&entry.interpreter, &target)
+                    .whatever("unable to bind mount emulator")?,
+            );
+            Ok(())
+        }
+        _ => {
+            let binary = format!("{name}-static");
+            if !register {
+                bail!(
+                    "no emulator for `{arch}` is registered with `binfmt_misc`, register one \
+                    on the host or pass `--register-emulators` to register `{binary}`"
+                );
+            }
+            let Some(interpreter) = find_binary(&binary) else {
+                bail!(
+                    "unable to find `{binary}`, which is required to run `{arch}` binaries on \
+                    this host (install `qemu-user-static`)"
+                );
+            };
+            info!("Registering {interpreter:?} for emulating `{arch}`.");
+            let (magic, mask) = elf_magic(arch);
+            let registration = format!(
+                ":{name}:M::{magic}:{mask}:{}:F",
+                interpreter.to_string_lossy()
+            );
+            fs::write(binfmt_dir.join("register"), registration)
+                .whatever("unable to register emulator with `binfmt_misc`")?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{runs_natively, BinfmtEntry};
+    use crate::config::systems::Architecture;
+
+    #[test]
+    fn test_runs_natively() {
+        assert!(runs_natively(Architecture::Amd64, Architecture::Amd64));
+        assert!(runs_natively(Architecture::Arm64, Architecture::Armhf));
+        assert!(!runs_natively(Architecture::Amd64, Architecture::Arm64));
+        assert!(!runs_natively(Architecture::Armv7, Architecture::Arm64));
+    }
+
+    #[test]
+    fn test_parse_binfmt_entry() {
+        let entry = BinfmtEntry::parse(
+            "enabled\ninterpreter /usr/bin/qemu-aarch64-static\nflags: OCF\noffset 0\n",
+        )
+        .unwrap();
+        assert_eq!(
+            entry,
+            BinfmtEntry {
+                enabled: true,
+                interpreter: PathBuf::from("/usr/bin/qemu-aarch64-static"),
+                fix_binary: true,
+            }
+        );
+        let entry =
+            BinfmtEntry::parse("disabled\ninterpreter /usr/bin/qemu-arm\nflags: \n").unwrap();
+        assert!(!entry.enabled);
+        assert!(!entry.fix_binary);
+        assert!(BinfmtEntry::parse("enabled\n").is_none());
+    }
+}
@@ -1,15 +1,19 @@
 use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 
 use tempfile::TempDir;
 use tracing::info;
-use xscript::{run, Run};
 
 use reportify::ResultExt;
 
 use rugix_fs::Copier;
 
+use crate::cli::status::archive_progress;
 use crate::project::ProjectRef;
-use crate::utils::caching::{mtime, ModificationTime};
+use crate::utils::archive::extract_tar;
+use crate::utils::caching::{mtime, verify_checksum, ModificationTime};
+use crate::utils::temp;
 use crate::BakeryResult;
 
 #[derive(Debug)]
@@ -33,10 +37,15 @@ impl FrozenLayer {
     }
 
     pub fn unfreeze(&self) -> BakeryResult<Layer> {
-        let tempdir = TempDir::new().whatever("unable to create temporary directory")?;
+        verify_checksum(&self.path)?;
+        let tempdir = temp::temp_dir()?;
         info!("Extracting layer.");
-        run!(["tar", "-xf", &self.path, "-C", tempdir.path()])
-            .whatever_with(|_| format!("unable to extract layer {}", self.name))?;
+        extract_tar(
+            &self.path,
+            tempdir.path(),
+            &mut archive_progress("Extracting", &self.path),
+        )
+        .whatever_with(|_| format!("unable to extract layer {}", self.name))?;
         Ok(Layer {
             name: self.name.clone(),
             tempdir,
@@ -66,8 +75,16 @@ impl AsRef<Path> for Layer {
     }
 }
 
+#[derive(Clone)]
 pub struct LayerContext {
     pub project: ProjectRef,
+    pub arch_variant: Option<String>,
+    /// Additional environment variables for recipe steps.
+    pub env: Vec<(String, String)>,
     pub build_dir: PathBuf,
     pub output_dir: PathBuf,
+    /// Indicates whether package lists must be updated before installing packages.
+    pub update_package_lists: Arc<AtomicBool>,
+    /// Number of retries of package manager operations failing due to network problems.
+    pub apt_retries: u32,
 }
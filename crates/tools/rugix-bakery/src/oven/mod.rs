@@ -1,63 +1,481 @@
 //! Functionality for baking layers and images.
 
 use std::fs;
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
 
 use clap::Args;
+use customize::{CustomizeOpts, ParameterOverrides, RecipeGraph, RecipeJob};
 use layer::FrozenLayer;
-use reportify::{bail, whatever, ResultExt};
+use reportify::{bail, ResultExt};
 use rugix_bundle::manifest::{self, BundleManifest, ChunkerAlgorithm};
+use rugix_common::disk::mbr::MbrId;
 use rugix_common::loop_dev::LoopDevice;
 use rugix_common::mount::Mounted;
-use tempfile::tempdir;
 use tracing::info;
 use url::Url;
 use xscript::{run, Run};
 
-use crate::config::systems::{Architecture, Target};
-use crate::project::library::LayerIdx;
+use crate::cli::status::archive_progress;
+use crate::config::images::{Filesystem, PartitionTableType};
+use crate::config::layers::LayerConfig;
+use crate::config::systems::{Architecture, SystemConfig, Target};
+use crate::project::library::{LayerIdx, Library};
 use crate::project::ProjectRef;
+use crate::utils::archive::{extract_tar, pack_tar};
 use crate::utils::caching::{download, Hasher};
+use crate::utils::setuid;
+use crate::utils::temp;
+use crate::utils::tree_diff::TreeDiff;
 use crate::BakeryResult;
 
+pub mod apt_mirror;
+pub mod bootstrap;
 pub mod customize;
+pub mod delta;
+pub mod emulation;
 pub mod layer;
+pub mod resize;
+pub mod retry;
 pub mod system;
 pub mod targets;
+pub mod verify;
 
-pub fn bake_system(project: &ProjectRef, system: &str, output: &Path) -> BakeryResult<()> {
-    let system_config = project
-        .config()
-        .get_system_config(system)
-        .ok_or_else(|| whatever!("unable to find image {system}"))?;
+/// System options.
+#[derive(Args, Clone, Debug, Default)]
+pub struct SystemOpts {
+    /// MBR disk id to use instead of a random one (8 hex digits).
+    #[clap(long, value_parser = parse_disk_id)]
+    pub disk_id: Option<MbrId>,
+    /// Directory to additionally write the output of each recipe step to.
+    #[clap(long)]
+    pub log_dir: Option<PathBuf>,
+    /// Replace identical files of the root layer with hardlinks.
+    #[clap(long)]
+    pub dedup_files: bool,
+    /// Fail if the root layer contains setuid or setgid files not on the given allowlist
+    /// (one absolute path per line).
+    #[clap(long)]
+    pub audit_setuid: Option<PathBuf>,
+    #[clap(flatten)]
+    pub parallel: ParallelOpts,
+    /// Environment file with additional variables for recipe steps (`KEY=value` lines).
+    #[clap(long)]
+    pub env_file: Option<PathBuf>,
+    /// TOML or JSON file with recipe parameters overriding those of the layers.
+    #[clap(long)]
+    pub params_file: Option<PathBuf>,
+    /// APT mirror to install packages from instead of the configured sources.
+    #[clap(long)]
+    pub mirror: Option<Url>,
+    /// Host of the APT sources served by the mirror (defaults to the Debian and
+    /// Raspberry Pi OS hosts).
+    #[clap(long = "mirror-host", requires = "mirror")]
+    pub mirror_hosts: Vec<String>,
+    /// Number of retries of package installations failing due to network problems
+    /// (defaults to 2).
+    #[clap(long)]
+    pub apt_retries: Option<u32>,
+    /// Write the partition layout of the image as JSON to the given path.
+    #[clap(long)]
+    pub layout_json: Option<PathBuf>,
+    /// Directory with the boot flow files and firmware (defaults to `/usr/share/rugix`).
+    #[clap(long)]
+    pub assets_dir: Option<PathBuf>,
+    /// Partition table of the default image layout (`mbr` or `gpt`).
+    #[clap(long, value_parser = parse_partition_table)]
+    pub partition_table: Option<PartitionTableType>,
+    /// Filesystem of the system partition of the default image layout (`ext4`, `f2fs`,
+    /// or `btrfs`, defaults to `ext4`).
+    #[clap(long, value_parser = parse_root_fs)]
+    pub root_fs: Option<Filesystem>,
+    /// Architecture to bake the system for, overriding the configured architecture.
+    #[clap(long)]
+    pub arch: Option<Architecture>,
+    /// Size of the image, overriding the size configured for the system (e.g., `4GiB`).
+    #[clap(long)]
+    pub image_size: Option<byte_calc::NumBytes>,
+    /// Free space of the system partition, instead of the default overhead (e.g., `1GiB`).
+    #[clap(long)]
+    pub free_space: Option<byte_calc::NumBytes>,
+    /// Populate the boot partitions of all slots of the default image layout, not only
+    /// the one of the `A` slot (requires a system partition for every slot).
+    #[clap(long)]
+    pub init_both_boot: bool,
+    /// Shrink the EXT4 system partition to its minimal size after populating it.
+    #[clap(long, conflicts_with = "image_size")]
+    pub shrink: bool,
+}
+
+impl SystemOpts {
+    /// Directory with the boot flow files and firmware.
+    pub fn assets_dir(&self) -> &Path {
+        self.assets_dir
+            .as_deref()
+            .unwrap_or(Path::new(targets::DEFAULT_ASSETS_DIR))
+    }
+
+    /// Resolve the configuration of the given system applying the overrides.
+    pub fn system_config(&self, project: &ProjectRef, system: &str) -> BakeryResult<SystemConfig> {
+        let mut config = project.config().resolve_system_config(system)?.clone();
+        if let Some(arch) = self.arch {
+            config.architecture = arch;
+        }
+        Ok(config)
+    }
+
+    /// Load the additional environment variables for recipe steps.
+    fn step_env(&self) -> BakeryResult<Vec<(String, String)>> {
+        match &self.env_file {
+            Some(env_file) => customize::load_step_env(env_file),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Load the recipe parameters overriding those of the layers.
+    fn recipe_parameters(&self) -> BakeryResult<ParameterOverrides> {
+        match &self.params_file {
+            Some(params_file) => customize::load_params_file(params_file),
+            None => Ok(ParameterOverrides::new()),
+        }
+    }
+}
+
+/// Parse an MBR disk id consisting of exactly 8 hexadecimal digits.
+fn parse_disk_id(value: &str) -> Result<MbrId, String> {
+    if value.len() != 8 || !value.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err("disk id must consist of exactly 8 hexadecimal digits".to_owned());
+    }
+    u32::from_str_radix(value, 16)
+        .map(MbrId::new)
+        .map_err(|error| error.to_string())
+}
+
+/// Parse a partition table type.
+pub fn parse_partition_table(value: &str) -> Result<PartitionTableType, String> {
+    match value {
+        "mbr" => Ok(PartitionTableType::Mbr),
+        "gpt" => Ok(PartitionTableType::Gpt),
+        _ => Err("partition table must be `mbr` or `gpt`".to_owned()),
+    }
+}
+
+/// Parse the filesystem of the system partition.
+pub fn parse_root_fs(value: &str) -> Result<Filesystem, String> {
+    match value {
+        "ext4" => Ok(Filesystem::Ext4),
+        "f2fs" => Ok(Filesystem::F2fs),
+        "btrfs" => Ok(Filesystem::Btrfs),
+        _ => Err("root filesystem must be `ext4`, `f2fs`, or `btrfs`".to_owned()),
+    }
+}
+
+/// Bake the given system into the given output directory.
+///
+/// Returns the path of the layer archive the system has been baked from.
+pub fn bake_system(
+    project: &ProjectRef,
+    system: &str,
+    output: &Path,
+    opts: &SystemOpts,
+) -> BakeryResult<PathBuf> {
+    let system_config = &opts.system_config(project, system)?;
     info!("baking image `{system}`");
-    let layer_bakery = LayerBakery::new(project, system_config.architecture);
+    let layer_bakery = LayerBakery::new(project, system_config.architecture)
+        .with_arch_variant(system_config.arch_variant.clone())
+        .with_log_dir(opts.log_dir.clone())
+        .with_dedup_files(opts.dedup_files)
+        .with_audit_setuid(opts.audit_setuid.clone())
+        .with_parallel_recipes(opts.parallel.max_jobs())
+        .with_env(opts.step_env()?)
+        .with_parameters(opts.recipe_parameters()?)
+        .with_apt_mirror(opts.mirror.clone())
+        .with_apt_mirror_hosts(opts.mirror_hosts.clone())
+        .with_apt_retries(opts.apt_retries);
+    let baked_layer = layer_bakery.bake_root(&system_config.layer)?;
+    let frozen = FrozenLayer::new(system_config.layer.clone(), baked_layer.clone());
+    system::make_system(system_config, &frozen, output, opts)?;
+    if let Some(layout_json) = &opts.layout_json {
+        system::write_layout_json(system_config, &output.join("system.img"), layout_json, opts)?;
+    }
+    Ok(baked_layer)
+}
+
+/// Write the manifest of an image of the given system baked from the given layer archive.
+pub fn write_image_manifest(
+    project: &ProjectRef,
+    system: &str,
+    image: &Path,
+    source: &Path,
+    path: &Path,
+    opts: &SystemOpts,
+) -> BakeryResult<()> {
+    let system_config = &opts.system_config(project, system)?;
+    system::ImageManifest::new(system, system_config, image, source, opts)?.write(path)
+}
+
+/// Compute the kernel command line of the given system without creating an image.
+pub fn preview_cmdline(
+    project: &ProjectRef,
+    system: &str,
+    opts: &SystemOpts,
+) -> BakeryResult<String> {
+    let system_config = &opts.system_config(project, system)?;
+    let layer_bakery = LayerBakery::new(project, system_config.architecture)
+        .with_arch_variant(system_config.arch_variant.clone())
+        .with_log_dir(opts.log_dir.clone())
+        .with_dedup_files(opts.dedup_files)
+        .with_audit_setuid(opts.audit_setuid.clone())
+        .with_parallel_recipes(opts.parallel.max_jobs())
+        .with_env(opts.step_env()?)
+        .with_parameters(opts.recipe_parameters()?)
+        .with_apt_mirror(opts.mirror.clone())
+        .with_apt_mirror_hosts(opts.mirror_hosts.clone())
+        .with_apt_retries(opts.apt_retries);
     let baked_layer = layer_bakery.bake_root(&system_config.layer)?;
     let frozen = FrozenLayer::new(system_config.layer.clone(), baked_layer);
-    system::make_system(system_config, &frozen, output)
+    system::preview_cmdline(system_config, &frozen, opts)
 }
 
 pub struct LayerBakery<'p> {
     project: &'p ProjectRef,
     arch: Architecture,
+    arch_variant: Option<String>,
+    resume: ResumeOpts,
+    overlay_dirs: Vec<PathBuf>,
+    log_dir: Option<PathBuf>,
+    dedup_files: bool,
+    audit_setuid: Option<PathBuf>,
+    parallel_recipes: Option<NonZeroUsize>,
+    env: Vec<(String, String)>,
+    parameters: ParameterOverrides,
+    apt_mirror: Option<Url>,
+    apt_mirror_hosts: Vec<String>,
+    apt_retries: u32,
+    subtree: Option<PathBuf>,
+    checksum: bool,
 }
 
 impl<'p> LayerBakery<'p> {
     pub fn new(project: &'p ProjectRef, arch: Architecture) -> Self {
-        Self { project, arch }
+        Self {
+            project,
+            arch,
+            arch_variant: None,
+            resume: ResumeOpts::default(),
+            overlay_dirs: Vec::new(),
+            log_dir: None,
+            dedup_files: false,
+            audit_setuid: None,
+            parallel_recipes: None,
+            env: Vec::new(),
+            parameters: ParameterOverrides::new(),
+            apt_mirror: None,
+            apt_mirror_hosts: Vec::new(),
+            apt_retries: retry::DEFAULT_APT_RETRIES,
+            subtree: None,
+            checksum: false,
+        }
+    }
+
+    /// Set the options for resuming a partial build of the root layer.
+    pub fn with_resume(mut self, resume: ResumeOpts) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// Set the overlay directories to copy over the root layer after applying recipes.
+    pub fn with_overlay_dirs(mut self, overlay_dirs: Vec<PathBuf>) -> Self {
+        self.overlay_dirs = overlay_dirs;
+        self
+    }
+
+    /// Set the directory to additionally write the output of each recipe step to.
+    pub fn with_log_dir(mut self, log_dir: Option<PathBuf>) -> Self {
+        self.log_dir = log_dir;
+        self
+    }
+
+    /// Set whether identical files of the root layer should be replaced with hardlinks.
+    pub fn with_dedup_files(mut self, dedup_files: bool) -> Self {
+        self.dedup_files = dedup_files;
+        self
+    }
+
+    /// Set the allowlist of setuid and setgid files to audit the root layer against.
+    pub fn with_audit_setuid(mut self, audit_setuid: Option<PathBuf>) -> Self {
+        self.audit_setuid = audit_setuid;
+        self
+    }
+
+    /// Set the maximal number of independent recipes to apply concurrently.
+    pub fn with_parallel_recipes(mut self, parallel_recipes: Option<NonZeroUsize>) -> Self {
+        self.parallel_recipes = parallel_recipes;
+        self
+    }
+
+    /// Set additional environment variables for recipe steps.
+    pub fn with_env(mut self, env: Vec<(String, String)>) -> Self {
+        self.env = env;
+        self
+    }
+
+    /// Set recipe parameters overriding those of the root layer configuration.
+    pub fn with_parameters(mut self, parameters: ParameterOverrides) -> Self {
+        self.parameters = parameters;
+        self
+    }
+
+    /// Set the APT mirror to install packages from while applying recipes.
+    pub fn with_apt_mirror(mut self, apt_mirror: Option<Url>) -> Self {
+        self.apt_mirror = apt_mirror;
+        self
+    }
+
+    /// Set the hosts of the APT sources served by the mirror.
+    ///
+    /// If no hosts are given, [`DEFAULT_MIRROR_HOSTS`](apt_mirror::DEFAULT_MIRROR_HOSTS)
+    /// are used.
+    pub fn with_apt_mirror_hosts(mut self, apt_mirror_hosts: Vec<String>) -> Self {
+        self.apt_mirror_hosts = apt_mirror_hosts;
+        self
+    }
+
+    /// Set the number of retries of package manager operations failing due to network
+    /// problems, if provided.
+    pub fn with_apt_retries(mut self, apt_retries: Option<u32>) -> Self {
+        if let Some(apt_retries) = apt_retries {
+            self.apt_retries = apt_retries;
+        }
+        self
+    }
+
+    /// Set the subtree of the root filesystem to apply the recipes of the root layer to.
+    pub fn with_subtree(mut self, subtree: Option<PathBuf>) -> Self {
+        self.subtree = subtree;
+        self
+    }
+
+    /// Set whether to write a SHA-256 checksum file next to the root layer archive.
+    pub fn with_checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Set the CPU variant of the architecture exposed to recipes.
+    pub fn with_arch_variant(mut self, arch_variant: Option<String>) -> Self {
+        self.arch_variant = arch_variant;
+        self
     }
 
     pub fn bake_root(&self, layer: &str) -> BakeryResult<PathBuf> {
         let library = self.project.library()?;
-        let Some(layer) = library.lookup_layer(library.repositories.root_repository, layer) else {
+        let Some(layer) = library.lookup_layer(library.repositories.root_repository, layer)? else {
+            bail!("unable to find layer {layer}");
+        };
+        let layer_path = self.bake_with(layer, true)?;
+        // The layer may have been taken from the cache, so the archive itself is audited.
+        if let Some(allowlist) = &self.audit_setuid {
+            setuid::audit_layer(&layer_path, allowlist)?;
+        }
+        Ok(layer_path)
+    }
+
+    /// Compute the recipes the given layer applies without applying them.
+    ///
+    /// Imported and bootstrapped layers do not apply any recipes.
+    pub fn plan_recipes(&self, layer: &str) -> BakeryResult<Vec<RecipeJob>> {
+        let library = self.project.library()?;
+        let Some(layer) = library.lookup_layer(library.repositories.root_repository, layer)? else {
+            bail!("unable to find layer {layer}");
+        };
+        let layer = &library.layers[layer];
+        let Some(config) = layer.config(self.arch) else {
+            bail!("no layer configuration for architecture `{}`", self.arch);
+        };
+        if config.url.is_some() || config.bootstrap.is_some() {
+            return Ok(Vec::new());
+        }
+        customize::plan_recipes(self.project, self.arch, layer, &self.parameters)
+    }
+
+    /// Resolve the recipes the given layer applies and their dependencies.
+    pub fn recipe_graph(&self, layer: &str) -> BakeryResult<RecipeGraph> {
+        let library = self.project.library()?;
+        let Some(layer) = library.lookup_layer(library.repositories.root_repository, layer)? else {
+            bail!("unable to find layer {layer}");
+        };
+        customize::recipe_graph(self.project, self.arch, &library.layers[layer])
+    }
+
+    /// Preview the changes the given recipe makes to the root filesystem of the layer.
+    pub fn preview_recipe(&self, layer: &str, recipe: &str) -> BakeryResult<TreeDiff> {
+        let library = self.project.library()?;
+        let Some(layer_idx) = library.lookup_layer(library.repositories.root_repository, layer)?
+        else {
             bail!("unable to find layer {layer}");
         };
-        self.bake(layer)
+        let layer = &library.layers[layer_idx];
+        let Some(config) = layer.config(self.arch) else {
+            bail!("no layer configuration for architecture `{}`", self.arch);
+        };
+        if !applies_recipes(config) {
+            bail!("layer `{}` does not apply any recipes", layer.name);
+        }
+        let src = self.bake_parent(&library, layer_idx)?;
+        let opts = CustomizeOpts {
+            arch_variant: self.arch_variant.clone(),
+            log_dir: self.log_dir.clone(),
+            env: self.env.clone(),
+            parameters: self.parameters.clone(),
+            apt_mirror: self.apt_mirror.clone(),
+            apt_mirror_hosts: self.apt_mirror_hosts.clone(),
+            apt_retries: self.apt_retries,
+            ..CustomizeOpts::default()
+        };
+        let layer_path = Path::new(".rugix/preview").join(&layer.name);
+        fs::create_dir_all(&layer_path).whatever("unable to create preview directory")?;
+        customize::preview_recipe(
+            self.project,
+            self.arch,
+            layer,
+            src.as_deref(),
+            &layer_path,
+            recipe,
+            &opts,
+        )
     }
 
     pub fn bake(&self, layer: LayerIdx) -> BakeryResult<PathBuf> {
+        self.bake_with(layer, false)
+    }
+
+    /// Bake the given layer, applying the resume options, overlays, deduplication, and
+    /// parameter overrides only if it is the root layer.
+    fn bake_with(&self, layer: LayerIdx, is_root: bool) -> BakeryResult<PathBuf> {
+        let mut opts = CustomizeOpts {
+            arch_variant: self.arch_variant.clone(),
+            log_dir: self.log_dir.clone(),
+            parallel_recipes: self.parallel_recipes,
+            env: self.env.clone(),
+            apt_mirror: self.apt_mirror.clone(),
+            apt_mirror_hosts: self.apt_mirror_hosts.clone(),
+            apt_retries: self.apt_retries,
+            ..CustomizeOpts::default()
+        };
+        if is_root {
+            opts.keep_work_dir = self.resume.keep_work_dir;
+            opts.continue_from = self.resume.continue_from.clone();
+            opts.incremental = self.resume.incremental;
+            opts.overlay_dirs = self.overlay_dirs.clone();
+            opts.dedup_files = self.dedup_files;
+            opts.subtree = self.subtree.clone();
+            opts.parameters = self.parameters.clone();
+            opts.checksum = self.checksum;
+        }
         let repositories = &self.project.repositories()?.repositories;
         let library = self.project.library()?;
+        let layer_idx = layer;
         let layer = &library.layers[layer];
         info!("baking layer `{}`", layer.name);
         let Some(config) = layer.config(self.arch) else {
@@ -67,23 +485,69 @@ impl<'p> LayerBakery<'p> {
         layer_id.push("layer", &layer.name);
         layer_id.push("repository", repositories[layer.repo].source.id.as_str());
         layer_id.push("arch", self.arch.as_str());
+        if let Some(arch_variant) = &self.arch_variant {
+            layer_id.push("arch_variant", arch_variant);
+        }
+        for overlay_dir in &opts.overlay_dirs {
+            layer_id.push("overlay", overlay_dir.to_string_lossy().as_bytes());
+        }
+        if opts.dedup_files {
+            layer_id.push("dedup_files", "true");
+        }
+        if let Some(subtree) = &opts.subtree {
+            layer_id.push("subtree", subtree.to_string_lossy().as_bytes());
+        }
+        let mut parameters = opts
+            .parameters
+            .iter()
+            .flat_map(|(recipe, values)| {
+                values
+                    .iter()
+                    .map(move |(name, value)| format!("{recipe}.{name}={value}"))
+            })
+            .collect::<Vec<_>>();
+        parameters.sort();
+        for parameter in parameters {
+            layer_id.push("parameter", parameter);
+        }
         if let Some(url) = &config.url {
             layer_id.push("url", url);
+            if let Some(sha256) = &config.sha256 {
+                layer_id.push("sha256", sha256);
+            }
+            let layer_id = layer_id.finalize();
+            let system_tar = self
+                .project
+                .dir()
+                .join(format!(".rugix/layers/{layer_id}/system.tar"));
+            if !system_tar.exists() {
+                extract(self.project, url, config.sha256.as_deref(), &system_tar)?;
+            }
+            Ok(system_tar)
+        } else if let Some(bootstrap) = &config.bootstrap {
+            layer_id.push("bootstrap-suite", &bootstrap.suite);
+            if let Some(mirror) = &bootstrap.mirror {
+                layer_id.push("bootstrap-mirror", mirror);
+            }
+            if let Some(variant) = &bootstrap.variant {
+                layer_id.push("bootstrap-variant", variant);
+            }
             let layer_id = layer_id.finalize();
             let system_tar = self
                 .project
                 .dir()
                 .join(format!(".rugix/layers/{layer_id}/system.tar"));
             if !system_tar.exists() {
-                extract(self.project, url, &system_tar)?;
+                bootstrap::bootstrap(bootstrap, self.arch, &system_tar)?;
             }
             Ok(system_tar)
-        } else if let Some(parent) = &config.parent {
-            layer_id.push("parent", parent);
-            let Some(parent) = library.lookup_layer(layer.repo, parent) else {
-                bail!("unable to find layer `{parent}`");
-            };
-            let src = self.bake(parent)?;
+        } else if applies_recipes(config) {
+            match &config.parent {
+                Some(parent) => layer_id.push("parent", parent),
+                None => layer_id.push("bare", "true"),
+            }
+            push_env(&mut layer_id, &opts.env);
+            let src = self.bake_parent(&library, layer_idx)?;
             let layer_id = layer_id.finalize();
             let layer_path = PathBuf::from(format!(".rugix/layers/{layer_id}"));
             let target = self.project.dir().join(&layer_path).join("system.tar");
@@ -92,26 +556,50 @@ impl<'p> LayerBakery<'p> {
                 self.project,
                 self.arch,
                 layer,
-                Some(&src),
+                src.as_deref(),
                 &target,
                 &layer_path,
+                &opts,
             )?;
             Ok(target)
-        } else if config.root.unwrap_or(false) {
-            layer_id.push("bare", "true");
-            let layer_id = layer_id.finalize();
-            let layer_path = PathBuf::from(format!(".rugix/layers/{layer_id}"));
-            let target = self.project.dir().join(&layer_path).join("system.tar");
-            fs::create_dir_all(target.parent().unwrap()).ok();
-            customize::customize(self.project, self.arch, layer, None, &target, &layer_path)?;
-            Ok(target)
         } else {
             bail!("invalid layer configuration")
         }
     }
+
+    /// Bake the parent of the given layer, if it has one.
+    ///
+    /// Shared by baking and previewing, so that recipes are applied to the same parent.
+    /// All parents are resolved upfront to not fail after baking some of them.
+    fn bake_parent(&self, library: &Library, layer: LayerIdx) -> BakeryResult<Option<PathBuf>> {
+        let chain = library.resolve_layer_chain(layer, self.arch)?;
+        match chain.len().checked_sub(2) {
+            Some(parent) => self.bake(chain[parent]).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Check whether the layer applies recipes, i.e., it is based on a parent or a root layer.
+fn applies_recipes(config: &LayerConfig) -> bool {
+    config.url.is_none()
+        && config.bootstrap.is_none()
+        && (config.parent.is_some() || config.root.unwrap_or(false))
+}
+
+/// Add the environment variables of recipe steps to the id of a layer.
+fn push_env(layer_id: &mut Hasher, env: &[(String, String)]) {
+    for (name, value) in env {
+        layer_id.push("env", format!("{name}={value}"));
+    }
 }
 
-fn extract(project: &ProjectRef, image_url: &str, layer_path: &Path) -> BakeryResult<()> {
+fn extract(
+    project: &ProjectRef,
+    image_url: &str,
+    sha256: Option<&str>,
+    layer_path: &Path,
+) -> BakeryResult<()> {
     let image_url = image_url
         .parse::<Url>()
         .whatever("unable to parse image URL")?;
@@ -121,7 +609,7 @@ fn extract(project: &ProjectRef, image_url: &str, layer_path: &Path) -> BakeryRe
             image_path.push(image_url.path().strip_prefix('/').unwrap());
             image_path
         }
-        _ => download(&image_url)?,
+        _ => download(&image_url, sha256, &project.cache_dir())?,
     };
     if image_path.extension() == Some("xz".as_ref()) {
         let decompressed_image_path = image_path.with_extension("");
@@ -144,7 +632,7 @@ fn extract(project: &ProjectRef, image_url: &str, layer_path: &Path) -> BakeryRe
             fs::create_dir_all(parent).whatever("unable to create layer path")?;
         }
     }
-    let temp_dir = tempdir().whatever("unable to create temporary directory")?;
+    let temp_dir = temp::temp_dir()?;
     let temp_dir_path = temp_dir.path();
     let system_dir = temp_dir_path.join("roots/system");
     let boot_dir = temp_dir_path.join("roots/boot");
@@ -152,10 +640,18 @@ fn extract(project: &ProjectRef, image_url: &str, layer_path: &Path) -> BakeryRe
     std::fs::create_dir_all(&boot_dir).whatever("unable to create boot directory")?;
     if image_path.extension() == Some("tar".as_ref()) {
         info!("Copying root filesystem {image_path:?}");
-        run!(["tar", "-x", "-f", &image_path, "-C", system_dir])
-            .whatever("unable to extract root file system")?;
-        run!(["tar", "-c", "-f", &layer_path, "-C", temp_dir_path, "."])
-            .whatever("unable to create layer tar file")?;
+        extract_tar(
+            &image_path,
+            &system_dir,
+            &mut archive_progress("Extracting", &image_path),
+        )
+        .whatever("unable to extract root file system")?;
+        pack_tar(
+            temp_dir_path,
+            &layer_path,
+            &mut archive_progress("Packing", &layer_path),
+        )
+        .whatever("unable to create layer tar file")?;
     } else {
         info!("creating `.tar` archive with system files");
         let loop_dev = LoopDevice::attach(image_path).whatever("unable to setup loop device")?;
@@ -163,12 +659,56 @@ fn extract(project: &ProjectRef, image_url: &str, layer_path: &Path) -> BakeryRe
             .whatever("unable to mount system partition")?;
         let _mounted_boot = Mounted::mount(loop_dev.partition(1), temp_dir_path.join("roots/boot"))
             .whatever("unable to mount boot partition")?;
-        run!(["tar", "-c", "-f", &layer_path, "-C", temp_dir_path, "."])
-            .whatever("unable to create layer tar file")?;
+        pack_tar(
+            temp_dir_path,
+            &layer_path,
+            &mut archive_progress("Packing", &layer_path),
+        )
+        .whatever("unable to create layer tar file")?;
     }
     Ok(())
 }
 
+/// Options for resuming a partial build of a layer.
+#[derive(Args, Clone, Debug, Default)]
+pub struct ResumeOpts {
+    /// Keep the work directory of the layer instead of using a temporary directory.
+    #[clap(long)]
+    pub keep_work_dir: bool,
+    /// Continue applying recipes from the given recipe using the kept work directory.
+    #[clap(long, requires = "keep_work_dir")]
+    pub continue_from: Option<String>,
+    /// Snapshot the layer after each recipe and, on the next build, resume from the
+    /// latest snapshot whose inputs are unchanged.
+    #[clap(long, conflicts_with = "keep_work_dir")]
+    pub incremental: bool,
+}
+
+/// Options for applying independent recipes concurrently.
+#[derive(Args, Clone, Debug, Default)]
+pub struct ParallelOpts {
+    /// Apply independent recipes concurrently, each to its own overlay filesystem.
+    #[clap(long)]
+    pub parallel_recipes: bool,
+    /// Maximal number of recipes to apply concurrently (defaults to the number of CPUs).
+    #[clap(long, requires = "parallel_recipes")]
+    pub jobs: Option<NonZeroUsize>,
+}
+
+impl ParallelOpts {
+    /// Maximal number of recipes to apply concurrently, if enabled.
+    pub fn max_jobs(&self) -> Option<NonZeroUsize> {
+        if !self.parallel_recipes {
+            return None;
+        }
+        Some(
+            self.jobs.unwrap_or_else(|| {
+                std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN)
+            }),
+        )
+    }
+}
+
 /// Bundle options.
 #[derive(Args, Clone, Debug)]
 pub struct BundleOpts {
@@ -182,17 +722,21 @@ pub fn bake_bundle(
     system: &str,
     system_path: &Path,
     output: &Path,
+    system_opts: &SystemOpts,
     opts: &BundleOpts,
 ) -> BakeryResult<()> {
-    let bundle_dir = tempdir().whatever("unable to create temporary directory")?;
+    let bundle_dir = temp::temp_dir()?;
     let bundle_dir = bundle_dir.path();
-    let system_config = project.config().resolve_system_config(system)?;
-    let config = match system_config.target.clone().unwrap_or(Target::Unknown) {
-        Target::GenericGrubEfi => efi_bundle_config(opts),
-        Target::RpiTryboot => rpi_bundle_config(opts),
-        Target::RpiUboot => rpi_bundle_config(opts),
-        Target::Unknown => bail!("cannot bake bundles for unknown targets"),
-    };
+    let system_config = &system_opts.system_config(project, system)?;
+    if matches!(system_config.target, None | Some(Target::Unknown)) {
+        bail!("cannot bake bundles for unknown targets");
+    }
+    let layout = system::image_layout(system_config, system_opts)?;
+    let config = bundle_config(
+        opts,
+        system::root_partition_index(&layout, "boot")?,
+        system::root_partition_index(&layout, "system")?,
+    );
     std::fs::write(
         bundle_dir.join("rugix-bundle.toml"),
         toml::to_string(&config).unwrap(),
@@ -214,46 +758,9 @@ pub fn bake_bundle(
     Ok(())
 }
 
-fn rpi_bundle_config(opts: &BundleOpts) -> BundleManifest {
-    let compression = if opts.without_compression {
-        None
-    } else {
-        Some(manifest::Compression::Xz(manifest::XzCompression::new()))
-    };
-    manifest::BundleManifest::new(
-        manifest::UpdateType::Full,
-        vec![
-            manifest::Payload::new(
-                manifest::DeliveryConfig::Slot(manifest::SlotDeliveryConfig {
-                    slot: "boot".to_owned(),
-                }),
-                "partition-2.img".to_owned(),
-            )
-            .with_block_encoding(Some(
-                manifest::BlockEncoding::new(ChunkerAlgorithm::Casync {
-                    avg_block_size_kib: 64,
-                })
-                .with_deduplicate(Some(true))
-                .with_compression(compression.clone()),
-            )),
-            manifest::Payload::new(
-                manifest::DeliveryConfig::Slot(manifest::SlotDeliveryConfig {
-                    slot: "system".to_owned(),
-                }),
-                "partition-5.img".to_owned(),
-            )
-            .with_block_encoding(Some(
-                manifest::BlockEncoding::new(ChunkerAlgorithm::Casync {
-                    avg_block_size_kib: 64,
-                })
-                .with_deduplicate(Some(true))
-                .with_compression(compression.clone()),
-            )),
-        ],
-    )
-}
-
-fn efi_bundle_config(opts: &BundleOpts) -> BundleManifest {
+/// Bundle configuration updating the boot and system slots from the partitions with the
+/// given indices in the image layout.
+fn bundle_config(opts: &BundleOpts, boot_index: usize, system_index: usize) -> BundleManifest {
     let compression = if opts.without_compression {
         None
     } else {
@@ -266,7 +773,7 @@ fn efi_bundle_config(opts: &BundleOpts) -> BundleManifest {
                 manifest::DeliveryConfig::Slot(manifest::SlotDeliveryConfig {
                     slot: "boot".to_owned(),
                 }),
-                "partition-2.img".to_owned(),
+                system::partition_image_name(boot_index),
             )
             .with_block_encoding(Some(
                 manifest::BlockEncoding::new(ChunkerAlgorithm::Casync {
@@ -279,7 +786,7 @@ fn efi_bundle_config(opts: &BundleOpts) -> BundleManifest {
                 manifest::DeliveryConfig::Slot(manifest::SlotDeliveryConfig {
                     slot: "system".to_owned(),
                 }),
-                "partition-4.img".to_owned(),
+                system::partition_image_name(system_index),
             )
             .with_block_encoding(Some(
                 manifest::BlockEncoding::new(ChunkerAlgorithm::Casync {
@@ -0,0 +1,88 @@
+//! Growing of existing images.
+
+use std::fs::File;
+use std::path::Path;
+use std::process::Command;
+
+use byte_calc::NumBytes;
+use reportify::{bail, ResultExt};
+use rugix_common::disk::gpt::gpt_types;
+use rugix_common::disk::mbr::mbr_types;
+use rugix_common::disk::{NumBlocks, PartitionTable};
+use rugix_common::loop_dev::LoopDevice;
+use tracing::info;
+use xscript::{run, Run};
+
+use crate::BakeryResult;
+
+/// Alignment of the end of the grown partition.
+const ALIGNMENT: NumBlocks = NumBlocks::from_raw(2048);
+
+/// Grow the given image to the given size.
+///
+/// Extends the last partition, which must be the system partition, to the end of the
+/// image and resizes its filesystem accordingly.
+pub fn resize_image(image: &Path, size: NumBytes) -> BakeryResult<()> {
+    let file = File::options()
+        .write(true)
+        .open(image)
+        .whatever("unable to open image file")?;
+    let current_size = file
+        .metadata()
+        .whatever("unable to read image metadata")?
+        .len();
+    if size.raw <= current_size {
+        bail!("new size ({size}) must be larger than the current size ({current_size} bytes)");
+    }
+    let table = PartitionTable::read(image).whatever("unable to read partition table")?;
+    let Some(last) = table.partitions.last() else {
+        bail!("image does not contain any partitions");
+    };
+    if last.ty != mbr_types::LINUX && last.ty != gpt_types::LINUX {
+        bail!(
+            "last partition {} is not a Linux partition, expected the system partition",
+            last.number
+        );
+    }
+    info!("Growing image file to {size}.");
+    file.set_len(size.raw)
+        .whatever("unable to grow image file")?;
+    drop(file);
+    let mut table = PartitionTable::read(image).whatever("unable to read partition table")?;
+    let new_end = (table.last_usable_block() + NumBlocks::ONE).floor_align_to(ALIGNMENT);
+    let number = {
+        let last = table.partitions.last_mut().unwrap();
+        if new_end <= last.end() {
+            bail!("new size is too small to grow the system partition");
+        }
+        last.size = new_end - last.start;
+        last.number
+    };
+    // Logical MBR partitions must stay within the extended partition.
+    if let Some(extended) = table
+        .partitions
+        .iter_mut()
+        .find(|partition| partition.ty.is_extended())
+    {
+        extended.size = new_end - extended.start;
+    }
+    info!("Growing system partition {number}.");
+    table
+        .write(image)
+        .whatever("unable to write partition table")?;
+    let loop_dev = LoopDevice::attach(image).whatever("unable to setup loop device")?;
+    let partition = loop_dev.partition(number.into());
+    // Exit codes 0 and 1 indicate that the filesystem is (now) consistent.
+    let status = Command::new("e2fsck")
+        .arg("-f")
+        .arg("-p")
+        .arg(&partition)
+        .status()
+        .whatever("unable to run `e2fsck`")?;
+    if !matches!(status.code(), Some(0 | 1)) {
+        bail!("unable to check system filesystem, `e2fsck` failed with {status}");
+    }
+    info!("Resizing system filesystem.");
+    run!(["resize2fs", &partition]).whatever("unable to resize system filesystem")?;
+    Ok(())
+}
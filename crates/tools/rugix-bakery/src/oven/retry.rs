@@ -0,0 +1,134 @@
+//! Retrying of package manager operations failing due to network problems.
+
+use std::time::Duration;
+
+use tracing::warn;
+
+/// Default number of retries of package manager operations.
+pub const DEFAULT_APT_RETRIES: u32 = 2;
+
+/// Delay before the first retry, doubled for every further retry.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Maximal delay between two attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(120);
+
+/// Output patterns of APT and APK indicating a network problem.
+const TRANSIENT_PATTERNS: &[&str] = &[
+    "Temporary failure resolving",
+    "Could not resolve",
+    "Could not connect to",
+    "Connection timed out",
+    "Connection failed",
+    "Unable to connect to",
+    "Failed to fetch",
+    "Unable to fetch some archives",
+    "Hash Sum mismatch",
+    "Service Unavailable",
+    "Bad Gateway",
+    "Gateway Timeout",
+    "temporary error (try again later)",
+    "network error",
+];
+
+/// Output patterns indicating a failure of `dpkg`, which is not retried.
+const PERMANENT_PATTERNS: &[&str] = &[
+    "dpkg: error",
+    "Sub-process /usr/bin/dpkg returned an error",
+    "dpkg was interrupted",
+];
+
+/// Checks whether the output of a failed package manager operation indicates a network
+/// problem, such that the operation may succeed when retried.
+///
+/// Failures of `dpkg` are never considered transient, as they typically indicate broken
+/// packages or maintainer scripts and leave the system in a state in which retrying is
+/// pointless.
+pub fn is_transient_failure(output: &str) -> bool {
+    if PERMANENT_PATTERNS
+        .iter()
+        .any(|pattern| output.contains(pattern))
+    {
+        return false;
+    }
+    TRANSIENT_PATTERNS
+        .iter()
+        .any(|pattern| output.contains(pattern))
+}
+
+/// Delay before the given retry, starting with zero for the first retry.
+pub fn backoff(retry: u32) -> Duration {
+    INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(retry))
+        .min(MAX_BACKOFF)
+}
+
+/// Run the given operation, retrying it up to `retries` times with exponential backoff
+/// as long as it fails transiently.
+///
+/// The operation returns its result together with whether a failure is transient. Retries
+/// are reported as warnings starting with *what*, e.g., `Installing packages`.
+pub fn with_retries<T, E>(
+    what: &str,
+    retries: u32,
+    mut operation: impl FnMut() -> (Result<T, E>, bool),
+) -> Result<T, E> {
+    let mut retry = 0;
+    loop {
+        match operation() {
+            (Err(_), true) if retry < retries => {
+                let delay = backoff(retry);
+                retry += 1;
+                warn!(
+                    "{what} failed due to a network problem, retrying in {}s (retry {retry} of {retries}).",
+                    delay.as_secs()
+                );
+                std::thread::sleep(delay);
+            }
+            (result, _) => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{backoff, is_transient_failure, with_retries};
+
+    #[test]
+    fn test_is_transient_failure() {
+        assert!(is_transient_failure(
+            "E: Failed to fetch http://deb.debian.org/debian/pool/main/c/curl/curl.deb  Temporary failure resolving 'deb.debian.org'"
+        ));
+        assert!(is_transient_failure(
+            "E: Failed to fetch http://deb.debian.org/debian/dists/bookworm/InRelease  503  Service Unavailable"
+        ));
+        assert!(!is_transient_failure(
+            "dpkg: error processing package nginx (--configure):\nE: Sub-process /usr/bin/dpkg returned an error code (1)"
+        ));
+        assert!(!is_transient_failure(
+            "E: Unable to locate package nonexistent"
+        ));
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(0), Duration::from_secs(5));
+        assert_eq!(backoff(1), Duration::from_secs(10));
+        assert_eq!(backoff(2), Duration::from_secs(20));
+        assert_eq!(backoff(10), Duration::from_secs(120));
+        assert_eq!(backoff(u32::MAX), Duration::from_secs(120));
+    }
+
+    #[test]
+    fn test_with_retries_permanent() {
+        let mut attempts = 0;
+        let result: Result<(), ()> = with_retries("test", 3, || {
+            attempts += 1;
+            (Err(()), false)
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+}
@@ -3,35 +3,64 @@ use std::io::Seek;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 
-use tracing::info;
+use serde::Serialize;
+use tracing::{info, warn};
 
 use reportify::{bail, whatever, ResultExt};
-use xscript::{run, Run};
+use xscript::{cmd, read_str, run, ParentEnv, Run};
 
 use rugix_common::disk::gpt::gpt_types;
-use rugix_common::disk::mbr::mbr_types;
+use rugix_common::disk::mbr::{mbr_types, MbrId};
 use rugix_common::disk::{
     parse_size, DiskId, NumBlocks, Partition, PartitionTable, PartitionTableType,
 };
 use rugix_common::fsutils::allocate_file;
 use rugix_common::utils::ascii_numbers;
 use rugix_common::utils::units::NumBytes;
-use rugix_common::{grub_patch_env, rpi_patch_boot, rpi_patch_config};
+use rugix_common::{
+    grub_bootargs, grub_patch_env, patch_fstab_file, rpi_patch_boot, rpi_patch_cmdline,
+    rpi_patch_config,
+};
 
-use crate::config::images::{Filesystem, ImageLayout};
+use crate::config::images::{self, Filesystem, ImageLayout, ImagePartition};
 use crate::config::systems::{SystemConfig, Target};
 use crate::oven::targets;
 use crate::oven::targets::generic_grub_efi::initialize_grub;
 use crate::oven::targets::rpi_tryboot::initialize_tryboot;
 use crate::oven::targets::rpi_uboot::initialize_uboot;
-use crate::utils::caching::mtime;
+use crate::utils::caching::{file_sha256, mtime};
 use crate::BakeryResult;
 
 use super::layer::FrozenLayer;
+use super::SystemOpts;
 
-pub fn make_system(config: &SystemConfig, frozen: &FrozenLayer, out: &Path) -> BakeryResult<()> {
+pub fn make_system(
+    config: &SystemConfig,
+    frozen: &FrozenLayer,
+    out: &Path,
+    opts: &SystemOpts,
+) -> BakeryResult<()> {
     let system_info = out.join("system-info.json");
-    if system_info.exists() {
+    let system_info_content = serde_json::json!({
+        "disk_id": opts.disk_id.map(|disk_id| disk_id.to_string()),
+        "assets_dir": opts.assets_dir,
+        "cmdline_extra": config.cmdline_extra,
+        "boot_path": config.boot_path,
+        "partition_table": opts.partition_table.map(|table_type| match table_type {
+            images::PartitionTableType::Mbr => "mbr",
+            images::PartitionTableType::Gpt => "gpt",
+        }),
+        "root_fs": opts.root_fs.as_ref().map(Filesystem::name),
+        "image_size": opts.image_size.map(|size| size.raw),
+        "free_space": opts.free_space.map(|size| size.raw),
+        "shrink": opts.shrink,
+        "init_both_boot": opts.init_both_boot,
+    })
+    .to_string();
+    // The image may have been removed after compressing it.
+    let system_info_unchanged = out.join("system.img").exists()
+        && fs::read_to_string(&system_info).is_ok_and(|content| content == system_info_content);
+    if system_info_unchanged {
         let system_mtime = mtime(&system_info).whatever("unable to get system mtime")?;
         let layer_mtime = frozen.last_modified()?;
         if layer_mtime < system_mtime {
@@ -58,25 +87,28 @@ pub fn make_system(config: &SystemConfig, frozen: &FrozenLayer, out: &Path) -> B
     let system_dir = layer_path.join("roots/system");
     fs::create_dir_all(&system_dir).whatever("unable to create system directory")?;
 
-    // Create directories for config and boot partitions.
+    // Create directories for config and boot partitions. Recipes may have already staged
+    // files in these directories, which end up in the respective partitions.
     info!("Creating config and boot directories.");
     let config_dir = layer_path.join("roots/config");
     fs::create_dir_all(&config_dir).whatever("unable to create config directory")?;
     let boot_dir = layer_path.join("roots/boot");
     fs::create_dir_all(&boot_dir).whatever("unable to create boot directory")?;
+    copy_boot_files(config, &layer_path.join("roots"))?;
 
-    // Initialize config partition.
+    // Initialize config partition. Files of the boot flow take precedence over files
+    // staged by recipes.
     info!("Initialize boot flow.");
     if let Some(target) = &config.target {
         match target {
             Target::RpiTryboot => {
-                initialize_tryboot(&config_dir)?;
+                initialize_tryboot(opts.assets_dir(), &config_dir)?;
             }
             Target::RpiUboot => {
-                initialize_uboot(config, &config_dir)?;
+                initialize_uboot(config, opts.assets_dir(), &config_dir)?;
             }
             Target::GenericGrubEfi => {
-                initialize_grub(&config, &config_dir)?;
+                initialize_grub(&config, opts.assets_dir(), &config_dir)?;
             }
             Target::Unknown => { /* nothing to do */ }
         }
@@ -89,22 +121,69 @@ pub fn make_system(config: &SystemConfig, frozen: &FrozenLayer, out: &Path) -> B
     }
 
     // At this point, everything is initialized and we can compute the partition table.
-    let layout = config
-        .image
-        .as_ref()
-        .and_then(|image| image.layout.clone())
-        .or_else(|| config.target.as_ref().and_then(targets::get_default_layout))
-        .ok_or_else(|| whatever!("image layout needs to be specified"))?;
+    let layout = image_layout(config, opts)?;
+
+    let image_config = config.image.as_ref();
+    if matches!(config.target, Some(Target::RpiTryboot | Target::RpiUboot))
+        && layout.ty == Some(images::PartitionTableType::Gpt)
+        && image_config
+            .and_then(|image| image.layout.as_ref())
+            .is_none()
+    {
+        let bootstrapping_config = system_dir.join("etc/rugix/bootstrapping.toml");
+        if bootstrapping_config.exists() {
+            info!("Keeping existing bootstrapping configuration.");
+        } else {
+            info!("Writing bootstrapping configuration for the GPT layout.");
+            let slots = image_config
+                .and_then(|image| image.slots)
+                .unwrap_or(targets::DEFAULT_SLOTS);
+            let system_size = image_config
+                .and_then(|image| image.system_size)
+                .unwrap_or(byte_calc::NumBytes::gibibytes(4));
+            fs::create_dir_all(system_dir.join("etc/rugix")).ok();
+            fs::write(
+                &bootstrapping_config,
+                rpi_gpt_bootstrapping_config(&layout, slots, system_size),
+            )
+            .whatever("unable to write bootstrapping configuration")?;
+        }
+    }
+
+    // Populate the boot directories of further slots, which are patched below.
+    for root in boot_roots(&layout).into_iter().skip(1) {
+        rugix_fs::Copier::new()
+            .copy_dir(&boot_dir, &layer_path.join("roots").join(root))
+            .whatever("unable to copy boot directory")
+            .with_info(|_| format!("root: {root}"))?;
+    }
 
     let image_file = out.join("system.img");
 
     info!("Computing partition table.");
-    let table = compute_partition_table(&layout, &layer_path.join("roots"))?;
+    let table = compute_partition_table(
+        &layout,
+        &layer_path.join("roots"),
+        opts.disk_id,
+        opts.free_space,
+    )?;
 
     let size_bytes = table.blocks_to_bytes(table.disk_size);
 
     info!("Allocating image file.");
-    if let Some(size) = &config.image.as_ref().and_then(|image| image.size) {
+    let image_size = opts
+        .image_size
+        .or_else(|| config.image.as_ref().and_then(|image| image.size));
+    if let Some(size) = image_size {
+        if opts.shrink {
+            bail!("image with a fixed size of {size} cannot be shrunk");
+        }
+        if size.raw < size_bytes.into_raw() {
+            bail!(
+                "image size of {size} is too small, the partitions require {} bytes",
+                size_bytes.into_raw()
+            );
+        }
         allocate_file(&image_file, size.raw)
     } else {
         allocate_file(&image_file, size_bytes.into_raw())
@@ -116,30 +195,60 @@ pub fn make_system(config: &SystemConfig, frozen: &FrozenLayer, out: &Path) -> B
         .write(&image_file)
         .whatever("error writing image partition table")?;
 
-    let table =
+    let mut table =
         PartitionTable::read(&image_file).whatever("error reading image partition table")?;
 
-    if let Some(target) = &config.target {
-        if matches!(target, Target::RpiTryboot | Target::RpiUboot) {
-            let disk_id = match table.disk_id {
-                DiskId::Mbr(mbr_id) => mbr_id.into_raw(),
-                _ => bail!("unsupported GPT partition layout"),
-            };
-            info!("Patching boot configuration.");
-            rpi_patch_boot(&boot_dir, format!("PARTUUID={disk_id:08X}-05"))
-                .whatever("unable to patch boot configuration")?;
-            info!("Patching `config.txt`.");
-            rpi_patch_config(boot_dir.join("config.txt"))
-                .whatever("unable to patch `config.txt`")?;
+    if let DiskId::Mbr(disk_id) = table.disk_id {
+        let fstab = system_dir.join("etc/fstab");
+        if fstab.is_file() {
+            info!("Patching `/etc/fstab`.");
+            patch_fstab_file(&fstab, &format!("{:08x}", disk_id.into_raw()))
+                .whatever("unable to patch `/etc/fstab`")?;
         }
-        if matches!(target, Target::GenericGrubEfi) {
-            let root_part = &table.partitions[3];
-            let part_uuid = root_part
-                .gpt_id
-                .unwrap()
-                .to_hex_str(ascii_numbers::Case::Lower);
-            grub_patch_env(boot_dir, part_uuid)
-                .whatever("unable to patch Grub boot environment")?;
+    }
+
+    if let Some(target) = &config.target {
+        // The boot configuration of each slot refers to the system partition of the slot.
+        for (slot, root) in boot_roots(&layout).into_iter().enumerate() {
+            let boot_dir = layer_path.join("roots").join(root);
+            if matches!(target, Target::RpiTryboot | Target::RpiUboot) {
+                let system_idx = system_partition_index(&layout, slot as u32)?;
+                let system_partition = &table.partitions[system_idx];
+                let Some(part_uuid) =
+                    part_uuid(&table, system_partition, ascii_numbers::Case::Upper)
+                else {
+                    bail!("system partition has no partition id");
+                };
+                let mut cmdline_extra = config.cmdline_extra.clone().unwrap_or_default();
+                if let Some(filesystem) = layout
+                    .partitions
+                    .iter()
+                    .flatten()
+                    .nth(system_idx)
+                    .and_then(|partition| partition.filesystem)
+                {
+                    // The kernel tries all filesystems it knows of, we only need to tell it
+                    // about filesystems other than EXT4 to not depend on the probing order.
+                    if filesystem != Filesystem::Ext4 {
+                        cmdline_extra.insert(0, format!("rootfstype={}", filesystem.name()));
+                    }
+                }
+                info!("Patching boot configuration of `{root}`.");
+                rpi_patch_boot(&boot_dir, format!("PARTUUID={part_uuid}"), &cmdline_extra)
+                    .whatever("unable to patch boot configuration")?;
+                info!("Patching `config.txt` of `{root}`.");
+                rpi_patch_config(boot_dir.join("config.txt"))
+                    .whatever("unable to patch `config.txt`")?;
+            }
+            if matches!(target, Target::GenericGrubEfi) {
+                let root_part = &table.partitions[system_partition_index(&layout, slot as u32)?];
+                let Some(part_uuid) = part_uuid(&table, root_part, ascii_numbers::Case::Lower)
+                else {
+                    bail!("system partition has no partition id");
+                };
+                grub_patch_env(&boot_dir, part_uuid)
+                    .whatever("unable to patch Grub boot environment")?;
+            }
         }
     }
 
@@ -147,6 +256,9 @@ pub fn make_system(config: &SystemConfig, frozen: &FrozenLayer, out: &Path) -> B
 
     std::fs::create_dir_all(&filesystems_dir).ok();
 
+    // Index and new size of the system partition, if it has been shrunk.
+    let mut shrunk = None;
+
     // Create filesystems.
     if let Some(partitions) = &layout.partitions {
         for (partition, (layout_partition, image_partition)) in
@@ -161,45 +273,62 @@ pub fn make_system(config: &SystemConfig, frozen: &FrozenLayer, out: &Path) -> B
                 image_partition.number,
                 image_partition.size.into_raw()
             );
-            let fs_image = filesystems_dir.join(format!("partition-{}.img", partition + 1));
+            let fs_image = filesystems_dir.join(partition_image_name(partition));
+            let mkfs_options = layout_partition.mkfs_options.as_deref().unwrap_or_default();
+            let size = table.blocks_to_bytes(image_partition.size);
+            allocate_file(&fs_image, size.into_raw())
+                .whatever("unable to allocate filesystem file")?;
+            let root = layout_partition
+                .root
+                .as_ref()
+                .map(|path| layer_path.join("roots").join(path));
             match filesystem {
                 Filesystem::Ext4 => {
-                    let size = table.blocks_to_bytes(image_partition.size);
-                    allocate_file(&fs_image, size.into_raw())
-                        .whatever("unable to allocate filesystem file")?;
-                    if let Some(path) = &layout_partition.root {
-                        run!([
-                            "mkfs.ext4",
-                            "-d",
-                            layer_path.join("roots").join(path),
-                            &fs_image
-                        ])
-                    } else {
-                        run!(["mkfs.ext4", &fs_image])
+                    let mut mkfs = cmd!("mkfs.ext4");
+                    mkfs.extend_args(mkfs_options);
+                    if let Some(root) = &root {
+                        mkfs.add_arg("-d").add_arg(root);
                     }
-                    .whatever("unable to create EXT4 filesystem")?;
-                    let mut src =
-                        File::open(&fs_image).whatever("unable to open filesystem image file")?;
-                    let mut dst = File::options()
-                        .write(true)
-                        .open(&image_file)
-                        .whatever("unable to open image file")?;
-                    dst.seek(std::io::SeekFrom::Start(
-                        table.blocks_to_bytes(image_partition.start).into_raw(),
-                    ))
-                    .whatever("unable to seek in image file")?;
-                    std::io::copy(&mut src, &mut dst)
-                        .whatever("error copying filesystem into image")?;
+                    mkfs.add_arg(&fs_image);
+                    ParentEnv
+                        .run(mkfs)
+                        .whatever("unable to create EXT4 filesystem")?;
+                }
+                Filesystem::F2fs => {
+                    let mut mkfs = cmd!("mkfs.f2fs");
+                    mkfs.extend_args(mkfs_options);
+                    mkfs.add_arg(&fs_image);
+                    ParentEnv
+                        .run(mkfs)
+                        .whatever("unable to create F2FS filesystem")?;
+                    // In contrast to `mkfs.ext4`, `mkfs.f2fs` cannot populate the
+                    // filesystem, so we use `sload.f2fs` preserving the ownership.
+                    if let Some(root) = &root {
+                        run!(["sload.f2fs", "-P", "-f", root, "-t", "/", &fs_image])
+                            .whatever("unable to copy files into F2FS filesystem")?;
+                    }
+                }
+                Filesystem::Btrfs => {
+                    let mut mkfs = cmd!("mkfs.btrfs");
+                    mkfs.extend_args(mkfs_options);
+                    if let Some(root) = &root {
+                        mkfs.add_arg("--rootdir").add_arg(root);
+                    }
+                    mkfs.add_arg(&fs_image);
+                    ParentEnv
+                        .run(mkfs)
+                        .whatever("unable to create Btrfs filesystem")?;
                 }
                 Filesystem::Fat32 => {
-                    let size = table.blocks_to_bytes(image_partition.size);
-                    allocate_file(&fs_image, size.into_raw())
-                        .whatever("error allocating filesystem image")?;
-                    run!(["mkfs.vfat", &fs_image]).whatever("error creating FAT32 filesystem")?;
-                    if let Some(path) = &layout_partition.root {
-                        let fs_path = layer_path.join("roots").join(path);
+                    let mut mkfs = cmd!("mkfs.vfat");
+                    mkfs.extend_args(mkfs_options);
+                    mkfs.add_arg(&fs_image);
+                    ParentEnv
+                        .run(mkfs)
+                        .whatever("error creating FAT32 filesystem")?;
+                    if let Some(root) = &root {
                         for entry in
-                            fs::read_dir(&fs_path).whatever("error reading filesystem content")?
+                            fs::read_dir(root).whatever("error reading filesystem content")?
                         {
                             let entry = entry.whatever("error reading filesystem entry")?;
                             run!([
@@ -213,24 +342,59 @@ pub fn make_system(config: &SystemConfig, frozen: &FrozenLayer, out: &Path) -> B
                             .whatever("error copying files into image")?;
                         }
                     }
-                    let mut src =
-                        File::open(&fs_image).whatever("unable to open filesystem image file")?;
-                    let mut dst = File::options()
-                        .write(true)
-                        .open(&image_file)
-                        .whatever("unable to open image file")?;
-                    dst.seek(std::io::SeekFrom::Start(
-                        table.blocks_to_bytes(image_partition.start).into_raw(),
-                    ))
-                    .whatever("unable to seek in image file")?;
-                    std::io::copy(&mut src, &mut dst)
-                        .whatever("error copying filesystem into image")?;
                 }
             }
+            let is_system = layout_partition.root.as_deref() == Some("system");
+            let mut min_free = None;
+            if is_system {
+                min_free = config
+                    .image
+                    .as_ref()
+                    .and_then(|image| image.rootfs_min_free);
+            }
+            // The free space is reserved in partitions without a fixed size (see
+            // `compute_fs_size`), so it must be available in their filesystems.
+            if layout_partition.root.is_some()
+                && layout_partition.size.is_none()
+                && *filesystem != Filesystem::Fat32
+            {
+                min_free = match (min_free, opts.free_space) {
+                    (Some(min_free), Some(free_space)) if free_space > min_free => Some(free_space),
+                    (min_free, free_space) => min_free.or(free_space),
+                };
+            }
+            if is_system {
+                if opts.shrink && layout_partition.size.is_some() {
+                    warn!("System partition has a fixed size and is not shrunk.");
+                } else if opts.shrink && *filesystem == Filesystem::Ext4 {
+                    let size = shrink_ext4(&fs_image, min_free)?;
+                    shrunk = Some((partition, table.bytes_to_blocks(size)));
+                }
+            }
+            if let Some(min_free) = min_free {
+                check_min_free(&fs_image, *filesystem, min_free)
+                    .with_info(|_| format!("partition: {}", image_partition.number))?;
+            }
+            let mut src = File::open(&fs_image).whatever("unable to open filesystem image file")?;
+            let mut dst = File::options()
+                .write(true)
+                .open(&image_file)
+                .whatever("unable to open image file")?;
+            dst.seek(std::io::SeekFrom::Start(
+                table.blocks_to_bytes(image_partition.start).into_raw(),
+            ))
+            .whatever("unable to seek in image file")?;
+            std::io::copy(&mut src, &mut dst).whatever("error copying filesystem into image")?;
         }
     }
 
-    std::fs::write(&system_info, "{}").whatever("unable to write system info")?;
+    check_boot_file_names(&layout, &filesystems_dir)?;
+
+    if let Some((partition, size)) = shrunk {
+        shrink_image(&mut table, partition, size, &image_file)?;
+    }
+
+    std::fs::write(&system_info, system_info_content).whatever("unable to write system info")?;
 
     Ok(())
 }
@@ -241,24 +405,718 @@ const BLOCK_SIZE: NumBytes = NumBytes::from_raw(512);
 /// We align everything to 2048 blocks, i.e., 1MiB.
 const ALIGNMENT: NumBlocks = NumBlocks::from_raw(2048);
 
+/// Index of the partition populated from the given root, e.g., `system`, in the layout.
+///
+/// As partition tables are written in the order of the layout, the index is the same
+/// for the partitions of the image.
+pub fn root_partition_index(layout: &ImageLayout, root: &str) -> BakeryResult<usize> {
+    layout
+        .partitions
+        .iter()
+        .flatten()
+        .position(|partition| partition.root.as_deref() == Some(root))
+        .ok_or_else(|| whatever!("image layout has no partition with root `{root}`"))
+}
+
+/// Roots of the boot directories in the order of their slots.
+///
+/// The `boot` directory of the `A` slot always comes first, even if the layout does not
+/// have a partition populated from it.
+fn boot_roots(layout: &ImageLayout) -> Vec<&str> {
+    let mut roots = vec!["boot"];
+    for root in layout
+        .partitions
+        .iter()
+        .flatten()
+        .filter_map(|partition| partition.root.as_deref())
+    {
+        if targets::is_boot_root(root) && !roots.contains(&root) {
+            roots.push(root);
+        }
+    }
+    roots
+}
+
+/// Index of the system partition of the given slot in the layout.
+///
+/// The system partition of the `A` slot is the one populated from `system`, the system
+/// partitions of further slots are identified by their label, e.g., `system-b`.
+fn system_partition_index(layout: &ImageLayout, slot: u32) -> BakeryResult<usize> {
+    if slot == 0 {
+        return root_partition_index(layout, "system");
+    }
+    let label = format!("system-{}", targets::slot_name(slot));
+    layout
+        .partitions
+        .iter()
+        .flatten()
+        .position(|partition| filesystem_label(partition) == Some(label.as_str()))
+        .ok_or_else(|| whatever!("image layout has no system partition with label `{label}`"))
+}
+
+/// Bootstrapping configuration of Rugix Ctrl matching the given Raspberry Pi GPT layout.
+///
+/// The default bootstrapping layout of Rugix Ctrl for GPT partition tables expects the
+/// partition types of the `generic-grub-efi` target, while the Raspberry Pi firmware
+/// requires FAT partitions of type *basic data*. Hence, the layout is shipped with the
+/// system. It extends the partitions of the image with the system partitions of the
+/// remaining slots, of the given size, and the data partition.
+fn rpi_gpt_bootstrapping_config(
+    layout: &ImageLayout,
+    slots: u32,
+    system_size: byte_calc::NumBytes,
+) -> String {
+    let mut partitions = Vec::new();
+    let mut system_slots = 0;
+    for partition in layout.partitions.iter().flatten() {
+        let is_system = partition.root.as_deref() == Some("system")
+            || filesystem_label(partition).is_some_and(|label| label.starts_with("system-"));
+        if is_system {
+            system_slots += 1;
+        }
+        let size = match (partition.size, is_system) {
+            (_, true) => Some(system_size),
+            (size, false) => size,
+        };
+        partitions.push((
+            filesystem_label(partition).map(str::to_lowercase),
+            size,
+            partition.ty,
+        ));
+    }
+    for slot in system_slots..slots {
+        let label = format!("system-{}", targets::slot_name(slot));
+        partitions.push((Some(label), Some(system_size), Some(gpt_types::LINUX)));
+    }
+    let mut config = String::from("[layout]\ntype = \"gpt\"\npartitions = [\n");
+    for (name, size, ty) in partitions {
+        let mut fields = Vec::new();
+        if let Some(name) = name {
+            fields.push(format!("name = \"{name}\""));
+        }
+        if let Some(size) = size {
+            fields.push(format!("size = \"{}\"", size.raw));
+        }
+        if let Some(ty) = ty {
+            fields.push(format!("type = \"{ty}\""));
+        }
+        config.push_str(&format!("    {{ {} }},\n", fields.join(", ")));
+    }
+    config.push_str(
+        "    { name = \"data\", filesystem = { type = \"Ext4\", label = \"data\" } },\n]\n",
+    );
+    config
+}
+
+/// Name of the filesystem image of the partition with the given index.
+pub fn partition_image_name(index: usize) -> String {
+    format!("partition-{}.img", index + 1)
+}
+
+/// `PARTUUID` of the given partition of the given table.
+///
+/// For MBR partition tables, the `PARTUUID` consists of the disk id and the number of
+/// the partition in hex, e.g., `1234abcd-05`.
+fn part_uuid(
+    table: &PartitionTable,
+    partition: &Partition,
+    case: ascii_numbers::Case,
+) -> Option<String> {
+    match (table.disk_id, partition.gpt_id) {
+        (DiskId::Mbr(disk_id), _) => Some(match case {
+            ascii_numbers::Case::Lower => {
+                format!("{:08x}-{:02x}", disk_id.into_raw(), partition.number)
+            }
+            ascii_numbers::Case::Upper => {
+                format!("{:08X}-{:02X}", disk_id.into_raw(), partition.number)
+            }
+        }),
+        (_, Some(gpt_id)) => Some(gpt_id.to_hex_str(case).to_string()),
+        _ => None,
+    }
+}
+
+/// Check that all populated boot partitions contain files with the same names.
+///
+/// All boot partitions are populated from copies of the same directory, which only differ
+/// in the contents of the patched boot configuration, hence, the names of their files
+/// should never differ. Only the paths of the files and directories are compared, not
+/// their contents. This guards against partially populated boot partitions, which
+/// would result in a broken slot.
+fn check_boot_file_names(layout: &ImageLayout, filesystems_dir: &Path) -> BakeryResult<()> {
+    let boot_partitions = layout
+        .partitions
+        .iter()
+        .flatten()
+        .enumerate()
+        .filter(|(_, partition)| partition.root.as_deref().is_some_and(targets::is_boot_root))
+        .filter_map(|(index, partition)| Some((index, partition.filesystem?)))
+        .collect::<Vec<_>>();
+    let Some(((first, first_filesystem), others)) = boot_partitions.split_first() else {
+        return Ok(());
+    };
+    if others.is_empty() {
+        return Ok(());
+    }
+    let first_tree = file_tree(
+        &filesystems_dir.join(partition_image_name(*first)),
+        *first_filesystem,
+    )?;
+    for (index, filesystem) in others {
+        let tree = file_tree(
+            &filesystems_dir.join(partition_image_name(*index)),
+            *filesystem,
+        )?;
+        if tree != first_tree {
+            bail!(
+                "boot partitions {} and {} have different files",
+                first + 1,
+                index + 1
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Sorted paths of the files and directories of the given filesystem image.
+fn file_tree(fs_image: &Path, filesystem: Filesystem) -> BakeryResult<Vec<String>> {
+    let mut paths = Vec::new();
+    match filesystem {
+        Filesystem::Fat32 => {
+            let listing = read_str!(["mdir", "-/", "-b", "-i", fs_image, "::/"])
+                .whatever("unable to list files of FAT32 filesystem")?;
+            paths.extend(listing.lines().map(str::to_owned));
+        }
+        Filesystem::Ext4 => {
+            let mut stack = vec![String::new()];
+            while let Some(dir) = stack.pop() {
+                let request = format!("ls -p {}/", dir);
+                let listing = read_str!(["debugfs", "-R", request, fs_image])
+                    .whatever("unable to list files of EXT4 filesystem")?;
+                // Each line has the form `/<inode>/<mode>/<uid>/<gid>/<name>/<size>/`.
+                for line in listing.lines() {
+                    let fields = line.split('/').collect::<Vec<_>>();
+                    let (Some(mode), Some(name)) = (fields.get(2), fields.get(5)) else {
+                        continue;
+                    };
+                    if name.is_empty() || matches!(*name, "." | "..") {
+                        continue;
+                    }
+                    let path = format!("{dir}/{name}");
+                    if mode.starts_with("04") {
+                        stack.push(path.clone());
+                    }
+                    paths.push(path);
+                }
+            }
+        }
+        _ => bail!("unable to list files of {} filesystem", filesystem.name()),
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Superblock information of an EXT4 filesystem image as printed by `dumpe2fs`.
+struct Ext4Info(String);
+
+impl Ext4Info {
+    /// Read the superblock information of the given filesystem image.
+    fn read(fs_image: &Path) -> BakeryResult<Self> {
+        read_str!(["dumpe2fs", "-h", fs_image])
+            .whatever("unable to read EXT4 filesystem information")
+            .map(Self)
+    }
+
+    /// Numeric value of the field with the given name.
+    fn field(&self, name: &str) -> BakeryResult<u64> {
+        self.0
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .and_then(|value| value.trim().parse().ok())
+            .ok_or_else(|| whatever!("unable to determine `{name}` of EXT4 filesystem"))
+    }
+}
+
+/// Shrink the EXT4 filesystem image to its minimal size, keeping the given free space.
+///
+/// Returns the new size of the filesystem. The image file is truncated accordingly.
+fn shrink_ext4(fs_image: &Path, min_free: Option<byte_calc::NumBytes>) -> BakeryResult<NumBytes> {
+    info!("Shrinking system filesystem.");
+    // `resize2fs` refuses to shrink filesystems which have not been checked.
+    run!(["e2fsck", "-f", "-y", fs_image]).whatever("unable to check EXT4 filesystem")?;
+    match min_free {
+        Some(min_free) => {
+            let info = Ext4Info::read(fs_image)?;
+            let block_size = info.field("Block size")?;
+            let estimate = read_str!(["resize2fs", "-P", fs_image])
+                .whatever("unable to estimate minimal size of EXT4 filesystem")?;
+            let Some(min_blocks) = estimate
+                .lines()
+                .find_map(|line| line.split_once("Estimated minimum size of the filesystem:"))
+                .and_then(|(_, blocks)| blocks.trim().parse::<u64>().ok())
+            else {
+                bail!("unable to parse minimal size of EXT4 filesystem");
+            };
+            let blocks = min_blocks
+                + min_free.raw.div_ceil(block_size)
+                + info.field("Reserved block count")?;
+            if blocks < info.field("Block count")? {
+                run!(["resize2fs", fs_image, blocks.to_string()])
+                    .whatever("unable to shrink EXT4 filesystem")?;
+            }
+        }
+        None => {
+            run!(["resize2fs", "-M", fs_image]).whatever("unable to shrink EXT4 filesystem")?;
+        }
+    }
+    let info = Ext4Info::read(fs_image)?;
+    let size = info.field("Block count")? * info.field("Block size")?;
+    File::options()
+        .write(true)
+        .open(fs_image)
+        .and_then(|file| file.set_len(size))
+        .whatever("unable to truncate filesystem image")?;
+    Ok(NumBytes::from_raw(size))
+}
+
+/// Shrink the given partition of the image to the given size, truncating the image.
+///
+/// Only the last partition of the image can be shrunk. If it is a logical partition,
+/// the extended partition is shrunk as well.
+fn shrink_image(
+    table: &mut PartitionTable,
+    partition: usize,
+    size: NumBlocks,
+    image_file: &Path,
+) -> BakeryResult<()> {
+    if !shrink_table(table, partition, size)? {
+        return Ok(());
+    }
+    info!("Shrinking image to {} bytes.", table.size().into_raw());
+    // The backup GPT header is placed based on the size of the image file, so we have to
+    // truncate the image before writing the partition table.
+    File::options()
+        .write(true)
+        .open(image_file)
+        .and_then(|file| file.set_len(table.size().into_raw()))
+        .whatever("unable to truncate image file")?;
+    table
+        .write(image_file)
+        .whatever("error writing image partition table")
+}
+
+/// Shrink the given partition of the table to the given size, adjusting the extended
+/// partition, if any, and the size of the disk.
+///
+/// Returns whether the partition has been shrunk.
+fn shrink_table(
+    table: &mut PartitionTable,
+    partition: usize,
+    size: NumBlocks,
+) -> BakeryResult<bool> {
+    if partition + 1 != table.partitions.len() {
+        bail!("the system partition can only be shrunk if it is the last partition");
+    }
+    let shrunk = &mut table.partitions[partition];
+    if size >= shrunk.size {
+        return Ok(false);
+    }
+    shrunk.size = size;
+    let end = shrunk.end().ceil_align_to(ALIGNMENT);
+    for partition in table.partitions.iter_mut() {
+        if partition.ty.is_extended() {
+            partition.size = (end - partition.start + NumBlocks::ONE).ceil_align_to(ALIGNMENT);
+        }
+    }
+    // As in `compute_partition_table`, we leave some space at the end for GPT.
+    table.disk_size = end + ALIGNMENT;
+    Ok(true)
+}
+
+/// Check that the filesystem image has at least the given amount of free space.
+fn check_min_free(
+    fs_image: &Path,
+    filesystem: Filesystem,
+    min_free: byte_calc::NumBytes,
+) -> BakeryResult<()> {
+    let available = free_space(fs_image, filesystem)?;
+    info!("Filesystem has {available} of free space.");
+    if available < min_free {
+        bail!("filesystem has only {available} of free space, but {min_free} are required");
+    }
+    Ok(())
+}
+
+/// Block size of F2FS filesystems, which is fixed.
+const F2FS_BLOCK_SIZE: u64 = 4096;
+
+/// Free space of the given filesystem image available for regular files.
+fn free_space(fs_image: &Path, filesystem: Filesystem) -> BakeryResult<byte_calc::NumBytes> {
+    let free = match filesystem {
+        Filesystem::Ext4 => {
+            let info = Ext4Info::read(fs_image)?;
+            let free_blocks = info
+                .field("Free blocks")?
+                .saturating_sub(info.field("Reserved block count")?);
+            free_blocks * info.field("Block size")?
+        }
+        Filesystem::F2fs => {
+            // The checkpoint is only printed with a debug level of at least one.
+            let info = read_str!(["dump.f2fs", "-d", "1", fs_image])
+                .whatever("unable to read F2FS filesystem information")?;
+            let user_blocks = f2fs_field(&info, "user_block_count")?;
+            let valid_blocks = f2fs_field(&info, "valid_block_count")?;
+            user_blocks.saturating_sub(valid_blocks) * F2FS_BLOCK_SIZE
+        }
+        Filesystem::Btrfs => {
+            let info = read_str!(["btrfs", "inspect-internal", "dump-super", fs_image])
+                .whatever("unable to read Btrfs filesystem information")?;
+            btrfs_field(&info, "total_bytes")?.saturating_sub(btrfs_field(&info, "bytes_used")?)
+        }
+        Filesystem::Fat32 => {
+            bail!("unable to determine free space of FAT32 filesystem")
+        }
+    };
+    Ok(byte_calc::NumBytes::new(free))
+}
+
+/// Numeric value of the field with the given name as printed by `dump.f2fs`.
+///
+/// Fields are printed as `<name> [0x<hex> : <decimal>]`.
+fn f2fs_field(info: &str, name: &str) -> BakeryResult<u64> {
+    info.lines()
+        .find_map(|line| {
+            let value = line.strip_prefix(name)?;
+            if !value.starts_with(char::is_whitespace) {
+                return None;
+            }
+            let (_, decimal) = value.split_once(':')?;
+            decimal.trim().strip_suffix(']')?.trim().parse().ok()
+        })
+        .ok_or_else(|| whatever!("unable to determine `{name}` of F2FS filesystem"))
+}
+
+/// Numeric value of the field with the given name as printed by `btrfs inspect-internal
+/// dump-super`.
+fn btrfs_field(info: &str, name: &str) -> BakeryResult<u64> {
+    info.lines()
+        .find_map(|line| {
+            let mut parts = line.split_whitespace();
+            if parts.next()? != name {
+                return None;
+            }
+            parts.next()?.parse().ok()
+        })
+        .ok_or_else(|| whatever!("unable to determine `{name}` of Btrfs filesystem"))
+}
+
+/// Copy the files of the configured boot path of the system to the boot partition.
+fn copy_boot_files(config: &SystemConfig, roots_dir: &Path) -> BakeryResult<()> {
+    let Some(boot_path) = &config.boot_path else {
+        return Ok(());
+    };
+    let system_boot_dir = roots_dir
+        .join("system")
+        .join(boot_path.trim_start_matches('/'));
+    if !system_boot_dir.is_dir() {
+        bail!("boot path `{boot_path}` is not a directory of the system");
+    }
+    info!("Copying boot files from `{boot_path}`.");
+    run!([
+        "cp",
+        "-a",
+        "--",
+        system_boot_dir.join("."),
+        roots_dir.join("boot")
+    ])
+    .whatever("unable to copy boot files")?;
+    Ok(())
+}
+
+/// Representative MBR disk id used when previewing the kernel command line.
+const PREVIEW_DISK_ID: MbrId = MbrId::new(0x1234_5678);
+
+/// Compute the kernel command line of the system without creating an image.
+///
+/// Uses the disk id of the options or, if none is given, a representative one. As the
+/// GPT partition ids are only assigned when writing the image, they are shown as a
+/// placeholder.
+pub fn preview_cmdline(
+    config: &SystemConfig,
+    frozen: &FrozenLayer,
+    opts: &SystemOpts,
+) -> BakeryResult<String> {
+    let layout = image_layout(config, opts)?;
+    match &config.target {
+        Some(Target::RpiTryboot | Target::RpiUboot) => {
+            let layer = frozen.unfreeze()?;
+            let roots_dir = layer.path().join("roots");
+            for root in ["system", "config", "boot"] {
+                fs::create_dir_all(roots_dir.join(root)).ok();
+            }
+            copy_boot_files(config, &roots_dir)?;
+            let cmdline = fs::read_to_string(roots_dir.join("boot/cmdline.txt"))
+                .whatever("unable to read `cmdline.txt` of boot partition")?;
+            let root = if matches!(
+                layout.ty,
+                Some(crate::config::images::PartitionTableType::Gpt)
+            ) {
+                "PARTUUID=<partuuid>".to_owned()
+            } else {
+                let disk_id = opts.disk_id.unwrap_or(PREVIEW_DISK_ID);
+                let table = compute_partition_table(&layout, &roots_dir, Some(disk_id), None)?;
+                let system_partition = &table.partitions[root_partition_index(&layout, "system")?];
+                let Some(part_uuid) =
+                    part_uuid(&table, system_partition, ascii_numbers::Case::Upper)
+                else {
+                    bail!("system partition has no partition id");
+                };
+                format!("PARTUUID={part_uuid}")
+            };
+            Ok(rpi_patch_cmdline(
+                &cmdline,
+                &root,
+                config.cmdline_extra.as_deref().unwrap_or_default(),
+            ))
+        }
+        Some(Target::GenericGrubEfi) => Ok(grub_bootargs("<partuuid>")),
+        _ => bail!("system has no target with a kernel command line"),
+    }
+}
+
+/// Partition layout of an image.
+#[derive(Debug, Serialize)]
+pub struct LayoutInfo {
+    /// Type of the partition table.
+    pub table_type: String,
+    /// Disk id of the partition table.
+    pub disk_id: String,
+    /// Size of the image in bytes.
+    pub size: u64,
+    /// Partitions of the image.
+    pub partitions: Vec<PartitionInfo>,
+}
+
+/// Partition of an image.
+#[derive(Debug, Serialize)]
+pub struct PartitionInfo {
+    /// Number of the partition.
+    pub number: u8,
+    /// Offset of the partition in bytes.
+    pub offset: u64,
+    /// Size of the partition in bytes.
+    pub size: u64,
+    /// MBR type in hex or GPT type GUID of the partition.
+    #[serde(rename = "type")]
+    pub ty: String,
+    /// Filesystem of the partition as given by the image layout.
+    pub filesystem: Option<&'static str>,
+    /// Label of the filesystem as given by the image layout.
+    pub label: Option<String>,
+    /// Partition id as reported by Linux.
+    pub partuuid: Option<String>,
+}
+
+impl LayoutInfo {
+    /// Read the partition layout of the given image of the given system.
+    ///
+    /// The partition table is read back from the image, so the layout reflects the
+    /// actual offsets and ids of the partitions.
+    pub fn read(config: &SystemConfig, image: &Path, opts: &SystemOpts) -> BakeryResult<Self> {
+        let layout = image_layout(config, opts)?;
+        let table = PartitionTable::read(image).whatever("error reading image partition table")?;
+        let partitions = layout
+            .partitions
+            .iter()
+            .flatten()
+            .zip(table.partitions.iter())
+            .map(|(layout_partition, image_partition)| PartitionInfo {
+                number: image_partition.number,
+                offset: table.blocks_to_bytes(image_partition.start).into_raw(),
+                size: table.blocks_to_bytes(image_partition.size).into_raw(),
+                ty: image_partition.ty.to_string(),
+                filesystem: layout_partition.filesystem.as_ref().map(Filesystem::name),
+                label: filesystem_label(layout_partition).map(str::to_owned),
+                partuuid: part_uuid(&table, image_partition, ascii_numbers::Case::Lower),
+            })
+            .collect();
+        Ok(Self {
+            table_type: table.ty().to_string(),
+            disk_id: table.disk_id.to_string(),
+            size: table.size().into_raw(),
+            partitions,
+        })
+    }
+}
+
+/// Write the partition layout of the given image as JSON to the given path.
+pub fn write_layout_json(
+    config: &SystemConfig,
+    image: &Path,
+    path: &Path,
+    opts: &SystemOpts,
+) -> BakeryResult<()> {
+    write_json(
+        &LayoutInfo::read(config, image, opts)?,
+        path,
+        "unable to write image layout",
+    )
+}
+
+/// Manifest describing a baked image for provenance tracking.
+#[derive(Debug, Serialize)]
+pub struct ImageManifest {
+    /// Version of Rugix Bakery which baked the image.
+    pub rugix_version: &'static str,
+    /// Name of the system.
+    pub system: String,
+    /// Layer archive the image has been baked from.
+    pub source: ManifestSource,
+    /// Partition layout of the image.
+    pub layout: LayoutInfo,
+}
+
+/// Layer archive an image has been baked from.
+#[derive(Debug, Serialize)]
+pub struct ManifestSource {
+    /// Path of the archive.
+    pub path: PathBuf,
+    /// Hex-encoded SHA-256 digest of the archive.
+    pub sha256: String,
+}
+
+impl ImageManifest {
+    /// Create the manifest for the given image baked from the given layer archive.
+    pub fn new(
+        system: &str,
+        config: &SystemConfig,
+        image: &Path,
+        source: &Path,
+        opts: &SystemOpts,
+    ) -> BakeryResult<Self> {
+        let sha256 = file_sha256(source)
+            .whatever("unable to hash layer archive")
+            .with_info(|_| format!("path: {source:?}"))?;
+        Ok(Self {
+            rugix_version: env!("CARGO_PKG_VERSION"),
+            system: system.to_owned(),
+            source: ManifestSource {
+                path: source.to_path_buf(),
+                sha256,
+            },
+            layout: LayoutInfo::read(config, image, opts)?,
+        })
+    }
+
+    /// Write the manifest as JSON to the given path.
+    pub fn write(&self, path: &Path) -> BakeryResult<()> {
+        write_json(self, path, "unable to write image manifest")
+    }
+}
+
+/// Write the given value as pretty-printed JSON to the given path.
+fn write_json(value: &impl Serialize, path: &Path, error: &'static str) -> BakeryResult<()> {
+    let content = serde_json::to_string_pretty(value).whatever(error)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).ok();
+    }
+    fs::write(path, content)
+        .whatever(error)
+        .with_info(|_| format!("path: {path:?}"))
+}
+
+/// Label of the filesystem of the given partition as given by its `mkfs` options.
+pub fn filesystem_label(partition: &ImagePartition) -> Option<&str> {
+    let label_option = partition.filesystem.as_ref()?.label_option();
+    let options = partition.mkfs_options.as_deref()?;
+    options
+        .iter()
+        .position(|option| option == label_option)
+        .and_then(|idx| options.get(idx + 1))
+        .map(String::as_str)
+}
+
+/// Image layout of the system, falling back to the default layout of the target.
+pub fn image_layout(config: &SystemConfig, opts: &SystemOpts) -> BakeryResult<ImageLayout> {
+    let image_config = config.image.as_ref();
+    match image_config.and_then(|image| image.layout.clone()) {
+        Some(layout) => {
+            if opts.partition_table.is_some() {
+                bail!("partition table cannot be overridden for custom image layouts");
+            }
+            if opts.root_fs.is_some() {
+                bail!("root filesystem cannot be overridden for custom image layouts");
+            }
+            if opts.init_both_boot {
+                bail!("boot partitions of custom image layouts cannot be initialized");
+            }
+            Some(layout)
+        }
+        None => match &config.target {
+            Some(target) => {
+                let slots = image_config
+                    .and_then(|image| image.slots)
+                    .unwrap_or(targets::DEFAULT_SLOTS);
+                let system_slots = image_config
+                    .and_then(|image| image.system_slots)
+                    .unwrap_or(1);
+                if opts.init_both_boot && system_slots < slots {
+                    bail!(
+                        "initializing all boot partitions requires a system partition for each \
+                        of the {slots} slots, got {system_slots}"
+                    );
+                }
+                let sizes = targets::PartitionSizes {
+                    config: image_config.and_then(|image| image.config_size),
+                    boot: image_config.and_then(|image| image.boot_size),
+                    system: image_config.and_then(|image| image.system_size),
+                };
+                targets::get_default_layout(
+                    target,
+                    slots,
+                    system_slots,
+                    if opts.init_both_boot { slots } else { 1 },
+                    opts.partition_table,
+                    sizes,
+                    opts.root_fs.unwrap_or(Filesystem::Ext4),
+                )?
+            }
+            None => None,
+        },
+    }
+    .ok_or_else(|| whatever!("image layout needs to be specified"))
+}
+
 /// Convert number of bytes to number of blocks.
 fn bytes_to_blocks(bytes: NumBytes) -> NumBlocks {
     NumBlocks::from_raw(bytes.into_raw().div_ceil(BLOCK_SIZE.into_raw()))
 }
 
 /// Compute the partition table for an image based on the provided layout.
-fn compute_partition_table(layout: &ImageLayout, roots_dir: &Path) -> BakeryResult<PartitionTable> {
+///
+/// If no disk id is provided, a random disk id is used. If free space is provided, it is
+/// reserved in partitions without a fixed size instead of the default overhead.
+/// Partitions without a fixed size and without a root directory, e.g., the empty system
+/// partitions of further slots, get the size computed for the last preceding partition
+/// with a root directory.
+fn compute_partition_table(
+    layout: &ImageLayout,
+    roots_dir: &Path,
+    disk_id: Option<MbrId>,
+    free_space: Option<byte_calc::NumBytes>,
+) -> BakeryResult<PartitionTable> {
     let table_type = layout
         .ty
         .map(|ty| match ty {
-            crate::config::images::PartitionTableType::Mbr => PartitionTableType::Mbr,
-            crate::config::images::PartitionTableType::Gpt => PartitionTableType::Gpt,
+            images::PartitionTableType::Mbr => PartitionTableType::Mbr,
+            images::PartitionTableType::Gpt => PartitionTableType::Gpt,
         })
         .unwrap_or(PartitionTableType::Mbr);
     let mut partitions = Vec::new();
     let mut next_usable = ALIGNMENT;
     let mut next_number = 1;
     let mut in_extended = false;
+    let mut root_size = None;
     if let Some(layout_partitions) = &layout.partitions {
         for partition in layout_partitions {
             // Partitions are numbered based on their appearance in the layout.
@@ -300,12 +1158,21 @@ fn compute_partition_table(layout: &ImageLayout, roots_dir: &Path) -> BakeryResu
             } else {
                 let size = match &partition.size {
                     Some(size) => bytes_to_blocks(size.raw.into()),
-                    None => {
-                        let Some(path) = &partition.root else {
-                            bail!("partitions without a fixed size must have a root path");
-                        };
-                        compute_fs_size(roots_dir.join(path))?
-                    }
+                    None => match (&partition.root, root_size) {
+                        (Some(path), _) => {
+                            let size = compute_fs_size(
+                                roots_dir.join(path),
+                                partition.filesystem.unwrap_or(Filesystem::Ext4),
+                                free_space,
+                            )?;
+                            root_size = Some(size);
+                            size
+                        }
+                        (None, Some(size)) => size,
+                        (None, None) => {
+                            bail!("partitions without a fixed size must have a root path")
+                        }
+                    },
                 };
                 partitions.push(Partition {
                     number,
@@ -334,9 +1201,13 @@ fn compute_partition_table(layout: &ImageLayout, roots_dir: &Path) -> BakeryResu
         }
         None => ALIGNMENT * 32,
     };
-    let table_id = match table_type {
-        PartitionTableType::Mbr => DiskId::random_mbr(),
-        PartitionTableType::Gpt => DiskId::random_gpt(),
+    let table_id = match (table_type, disk_id) {
+        (PartitionTableType::Mbr, Some(disk_id)) => DiskId::Mbr(disk_id),
+        (PartitionTableType::Mbr, None) => DiskId::random_mbr(),
+        (PartitionTableType::Gpt, Some(_)) => {
+            bail!("explicit disk ids are only supported for MBR partition tables")
+        }
+        (PartitionTableType::Gpt, None) => DiskId::random_gpt(),
     };
     let mut table = PartitionTable::new(table_id, image_size);
     table.partitions = partitions;
@@ -347,7 +1218,15 @@ fn compute_partition_table(layout: &ImageLayout, roots_dir: &Path) -> BakeryResu
 }
 
 /// Compute the required size for a filesystem based on the given root path.
-fn compute_fs_size(root: PathBuf) -> BakeryResult<NumBlocks> {
+///
+/// The overhead of the filesystem is added to the size of the files and, if provided,
+/// the free space. For EXT4, the blocks reserved for `root` are added as well, such that
+/// the free space is available to regular users.
+fn compute_fs_size(
+    root: PathBuf,
+    filesystem: Filesystem,
+    free_space: Option<byte_calc::NumBytes>,
+) -> BakeryResult<NumBlocks> {
     let mut size = NumBytes::from_raw(0);
     let mut stack = vec![root];
     while let Some(top) = stack.pop() {
@@ -361,8 +1240,183 @@ fn compute_fs_size(root: PathBuf) -> BakeryResult<NumBlocks> {
             }
         }
     }
-    size = size.max(parse_size("64M").unwrap());
-    // Add an overhead of 20% for filesystem metadata.
-    size += NumBytes::from_raw(size.into_raw().div_ceil(5));
+    let (min_size, overhead) = fs_size_overhead(filesystem);
+    if let Some(free_space) = free_space {
+        size += NumBytes::from_raw(free_space.raw);
+    }
+    size = size.max(min_size);
+    size += NumBytes::from_raw((size.into_raw() * overhead).div_ceil(100));
+    if free_space.is_some() && filesystem == Filesystem::Ext4 {
+        let reserved = 100 - EXT4_RESERVED_PERCENT;
+        size = NumBytes::from_raw((size.into_raw() * 100).div_ceil(reserved));
+    }
     Ok(bytes_to_blocks(size))
 }
+
+/// Percentage of the blocks of EXT4 filesystems reserved for `root` by default.
+const EXT4_RESERVED_PERCENT: u64 = 5;
+
+/// Minimal size and overhead (in percent) for metadata of the given filesystem.
+fn fs_size_overhead(filesystem: Filesystem) -> (NumBytes, u64) {
+    match filesystem {
+        Filesystem::Ext4 | Filesystem::Fat32 => (parse_size("64M").unwrap(), 20),
+        // F2FS reserves additional space for overprovisioning and garbage collection.
+        Filesystem::F2fs => (parse_size("64M").unwrap(), 35),
+        // Btrfs duplicates its metadata and requires more space for its chunks.
+        Filesystem::Btrfs => (parse_size("128M").unwrap(), 30),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rugix_common::disk::PartitionType;
+
+    use super::*;
+
+    /// Partition of the given type with the given number, start, and size.
+    fn partition(number: u8, start: u64, size: u64, ty: PartitionType) -> Partition {
+        Partition {
+            number,
+            start: NumBlocks::from_raw(start),
+            size: NumBlocks::from_raw(size),
+            ty,
+            name: None,
+            gpt_id: None,
+        }
+    }
+
+    #[test]
+    fn test_shrink_table_extended() {
+        let mut table = PartitionTable::new(DiskId::Mbr(MbrId::new(0)), NumBlocks::from_raw(0));
+        table.partitions = vec![
+            partition(1, 2048, 2048, mbr_types::FAT32_LBA),
+            partition(2, 4096, 2048, mbr_types::EXTENDED),
+            partition(5, 6144, 100_000, mbr_types::LINUX),
+        ];
+        assert!(shrink_table(&mut table, 2, NumBlocks::from_raw(5000)).unwrap());
+        assert_eq!(table.partitions[2].size, NumBlocks::from_raw(5000));
+        // The logical partition ends at 11144, which is aligned to 12288.
+        assert_eq!(table.partitions[1].size, NumBlocks::from_raw(10240));
+        assert_eq!(table.disk_size, NumBlocks::from_raw(14336));
+    }
+
+    #[test]
+    fn test_shrink_table_gpt() {
+        let mut table = PartitionTable::new(DiskId::random_gpt(), NumBlocks::from_raw(0));
+        table.partitions = vec![
+            partition(1, 2048, 2048, gpt_types::LINUX),
+            partition(2, 4096, 100_000, gpt_types::LINUX),
+        ];
+        assert!(shrink_table(&mut table, 1, NumBlocks::from_raw(4096)).unwrap());
+        assert_eq!(table.partitions[1].size, NumBlocks::from_raw(4096));
+        // The aligned end of the partition is followed by space for the backup GPT.
+        assert_eq!(table.disk_size, NumBlocks::from_raw(8192 + 2048));
+        // Partitions are never grown.
+        assert!(!shrink_table(&mut table, 1, NumBlocks::from_raw(8192)).unwrap());
+        assert_eq!(table.partitions[1].size, NumBlocks::from_raw(4096));
+    }
+
+    #[test]
+    fn test_shrink_table_last_partition() {
+        let mut table = PartitionTable::new(DiskId::random_gpt(), NumBlocks::from_raw(0));
+        table.partitions = vec![
+            partition(1, 2048, 100_000, gpt_types::LINUX),
+            partition(2, 102_400, 2048, gpt_types::LINUX),
+        ];
+        assert!(shrink_table(&mut table, 0, NumBlocks::from_raw(4096)).is_err());
+        assert_eq!(table.partitions[0].size, NumBlocks::from_raw(100_000));
+    }
+
+    #[test]
+    fn test_f2fs_field() {
+        let info = indoc::indoc! {"
+            user_block_count                        [0x    3e00 : 15872]
+            valid_block_count                       [0x     1f0 : 496]
+            valid_block_count_extra                 [0x       1 : 1]
+        "};
+        assert_eq!(f2fs_field(info, "user_block_count").unwrap(), 15872);
+        assert_eq!(f2fs_field(info, "valid_block_count").unwrap(), 496);
+        assert!(f2fs_field(info, "free_segment_count").is_err());
+    }
+
+    #[test]
+    fn test_btrfs_field() {
+        let info = indoc::indoc! {"
+            total_bytes\t\t\t268435456
+            bytes_used\t\t\t5226496
+            bytes_used_extra\t\t\t1
+        "};
+        assert_eq!(btrfs_field(info, "total_bytes").unwrap(), 268435456);
+        assert_eq!(btrfs_field(info, "bytes_used").unwrap(), 5226496);
+        assert!(btrfs_field(info, "sectorsize").is_err());
+    }
+
+    #[test]
+    fn test_boot_slot_system_partitions() {
+        let layout = targets::get_default_layout(
+            &Target::RpiTryboot,
+            2,
+            2,
+            2,
+            None,
+            targets::PartitionSizes::default(),
+            Filesystem::Ext4,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(boot_roots(&layout), ["boot", "boot-b"]);
+        let partitions = layout.partitions.as_ref().unwrap();
+        let system_a = &partitions[system_partition_index(&layout, 0).unwrap()];
+        let system_b = &partitions[system_partition_index(&layout, 1).unwrap()];
+        assert_eq!(filesystem_label(system_a), Some("system-a"));
+        assert_eq!(filesystem_label(system_b), Some("system-b"));
+        assert!(system_partition_index(&layout, 2).is_err());
+    }
+
+    #[test]
+    fn test_rpi_gpt_bootstrapping_config() {
+        let layout = targets::get_default_layout(
+            &Target::RpiTryboot,
+            2,
+            1,
+            1,
+            Some(images::PartitionTableType::Gpt),
+            targets::PartitionSizes::default(),
+            Filesystem::Ext4,
+        )
+        .unwrap()
+        .unwrap();
+        let config = rpi_gpt_bootstrapping_config(&layout, 2, byte_calc::NumBytes::gibibytes(4));
+        let basic_data = gpt_types::BASIC_DATA;
+        let linux = gpt_types::LINUX;
+        assert_eq!(
+            config,
+            format!(
+                "[layout]\n\
+                type = \"gpt\"\n\
+                partitions = [\n    \
+                    {{ size = \"268435456\", type = \"{basic_data}\" }},\n    \
+                    {{ name = \"boot-a\", size = \"134217728\", type = \"{basic_data}\" }},\n    \
+                    {{ size = \"134217728\", type = \"{basic_data}\" }},\n    \
+                    {{ name = \"system-a\", size = \"4294967296\", type = \"{linux}\" }},\n    \
+                    {{ name = \"system-b\", size = \"4294967296\", type = \"{linux}\" }},\n    \
+                    {{ name = \"data\", filesystem = {{ type = \"Ext4\", label = \"data\" }} }},\n\
+                ]\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_compute_fs_size_free_space() {
+        let root = tempfile::tempdir().unwrap();
+        let free_space = byte_calc::NumBytes::new(100 * 1024 * 1024);
+        let size = |filesystem| {
+            let size = compute_fs_size(root.path().to_path_buf(), filesystem, Some(free_space));
+            size.unwrap().into_raw() * BLOCK_SIZE.into_raw()
+        };
+        // The free space, the overhead, and the reserved blocks must fit.
+        assert!(size(Filesystem::Ext4) >= free_space.raw * 120 / 95);
+        assert!(size(Filesystem::F2fs) >= free_space.raw * 135 / 100);
+        assert!(size(Filesystem::Btrfs) >= free_space.raw * 130 / 100);
+    }
+}
@@ -5,15 +5,20 @@ use reportify::{bail, ResultExt};
 use rugix_common::boot::grub::grub_write_defaults;
 
 use crate::config::systems::{Architecture, SystemConfig};
+use crate::oven::targets::asset_path;
 use crate::BakeryResult;
 
-pub fn initialize_grub<'cx>(config: &SystemConfig, config_dir: &Path) -> BakeryResult<()> {
+pub fn initialize_grub<'cx>(
+    config: &SystemConfig,
+    assets_dir: &Path,
+    config_dir: &Path,
+) -> BakeryResult<()> {
     rugix_fs::create_dir_recursive(&config_dir.join("EFI/BOOT")).ok();
     rugix_fs::create_dir_recursive(&config_dir.join("rugpi")).ok();
     let mut copier = rugix_fs::Copier::new();
     copier
         .copy_file(
-            "/usr/share/rugix/boot/grub/cfg/first.grub.cfg".as_ref(),
+            &asset_path(assets_dir, "boot/grub/cfg/first.grub.cfg")?,
             &config_dir.join("rugpi/grub.cfg"),
         )
         .whatever("unable to copy first stage boot script")?;
@@ -22,7 +27,7 @@ pub fn initialize_grub<'cx>(config: &SystemConfig, config_dir: &Path) -> BakeryR
         Architecture::Arm64 => {
             copier
                 .copy_file(
-                    "/usr/share/rugix/boot/grub/bin/BOOTAA64.efi".as_ref(),
+                    &asset_path(assets_dir, "boot/grub/bin/BOOTAA64.efi")?,
                     &config_dir.join("EFI/BOOT/BOOTAA64.efi"),
                 )
                 .whatever("unable to copy Grub binary")?;
@@ -30,7 +35,7 @@ pub fn initialize_grub<'cx>(config: &SystemConfig, config_dir: &Path) -> BakeryR
         Architecture::Amd64 => {
             copier
                 .copy_file(
-                    "/usr/share/rugix/boot/grub/bin/BOOTX64.efi".as_ref(),
+                    &asset_path(assets_dir, "boot/grub/bin/BOOTX64.efi")?,
                     &config_dir.join("EFI/BOOT/BOOTX64.efi"),
                 )
                 .whatever("unable to copy Grub binary")?;
@@ -1,79 +1,504 @@
+use std::path::{Path, PathBuf};
+
 use byte_calc::NumBytes;
 
+use reportify::bail;
 use rugix_common::disk::gpt::gpt_types;
 use rugix_common::disk::mbr::mbr_types;
 
 use crate::config::images::{Filesystem, ImageLayout, ImagePartition, PartitionTableType};
 use crate::config::systems::Target;
+use crate::BakeryResult;
 
 pub mod generic_grub_efi;
 pub mod rpi_tryboot;
 pub mod rpi_uboot;
 
-/// Get the default image layout for the provided target.
-pub fn get_default_layout(target: &Target) -> Option<ImageLayout> {
-    match target {
-        Target::GenericGrubEfi => Some(default_gpt_layout()),
-        Target::RpiTryboot => Some(default_mbr_layout()),
-        Target::RpiUboot => Some(default_mbr_layout()),
-        Target::Unknown => None,
+/// Default directory with the boot flow files and firmware.
+pub const DEFAULT_ASSETS_DIR: &str = "/usr/share/rugix";
+
+/// Resolve the path of an asset in the given assets directory.
+///
+/// Fails if the asset does not exist.
+pub fn asset_path(assets_dir: &Path, asset: &str) -> BakeryResult<PathBuf> {
+    let path = assets_dir.join(asset);
+    if !path.exists() {
+        bail!("assets directory {assets_dir:?} does not contain `{asset}`");
+    }
+    Ok(path)
+}
+
+/// Default number of boot/system slots (A/B).
+pub const DEFAULT_SLOTS: u32 = 2;
+
+/// Sizes of the partitions of the default layouts.
+///
+/// If a size is not provided, the default of the layout is used.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PartitionSizes {
+    /// Size of the config partition.
+    pub config: Option<NumBytes>,
+    /// Size of each boot partition.
+    ///
+    /// All boot partitions have the same size, such that any slot can hold the boot
+    /// files of any other slot.
+    pub boot: Option<NumBytes>,
+    /// Size of each system partition.
+    ///
+    /// If not provided, the system partition of the `A` slot is sized based on its
+    /// contents and the system partitions of further slots get the same size.
+    pub system: Option<NumBytes>,
+}
+
+/// Get the default image layout for the provided target and number of slots.
+///
+/// The first *system_slots* slots get a system partition in the image, while the system
+/// partitions of the other slots are created by Rugix Ctrl when bootstrapping. The boot
+/// partitions of the first *boot_slots* slots are populated with the boot files, while
+/// the boot partitions of the other slots are left empty. As the boot files of a slot
+/// refer to the system partition of the slot, *boot_slots* must not exceed
+/// *system_slots* unless only the `A` slot is populated. If no partition table type is
+/// provided, the default of the target is used. The system
+/// partitions are created with the given root filesystem.
+pub fn get_default_layout(
+    target: &Target,
+    slots: u32,
+    system_slots: u32,
+    boot_slots: u32,
+    table_type: Option<PartitionTableType>,
+    sizes: PartitionSizes,
+    root_fs: Filesystem,
+) -> BakeryResult<Option<ImageLayout>> {
+    if slots == 0 || slots > 26 {
+        bail!("number of slots must be between 1 and 26, got {slots}");
+    }
+    if system_slots == 0 || system_slots > slots {
+        bail!("number of system slots must be between 1 and {slots}, got {system_slots}");
+    }
+    if boot_slots == 0 || boot_slots > slots {
+        bail!("number of boot slots must be between 1 and {slots}, got {boot_slots}");
+    }
+    if boot_slots > 1 && boot_slots > system_slots {
+        bail!("number of boot slots must not exceed {system_slots} system slots, got {boot_slots}");
     }
+    if root_fs == Filesystem::Fat32 {
+        bail!("root filesystem must be `ext4`, `f2fs`, or `btrfs`");
+    }
+    for (name, size) in [
+        ("config", sizes.config),
+        ("boot", sizes.boot),
+        ("system", sizes.system),
+    ] {
+        if size.is_some_and(|size| size < NumBytes::mebibytes(1)) {
+            bail!("size of the {name} partition must be at least 1MiB");
+        }
+    }
+    Ok(match (target, table_type) {
+        (Target::GenericGrubEfi, None | Some(PartitionTableType::Gpt)) => Some(default_gpt_layout(
+            slots,
+            system_slots,
+            boot_slots,
+            sizes,
+            root_fs,
+        )),
+        (Target::GenericGrubEfi, Some(PartitionTableType::Mbr)) => {
+            bail!("target `generic-grub-efi` requires a GPT partition table")
+        }
+        (Target::RpiTryboot | Target::RpiUboot, None | Some(PartitionTableType::Mbr)) => Some(
+            default_mbr_layout(slots, system_slots, boot_slots, sizes, root_fs),
+        ),
+        (Target::RpiTryboot | Target::RpiUboot, Some(PartitionTableType::Gpt)) => Some(
+            default_rpi_gpt_layout(slots, system_slots, boot_slots, sizes, root_fs),
+        ),
+        (Target::Unknown, _) => None,
+    })
 }
 
-fn default_mbr_layout() -> ImageLayout {
+/// Name of the slot with the given index (`a`, `b`, `c`, ...).
+pub fn slot_name(index: u32) -> char {
+    char::from(b'a' + index as u8)
+}
+
+fn default_mbr_layout(
+    slots: u32,
+    system_slots: u32,
+    boot_slots: u32,
+    sizes: PartitionSizes,
+    root_fs: Filesystem,
+) -> ImageLayout {
+    // Config partition.
+    let mut partitions = vec![ImagePartition::new()
+        .with_size(Some(sizes.config.unwrap_or(NumBytes::mebibytes(256))))
+        .with_ty(Some(mbr_types::FAT32_LBA))
+        .with_filesystem(Some(Filesystem::Fat32))
+        .with_root(Some("config".to_owned()))];
+    for slot in 0..slots {
+        // MBR allows only four primary partitions, so further boot partitions become
+        // logical partitions in the extended partition.
+        if slot == 2 {
+            partitions.push(ImagePartition::new().with_ty(Some(mbr_types::EXTENDED)));
+        }
+        partitions.push(
+            boot_partition(slot, boot_slots, Filesystem::Fat32)
+                .with_size(Some(sizes.boot.unwrap_or(NumBytes::mebibytes(128))))
+                .with_ty(Some(mbr_types::FAT32_LBA)),
+        );
+    }
+    if slots <= 2 {
+        // MBR extended partition.
+        partitions.push(ImagePartition::new().with_ty(Some(mbr_types::EXTENDED)));
+    }
+    for slot in 0..system_slots {
+        partitions.push(system_partition(slot, sizes, root_fs).with_ty(Some(mbr_types::LINUX)));
+    }
     ImageLayout::new()
         .with_ty(Some(PartitionTableType::Mbr))
-        .with_partitions(Some(vec![
-            // Config partition.
-            ImagePartition::new()
-                .with_size(Some(NumBytes::mebibytes(256)))
-                .with_ty(Some(mbr_types::FAT32_LBA))
-                .with_filesystem(Some(Filesystem::Fat32))
-                .with_root(Some("config".to_owned())),
-            // `A` boot partition.
-            ImagePartition::new()
-                .with_size(Some(NumBytes::mebibytes(128)))
-                .with_ty(Some(mbr_types::FAT32_LBA))
-                .with_filesystem(Some(Filesystem::Fat32))
-                .with_root(Some("boot".to_owned())),
-            // `B` boot partition.
-            ImagePartition::new()
-                .with_size(Some(NumBytes::mebibytes(128)))
-                .with_ty(Some(mbr_types::FAT32_LBA)),
-            // MBR extended partition.
-            ImagePartition::new().with_ty(Some(mbr_types::EXTENDED)),
-            // `A` system partition.
-            ImagePartition::new()
-                .with_ty(Some(mbr_types::LINUX))
-                .with_filesystem(Some(Filesystem::Ext4))
-                .with_root(Some("system".to_owned())),
-        ]))
+        .with_partitions(Some(partitions))
 }
 
-fn default_gpt_layout() -> ImageLayout {
+fn default_gpt_layout(
+    slots: u32,
+    system_slots: u32,
+    boot_slots: u32,
+    sizes: PartitionSizes,
+    root_fs: Filesystem,
+) -> ImageLayout {
+    // Config partition.
+    let mut partitions = vec![ImagePartition::new()
+        .with_size(Some(sizes.config.unwrap_or(NumBytes::mebibytes(256))))
+        .with_ty(Some(gpt_types::EFI))
+        .with_filesystem(Some(Filesystem::Fat32))
+        .with_root(Some("config".to_owned()))];
+    for slot in 0..slots {
+        partitions.push(
+            boot_partition(slot, boot_slots, Filesystem::Ext4)
+                .with_size(Some(sizes.boot.unwrap_or(NumBytes::mebibytes(256))))
+                .with_ty(Some(gpt_types::LINUX)),
+        );
+    }
+    for slot in 0..system_slots {
+        partitions.push(system_partition(slot, sizes, root_fs).with_ty(Some(gpt_types::LINUX)));
+    }
     ImageLayout::new()
         .with_ty(Some(PartitionTableType::Gpt))
-        .with_partitions(Some(vec![
-            // Config partition.
-            ImagePartition::new()
-                .with_size(Some(NumBytes::mebibytes(256)))
-                .with_ty(Some(gpt_types::EFI))
-                .with_filesystem(Some(Filesystem::Fat32))
-                .with_root(Some("config".to_owned())),
-            // `A` boot partition.
-            ImagePartition::new()
-                .with_size(Some(NumBytes::mebibytes(256)))
-                .with_ty(Some(gpt_types::LINUX))
-                .with_filesystem(Some(Filesystem::Ext4))
-                .with_root(Some("boot".to_owned())),
-            // `B` boot partition.
-            ImagePartition::new()
-                .with_size(Some(NumBytes::mebibytes(256)))
-                .with_ty(Some(gpt_types::LINUX)),
-            // `A` system partition.
-            ImagePartition::new()
-                .with_ty(Some(gpt_types::LINUX))
-                .with_filesystem(Some(Filesystem::Ext4))
-                .with_root(Some("system".to_owned())),
-        ]))
+        .with_partitions(Some(partitions))
+}
+
+/// GPT layout for Raspberry Pi targets.
+///
+/// Uses the same partitions as the MBR layout, except for the extended partition.
+fn default_rpi_gpt_layout(
+    slots: u32,
+    system_slots: u32,
+    boot_slots: u32,
+    sizes: PartitionSizes,
+    root_fs: Filesystem,
+) -> ImageLayout {
+    // Config partition.
+    let mut partitions = vec![ImagePartition::new()
+        .with_size(Some(sizes.config.unwrap_or(NumBytes::mebibytes(256))))
+        .with_ty(Some(gpt_types::BASIC_DATA))
+        .with_filesystem(Some(Filesystem::Fat32))
+        .with_root(Some("config".to_owned()))];
+    for slot in 0..slots {
+        partitions.push(
+            boot_partition(slot, boot_slots, Filesystem::Fat32)
+                .with_size(Some(sizes.boot.unwrap_or(NumBytes::mebibytes(128))))
+                .with_ty(Some(gpt_types::BASIC_DATA)),
+        );
+    }
+    for slot in 0..system_slots {
+        partitions.push(system_partition(slot, sizes, root_fs).with_ty(Some(gpt_types::LINUX)));
+    }
+    ImageLayout::new()
+        .with_ty(Some(PartitionTableType::Gpt))
+        .with_partitions(Some(partitions))
+}
+
+/// Boot partition of the given slot.
+///
+/// Only the boot partitions of the first *boot_slots* slots are populated, the other
+/// boot partitions are left empty and are written by updates.
+fn boot_partition(slot: u32, boot_slots: u32, filesystem: Filesystem) -> ImagePartition {
+    if slot >= boot_slots {
+        return ImagePartition::new();
+    }
+    let label = format!("boot-{}", slot_name(slot));
+    let label = match filesystem {
+        // FAT labels are conventionally uppercase.
+        Filesystem::Fat32 => label.to_uppercase(),
+        _ => label,
+    };
+    ImagePartition::new()
+        .with_filesystem(Some(filesystem))
+        .with_root(Some(boot_root(slot)))
+        .with_mkfs_options(Some(vec![filesystem.label_option().to_owned(), label]))
+}
+
+/// Root directory of the boot partition of the given slot.
+///
+/// The boot partition of the `A` slot is populated from `boot`, the boot partitions of
+/// further slots from copies of it, e.g., `boot-b`, whose boot configuration refers to
+/// the system partition of the respective slot.
+pub fn boot_root(slot: u32) -> String {
+    if slot == 0 {
+        "boot".to_owned()
+    } else {
+        format!("boot-{}", slot_name(slot))
+    }
+}
+
+/// Indicates whether the given root directory is the root of a boot partition.
+pub fn is_boot_root(root: &str) -> bool {
+    root == "boot" || root.starts_with("boot-")
+}
+
+/// System partition of the given slot.
+///
+/// Only the system partition of the `A` slot is populated, the system partitions of the
+/// other slots are left empty and are written by updates.
+fn system_partition(slot: u32, sizes: PartitionSizes, root_fs: Filesystem) -> ImagePartition {
+    let partition = ImagePartition::new()
+        .with_size(sizes.system)
+        .with_filesystem(Some(root_fs))
+        .with_mkfs_options(Some(vec![
+            root_fs.label_option().to_owned(),
+            format!("system-{}", slot_name(slot)),
+        ]));
+    if slot == 0 {
+        partition.with_root(Some("system".to_owned()))
+    } else {
+        partition
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rpi_gpt_layout() {
+        let mbr = get_default_layout(
+            &Target::RpiTryboot,
+            DEFAULT_SLOTS,
+            1,
+            1,
+            None,
+            PartitionSizes::default(),
+            Filesystem::Ext4,
+        )
+        .unwrap()
+        .unwrap();
+        let gpt = get_default_layout(
+            &Target::RpiTryboot,
+            DEFAULT_SLOTS,
+            1,
+            1,
+            Some(PartitionTableType::Gpt),
+            PartitionSizes::default(),
+            Filesystem::Ext4,
+        )
+        .unwrap()
+        .unwrap();
+        // The GPT layout has the same partitions except for the extended partition.
+        let mbr_partitions = mbr
+            .partitions
+            .unwrap()
+            .into_iter()
+            .filter(|partition| partition.ty != Some(mbr_types::EXTENDED))
+            .collect::<Vec<_>>();
+        let gpt_partitions = gpt.partitions.unwrap();
+        assert_eq!(mbr_partitions.len(), gpt_partitions.len());
+        for (mbr_partition, gpt_partition) in mbr_partitions.iter().zip(&gpt_partitions) {
+            assert_eq!(mbr_partition.size, gpt_partition.size);
+            assert_eq!(mbr_partition.root, gpt_partition.root);
+            assert_eq!(mbr_partition.mkfs_options, gpt_partition.mkfs_options);
+        }
+        assert!(get_default_layout(
+            &Target::GenericGrubEfi,
+            DEFAULT_SLOTS,
+            1,
+            1,
+            Some(PartitionTableType::Mbr),
+            PartitionSizes::default(),
+            Filesystem::Ext4,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_partition_sizes() {
+        let sizes = PartitionSizes {
+            config: Some(NumBytes::mebibytes(64)),
+            boot: Some(NumBytes::mebibytes(512)),
+            system: None,
+        };
+        let layout =
+            get_default_layout(&Target::RpiTryboot, 3, 1, 1, None, sizes, Filesystem::Ext4)
+                .unwrap()
+                .unwrap();
+        let partitions = layout.partitions.unwrap();
+        let sized = partitions
+            .iter()
+            .filter_map(|partition| partition.size)
+            .collect::<Vec<_>>();
+        let config = NumBytes::mebibytes(64);
+        let boot = NumBytes::mebibytes(512);
+        assert_eq!(sized, [config, boot, boot, boot]);
+        let too_small = PartitionSizes {
+            boot: Some(NumBytes::kibibytes(512)),
+            ..PartitionSizes::default()
+        };
+        assert!(get_default_layout(
+            &Target::RpiTryboot,
+            DEFAULT_SLOTS,
+            1,
+            1,
+            None,
+            too_small,
+            Filesystem::Ext4
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_root_filesystem() {
+        for target in [Target::RpiTryboot, Target::GenericGrubEfi] {
+            let layout = get_default_layout(
+                &target,
+                DEFAULT_SLOTS,
+                1,
+                1,
+                None,
+                PartitionSizes::default(),
+                Filesystem::F2fs,
+            )
+            .unwrap()
+            .unwrap();
+            let system = layout
+                .partitions
+                .unwrap()
+                .into_iter()
+                .find(|partition| partition.root.as_deref() == Some("system"))
+                .unwrap();
+            assert_eq!(system.filesystem, Some(Filesystem::F2fs));
+            assert_eq!(
+                system.mkfs_options,
+                Some(vec!["-l".to_owned(), "system-a".to_owned()])
+            );
+        }
+        assert!(get_default_layout(
+            &Target::RpiTryboot,
+            DEFAULT_SLOTS,
+            1,
+            1,
+            None,
+            PartitionSizes::default(),
+            Filesystem::Fat32,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_boot_slots() {
+        for (boot_slots, expected) in [
+            (1, [("boot", "boot-a")].as_slice()),
+            (2, &[("boot", "boot-a"), ("boot-b", "boot-b")]),
+        ] {
+            let layout = get_default_layout(
+                &Target::GenericGrubEfi,
+                DEFAULT_SLOTS,
+                DEFAULT_SLOTS,
+                boot_slots,
+                None,
+                PartitionSizes::default(),
+                Filesystem::Ext4,
+            )
+            .unwrap()
+            .unwrap();
+            let labels = layout
+                .partitions
+                .unwrap()
+                .into_iter()
+                .filter_map(|partition| {
+                    let root = partition.root.filter(|root| is_boot_root(root))?;
+                    Some((root, partition.mkfs_options.unwrap()[1].clone()))
+                })
+                .collect::<Vec<_>>();
+            let expected = expected
+                .iter()
+                .map(|(root, label)| (root.to_string(), label.to_string()))
+                .collect::<Vec<_>>();
+            assert_eq!(labels, expected);
+        }
+        // The boot files of the `B` slot would refer to a missing system partition.
+        assert!(get_default_layout(
+            &Target::GenericGrubEfi,
+            DEFAULT_SLOTS,
+            1,
+            2,
+            None,
+            PartitionSizes::default(),
+            Filesystem::Ext4,
+        )
+        .is_err());
+        assert!(get_default_layout(
+            &Target::RpiTryboot,
+            DEFAULT_SLOTS,
+            1,
+            3,
+            None,
+            PartitionSizes::default(),
+            Filesystem::Ext4,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_system_slots() {
+        for target in [Target::RpiTryboot, Target::GenericGrubEfi] {
+            let layout = get_default_layout(
+                &target,
+                3,
+                3,
+                1,
+                None,
+                PartitionSizes::default(),
+                Filesystem::Ext4,
+            )
+            .unwrap()
+            .unwrap();
+            let systems = layout
+                .partitions
+                .unwrap()
+                .into_iter()
+                .filter(|partition| {
+                    partition
+                        .mkfs_options
+                        .as_ref()
+                        .is_some_and(|options| options[1].starts_with("system-"))
+                })
+                .collect::<Vec<_>>();
+            let labels = systems
+                .iter()
+                .map(|partition| partition.mkfs_options.as_ref().unwrap()[1].as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(labels, ["system-a", "system-b", "system-c"]);
+            // Only the system partition of the `A` slot is populated.
+            assert_eq!(systems[0].root.as_deref(), Some("system"));
+            assert!(systems[1..]
+                .iter()
+                .all(|partition| partition.root.is_none()));
+        }
+        assert!(get_default_layout(
+            &Target::RpiTryboot,
+            DEFAULT_SLOTS,
+            3,
+            1,
+            None,
+            PartitionSizes::default(),
+            Filesystem::Ext4,
+        )
+        .is_err());
+    }
 }
@@ -4,10 +4,11 @@ use reportify::ResultExt;
 
 use rugix_common::fsutils::copy_recursive;
 
+use crate::oven::targets::asset_path;
 use crate::BakeryResult;
 
-pub fn initialize_tryboot(config_dir: &Path) -> BakeryResult<()> {
-    copy_recursive("/usr/share/rugix/boot/tryboot", &config_dir)
+pub fn initialize_tryboot(assets_dir: &Path, config_dir: &Path) -> BakeryResult<()> {
+    copy_recursive(asset_path(assets_dir, "boot/tryboot")?, &config_dir)
         .whatever("unable to initialize tryboot")?;
     Ok(())
 }
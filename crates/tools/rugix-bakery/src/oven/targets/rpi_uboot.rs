@@ -6,33 +6,41 @@ use rugix_common::boot::uboot::UBootEnv;
 use rugix_common::fsutils::copy_recursive;
 
 use crate::config::systems::{Architecture, SystemConfig};
+use crate::oven::targets::asset_path;
 use crate::BakeryResult;
 
-pub fn initialize_uboot(config: &SystemConfig, config_dir: &Path) -> BakeryResult<()> {
-    copy_recursive("/usr/share/rugix/pi/firmware", &config_dir)
+pub fn initialize_uboot(
+    config: &SystemConfig,
+    assets_dir: &Path,
+    config_dir: &Path,
+) -> BakeryResult<()> {
+    copy_recursive(asset_path(assets_dir, "pi/firmware")?, &config_dir)
         .whatever("unable to copy RPi firmware")?;
     match config.architecture {
         Architecture::Arm64 => {
             copy_recursive(
-                "/usr/share/rugix/boot/u-boot/arm64_config.txt",
+                asset_path(assets_dir, "boot/u-boot/arm64_config.txt")?,
                 config_dir.join("config.txt"),
             )
             .whatever("unable to copy `config.txt`")?;
             copy_recursive(
-                "/usr/share/rugix/boot/u-boot/bin/u-boot-arm64.bin",
+                asset_path(assets_dir, "boot/u-boot/bin/u-boot-arm64.bin")?,
                 config_dir.join("u-boot-arm64.bin"),
             )
             .whatever("unable to copy U-Boot binary")?;
         }
         Architecture::Armhf => {
             copy_recursive(
-                "/usr/share/rugix/boot/u-boot/armhf_config.txt",
+                asset_path(assets_dir, "boot/u-boot/armhf_config.txt")?,
                 config_dir.join("config.txt"),
             )
             .whatever("unable to copy `config.txt`")?;
             for model in ["zerow", "pi1", "pi2", "pi3"] {
                 copy_recursive(
-                    format!("/usr/share/rugix/boot/u-boot/bin/u-boot-armhf-{model}.bin"),
+                    asset_path(
+                        assets_dir,
+                        &format!("boot/u-boot/bin/u-boot-armhf-{model}.bin"),
+                    )?,
                     config_dir.join(format!("u-boot-armhf-{model}.bin")),
                 )
                 .whatever("unable to copy U-Boot binary")?;
@@ -46,7 +54,7 @@ pub fn initialize_uboot(config: &SystemConfig, config_dir: &Path) -> BakeryResul
         }
     }
     copy_recursive(
-        "/usr/share/rugix/boot/u-boot/bin/boot.scr",
+        asset_path(assets_dir, "boot/u-boot/bin/boot.scr")?,
         config_dir.join("boot.scr"),
     )
     .whatever("unable to copy first stage boot script")?;
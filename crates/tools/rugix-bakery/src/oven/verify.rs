@@ -0,0 +1,252 @@
+//! Verification of images against the layout of their system.
+
+use std::fmt;
+use std::path::Path;
+
+use reportify::{bail, ResultExt};
+use rugix_common::disk::PartitionTable;
+use rugix_common::loop_dev::LoopDevice;
+use tracing::{error, info};
+use xscript::{read_str, Run};
+
+use crate::config::images::{ImageLayout, PartitionTableType};
+use crate::config::systems::SystemConfig;
+use crate::oven::system::{filesystem_label, image_layout};
+use crate::oven::SystemOpts;
+use crate::BakeryResult;
+
+/// Difference between the expected and the actual layout of an image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// Property of the image which differs, e.g., `type of partition 2`.
+    pub property: String,
+    /// Expected value as given by the layout.
+    pub expected: String,
+    /// Actual value found in the image.
+    pub actual: String,
+}
+
+impl Mismatch {
+    fn new(property: String, expected: impl ToString, actual: impl ToString) -> Self {
+        Self {
+            property,
+            expected: expected.to_string(),
+            actual: actual.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:\n  - {}\n  + {}",
+            self.property, self.expected, self.actual
+        )
+    }
+}
+
+/// Verify that the given image matches the layout of the given system.
+///
+/// Fails listing all mismatches, if the image does not match.
+pub fn verify_image(config: &SystemConfig, image: &Path, opts: &SystemOpts) -> BakeryResult<()> {
+    let layout = image_layout(config, opts)?;
+    let table = PartitionTable::read(image).whatever("unable to read partition table")?;
+    let mut mismatches = compare_table(&layout, &table);
+    // Labels can only be compared if the partitions correspond to the layout.
+    if mismatches.is_empty() {
+        info!("Reading filesystem labels.");
+        let loop_dev =
+            LoopDevice::attach(image).whatever("unable to attach image to loop device")?;
+        let labels = table
+            .partitions
+            .iter()
+            .map(|partition| {
+                read_str!([
+                    "blkid",
+                    "-o",
+                    "value",
+                    "-s",
+                    "LABEL",
+                    loop_dev.partition(partition.number as usize)
+                ])
+                .ok()
+                .map(|label| label.trim().to_owned())
+                .filter(|label| !label.is_empty())
+            })
+            .collect::<Vec<_>>();
+        mismatches.extend(compare_labels(&layout, &table, &labels));
+    }
+    if !mismatches.is_empty() {
+        for mismatch in &mismatches {
+            error!("{mismatch}");
+        }
+        bail!(
+            "image does not match the layout of the system ({} mismatches)",
+            mismatches.len()
+        );
+    }
+    info!("Image matches the layout of the system.");
+    Ok(())
+}
+
+/// Compare the partition table of an image with the given layout.
+fn compare_table(layout: &ImageLayout, table: &PartitionTable) -> Vec<Mismatch> {
+    let mut mismatches = Vec::new();
+    if let Some(ty) = layout.ty {
+        let expected = match ty {
+            PartitionTableType::Mbr => "mbr",
+            PartitionTableType::Gpt => "gpt",
+        };
+        let actual = table.ty().to_string();
+        if actual != expected {
+            mismatches.push(Mismatch::new(
+                "partition table type".to_owned(),
+                expected,
+                actual,
+            ));
+        }
+    }
+    let layout_partitions = layout.partitions.as_deref().unwrap_or_default();
+    if layout_partitions.len() != table.partitions.len() {
+        mismatches.push(Mismatch::new(
+            "number of partitions".to_owned(),
+            layout_partitions.len(),
+            table.partitions.len(),
+        ));
+    }
+    for (layout_partition, image_partition) in layout_partitions.iter().zip(&table.partitions) {
+        let number = image_partition.number;
+        if let Some(ty) = layout_partition.ty {
+            if ty != image_partition.ty {
+                mismatches.push(Mismatch::new(
+                    format!("type of partition {number}"),
+                    ty,
+                    image_partition.ty,
+                ));
+            }
+        }
+        if let Some(size) = layout_partition.size {
+            let expected = table.bytes_to_blocks(size.raw.into());
+            if expected != image_partition.size {
+                mismatches.push(Mismatch::new(
+                    format!("size of partition {number}"),
+                    format!("{} bytes", table.blocks_to_bytes(expected).into_raw()),
+                    format!(
+                        "{} bytes",
+                        table.blocks_to_bytes(image_partition.size).into_raw()
+                    ),
+                ));
+            }
+        }
+    }
+    mismatches
+}
+
+/// Compare the filesystem labels of the partitions of an image with the given layout.
+fn compare_labels(
+    layout: &ImageLayout,
+    table: &PartitionTable,
+    labels: &[Option<String>],
+) -> Vec<Mismatch> {
+    let mut mismatches = Vec::new();
+    for ((layout_partition, image_partition), label) in layout
+        .partitions
+        .iter()
+        .flatten()
+        .zip(&table.partitions)
+        .zip(labels)
+    {
+        let Some(expected) = filesystem_label(layout_partition) else {
+            continue;
+        };
+        if label.as_deref() != Some(expected) {
+            mismatches.push(Mismatch::new(
+                format!("label of partition {}", image_partition.number),
+                expected,
+                label.as_deref().unwrap_or("<none>"),
+            ));
+        }
+    }
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use rugix_common::disk::mbr::mbr_types;
+    use rugix_common::disk::{DiskId, NumBlocks, Partition, PartitionTable};
+
+    use super::{compare_labels, compare_table};
+    use crate::config::images::{Filesystem, ImageLayout};
+    use crate::config::systems::Target;
+    use crate::oven::targets::{get_default_layout, PartitionSizes, DEFAULT_SLOTS};
+
+    /// Partition table matching the given layout.
+    fn table_for(layout: &ImageLayout) -> PartitionTable {
+        let mut table = PartitionTable::new(DiskId::random_mbr(), NumBlocks::from_raw(1 << 24));
+        let mut start = NumBlocks::from_raw(2048);
+        for (idx, partition) in layout.partitions.iter().flatten().enumerate() {
+            let size = match partition.size {
+                Some(size) => table.bytes_to_blocks(size.raw.into()),
+                None => NumBlocks::from_raw(2048),
+            };
+            table.partitions.push(Partition {
+                number: idx as u8 + 1,
+                start,
+                size,
+                ty: partition.ty.unwrap(),
+                name: None,
+                gpt_id: None,
+            });
+            start = start + size;
+        }
+        table
+    }
+
+    #[test]
+    fn test_compare_layout() {
+        let layout = get_default_layout(
+            &Target::RpiTryboot,
+            DEFAULT_SLOTS,
+            1,
+            1,
+            None,
+            PartitionSizes::default(),
+            Filesystem::Ext4,
+        )
+        .unwrap()
+        .unwrap();
+        let table = table_for(&layout);
+        assert!(compare_table(&layout, &table).is_empty());
+
+        let mut wrong_type = table.clone();
+        wrong_type.partitions[1].ty = mbr_types::LINUX;
+        let mismatches = compare_table(&layout, &wrong_type);
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].property, "type of partition 2");
+
+        let mut wrong_size = table.clone();
+        wrong_size.partitions[2].size = NumBlocks::from_raw(2048);
+        let mismatches = compare_table(&layout, &wrong_size);
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].property, "size of partition 3");
+
+        let mut missing = table.clone();
+        missing.partitions.pop();
+        let mismatches = compare_table(&layout, &missing);
+        assert_eq!(mismatches[0].property, "number of partitions");
+
+        let labels = [
+            None,
+            Some("BOOT-A".to_owned()),
+            None,
+            None,
+            Some("system-a".to_owned()),
+        ];
+        assert!(compare_labels(&layout, &table, &labels).is_empty());
+        let labels = [None, Some("BOOT-A".to_owned()), None, None, None];
+        let mismatches = compare_labels(&layout, &table, &labels);
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].actual, "<none>");
+    }
+}
@@ -1,18 +1,22 @@
 use std::collections::HashMap;
 
+use serde::{Deserialize, Serialize};
+
 use crate::config::layers::LayerConfig;
 use crate::config::systems::Architecture;
+use crate::config::ArchitectureFamily;
 use crate::utils::caching::ModificationTime;
 
 use super::repositories::RepositoryIdx;
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Layer {
     pub name: String,
     pub repo: RepositoryIdx,
     pub modified: ModificationTime,
     pub default_config: Option<LayerConfig>,
     pub arch_configs: HashMap<Architecture, LayerConfig>,
+    pub family_configs: HashMap<ArchitectureFamily, LayerConfig>,
 }
 
 impl Layer {
@@ -23,13 +27,21 @@ impl Layer {
             modified,
             default_config: None,
             arch_configs: HashMap::new(),
+            family_configs: HashMap::new(),
         }
     }
 
     /// The layer configuration for the given architecture.
+    ///
+    /// Resolves the configuration by walking the fallback chain of the architecture:
+    /// exact architecture, architecture family, and, finally, the default configuration.
     pub fn config(&self, arch: Architecture) -> Option<&LayerConfig> {
         self.arch_configs
             .get(&arch)
+            .or_else(|| {
+                arch.family()
+                    .and_then(|family| self.family_configs.get(&family))
+            })
             .or(self.default_config.as_ref())
     }
 }
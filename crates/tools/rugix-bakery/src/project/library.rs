@@ -1,22 +1,26 @@
 use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fs;
+use std::io;
 use std::ops::Deref;
 use std::path::Path;
 use std::str::FromStr;
 use std::sync::Arc;
 
-use reportify::{whatever, ResultExt};
+use reportify::{bail, whatever, ResultExt};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
 
-use crate::config::load_config;
+use crate::config::load_config_with;
 use crate::config::systems::Architecture;
-use crate::utils::caching::mtime;
+use crate::config::ArchitectureFamily;
+use crate::utils::caching::{mtime, mtime_recursive, ModificationTime};
 use crate::utils::idx_vec::{new_idx_type, IdxVec};
 use crate::BakeryResult;
 
 use super::layers::Layer;
 use super::recipes::{Recipe, RecipeLoader};
-use super::repositories::{ProjectRepositories, RepositoryIdx};
+use super::repositories::{ProjectRepositories, Repository, RepositoryIdx};
 
 #[derive(Debug)]
 pub struct Library {
@@ -28,116 +32,176 @@ pub struct Library {
 }
 
 impl Library {
-    #[allow(clippy::assigning_clones)]
-    pub fn load(repositories: Arc<ProjectRepositories>) -> BakeryResult<Self> {
+    /// Load the recipes and layers of all repositories.
+    ///
+    /// If *validate_schema* is set, unknown configuration keys are rejected.
+    pub fn load(
+        repositories: Arc<ProjectRepositories>,
+        validate_schema: bool,
+    ) -> BakeryResult<Self> {
+        Self::load_from(repositories, None, validate_schema)
+    }
+
+    /// Load the recipes and layers of all repositories using the given cache directory.
+    ///
+    /// The contents of repositories which did not change since they have been cached
+    /// are loaded from the cache instead of parsing them again.
+    pub fn load_with_cache(
+        repositories: Arc<ProjectRepositories>,
+        cache_dir: &Path,
+    ) -> BakeryResult<Self> {
+        Self::load_from(repositories, Some(cache_dir), false)
+    }
+
+    fn load_from(
+        repositories: Arc<ProjectRepositories>,
+        cache_dir: Option<&Path>,
+        validate_schema: bool,
+    ) -> BakeryResult<Self> {
         let mut recipes = IdxVec::new();
-        let mut tables = IdxVec::<RepositoryIdx, _>::new();
-        for (idx, repository) in repositories.iter() {
-            let mut table = HashMap::new();
-            let loader = RecipeLoader::new(idx).with_default(idx == repositories.root_repository);
-            let recipes_dir = repository.source.dir.join("recipes");
-            if recipes_dir.is_dir() {
-                for entry in fs::read_dir(repository.source.dir.join("recipes"))
-                    .whatever("error reading recipes from directory")?
-                {
-                    let entry = entry.whatever("error reading recipe directory entry")?;
-                    let path = entry.path();
-                    if !path.is_dir() || should_ignore_path(&path) {
-                        continue;
-                    }
-                    let recipe = loader.load(&entry.path())?;
-                    let recipe_idx = recipes.push(Arc::new(recipe));
-                    table.insert(recipes[recipe_idx].name.deref().to_owned(), recipe_idx);
-                }
-            }
-            tables.push(table);
-        }
+        let mut recipe_tables = IdxVec::<RepositoryIdx, _>::new();
         let mut layers = IdxVec::new();
         let mut layer_tables = IdxVec::<RepositoryIdx, _>::new();
         for (idx, repository) in repositories.iter() {
-            let mut table = HashMap::new();
-            let layers_dir = repository.source.dir.join("layers");
-            if !layers_dir.exists() {
-                layer_tables.push(table);
-                continue;
-            }
-            for entry in
-                fs::read_dir(layers_dir).whatever("unable to read layers from directory")?
-            {
-                let entry = entry.whatever("unable to read layer directory entry")?;
-                let path = entry.path();
-                if !path.is_file() || should_ignore_path(&path) {
-                    continue;
-                }
-                if path.extension() != Some(OsStr::new("toml")) {
-                    continue;
-                }
-                let mut name = path.file_stem().unwrap().to_string_lossy().into_owned();
-                let mut arch = None;
-                if let Some((layer_name, arch_str)) = name.split_once('.') {
-                    arch = Some(
-                        Architecture::from_str(arch_str)
-                            .whatever("unable to parse architecture")?,
-                    );
-                    name = layer_name.to_owned();
-                }
-                let modified = mtime(&path).whatever("unable to obtain layer mtime")?;
-                let layer_config = load_config(&path)?;
-                let layer_idx = *table
-                    .entry(name.clone())
-                    .or_insert_with(|| layers.push(Layer::new(name, idx, modified)));
-                layers[layer_idx].modified = layers[layer_idx].modified.max(modified);
-                match arch {
-                    Some(arch) => {
-                        layers[layer_idx].arch_configs.insert(arch, layer_config);
-                    }
-                    None => {
-                        layers[layer_idx].default_config = Some(layer_config);
-                    }
+            let is_root = idx == repositories.root_repository;
+            let contents = match cache_dir {
+                Some(cache_dir) => {
+                    RepositoryContents::load_cached(idx, repository, is_root, cache_dir)?
                 }
+                None => RepositoryContents::load(idx, repository, is_root, validate_schema)?,
+            };
+            let mut recipe_table = HashMap::new();
+            for recipe in contents.recipes {
+                let name = recipe.name.deref().to_owned();
+                recipe_table.insert(name, recipes.push(Arc::new(recipe)));
             }
-            layer_tables.push(table);
+            recipe_tables.push(recipe_table);
+            let mut layer_table = HashMap::new();
+            for layer in contents.layers {
+                layer_table.insert(layer.name.clone(), layers.push(layer));
+            }
+            layer_tables.push(layer_table);
         }
         Ok(Self {
             repositories,
             recipes,
-            recipe_tables: tables,
+            recipe_tables,
             layers,
             layer_tables,
         })
     }
 
-    pub fn lookup(&self, repository: RepositoryIdx, name: &str) -> Option<RecipeIdx> {
-        if let Some((dependency_name, recipe_name)) = name.split_once('/') {
-            let dependency_idx = match dependency_name {
-                "core" => self.repositories.core_repository,
-                _ => *self.repositories.repositories[repository]
-                    .repositories
-                    .get(dependency_name)?,
+    /// Resolve the repository of the given name relative to the given repository.
+    ///
+    /// Names may be qualified with a path of repositories, e.g., `a/b/recipe`, where each
+    /// segment refers to a repository used by the repository of the previous segment. At
+    /// the first segment, `core` refers to the core repository. Returns the repository and
+    /// the unqualified name. Fails with the segment which cannot be resolved.
+    pub fn resolve_name<'n>(
+        &self,
+        repository: RepositoryIdx,
+        name: &'n str,
+    ) -> BakeryResult<(RepositoryIdx, &'n str)> {
+        let Some((path, name)) = name.rsplit_once('/') else {
+            return Ok((repository, name));
+        };
+        let mut current = repository;
+        for (idx, segment) in path.split('/').enumerate() {
+            current = match segment {
+                "core" if idx == 0 => self.repositories.core_repository,
+                _ => {
+                    let current = &self.repositories[current];
+                    match current.repositories.get(segment) {
+                        Some(dependency) => *dependency,
+                        None => bail!(
+                            "repository `{}` does not use a repository `{segment}`",
+                            current
+                                .config
+                                .name
+                                .as_deref()
+                                .unwrap_or(current.source.id.as_str())
+                        ),
+                    }
+                }
             };
-            self.recipe_tables[dependency_idx].get(recipe_name).cloned()
-        } else {
-            self.recipe_tables[repository].get(name).cloned()
         }
+        Ok((current, name))
+    }
+
+    pub fn lookup(&self, repository: RepositoryIdx, name: &str) -> Option<RecipeIdx> {
+        let (repository, name) = self.resolve_name(repository, name).ok()?;
+        self.recipe_tables[repository].get(name).cloned()
     }
 
     pub fn try_lookup(&self, repo: RepositoryIdx, name: &str) -> BakeryResult<RecipeIdx> {
-        self.lookup(repo, name)
+        let (repository, recipe) = self
+            .resolve_name(repo, name)
+            .with_info(|_| format!("unable to find recipe {name}"))?;
+        self.recipe_tables[repository]
+            .get(recipe)
+            .cloned()
             .ok_or_else(|| whatever!("unable to find recipe {name}"))
     }
 
-    pub fn lookup_layer(&self, repo: RepositoryIdx, name: &str) -> Option<LayerIdx> {
-        if let Some((dependency_name, layer_name)) = name.split_once('/') {
-            let dependency_idx = match dependency_name {
-                "core" => self.repositories.core_repository,
-                _ => *self.repositories.repositories[repo]
-                    .repositories
-                    .get(dependency_name)?,
+    /// Look up the layer with the given name relative to the given repository.
+    ///
+    /// Returns `None` if there is no such layer and fails if the repositories of a
+    /// qualified name cannot be resolved.
+    pub fn lookup_layer(&self, repo: RepositoryIdx, name: &str) -> BakeryResult<Option<LayerIdx>> {
+        let (repository, layer) = self
+            .resolve_name(repo, name)
+            .with_info(|_| format!("unable to find layer `{name}`"))?;
+        Ok(self.layer_tables[repository].get(layer).cloned())
+    }
+
+    /// Resolve the given layer and its parents for the given architecture.
+    ///
+    /// Returns the layers in the order they are baked, i.e., starting with the base
+    /// layer and ending with the given layer. Fails if a parent cannot be found, a layer
+    /// has no configuration for the architecture, or the parents form a cycle.
+    pub fn resolve_layer_chain(
+        &self,
+        layer: LayerIdx,
+        arch: Architecture,
+    ) -> BakeryResult<Vec<LayerIdx>> {
+        let mut chain = vec![layer];
+        loop {
+            let current = &self.layers[*chain.last().unwrap()];
+            let Some(config) = current.config(arch) else {
+                match chain.len() {
+                    1 => bail!(
+                        "layer `{}` has no configuration for architecture `{arch}`",
+                        current.name
+                    ),
+                    _ => bail!(
+                        "layer `{}` has no configuration for architecture `{arch}`, \
+                        which is required by layer `{}`",
+                        current.name,
+                        self.layers[chain[chain.len() - 2]].name
+                    ),
+                }
             };
-            self.layer_tables[dependency_idx].get(layer_name).cloned()
-        } else {
-            self.layer_tables[repo].get(name).cloned()
+            let Some(parent_name) = &config.parent else {
+                break;
+            };
+            let Some(parent) = self.lookup_layer(current.repo, parent_name)? else {
+                bail!(
+                    "unable to find layer `{parent_name}`, the parent of layer `{}`",
+                    current.name
+                );
+            };
+            if let Some(start) = chain.iter().position(|idx| *idx == parent) {
+                let cycle = chain[start..]
+                    .iter()
+                    .chain(std::iter::once(&parent))
+                    .map(|idx| self.layers[*idx].name.as_str())
+                    .collect::<Vec<_>>();
+                bail!("layer cycle detected: {}", cycle.join(" -> "));
+            }
+            chain.push(parent);
         }
+        chain.reverse();
+        Ok(chain)
     }
 }
 
@@ -151,6 +215,174 @@ new_idx_type! {
     pub LayerIdx
 }
 
+/// Recipes and layers of a single repository.
+#[derive(Debug, Serialize, Deserialize)]
+struct RepositoryContents {
+    /// The latest modification time of the repository's recipes and layers.
+    modified: ModificationTime,
+    /// The recipes of the repository.
+    recipes: Vec<Recipe>,
+    /// The layers of the repository.
+    layers: Vec<Layer>,
+}
+
+impl RepositoryContents {
+    /// Load the contents of the given repository.
+    fn load(
+        idx: RepositoryIdx,
+        repository: &Repository,
+        is_root: bool,
+        validate_schema: bool,
+    ) -> BakeryResult<Self> {
+        let modified = contents_mtime(&repository.source.dir)?;
+        let mut recipes = Vec::new();
+        let loader = RecipeLoader::new(idx)
+            .with_default(is_root)
+            .with_validate_schema(validate_schema);
+        let recipes_dir = repository.source.dir.join("recipes");
+        if recipes_dir.is_dir() {
+            for entry in
+                fs::read_dir(&recipes_dir).whatever("error reading recipes from directory")?
+            {
+                let entry = entry.whatever("error reading recipe directory entry")?;
+                let path = entry.path();
+                if !path.is_dir() || should_ignore_path(&path) {
+                    continue;
+                }
+                recipes.push(loader.load(&path)?);
+            }
+        }
+        let layers_dir = repository.source.dir.join("layers");
+        let layers = if layers_dir.exists() {
+            load_layers(idx, &layers_dir, validate_schema)?
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            modified,
+            recipes,
+            layers,
+        })
+    }
+
+    /// Load the contents of the given repository using the provided cache directory.
+    ///
+    /// The cache is invalidated when any recipe or layer file is newer than the cache.
+    fn load_cached(
+        idx: RepositoryIdx,
+        repository: &Repository,
+        is_root: bool,
+        cache_dir: &Path,
+    ) -> BakeryResult<Self> {
+        let cache_file = cache_dir.join(format!("{}.json", repository.source.id));
+        let modified = contents_mtime(&repository.source.dir)?;
+        if let Some(mut contents) = read_cache(&cache_file) {
+            if contents.modified == modified
+                && mtime(&cache_file).is_ok_and(|cached| modified < cached)
+            {
+                debug!(
+                    "using cached contents of repository {}",
+                    repository.source.id
+                );
+                // Indices may have changed since the contents have been cached.
+                for recipe in &mut contents.recipes {
+                    recipe.repository = idx;
+                }
+                for layer in &mut contents.layers {
+                    layer.repo = idx;
+                }
+                return Ok(contents);
+            }
+        }
+        let contents = Self::load(idx, repository, is_root, false)?;
+        let write_result = fs::create_dir_all(cache_dir).and_then(|_| {
+            fs::write(
+                &cache_file,
+                serde_json::to_vec(&contents).map_err(io::Error::other)?,
+            )
+        });
+        if let Err(error) = write_result {
+            warn!(
+                file = %cache_file.display(),
+                "Unable to write repository cache: {error}."
+            );
+        }
+        Ok(contents)
+    }
+}
+
+/// Read the cached contents of a repository, if there are any.
+/// Load the layers of a repository from the given layers directory.
+///
+/// Layer configuration files are named `<layer>.toml`, `<layer>.<arch>.toml`, or
+/// `<layer>.<family>.toml` for architecture families.
+fn load_layers(
+    idx: RepositoryIdx,
+    layers_dir: &Path,
+    validate_schema: bool,
+) -> BakeryResult<Vec<Layer>> {
+    let mut layers = Vec::<Layer>::new();
+    let mut table = HashMap::new();
+    for entry in fs::read_dir(layers_dir).whatever("unable to read layers from directory")? {
+        let entry = entry.whatever("unable to read layer directory entry")?;
+        let path = entry.path();
+        if !path.is_file() || should_ignore_path(&path) {
+            continue;
+        }
+        if path.extension() != Some(OsStr::new("toml")) {
+            continue;
+        }
+        let mut name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        let mut arch = None;
+        let mut family = None;
+        if let Some((layer_name, suffix)) = name.split_once('.') {
+            if let Ok(parsed) = ArchitectureFamily::from_str(suffix) {
+                family = Some(parsed);
+            } else {
+                arch = Some(
+                    Architecture::from_str(suffix)
+                        .whatever("unable to parse architecture")
+                        .with_info(|_| format!("layer: {path:?}"))?,
+                );
+            }
+            name = layer_name.to_owned();
+        }
+        let modified = mtime(&path).whatever("unable to obtain layer mtime")?;
+        let layer_config = load_config_with(&path, validate_schema)?;
+        let layer_idx = *table.entry(name.clone()).or_insert_with(|| {
+            layers.push(Layer::new(name, idx, modified));
+            layers.len() - 1
+        });
+        let layer = &mut layers[layer_idx];
+        layer.modified = layer.modified.max(modified);
+        if let Some(arch) = arch {
+            layer.arch_configs.insert(arch, layer_config);
+        } else if let Some(family) = family {
+            layer.family_configs.insert(family, layer_config);
+        } else {
+            layer.default_config = Some(layer_config);
+        }
+    }
+    Ok(layers)
+}
+
+fn read_cache(cache_file: &Path) -> Option<RepositoryContents> {
+    serde_json::from_slice(&fs::read(cache_file).ok()?).ok()
+}
+
+/// The latest modification time of the recipes and layers in a repository directory.
+fn contents_mtime(repository_dir: &Path) -> BakeryResult<ModificationTime> {
+    let mut modified = mtime(repository_dir).whatever("unable to obtain repository mtime")?;
+    for dir in ["recipes", "layers"] {
+        let path = repository_dir.join(dir);
+        if path.exists() {
+            modified =
+                modified.max(mtime_recursive(&path).whatever("unable to obtain repository mtime")?);
+        }
+    }
+    Ok(modified)
+}
+
 /// Indicates whether the given path should be ignored when scanning for recipes and
 /// layers.
 fn should_ignore_path(path: &Path) -> bool {
@@ -159,3 +391,167 @@ fn should_ignore_path(path: &Path) -> bool {
     };
     matches!(&*file_name.to_string_lossy(), ".DS_Store")
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::config::repositories::{PathSourceConfig, RepositoryConfig, SourceConfig};
+    use crate::project::repositories::{Source, SourceId};
+    use crate::utils::idx_vec::Idx;
+
+    /// Library without recipes and layers for the given repositories.
+    ///
+    /// Each repository is given by its name and the names and indices of the
+    /// repositories it uses. The first repository is the core repository.
+    fn empty_library(repositories: &[(&str, &[(&str, usize)])]) -> Library {
+        let mut loaded = IdxVec::new();
+        for (idx, (name, dependencies)) in repositories.iter().enumerate() {
+            loaded.push(Repository {
+                idx: RepositoryIdx::from_usize(idx),
+                source: Source {
+                    id: SourceId::from_parts(&[name.as_bytes()]),
+                    config: SourceConfig::Path(PathSourceConfig {
+                        path: (*name).to_owned(),
+                    }),
+                    dir: (*name).into(),
+                },
+                config: RepositoryConfig {
+                    name: Some((*name).to_owned()),
+                    description: None,
+                    repositories: None,
+                },
+                repositories: dependencies
+                    .iter()
+                    .map(|(name, idx)| ((*name).to_owned(), RepositoryIdx::from_usize(*idx)))
+                    .collect(),
+            });
+        }
+        let mut recipe_tables = IdxVec::new();
+        let mut layer_tables = IdxVec::new();
+        for _ in repositories {
+            recipe_tables.push(HashMap::new());
+            layer_tables.push(HashMap::new());
+        }
+        Library {
+            repositories: Arc::new(ProjectRepositories {
+                repositories: loaded,
+                root_repository: RepositoryIdx::from_usize(1),
+                core_repository: RepositoryIdx::from_usize(0),
+            }),
+            recipes: IdxVec::new(),
+            layers: IdxVec::new(),
+            recipe_tables,
+            layer_tables,
+        }
+    }
+
+    #[test]
+    fn test_resolve_name() {
+        let library = empty_library(&[
+            ("core", &[]),
+            ("root", &[("a", 2)]),
+            ("a", &[("b", 3), ("core", 3)]),
+            ("b", &[]),
+        ]);
+        let root = RepositoryIdx::from_usize(1);
+        let resolve = |name| {
+            library
+                .resolve_name(root, name)
+                .map(|(repository, _)| repository.as_usize())
+                .map_err(|error| format!("{error:?}"))
+        };
+        assert_eq!(library.resolve_name(root, "a/b/nginx").unwrap().1, "nginx");
+        assert_eq!(resolve("nginx").unwrap(), 1);
+        assert_eq!(resolve("a/nginx").unwrap(), 2);
+        assert_eq!(resolve("a/b/nginx").unwrap(), 3);
+        assert_eq!(resolve("core/nginx").unwrap(), 0);
+        // Only the first segment refers to the core repository.
+        assert_eq!(resolve("a/core/nginx").unwrap(), 3);
+        let error = resolve("a/c/nginx").unwrap_err();
+        assert!(error.contains("repository `a` does not use a repository `c`"));
+        let error = resolve("b/nginx").unwrap_err();
+        assert!(error.contains("repository `root` does not use a repository `b`"));
+        let error = library.lookup_layer(root, "a/c/base").unwrap_err();
+        assert!(format!("{error:?}").contains("unable to find layer `a/c/base`"));
+        assert_eq!(library.lookup_layer(root, "a/base").unwrap(), None);
+    }
+
+    #[test]
+    fn test_load_layers_config_selection() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let layers_dir = temp_dir.path();
+        for (file, name) in [
+            ("base.toml", "default"),
+            ("base.arm-family.toml", "arm-family"),
+            ("base.arm64.toml", "arm64"),
+        ] {
+            fs::write(layers_dir.join(file), format!("name = \"{name}\"\n")).unwrap();
+        }
+        let layers = load_layers(RepositoryIdx::from_usize(0), layers_dir, true).unwrap();
+        assert_eq!(layers.len(), 1);
+        let name = |arch| layers[0].config(arch).unwrap().name.as_deref().unwrap();
+        assert_eq!(name(Architecture::Arm64), "arm64");
+        assert_eq!(name(Architecture::Armv7), "arm-family");
+        assert_eq!(name(Architecture::Arm), "arm-family");
+        assert_eq!(name(Architecture::Amd64), "default");
+        fs::write(layers_dir.join("base.sparc.toml"), "").unwrap();
+        assert!(load_layers(RepositoryIdx::from_usize(0), layers_dir, true).is_err());
+    }
+
+    #[test]
+    fn test_resolve_layer_chain() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let layers_dir = temp_dir.path();
+        for (file, contents) in [
+            ("base.toml", "root = true\n"),
+            ("app.toml", "parent = \"base\"\n"),
+            ("customized.toml", "parent = \"app\"\n"),
+            ("orphan.toml", "parent = \"missing\"\n"),
+            ("first.toml", "parent = \"second\"\n"),
+            ("second.toml", "parent = \"first\"\n"),
+            ("native.amd64.toml", "parent = \"base\"\n"),
+            ("tool.toml", "parent = \"native\"\n"),
+        ] {
+            fs::write(layers_dir.join(file), contents).unwrap();
+        }
+        let root = RepositoryIdx::from_usize(1);
+        let mut library = empty_library(&[("core", &[]), ("root", &[])]);
+        for layer in load_layers(root, layers_dir, true).unwrap() {
+            let name = layer.name.clone();
+            let idx = library.layers.push(layer);
+            library.layer_tables[root].insert(name, idx);
+        }
+        let resolve = |name, arch| {
+            let layer = library.lookup_layer(root, name).unwrap().unwrap();
+            library
+                .resolve_layer_chain(layer, arch)
+                .map(|chain| {
+                    chain
+                        .iter()
+                        .map(|idx| library.layers[*idx].name.as_str())
+                        .collect::<Vec<_>>()
+                })
+                .map_err(|error| format!("{error:?}"))
+        };
+        assert_eq!(
+            resolve("customized", Architecture::Arm64).unwrap(),
+            ["base", "app", "customized"]
+        );
+        assert_eq!(resolve("base", Architecture::Arm64).unwrap(), ["base"]);
+        let error = resolve("orphan", Architecture::Arm64).unwrap_err();
+        assert!(error.contains("unable to find layer `missing`, the parent of layer `orphan`"));
+        let error = resolve("first", Architecture::Arm64).unwrap_err();
+        assert!(error.contains("layer cycle detected: first -> second -> first"));
+        assert_eq!(
+            resolve("tool", Architecture::Amd64).unwrap(),
+            ["base", "native", "tool"]
+        );
+        let error = resolve("tool", Architecture::Arm64).unwrap_err();
+        assert!(error.contains(
+            "layer `native` has no configuration for architecture `arm64`, \
+            which is required by layer `tool`"
+        ));
+    }
+}
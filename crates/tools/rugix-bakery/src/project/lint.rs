@@ -0,0 +1,196 @@
+//! Static checks of the recipes of a library.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use super::library::Library;
+use super::recipes::Recipe;
+
+/// Severity of a finding.
+///
+/// The severities correspond to the levels of ShellCheck.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// Stylistic issue, e.g., a more idiomatic way to write something.
+    Style,
+    /// Informational note.
+    Info,
+    /// Likely a mistake, but the recipe can be applied.
+    Warning,
+    /// The recipe cannot be applied as is.
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Style => "style",
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+/// Problem found in a recipe.
+#[derive(Debug, Clone, Serialize)]
+pub struct Finding {
+    /// Severity of the finding.
+    pub severity: Severity,
+    /// Id of the repository of the recipe.
+    pub repository: String,
+    /// Name of the recipe.
+    pub recipe: String,
+    /// Description of the problem.
+    pub message: String,
+}
+
+/// Check the recipes of the library for problems without applying them.
+///
+/// Only the recipes of the root repository are checked, unless *all* is set. Findings
+/// are sorted by repository and recipe.
+pub fn lint_library(library: &Library, all: bool) -> Vec<Finding> {
+    let repositories = &library.repositories;
+    let mut definitions = HashMap::<&str, Vec<&str>>::new();
+    for (_, recipe) in library.recipes.iter() {
+        definitions.entry(recipe.name.deref()).or_default().push(
+            repositories.repositories[recipe.repository]
+                .source
+                .id
+                .as_str(),
+        );
+    }
+    let mut findings = Vec::new();
+    for (_, recipe) in library.recipes.iter() {
+        if !all && recipe.repository != repositories.root_repository {
+            continue;
+        }
+        let repository = repositories.repositories[recipe.repository]
+            .source
+            .id
+            .as_str();
+        let mut problems = lint_recipe(recipe);
+        problems.extend(lint_dependencies(library, recipe));
+        let mut others = definitions[recipe.name.deref()]
+            .iter()
+            .filter(|other| **other != repository)
+            .map(|other| format!("`{other}`"))
+            .collect::<Vec<_>>();
+        if !others.is_empty() {
+            others.sort();
+            problems.push((
+                Severity::Warning,
+                format!("recipe is also defined in {}", others.join(", ")),
+            ));
+        }
+        findings.extend(problems.into_iter().map(|(severity, message)| Finding {
+            severity,
+            repository: repository.to_owned(),
+            recipe: recipe.name.to_string(),
+            message,
+        }));
+    }
+    findings.sort_by(|a, b| (&a.repository, &a.recipe).cmp(&(&b.repository, &b.recipe)));
+    findings
+}
+
+/// Check the steps and parameters of the given recipe.
+fn lint_recipe(recipe: &Recipe) -> Vec<(Severity, String)> {
+    let mut problems = Vec::new();
+    if recipe.steps.is_empty() {
+        problems.push((Severity::Warning, "recipe has no steps".to_owned()));
+    }
+    for problem in recipe.check_steps() {
+        problems.push((Severity::Error, problem));
+    }
+    let mut parameters = recipe
+        .config
+        .parameters
+        .iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    parameters.sort_by_key(|(name, _)| *name);
+    for (name, def) in parameters {
+        let Some(default) = &def.default else {
+            continue;
+        };
+        if let Err(error) = recipe.parameter_value(name, default) {
+            problems.push((
+                Severity::Error,
+                format!("default value of parameter `{name}` is invalid: {error:?}"),
+            ));
+        }
+    }
+    problems
+}
+
+/// Check that the dependencies of the given recipe exist and satisfy their constraints.
+fn lint_dependencies(library: &Library, recipe: &Recipe) -> Vec<(Severity, String)> {
+    let mut problems = Vec::new();
+    for (name, constraint) in recipe.config.dependencies.iter().flat_map(|d| d.iter()) {
+        let Some(dependency) = library.lookup(recipe.repository, name) else {
+            problems.push((
+                Severity::Error,
+                format!("dependency `{name}` does not exist"),
+            ));
+            continue;
+        };
+        if let Some(constraint) = constraint {
+            if let Err(error) = library.recipes[dependency].check_version(constraint) {
+                problems.push((
+                    Severity::Error,
+                    format!("dependency `{name}` is incompatible: {error:?}"),
+                ));
+            }
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::{lint_recipe, Severity};
+    use crate::project::recipes::RecipeLoader;
+    use crate::project::repositories::RepositoryIdx;
+
+    #[test]
+    fn test_lint_recipe() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("recipe");
+        fs::create_dir_all(path.join("steps")).unwrap();
+        fs::write(
+            path.join("recipe.toml"),
+            "[parameters]\nhostname = { default = \"Invalid_Host\", pattern = \"[a-z-]+\" }\n",
+        )
+        .unwrap();
+        let loader = RecipeLoader::new(RepositoryIdx::from_usize(0));
+        let problems = lint_recipe(&loader.load(&path).unwrap());
+        assert_eq!(problems.len(), 2);
+        assert_eq!(
+            problems[0],
+            (Severity::Warning, "recipe has no steps".to_owned())
+        );
+        assert_eq!(problems[1].0, Severity::Error);
+        assert!(problems[1].1.contains("hostname"));
+
+        fs::write(path.join("recipe.toml"), "description = \"Test recipe.\"\n").unwrap();
+        fs::write(path.join("steps/00-install.sh"), "#!/bin/sh\n").unwrap();
+        fs::set_permissions(
+            path.join("steps/00-install.sh"),
+            fs::Permissions::from_mode(0o644),
+        )
+        .unwrap();
+        let problems = lint_recipe(&loader.load(&path).unwrap());
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].0, Severity::Error);
+        assert!(problems[0].1.contains("not executable"));
+    }
+}
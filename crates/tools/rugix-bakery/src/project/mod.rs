@@ -6,15 +6,18 @@ use std::sync::{Arc, Mutex};
 use library::Library;
 use reportify::ResultExt;
 use repositories::ProjectRepositories;
+use stats::BuildStats;
 
-use crate::config::load_config;
+use crate::config::load_project_config;
 use crate::config::projects::ProjectConfig;
 use crate::BakeryResult;
 
 pub mod layers;
 pub mod library;
+pub mod lint;
 pub mod recipes;
 pub mod repositories;
+pub mod stats;
 
 /// Shared reference to an in-memory project.
 #[derive(Debug, Clone)]
@@ -34,6 +37,75 @@ impl ProjectRef {
         &self.shared.config
     }
 
+    /// Indicates whether configuration warnings should be treated as errors.
+    pub fn is_strict(&self) -> bool {
+        self.shared.strict
+    }
+
+    /// Indicates whether emulators for foreign architectures should be registered.
+    pub fn register_emulators(&self) -> bool {
+        self.shared.register_emulators
+    }
+
+    /// Indicates whether unknown configuration keys should be rejected.
+    pub fn validate_schema(&self) -> bool {
+        self.shared.validate_schema
+    }
+
+    /// Directory to cache downloaded files in.
+    pub fn cache_dir(&self) -> PathBuf {
+        match &self.shared.cache_dir {
+            Some(cache_dir) => self.dir().join(cache_dir),
+            None => self.dir().join(".rugix/cache"),
+        }
+    }
+
+    /// Directory to cache layers in by the contents of their inputs, if enabled.
+    pub fn layer_cache_dir(&self) -> Option<PathBuf> {
+        self.shared
+            .layer_cache
+            .then(|| self.cache_dir().join("layers"))
+    }
+
+    /// Maximal number of recipes a layer may apply, if limited.
+    pub fn max_recipes(&self) -> Option<usize> {
+        self.shared.max_recipes
+    }
+
+    /// Statistics of the builds of the project.
+    pub fn stats(&self) -> &Arc<BuildStats> {
+        &self.shared.stats
+    }
+
+    /// Reload the project configuration.
+    ///
+    /// The repositories and the library are loaded again lazily on their next access.
+    /// Other references to the project are not affected and keep the previous state.
+    pub fn reload(&mut self) -> BakeryResult<()> {
+        let shared = &self.shared;
+        let config = Arc::new(load_project_config(
+            &shared.config_path,
+            shared.profile.as_deref(),
+            shared.validate_schema,
+        )?);
+        self.shared = Arc::new(ProjectShared {
+            dir: shared.dir.clone(),
+            config_path: shared.config_path.clone(),
+            profile: shared.profile.clone(),
+            config,
+            library_cache: shared.library_cache,
+            layer_cache: shared.layer_cache,
+            strict: shared.strict,
+            register_emulators: shared.register_emulators,
+            validate_schema: shared.validate_schema,
+            max_recipes: shared.max_recipes,
+            cache_dir: shared.cache_dir.clone(),
+            stats: shared.stats.clone(),
+            lazy: ProjectLazy::default(),
+        });
+        Ok(())
+    }
+
     /// Retrieve the repositories of the project.
     ///
     /// This may load the repositories lazily.
@@ -55,7 +127,14 @@ impl ProjectRef {
         if let Some(library) = &*library {
             Ok(library.clone())
         } else {
-            *library = Some(Library::load(self.repositories()?).map(Arc::new)?);
+            let repositories = self.repositories()?;
+            // Cached contents have not been validated, so the cache is bypassed.
+            let loaded = if self.shared.library_cache && !self.validate_schema() {
+                Library::load_with_cache(repositories, &self.dir().join(".rugix/library"))?
+            } else {
+                Library::load(repositories, self.validate_schema())?
+            };
+            *library = Some(Arc::new(loaded));
             Ok(library.clone().unwrap())
         }
     }
@@ -66,8 +145,28 @@ impl ProjectRef {
 struct ProjectShared {
     /// Project directory.
     dir: PathBuf,
+    /// Path to the configuration file.
+    config_path: PathBuf,
+    /// Profile applied to the configuration.
+    profile: Option<String>,
     /// Project configuration.
     config: Arc<ProjectConfig>,
+    /// Indicates whether the contents of unchanged repositories should be cached.
+    library_cache: bool,
+    /// Indicates whether layers should be cached by the contents of their inputs.
+    layer_cache: bool,
+    /// Indicates whether configuration warnings should be treated as errors.
+    strict: bool,
+    /// Indicates whether emulators for foreign architectures should be registered.
+    register_emulators: bool,
+    /// Indicates whether unknown configuration keys should be rejected.
+    validate_schema: bool,
+    /// Maximal number of recipes a layer may apply, if limited.
+    max_recipes: Option<usize>,
+    /// Directory to cache downloaded files in.
+    cache_dir: Option<PathBuf>,
+    /// Statistics of the builds of the project.
+    stats: Arc<BuildStats>,
     /// Lazily-loaded project data.
     lazy: ProjectLazy,
 }
@@ -85,6 +184,22 @@ pub struct ProjectLoader {
     project_dir: PathBuf,
     /// Path to the configuration file.
     config_file: Option<PathBuf>,
+    /// Indicates whether the contents of unchanged repositories should be cached.
+    library_cache: bool,
+    /// Indicates whether layers should be cached by the contents of their inputs.
+    layer_cache: bool,
+    /// Indicates whether configuration warnings should be treated as errors.
+    strict: bool,
+    /// Indicates whether emulators for foreign architectures should be registered.
+    register_emulators: bool,
+    /// Indicates whether unknown configuration keys should be rejected.
+    validate_schema: bool,
+    /// Maximal number of recipes a layer may apply, if limited.
+    max_recipes: Option<usize>,
+    /// Directory to cache downloaded files in.
+    cache_dir: Option<PathBuf>,
+    /// Profile to apply to the configuration.
+    profile: Option<String>,
 }
 
 impl ProjectLoader {
@@ -93,6 +208,14 @@ impl ProjectLoader {
         Self {
             project_dir: project_dir.to_path_buf(),
             config_file: None,
+            library_cache: false,
+            layer_cache: false,
+            strict: false,
+            register_emulators: false,
+            validate_schema: false,
+            max_recipes: None,
+            cache_dir: None,
+            profile: None,
         }
     }
 
@@ -109,6 +232,54 @@ impl ProjectLoader {
         self
     }
 
+    /// Set whether the contents of unchanged repositories should be cached.
+    pub fn with_library_cache(mut self, library_cache: bool) -> Self {
+        self.library_cache = library_cache;
+        self
+    }
+
+    /// Set whether layers should be cached by the contents of their inputs.
+    pub fn with_layer_cache(mut self, layer_cache: bool) -> Self {
+        self.layer_cache = layer_cache;
+        self
+    }
+
+    /// Set whether configuration warnings should be treated as errors.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Set whether emulators for foreign architectures should be registered.
+    pub fn with_register_emulators(mut self, register_emulators: bool) -> Self {
+        self.register_emulators = register_emulators;
+        self
+    }
+
+    /// Set whether unknown configuration keys should be rejected.
+    pub fn with_validate_schema(mut self, validate_schema: bool) -> Self {
+        self.validate_schema = validate_schema;
+        self
+    }
+
+    /// Set the maximal number of recipes a layer may apply.
+    pub fn with_max_recipes(mut self, max_recipes: Option<usize>) -> Self {
+        self.max_recipes = max_recipes;
+        self
+    }
+
+    /// Set the directory to cache downloaded files in, relative to the project directory.
+    pub fn with_cache_dir(mut self, cache_dir: Option<&Path>) -> Self {
+        self.cache_dir = cache_dir.map(Path::to_path_buf);
+        self
+    }
+
+    /// Set the profile to apply to the configuration.
+    pub fn with_profile(mut self, profile: Option<&str>) -> Self {
+        self.profile = profile.map(str::to_owned);
+        self
+    }
+
     /// The full path to the configuration file.
     fn config_path(&self) -> PathBuf {
         self.project_dir.join(
@@ -120,13 +291,53 @@ impl ProjectLoader {
 
     /// Load the project.
     pub fn load(self) -> BakeryResult<ProjectRef> {
-        let config = load_config(&self.config_path())?;
+        let config_path = self.config_path();
+        let config = Arc::new(load_project_config(
+            &config_path,
+            self.profile.as_deref(),
+            self.validate_schema,
+        )?);
         Ok(ProjectRef {
             shared: Arc::new(ProjectShared {
                 dir: self.project_dir,
+                config_path,
+                profile: self.profile,
                 config,
+                library_cache: self.library_cache,
+                layer_cache: self.layer_cache,
+                strict: self.strict,
+                register_emulators: self.register_emulators,
+                validate_schema: self.validate_schema,
+                max_recipes: self.max_recipes,
+                cache_dir: self.cache_dir,
+                stats: Arc::default(),
                 lazy: ProjectLazy::default(),
             }),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::ProjectLoader;
+
+    #[test]
+    fn test_reload() {
+        let project_dir = tempfile::tempdir().unwrap();
+        let config_path = project_dir.path().join("rugix-bakery.toml");
+        fs::write(&config_path, "").unwrap();
+        let mut project = ProjectLoader::new(project_dir.path()).load().unwrap();
+        let previous = project.clone();
+        assert!(project.config().get_system_config("customized").is_none());
+        fs::write(
+            &config_path,
+            "[systems.customized]\nlayer = \"customized\"\narchitecture = \"arm64\"\n",
+        )
+        .unwrap();
+        project.reload().unwrap();
+        assert!(project.config().get_system_config("customized").is_some());
+        assert!(previous.config().get_system_config("customized").is_none());
+    }
+}
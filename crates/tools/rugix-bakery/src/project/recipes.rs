@@ -1,7 +1,10 @@
 //! Data structures for representing recipes.
 
 use std::ffi::OsStr;
-use std::path::{Path, PathBuf};
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
 use std::{fmt, fs, ops};
 
@@ -9,19 +12,27 @@ use serde::{Deserialize, Serialize};
 
 use reportify::{bail, whatever, ResultExt};
 
-use crate::config::load_config;
-use crate::config::recipes::RecipeConfig;
-use crate::utils::caching::{mtime_recursive, ModificationTime};
+use crate::config::recipes::{
+    CopyStepConfig, ParameterDef, ParameterType, ParameterValue, RecipeConfig, RecipePhase,
+};
+use crate::config::systems::Architecture;
+use crate::config::{load_config, load_config_with};
+use crate::utils::caching::{hash_recursive, mtime_recursive, Hasher, ModificationTime};
 use crate::BakeryResult;
 
 use super::repositories::RepositoryIdx;
 
+/// Name of the layer parameter referencing a preset, which recipes cannot declare.
+pub const PRESET_PARAMETER: &str = "preset";
+
 /// Auxiliary data structure for loading recipes.
 #[derive(Debug)]
 pub struct RecipeLoader {
     repository: RepositoryIdx,
     /// Indicates whether the recipe should be included by default.
     default: Option<bool>,
+    /// Indicates whether unknown configuration keys should be rejected.
+    validate_schema: bool,
 }
 
 impl RecipeLoader {
@@ -30,6 +41,7 @@ impl RecipeLoader {
         Self {
             repository,
             default: None,
+            validate_schema: false,
         }
     }
 
@@ -39,17 +51,32 @@ impl RecipeLoader {
         self
     }
 
+    /// Sets whether unknown configuration keys should be rejected.
+    pub fn with_validate_schema(mut self, validate_schema: bool) -> Self {
+        self.validate_schema = validate_schema;
+        self
+    }
+
     /// Loads a recipe from the given path.
     pub fn load(&self, path: &Path) -> BakeryResult<Recipe> {
         let path = path.to_path_buf();
         let modified = mtime_recursive(&path).whatever("unable to determine mtime")?;
-        let name = path
+        let name: RecipeName = path
             .file_name()
             .ok_or_else(|| whatever!("unable to determine recipe name from path `{path:?}`"))?
             .to_string_lossy()
             .into();
         let config_path = path.join("recipe.toml");
-        let config = load_config(&config_path)?;
+        let config: RecipeConfig = load_config_with(&config_path, self.validate_schema)?;
+        for (param, def) in config.parameters.iter().flatten() {
+            if param == PRESET_PARAMETER {
+                bail!("recipe `{name}` declares reserved parameter `{PRESET_PARAMETER}`");
+            }
+            if let Some(default) = &def.default {
+                parameter_items(&name, param, def, default)
+                    .with_info(|_| format!("default value of parameter `{param}`"))?;
+            }
+        }
         let mut steps = Vec::new();
         let steps_dir = path.join("steps");
         if steps_dir.exists() {
@@ -60,6 +87,50 @@ impl RecipeLoader {
             }
         }
         steps.sort_by_key(|step| step.position);
+        for (filename, step_config) in config.steps.iter().flatten() {
+            let Some(step) = steps.iter_mut().find(|step| &step.filename == filename) else {
+                bail!("recipe `{name}` configures unknown step `{filename}`");
+            };
+            if let Some(arch) = &step_config.arch {
+                let arch = arch
+                    .iter()
+                    .map(|arch| Architecture::from_str(arch))
+                    .collect::<Result<Vec<_>, _>>()
+                    .whatever("unable to parse architecture of step")
+                    .with_info(|_| format!("step: {filename}"))?;
+                step.arch = Some(arch);
+            }
+            if step_config.recommends.is_some() || step_config.options.is_some() {
+                let StepKind::Packages {
+                    recommends,
+                    options,
+                    ..
+                } = &mut step.kind
+                else {
+                    bail!("recipe `{name}` configures package options of non-`packages` step `{filename}`");
+                };
+                if let Some(value) = step_config.recommends {
+                    *recommends = value;
+                }
+                if let Some(value) = &step_config.options {
+                    *options = value.clone();
+                }
+            }
+        }
+        for step in &steps {
+            if !matches!(step.kind, StepKind::Run | StepKind::Install) {
+                continue;
+            }
+            // Scripts which are not executable are run with the interpreter of their
+            // shebang, without one, there is no way to run them.
+            let script = steps_dir.join(&step.filename);
+            if !is_executable(&script) && shebang_interpreter(&script).is_none() {
+                bail!(
+                    "step `{}` of recipe `{name}` is not executable",
+                    step.filename
+                );
+            }
+        }
         let recipe = Recipe {
             repository: self.repository,
             modified,
@@ -73,7 +144,7 @@ impl RecipeLoader {
 }
 
 /// A recipe.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Recipe {
     /// The lastest modification time of the recipe.
     pub modified: ModificationTime,
@@ -88,6 +159,296 @@ pub struct Recipe {
     pub path: PathBuf,
 }
 
+impl Recipe {
+    /// Key by which the recipe is scheduled.
+    ///
+    /// Recipes are applied in the order of their phase and, within a phase, in the order
+    /// of their priority (higher means earlier).
+    pub fn schedule_key(&self) -> (u8, i64) {
+        let phase = match self.phase() {
+            RecipePhase::Pre => 0,
+            RecipePhase::Main => 1,
+            RecipePhase::Post => 2,
+        };
+        (phase, -self.config.priority.unwrap_or_default())
+    }
+
+    /// Phase in which the recipe is applied.
+    pub fn phase(&self) -> RecipePhase {
+        self.config.phase.unwrap_or(RecipePhase::Main)
+    }
+
+    /// Checks whether the version of the recipe satisfies the given constraint.
+    pub fn check_version(&self, constraint: &str) -> BakeryResult<()> {
+        check_version_constraint(&self.name, self.config.version.as_deref(), constraint)
+    }
+
+    /// Checks whether the given value of a parameter matches the parameter's type and
+    /// pattern and converts it to the string exposed to the recipe's steps.
+    ///
+    /// The elements of lists are joined with newlines. For lists, the pattern applies to
+    /// each element.
+    pub fn parameter_value(&self, param: &str, value: &ParameterValue) -> BakeryResult<String> {
+        let Some(def) = self
+            .config
+            .parameters
+            .as_ref()
+            .and_then(|parameters| parameters.get(param))
+        else {
+            return Ok(value.to_string());
+        };
+        let items = parameter_items(&self.name, param, def, value)?;
+        if let Some(pattern) = &def.pattern {
+            for item in &items {
+                check_parameter_pattern(&self.name, param, pattern, item)?;
+            }
+        }
+        Ok(items.join("\n"))
+    }
+
+    /// Checks that the scripts of `run` and `install` steps can be executed.
+    ///
+    /// Scripts must have the executable bit set and start with a shebang. Returns a
+    /// description of each problem found.
+    pub fn check_steps(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for step in &self.steps {
+            if !matches!(step.kind, StepKind::Run | StepKind::Install) {
+                continue;
+            }
+            let path = self.path.join("steps").join(&step.filename);
+            let name = &self.name;
+            let filename = &step.filename;
+            match fs::metadata(&path) {
+                Ok(metadata) if metadata.permissions().mode() & 0o111 == 0 => {
+                    problems.push(format!(
+                        "step `{filename}` of recipe `{name}` is not executable"
+                    ));
+                }
+                Ok(_) => {}
+                Err(error) => {
+                    problems.push(format!(
+                        "unable to read step `{filename}` of recipe `{name}`: {error}"
+                    ));
+                    continue;
+                }
+            }
+            match read_shebang(&path) {
+                Some(shebang) if shebang.ends_with('\r') => {
+                    problems.push(format!(
+                        "shebang of step `{filename}` of recipe `{name}` ends with a carriage return (CRLF line endings)"
+                    ));
+                }
+                Some(_) => {}
+                None => {
+                    problems.push(format!(
+                        "step `{filename}` of recipe `{name}` does not start with a shebang (`#!`)"
+                    ));
+                }
+            }
+        }
+        problems
+    }
+
+    /// Interpreter to run the script of the given step with.
+    ///
+    /// Scripts with the executable bit set are run directly and `None` is returned. For
+    /// other scripts, the interpreter and its optional argument are taken from the
+    /// shebang, which the loader ensures exists.
+    pub fn step_interpreter(&self, step: &RecipeStep) -> Option<Vec<String>> {
+        let path = self.path.join("steps").join(&step.filename);
+        if is_executable(&path) {
+            return None;
+        }
+        shebang_interpreter(&path)
+    }
+
+    /// Computes a fingerprint of the recipe's contents.
+    ///
+    /// The fingerprint covers the configuration, including parameter definitions, and the
+    /// steps as well as any other files of the recipe. It is independent of the name,
+    /// location, and modification times of the recipe and therefore suitable as a key for
+    /// content-based caching.
+    pub fn fingerprint(&self) -> BakeryResult<RecipeFingerprint> {
+        let mut hasher = Hasher::new();
+        hash_recursive(&mut hasher, &self.path)
+            .whatever("unable to hash recipe contents")
+            .with_info(|_| format!("recipe: {}", self.name))?;
+        Ok(RecipeFingerprint(hasher.finalize()))
+    }
+}
+
+/// A fingerprint of the contents of a recipe.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RecipeFingerprint(String);
+
+impl RecipeFingerprint {
+    /// The fingerprint as a hex string.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RecipeFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Checks whether the file at the given path has any executable bit set.
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|metadata| metadata.permissions().mode() & 0o111 != 0)
+}
+
+/// Interpreter and its optional argument from the shebang of the script at the given
+/// path.
+///
+/// Returns `None` if the script does not start with a non-empty shebang.
+fn shebang_interpreter(path: &Path) -> Option<Vec<String>> {
+    let shebang = read_shebang(path)?;
+    let shebang = shebang.trim();
+    if shebang.is_empty() {
+        return None;
+    }
+    Some(match shebang.split_once(char::is_whitespace) {
+        Some((interpreter, arg)) => vec![interpreter.to_owned(), arg.trim().to_owned()],
+        None => vec![shebang.to_owned()],
+    })
+}
+
+/// Read the shebang of the script at the given path without the leading `#!`.
+///
+/// Returns `None` if the script cannot be read or does not start with a shebang.
+fn read_shebang(path: &Path) -> Option<String> {
+    let mut file = fs::File::open(path).ok()?;
+    let mut head = [0; 256];
+    let mut len = 0;
+    while len < head.len() {
+        match file.read(&mut head[len..]) {
+            Ok(0) => break,
+            Ok(read) => len += read,
+            Err(_) => return None,
+        }
+    }
+    let line = head[..len].split(|byte| *byte == b'\n').next()?;
+    let shebang = line.strip_prefix(b"#!")?;
+    Some(String::from_utf8_lossy(shebang).into_owned())
+}
+
+/// Checks whether the value of a parameter matches the type of its definition and
+/// returns its elements.
+///
+/// Values of parameters of type `list` may also be given as a string, which is split at
+/// whitespace. List elements must not contain newlines, as they are joined with newlines
+/// when exposed to steps. Values of all other types consist of a single element.
+fn parameter_items(
+    name: &str,
+    param: &str,
+    def: &ParameterDef,
+    value: &ParameterValue,
+) -> BakeryResult<Vec<String>> {
+    match (def.ty.unwrap_or(ParameterType::String), value) {
+        (ParameterType::List, ParameterValue::List(items)) => {
+            if let Some(item) = items.iter().find(|item| item.contains('\n')) {
+                bail!(
+                    "element {item:?} of parameter `{param}` of recipe `{name}` contains a newline"
+                );
+            }
+            Ok(items.clone())
+        }
+        (ParameterType::List, ParameterValue::String(value)) => {
+            Ok(value.split_whitespace().map(str::to_owned).collect())
+        }
+        (ParameterType::List, value) => {
+            bail!("parameter `{param}` of recipe `{name}` expects list, got {value}")
+        }
+        (ty, ParameterValue::List(_)) => {
+            bail!(
+                "parameter `{param}` of recipe `{name}` expects {}, got list",
+                ty.name()
+            )
+        }
+        (_, value) => {
+            let value = value.to_string();
+            check_parameter_type(name, param, def, &value)?;
+            Ok(vec![value])
+        }
+    }
+}
+
+/// Checks whether the value of a parameter matches the type of its definition.
+fn check_parameter_type(
+    name: &str,
+    param: &str,
+    def: &ParameterDef,
+    value: &str,
+) -> BakeryResult<()> {
+    let valid = match def.ty.unwrap_or(ParameterType::String) {
+        ParameterType::String | ParameterType::List => true,
+        ParameterType::Integer => value.parse::<i64>().is_ok(),
+        ParameterType::Boolean => matches!(value, "true" | "false"),
+        ParameterType::Enum => {
+            let Some(values) = &def.values else {
+                bail!("parameter `{param}` of recipe `{name}` of type enum has no `values`");
+            };
+            if !values.iter().any(|allowed| allowed == value) {
+                bail!(
+                    "parameter `{param}` of recipe `{name}` expects one of {}, got {value:?}",
+                    values.join(", ")
+                );
+            }
+            true
+        }
+    };
+    if !valid {
+        bail!(
+            "parameter `{param}` of recipe `{name}` expects {}, got {value:?}",
+            def.ty.map_or("string", ParameterType::name)
+        );
+    }
+    Ok(())
+}
+
+/// Checks whether the value of a parameter matches the given pattern.
+///
+/// The pattern must match the entire value.
+fn check_parameter_pattern(
+    name: &str,
+    param: &str,
+    pattern: &str,
+    value: &str,
+) -> BakeryResult<()> {
+    let regex = regex::Regex::new(&format!("^(?:{pattern})$")).whatever_with(|_| {
+        format!("invalid pattern `{pattern}` for parameter `{param}` of recipe `{name}`")
+    })?;
+    if !regex.is_match(value) {
+        bail!(
+            "value `{value}` of parameter `{param}` of recipe `{name}` does not match `{pattern}`"
+        );
+    }
+    Ok(())
+}
+
+/// Checks whether the version of a recipe satisfies the given constraint.
+fn check_version_constraint(
+    name: &str,
+    version: Option<&str>,
+    constraint: &str,
+) -> BakeryResult<()> {
+    let requirement = semver::VersionReq::parse(constraint)
+        .whatever_with(|_| format!("invalid version constraint `{constraint}` for `{name}`"))?;
+    let Some(version) = version else {
+        bail!("recipe `{name}` has no version but constraint `{constraint}` is required");
+    };
+    let parsed = semver::Version::parse(version)
+        .whatever_with(|_| format!("invalid version `{version}` of recipe `{name}`"))?;
+    if !requirement.matches(&parsed) {
+        bail!("version `{version}` of recipe `{name}` does not satisfy `{constraint}`");
+    }
+    Ok(())
+}
+
 /// A name of a recipe.
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(transparent)]
@@ -122,6 +483,8 @@ pub struct RecipeStep {
     pub kind: StepKind,
     /// The filename of the step.
     pub filename: String,
+    /// Architectures the step applies to, if it does not apply to all architectures.
+    pub arch: Option<Vec<Architecture>>,
 }
 
 impl RecipeStep {
@@ -148,18 +511,32 @@ impl RecipeStep {
                     Some((_, "apk")) => Some(PackageManager::Apk),
                     _ => None,
                 };
-                StepKind::Packages { packages, manager }
+                StepKind::Packages {
+                    packages,
+                    manager,
+                    recommends: true,
+                    options: Vec::new(),
+                }
             }
             "install" => StepKind::Install,
             "run" => StepKind::Run,
+            "copy" => StepKind::Copy {
+                files: load_copy_files(path)?,
+            },
             _ => bail!("unknown step kind `{kind}`"),
         };
         Ok(Self {
             position,
             kind,
             filename,
+            arch: None,
         })
     }
+
+    /// Indicates whether the step applies to the given architecture.
+    pub fn applies_to(&self, arch: Architecture) -> bool {
+        self.arch.as_ref().is_none_or(|archs| archs.contains(&arch))
+    }
 }
 
 /// A step kind.
@@ -169,11 +546,109 @@ pub enum StepKind {
     Packages {
         manager: Option<PackageManager>,
         packages: Vec<String>,
+        /// Indicates whether recommended packages should be installed.
+        recommends: bool,
+        /// Additional options passed to the package manager.
+        options: Vec<String>,
     },
     /// Run a script in the `chroot` environment of the system.
     Install,
     /// Run a script on the host machine.
     Run,
+    /// Copy files from the recipe directory into the root filesystem.
+    Copy { files: Vec<CopyFile> },
+}
+
+impl StepKind {
+    /// Name of the step kind as used in step filenames.
+    pub fn name(&self) -> &'static str {
+        match self {
+            StepKind::Packages { .. } => "packages",
+            StepKind::Install => "install",
+            StepKind::Run => "run",
+            StepKind::Copy { .. } => "copy",
+        }
+    }
+
+    /// Indicates whether the step runs within the `chroot` environment of the system.
+    ///
+    /// Such steps require `/dev`, `/proc`, `/sys`, and further directories to be mounted.
+    pub fn needs_chroot(&self) -> bool {
+        matches!(self, StepKind::Packages { .. } | StepKind::Install)
+    }
+}
+
+/// File or directory to copy from the recipe directory into the root filesystem.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CopyFile {
+    /// Path relative to the recipe directory.
+    pub src: PathBuf,
+    /// Path relative to the root directory of the system.
+    pub dest: PathBuf,
+    /// Owner of the copied files as `user:group`.
+    pub owner: String,
+    /// Mode of the copied files, if it should not be taken from the source files.
+    pub mode: Option<u32>,
+}
+
+/// Load the files to copy of the `copy` step with the given path.
+///
+/// Source and destination paths must not escape the recipe directory and the root
+/// directory, respectively.
+fn load_copy_files(path: &Path) -> BakeryResult<Vec<CopyFile>> {
+    let config: CopyStepConfig = load_config(path)?;
+    let Some(files) = config.files else {
+        return Ok(vec![CopyFile {
+            src: PathBuf::from("files"),
+            dest: PathBuf::new(),
+            owner: "root:root".to_owned(),
+            mode: None,
+        }]);
+    };
+    files
+        .into_iter()
+        .map(|file| {
+            let Some(src) = contained_path(&file.src) else {
+                bail!(
+                    "source `{}` of copy step must be within the recipe",
+                    file.src
+                );
+            };
+            let Some(dest) = contained_path(&file.dest) else {
+                bail!("destination `{}` of copy step escapes the root", file.dest);
+            };
+            let mode = file
+                .mode
+                .map(|mode| {
+                    u32::from_str_radix(&mode, 8)
+                        .ok()
+                        .filter(|mode| *mode <= 0o7777)
+                        .ok_or_else(|| whatever!("invalid mode `{mode}` of copy step"))
+                })
+                .transpose()?;
+            Ok(CopyFile {
+                src,
+                dest,
+                owner: file.owner.unwrap_or_else(|| "root:root".to_owned()),
+                mode,
+            })
+        })
+        .collect()
+}
+
+/// Convert the given path to a relative path which does not escape its base directory.
+///
+/// Leading slashes are ignored, i.e., absolute paths are taken relative to the base.
+fn contained_path(path: &str) -> Option<PathBuf> {
+    let mut contained = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(component) => contained.push(component),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(contained)
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -181,3 +656,273 @@ pub enum PackageManager {
     Apt,
     Apk,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::idx_vec::Idx;
+
+    #[test]
+    fn test_version_constraint_satisfied() {
+        check_version_constraint("base", Some("1.2.0"), ">=1.2, <2").unwrap();
+        check_version_constraint("base", Some("1.9.3"), ">=1.2, <2").unwrap();
+    }
+
+    #[test]
+    fn test_version_constraint_violated() {
+        assert!(check_version_constraint("base", Some("1.1.9"), ">=1.2, <2").is_err());
+        assert!(check_version_constraint("base", Some("2.0.0"), ">=1.2, <2").is_err());
+        assert!(check_version_constraint("base", None, ">=1.2, <2").is_err());
+    }
+
+    #[test]
+    fn test_version_constraint_invalid() {
+        assert!(check_version_constraint("base", Some("1.2.0"), "not a constraint").is_err());
+        assert!(check_version_constraint("base", Some("latest"), ">=1.2").is_err());
+    }
+
+    #[test]
+    fn test_parameter_pattern_matches() {
+        check_parameter_pattern("base", "hostname", "[a-z][a-z0-9-]*", "rugix-01").unwrap();
+        check_parameter_pattern("base", "port", r"\d+|auto", "auto").unwrap();
+    }
+
+    #[test]
+    fn test_parameter_pattern_mismatch() {
+        assert!(check_parameter_pattern("base", "hostname", "[a-z][a-z0-9-]*", "Rugix").is_err());
+        // The pattern must match the entire value.
+        assert!(check_parameter_pattern("base", "port", r"\d+", "80a").is_err());
+        assert!(check_parameter_pattern("base", "port", r"\d+|auto", "automatic").is_err());
+        assert!(check_parameter_pattern("base", "port", "(", "80").is_err());
+    }
+
+    #[test]
+    fn test_parameter_type() {
+        let def = |ty, values: Option<&[&str]>| {
+            ParameterDef::new()
+                .with_ty(Some(ty))
+                .with_values(values.map(|values| values.iter().map(|v| v.to_string()).collect()))
+        };
+        let integer = def(ParameterType::Integer, None);
+        check_parameter_type("nginx", "port", &integer, "8080").unwrap();
+        check_parameter_type("nginx", "port", &integer, "-1").unwrap();
+        assert!(check_parameter_type("nginx", "port", &integer, "abc").is_err());
+        assert!(check_parameter_type("nginx", "port", &integer, "1.5").is_err());
+        let boolean = def(ParameterType::Boolean, None);
+        check_parameter_type("nginx", "tls", &boolean, "false").unwrap();
+        assert!(check_parameter_type("nginx", "tls", &boolean, "yes").is_err());
+        let choice = def(ParameterType::Enum, Some(&["debug", "info"]));
+        check_parameter_type("nginx", "log_level", &choice, "info").unwrap();
+        assert!(check_parameter_type("nginx", "log_level", &choice, "trace").is_err());
+        // Enums must specify their allowed values.
+        let choice = def(ParameterType::Enum, None);
+        assert!(check_parameter_type("nginx", "log_level", &choice, "info").is_err());
+        let string = ParameterDef::new();
+        check_parameter_type("nginx", "hostname", &string, "anything").unwrap();
+    }
+
+    #[test]
+    fn test_copy_step() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let step = temp_dir.path().join("00-copy.toml");
+        fs::write(&step, "").unwrap();
+        let files = load_copy_files(&step).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].src, Path::new("files"));
+        assert_eq!(files[0].dest, Path::new(""));
+        fs::write(
+            &step,
+            "[[files]]\nsrc = \"nginx.conf\"\ndest = \"/etc/nginx/nginx.conf\"\nmode = \"640\"\n",
+        )
+        .unwrap();
+        let files = load_copy_files(&step).unwrap();
+        assert_eq!(files[0].dest, Path::new("etc/nginx/nginx.conf"));
+        assert_eq!(files[0].mode, Some(0o640));
+        assert_eq!(files[0].owner, "root:root");
+        for invalid in [
+            "[[files]]\nsrc = \"nginx.conf\"\ndest = \"/etc/../../host\"\n",
+            "[[files]]\nsrc = \"../secret\"\ndest = \"/etc/secret\"\n",
+            "[[files]]\nsrc = \"nginx.conf\"\ndest = \"/etc/nginx.conf\"\nmode = \"rw\"\n",
+        ] {
+            fs::write(&step, invalid).unwrap();
+            assert!(load_copy_files(&step).is_err());
+        }
+    }
+
+    #[test]
+    fn test_step_arch() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        fs::create_dir_all(dir.join("steps")).unwrap();
+        fs::write(dir.join("steps/00-packages"), "libraspberrypi-bin").unwrap();
+        fs::write(dir.join("steps/01-run.sh"), "#!/bin/sh\necho hello\n").unwrap();
+        let loader = RecipeLoader::new(RepositoryIdx::from_usize(0));
+        fs::write(
+            dir.join("recipe.toml"),
+            "[steps.00-packages]\narch = [\"armhf\", \"arm64\"]\n",
+        )
+        .unwrap();
+        let recipe = loader.load(dir).unwrap();
+        assert!(recipe.steps[0].applies_to(Architecture::Arm64));
+        assert!(!recipe.steps[0].applies_to(Architecture::Amd64));
+        assert!(recipe.steps[1].applies_to(Architecture::Amd64));
+        fs::write(
+            dir.join("recipe.toml"),
+            "[steps.00-packages]\narch = [\"arm\"]\n",
+        )
+        .unwrap();
+        let recipe = loader.load(dir).unwrap();
+        assert!(recipe.steps[0].applies_to(Architecture::Arm));
+        assert!(!recipe.steps[0].applies_to(Architecture::Armv7));
+        assert!(!recipe.steps[0].applies_to(Architecture::Arm64));
+        for invalid in [
+            "[steps.00-packages]\narch = [\"sparc\"]\n",
+            "[steps.02-install]\narch = [\"arm64\"]\n",
+        ] {
+            fs::write(dir.join("recipe.toml"), invalid).unwrap();
+            assert!(loader.load(dir).is_err());
+        }
+    }
+
+    #[test]
+    fn test_reserved_parameter() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let loader = RecipeLoader::new(RepositoryIdx::from_usize(0));
+        fs::write(dir.join("recipe.toml"), "[parameters.presets]\n").unwrap();
+        assert!(loader.load(dir).is_ok());
+        fs::write(dir.join("recipe.toml"), "[parameters.preset]\n").unwrap();
+        assert!(loader.load(dir).is_err());
+    }
+
+    #[test]
+    fn test_step_package_options() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        fs::create_dir_all(dir.join("steps")).unwrap();
+        fs::write(dir.join("steps/00-packages"), "curl").unwrap();
+        fs::write(dir.join("steps/01-run.sh"), "#!/bin/sh\necho hello\n").unwrap();
+        let loader = RecipeLoader::new(RepositoryIdx::from_usize(0));
+        fs::write(
+            dir.join("recipe.toml"),
+            "[steps.00-packages]\nrecommends = false\noptions = [\"-o\", \"APT::Get::Fix-Missing=true\"]\n",
+        )
+        .unwrap();
+        let recipe = loader.load(dir).unwrap();
+        let StepKind::Packages {
+            recommends,
+            options,
+            ..
+        } = &recipe.steps[0].kind
+        else {
+            panic!("expected `packages` step");
+        };
+        assert!(!recommends);
+        assert_eq!(options, &["-o", "APT::Get::Fix-Missing=true"]);
+        fs::write(
+            dir.join("recipe.toml"),
+            "[steps.\"01-run.sh\"]\nrecommends = false\n",
+        )
+        .unwrap();
+        assert!(loader.load(dir).is_err());
+    }
+
+    #[test]
+    fn test_schedule_key() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let loader = RecipeLoader::new(RepositoryIdx::from_usize(0));
+        let mut recipes = [
+            ("cleanup", "phase = \"post\"\npriority = 100\n"),
+            ("base", "priority = 10\n"),
+            ("prepare", "phase = \"pre\"\npriority = -100\n"),
+            ("first", "phase = \"main\"\npriority = 50\n"),
+        ]
+        .map(|(name, config)| {
+            let dir = temp_dir.path().join(name);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("recipe.toml"), config).unwrap();
+            loader.load(&dir).unwrap()
+        });
+        recipes.sort_by_key(|recipe| recipe.schedule_key());
+        let names = recipes
+            .iter()
+            .map(|recipe| &*recipe.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["prepare", "first", "base", "cleanup"]);
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        fn create_recipe(dir: &Path, step: &str) -> Recipe {
+            fs::create_dir_all(dir.join("steps")).unwrap();
+            fs::write(dir.join("recipe.toml"), "description = \"Test recipe.\"\n").unwrap();
+            fs::write(dir.join("steps/00-run.sh"), step).unwrap();
+            RecipeLoader::new(RepositoryIdx::from_usize(0))
+                .load(dir)
+                .unwrap()
+        }
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let first = create_recipe(&temp_dir.path().join("first"), "#!/bin/sh\necho hello\n");
+        let second = create_recipe(&temp_dir.path().join("second"), "#!/bin/sh\necho hello\n");
+        let changed = create_recipe(&temp_dir.path().join("changed"), "#!/bin/sh\necho world\n");
+        assert_eq!(first.fingerprint().unwrap(), second.fingerprint().unwrap());
+        assert_ne!(first.fingerprint().unwrap(), changed.fingerprint().unwrap());
+    }
+
+    #[test]
+    fn test_check_steps() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("recipe");
+        fs::create_dir_all(path.join("steps")).unwrap();
+        fs::write(path.join("recipe.toml"), "description = \"Test recipe.\"\n").unwrap();
+        fs::write(path.join("steps/00-packages"), "curl").unwrap();
+        fs::write(path.join("steps/01-run.sh"), "#!/bin/sh\necho ok\n").unwrap();
+        fs::write(path.join("steps/02-install.sh"), "echo missing shebang\n").unwrap();
+        fs::set_permissions(
+            path.join("steps/01-run.sh"),
+            fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        fs::set_permissions(
+            path.join("steps/02-install.sh"),
+            fs::Permissions::from_mode(0o644),
+        )
+        .unwrap();
+        let loader = RecipeLoader::new(RepositoryIdx::from_usize(0));
+        let error = format!("{:?}", loader.load(&path).unwrap_err());
+        assert!(error.contains("step `02-install.sh` of recipe `recipe` is not executable"));
+
+        fs::set_permissions(
+            path.join("steps/02-install.sh"),
+            fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        let recipe = loader.load(&path).unwrap();
+        let problems = recipe.check_steps();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("02-install.sh"));
+        assert_eq!(recipe.step_interpreter(&recipe.steps[1]), None);
+        assert_eq!(recipe.step_interpreter(&recipe.steps[2]), None);
+
+        fs::set_permissions(
+            path.join("steps/02-install.sh"),
+            fs::Permissions::from_mode(0o644),
+        )
+        .unwrap();
+        fs::write(
+            path.join("steps/02-install.sh"),
+            "#!/usr/bin/env bash\r\necho ok\r\n",
+        )
+        .unwrap();
+        let problems = recipe.check_steps();
+        assert_eq!(problems.len(), 2);
+        assert!(problems
+            .iter()
+            .any(|problem| problem.contains("carriage return")));
+        assert_eq!(
+            recipe.step_interpreter(&recipe.steps[2]),
+            Some(vec!["/usr/bin/env".to_owned(), "bash".to_owned()])
+        );
+    }
+}
@@ -206,20 +206,10 @@ impl Source {
     ///
     /// The *update* flag indicates whether remote repositories should be updated.
     pub fn materialize(config: SourceConfig, root_dir: &Path, update: bool) -> BakeryResult<Self> {
-        let id = compute_source_id(&config);
+        let source = config.as_source();
+        let id = source.id();
         debug!("materializing source {id}");
-        let path = match &config {
-            SourceConfig::Path(config) => root_dir.join(&config.path),
-            SourceConfig::Git(config) => {
-                let mut path = root_dir.join(".rugix/repositories");
-                path.push(id.as_str());
-                check_out_git_source(config, &path, update)?;
-                if let Some(repository_path) = &config.dir {
-                    path.push(repository_path);
-                }
-                path
-            }
-        };
+        let path = source.materialize(&id, root_dir, update)?;
         Ok(Self {
             id,
             config,
@@ -228,6 +218,56 @@ impl Source {
     }
 }
 
+/// Kind of source from which a repository can be obtained.
+///
+/// New kinds of sources plug in by implementing this trait.
+pub trait RepositorySource: std::fmt::Debug {
+    /// Compute the globally unique id of the source.
+    fn id(&self) -> SourceId;
+
+    /// Materialize the source in a local directory and return the directory.
+    ///
+    /// The *update* flag indicates whether remote sources should be updated.
+    fn materialize(&self, id: &SourceId, root_dir: &Path, update: bool) -> BakeryResult<PathBuf>;
+}
+
+impl SourceConfig {
+    /// The source described by the configuration.
+    pub fn as_source(&self) -> &dyn RepositorySource {
+        match self {
+            SourceConfig::Path(config) => config,
+            SourceConfig::Git(config) => config,
+        }
+    }
+}
+
+impl RepositorySource for PathSourceConfig {
+    fn id(&self) -> SourceId {
+        SourceId::from_parts(&[b"path", self.path.as_bytes()])
+    }
+
+    fn materialize(&self, _id: &SourceId, root_dir: &Path, _update: bool) -> BakeryResult<PathBuf> {
+        Ok(root_dir.join(&self.path))
+    }
+}
+
+impl RepositorySource for GitSourceConfig {
+    fn id(&self) -> SourceId {
+        let dir = self.dir.as_deref().unwrap_or_default();
+        SourceId::from_parts(&[b"git", self.url.as_bytes(), dir.as_bytes()])
+    }
+
+    fn materialize(&self, id: &SourceId, root_dir: &Path, update: bool) -> BakeryResult<PathBuf> {
+        let mut path = root_dir.join(".rugix/repositories");
+        path.push(id.as_str());
+        check_out_git_source(self, &path, update)?;
+        if let Some(repository_path) = &self.dir {
+            path.push(repository_path);
+        }
+        Ok(path)
+    }
+}
+
 /// Globally unique id of a source.
 ///
 /// The id is computed by hashing the path or URL of a source.
@@ -235,6 +275,15 @@ impl Source {
 pub struct SourceId(Arc<str>);
 
 impl SourceId {
+    /// Compute an id by hashing the given parts.
+    pub fn from_parts(parts: &[&[u8]]) -> Self {
+        let mut hasher = Sha1::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        SourceId(hex::encode(&hasher.finalize()[..]).into())
+    }
+
     /// The string representation of the id.
     pub fn as_str(&self) -> &str {
         &self.0
@@ -254,39 +303,33 @@ impl Display for SourceId {
 
 /// Compute the globally unique id of the source.
 fn compute_source_id(config: &SourceConfig) -> SourceId {
-    let mut hasher = Sha1::new();
-    match config {
-        SourceConfig::Path(path_source) => {
-            hasher.update(b"path");
-            hasher.update(path_source.path.as_bytes());
-        }
-        SourceConfig::Git(git_source) => {
-            hasher.update(b"git");
-            hasher.update(git_source.url.as_bytes());
-            if let Some(inner_path) = &git_source.dir {
-                hasher.update(inner_path.as_bytes());
-            }
-        }
-    }
-    SourceId(hex::encode(&hasher.finalize()[..]).into())
+    config.as_source().id()
 }
 
 /// Check out the Git repository in the given directory.
 ///
-/// The *fetch* flag indicates whether updates should be fetched from the remote.
+/// The *fetch* flag indicates whether updates should be fetched from the remote. If the
+/// source is pinned to a revision which is already present in the checkout, nothing is
+/// fetched as the revision cannot change.
 fn check_out_git_source(config: &GitSourceConfig, path: &Path, fetch: bool) -> BakeryResult<()> {
     if !path.exists() {
         run!(["git", "clone", &config.url, path]).whatever("unable to clone repository")?;
     }
     let env = LocalEnv::new(path);
-    if fetch {
-        run!(env, ["git", "fetch", "--all"]).whatever("unable to fetch updates of repository")?;
-    }
     macro_rules! rev_parse {
         ($rev:literal) => {
             read_str!(env, ["git", "rev-parse", "--verify", $rev]).whatever("unable to parse rev")
         };
     }
+    let is_pinned = config
+        .rev
+        .as_ref()
+        .is_some_and(|rev| rev_parse!("{rev}^{{commit}}").is_ok());
+    if fetch && !is_pinned {
+        run!(env, ["git", "fetch", "--all"]).whatever("unable to fetch updates of repository")?;
+    } else if is_pinned {
+        debug!("reusing checkout of pinned revision in {path:?}");
+    }
     let mut commit = rev_parse!("refs/remotes/origin/HEAD^{{commit}}")?;
     if let Some(tag) = &config.tag {
         commit = rev_parse!("refs/tags/{tag}^{{commit}}")?;
@@ -0,0 +1,52 @@
+//! Statistics collected while building the layers of a project.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+/// Statistics of the builds of a project.
+///
+/// Shared by all references to the project, also across reloads.
+#[derive(Debug, Default)]
+pub struct BuildStats {
+    /// Durations of the steps run so far.
+    timings: Mutex<Vec<StepTiming>>,
+    /// Number of recipes applied so far.
+    recipes_applied: AtomicUsize,
+}
+
+impl BuildStats {
+    /// Add the given step durations.
+    pub fn add_timings(&self, timings: impl IntoIterator<Item = StepTiming>) {
+        self.timings.lock().unwrap().extend(timings);
+    }
+
+    /// Durations of the steps run so far.
+    pub fn timings(&self) -> Vec<StepTiming> {
+        self.timings.lock().unwrap().clone()
+    }
+
+    /// Add the given number of applied recipes.
+    pub fn add_recipes_applied(&self, count: usize) {
+        self.recipes_applied.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Number of recipes applied so far.
+    pub fn recipes_applied(&self) -> usize {
+        self.recipes_applied.load(Ordering::Relaxed)
+    }
+}
+
+/// Duration of a step of a recipe.
+#[derive(Debug, Clone, Serialize)]
+pub struct StepTiming {
+    /// Name of the layer the recipe has been applied to.
+    pub layer: String,
+    /// Name of the recipe.
+    pub recipe: String,
+    /// Filename of the step.
+    pub step: String,
+    /// Duration of the step in seconds.
+    pub duration_secs: f64,
+}
@@ -30,7 +30,13 @@ pub fn main(project: &ProjectRef, test_path: &Path) -> BakeryResult<()> {
 
     for system in &test_config.systems {
         let system_out = Path::new("build").join(&system.system);
-        oven::bake_system(&project, &system.system, &system_out).whatever("error baking system")?;
+        oven::bake_system(
+            &project,
+            &system.system,
+            &system_out,
+            &oven::SystemOpts::default(),
+        )
+        .whatever("error baking system")?;
 
         let test_status = rugix_cli::add_status(TestCliStatus {
             total_steps: test_config.steps.len() as u64,
@@ -0,0 +1,188 @@
+//! Utilities for working with archives.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+
+use reportify::{bail, ResultExt};
+use tracing::debug;
+use xscript::{run, Run};
+
+use crate::BakeryResult;
+
+/// Extract a `.tar` archive into the given directory.
+///
+/// The progress is reported with the number of bytes read so far and the size of the
+/// archive. Falls back to extracting the archive without progress if its size is unknown.
+pub fn extract_tar(
+    archive: &Path,
+    dst: &Path,
+    progress: &mut dyn FnMut(u64, u64),
+) -> BakeryResult<()> {
+    let Ok(length) = archive.metadata().map(|metadata| metadata.len()) else {
+        debug!("unable to determine archive size, extracting without progress");
+        run!(["tar", "-x", "-f", archive, "-C", dst]).whatever("unable to extract archive")?;
+        return Ok(());
+    };
+    let mut file = File::open(archive).whatever("unable to open archive")?;
+    let mut child = Command::new("tar")
+        .args(["-x", "-f", "-", "-C"])
+        .arg(dst)
+        .stdin(Stdio::piped())
+        .spawn()
+        .whatever("unable to spawn `tar`")?;
+    let mut stdin = child.stdin.take().unwrap();
+    let mut buffer = vec![0; 64 * 1024];
+    let mut position = 0;
+    loop {
+        let read = match file.read(&mut buffer) {
+            Ok(read) => read,
+            Err(error) => {
+                abort(&mut child);
+                return Err(error).whatever("unable to read from archive");
+            }
+        };
+        if read == 0 {
+            break;
+        }
+        if stdin.write_all(&buffer[..read]).is_err() {
+            // `tar` exited prematurely, the exit status below reports the error.
+            break;
+        }
+        position += read as u64;
+        progress(position, length);
+    }
+    drop(stdin);
+    let status = child.wait().whatever("unable to wait for `tar`")?;
+    if !status.success() {
+        bail!("unable to extract archive, `tar` exited with {status}");
+    }
+    Ok(())
+}
+
+/// Pack the contents of the given directory into a `.tar` archive.
+///
+/// The progress is reported with the number of bytes written so far and an estimate of
+/// the size of the archive computed from the sizes of the files in the directory. If
+/// packing fails, the partial archive is removed.
+pub fn pack_tar(
+    src: &Path,
+    archive: &Path,
+    progress: &mut dyn FnMut(u64, u64),
+) -> BakeryResult<()> {
+    let length = estimate_tar_size(src).whatever("unable to determine size of directory")?;
+    let mut file = File::create(archive).whatever("unable to create archive")?;
+    let mut child = match Command::new("tar")
+        .args(["-c", "-f", "-", "-C"])
+        .arg(src)
+        .arg(".")
+        .stdout(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(error) => {
+            let _ = std::fs::remove_file(archive);
+            return Err(error).whatever("unable to spawn `tar`");
+        }
+    };
+    let result = copy_output(&mut child, &mut file, length, progress);
+    drop(file);
+    if result.is_err() {
+        let _ = std::fs::remove_file(archive);
+    }
+    result
+}
+
+/// Copy the output of the given `tar` process into the given archive file and wait for
+/// the process to exit.
+///
+/// If copying fails, the process is killed and reaped.
+fn copy_output(
+    child: &mut Child,
+    file: &mut File,
+    length: u64,
+    progress: &mut dyn FnMut(u64, u64),
+) -> BakeryResult<()> {
+    let mut stdout = child.stdout.take().unwrap();
+    let mut buffer = vec![0; 64 * 1024];
+    let mut position = 0;
+    loop {
+        let read = match stdout.read(&mut buffer) {
+            Ok(read) => read,
+            Err(error) => {
+                abort(child);
+                return Err(error).whatever("unable to read from `tar`");
+            }
+        };
+        if read == 0 {
+            break;
+        }
+        if let Err(error) = file.write_all(&buffer[..read]) {
+            abort(child);
+            return Err(error).whatever("unable to write to archive");
+        }
+        position += read as u64;
+        progress(position, length);
+    }
+    let status = child.wait().whatever("unable to wait for `tar`")?;
+    if !status.success() {
+        bail!("unable to pack archive, `tar` exited with {status}");
+    }
+    Ok(())
+}
+
+/// Kill the given process and wait for it to exit.
+fn abort(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Estimate the size of a `.tar` archive with the contents of the given directory.
+///
+/// Every entry takes a 512-byte header and the contents of files are padded to
+/// 512-byte blocks. Extended headers, e.g., for long names, are not accounted for.
+fn estimate_tar_size(dir: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    let mut stack = vec![dir.to_path_buf()];
+    while let Some(top) = stack.pop() {
+        let metadata = std::fs::symlink_metadata(&top)?;
+        size += 512;
+        if metadata.is_file() {
+            size += metadata.len().div_ceil(512) * 512;
+        } else if metadata.is_dir() {
+            for entry in std::fs::read_dir(&top)? {
+                stack.push(entry?.path());
+            }
+        }
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{estimate_tar_size, pack_tar};
+
+    #[test]
+    fn test_estimate_tar_size() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("file"), vec![0; 1000]).unwrap();
+        fs::create_dir(dir.path().join("empty")).unwrap();
+        // Two directories and one file with two blocks of contents.
+        assert_eq!(estimate_tar_size(dir.path()).unwrap(), 3 * 512 + 1024);
+    }
+
+    #[test]
+    fn test_pack_tar_removes_partial_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, "not a directory").unwrap();
+        // Packing fails as `tar` cannot change into the file.
+        let archive = dir.path().join("layer.tar");
+        let result = pack_tar(&file, &archive, &mut |_, _| ());
+        assert!(result.is_err());
+        assert!(!archive.exists());
+    }
+}
@@ -2,6 +2,7 @@
 
 use std::fs;
 use std::io::{self, Read, Write};
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
@@ -9,62 +10,277 @@ use indicatif::{ProgressBar, ProgressStyle};
 use reportify::{bail, ResultExt};
 use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
 use tracing::info;
 use url::Url;
 
 use crate::BakeryResult;
 
-pub fn download(url: &Url) -> BakeryResult<PathBuf> {
+/// Download the file at the given URL into the given cache directory.
+///
+/// If a SHA-256 checksum is provided, the downloaded file is verified against it before
+/// it is used. Downloads are first written to a `.partial` file, such that interrupted
+/// downloads are resumed, if the server supports range requests.
+pub fn download(url: &Url, sha256: Option<&str>, cache_dir: &Path) -> BakeryResult<PathBuf> {
+    let cache_file_path = cache_dir.join(download_cache_name(url, sha256)?);
+    if cache_file_path.exists() {
+        return Ok(cache_file_path);
+    }
+    info!("downloading `{url}`");
+    fs::create_dir_all(cache_dir).whatever("error creating cache directory")?;
+    let mut partial_path = cache_file_path.clone().into_os_string();
+    partial_path.push(".partial");
+    let partial_path = PathBuf::from(partial_path);
+    let offset = fs::metadata(&partial_path).map_or(0, |metadata| metadata.len());
+    let client = reqwest::blocking::Client::new();
+    let mut request = client.get(url.clone());
+    if offset > 0 {
+        info!("Resuming download at {offset} bytes.");
+        request = request.header(reqwest::header::RANGE, format!("bytes={offset}-"));
+    }
+    let mut response = request.send().whatever("error retrieving URL")?;
+    let status = response.status();
+    if status == reqwest::StatusCode::RANGE_NOT_SATISFIABLE && offset > 0 {
+        // The partial file is complete if it has the length of the remote file, which the
+        // server reports as `bytes */<length>`. Otherwise, the remote file has changed.
+        let complete = response
+            .headers()
+            .get(reqwest::header::CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("bytes */"))
+            .and_then(|length| length.parse::<u64>().ok())
+            == Some(offset);
+        if !complete {
+            info!("Restarting download, the remote file has changed.");
+            fs::remove_file(&partial_path).whatever("unable to remove partial download")?;
+            return download(url, sha256, cache_dir);
+        }
+    } else if status.is_success() {
+        let resumed = status == reqwest::StatusCode::PARTIAL_CONTENT;
+        // The progress starts at the length of the partial file when resuming.
+        let existing = if resumed { offset } else { 0 };
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(resumed)
+            .truncate(!resumed)
+            .open(&partial_path)
+            .whatever("error creating cache file")?;
+        let progress = match response.content_length() {
+            Some(size) => ProgressBar::new(existing + size).with_style(
+                ProgressStyle::with_template(
+                    "[{elapsed_precise}] {bar:40.cyan/blue} {bytes}/{total_bytes} [{bytes_per_sec}] {msg}",
+                )
+                .unwrap(),
+            ),
+            None => ProgressBar::new_spinner(),
+        }
+        .with_position(existing);
+        let mut buffer = vec![0u8; 8096];
+        loop {
+            let chunk_size = response
+                .read(&mut buffer)
+                .whatever("error reading from response")?;
+            if chunk_size > 0 {
+                file.write_all(&buffer[..chunk_size])
+                    .whatever("error writing to cache file")?;
+                progress.inc(chunk_size as u64);
+            } else {
+                break;
+            }
+        }
+        progress.finish_and_clear();
+    } else {
+        bail!("error downloading file: {status}");
+    }
+    if let Some(expected) = sha256 {
+        let actual = file_sha256(&partial_path).whatever("unable to hash downloaded file")?;
+        if !actual.eq_ignore_ascii_case(expected) {
+            fs::remove_file(&partial_path).ok();
+            bail!("checksum mismatch for `{url}`: expected {expected}, found {actual}");
+        }
+    }
+    fs::rename(&partial_path, &cache_file_path).whatever("unable to move downloaded file")?;
+    Ok(cache_file_path)
+}
+
+/// Name of the cache file for the given URL and checksum.
+///
+/// The name is derived from the URL and the checksum, such that changing the checksum
+/// leads to a new download, and keeps the extensions of the downloaded file.
+fn download_cache_name(url: &Url, sha256: Option<&str>) -> BakeryResult<String> {
     let Some(file_name) = url.path_segments().and_then(|segments| segments.last()) else {
         bail!("unable to obtain file name from URL");
     };
     let file_extension = file_name.split_once('.').map(|(_, extension)| extension);
     let mut url_hasher = Sha1::new();
     url_hasher.update(url.as_str().as_bytes());
+    if let Some(sha256) = sha256 {
+        url_hasher.update(b"\0");
+        url_hasher.update(sha256.to_ascii_lowercase().as_bytes());
+    }
     let url_hash = url_hasher.finalize();
     let mut cache_file_name = hex::encode(url_hash);
     if let Some(extension) = file_extension {
         cache_file_name.push('.');
         cache_file_name.push_str(extension);
     }
-    let cache_file_path = Path::new(".rugix/cache").join(cache_file_name);
-    if !cache_file_path.exists() {
-        info!("downloading `{url}`");
-        std::fs::create_dir_all(".rugix/cache").whatever("error creating cache directory")?;
-        let mut response = reqwest::blocking::get(url.clone()).whatever("error retrieving URL")?;
-        if response.status().is_success() {
-            let Some(size) = response.content_length() else {
-                bail!("server did not send `Content-Length` header");
-            };
-            let progress = ProgressBar::new(size).with_style(
-                ProgressStyle::with_template(
-                    "[{elapsed_precise}] {bar:40.cyan/blue} {bytes}/{total_bytes} [{bytes_per_sec}] {msg}",
-                )
-                .unwrap(),
-            );
-            let mut file =
-                fs::File::create(&cache_file_path).whatever("error creating hash file")?;
-            let mut buffer = vec![0u8; 8096];
-            loop {
-                let chunk_size = response
-                    .read(&mut buffer)
-                    .whatever("error reading from response")?;
-                if chunk_size > 0 {
-                    file.write_all(&buffer[..chunk_size])
-                        .whatever("error writing to cache file")?;
-                    progress.inc(chunk_size as u64);
-                } else {
-                    break;
-                }
-            }
-        } else {
-            bail!("error downloading file: {}", response.status());
-        }
+    Ok(cache_file_name)
+}
+
+/// Store a copy of the given archive in the given directory, named by its content hash.
+///
+/// The copy is named `sha1-<hash>.<extension>` where `<hash>` is the hex-encoded SHA-1
+/// digest of the archive's contents and `<extension>` is the extension of the archive.
+/// If a copy with the same name already exists, it is reused.
+pub fn store_hashed(archive: &Path, dir: &Path) -> BakeryResult<PathBuf> {
+    let mut name = format!(
+        "sha1-{}",
+        file_digest(archive).whatever("unable to hash archive")?
+    );
+    if let Some(extension) = archive.extension() {
+        name.push('.');
+        name.push_str(&extension.to_string_lossy());
     }
-    Ok(cache_file_path)
+    let path = dir.join(name);
+    if !path.exists() {
+        store_copy(archive, &path)?;
+    }
+    info!("Stored archive as {path:?}.");
+    Ok(path)
 }
 
-#[derive(Debug, Default)]
+/// Atomically store a copy of the given archive at the given path.
+pub fn store_copy(archive: &Path, path: &Path) -> BakeryResult<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).whatever("unable to create output directory")?;
+    }
+    // The archive may be overwritten in place by later builds, so we cannot hardlink it.
+    let mut temp_path = path.to_path_buf().into_os_string();
+    temp_path.push(".partial");
+    fs::copy(archive, &temp_path).whatever("unable to copy archive")?;
+    fs::rename(&temp_path, path).whatever("unable to rename archive")?;
+    Ok(())
+}
+
+/// Hex-encoded SHA-1 digest of the contents of the given file.
+pub fn file_digest(path: &Path) -> Result<String, io::Error> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha1::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Hex-encoded SHA-256 digest of the contents of the given file.
+pub fn file_sha256(path: &Path) -> Result<String, io::Error> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Path of the SHA-256 checksum file accompanying the given file.
+pub fn checksum_path(path: &Path) -> PathBuf {
+    let mut checksum_path = path.as_os_str().to_owned();
+    checksum_path.push(".sha256");
+    PathBuf::from(checksum_path)
+}
+
+/// Write the SHA-256 checksum of the given file to its checksum file.
+///
+/// The checksum file uses the format of `sha256sum` such that it can also be checked
+/// with `sha256sum --check`.
+pub fn write_checksum(path: &Path) -> BakeryResult<()> {
+    let digest = file_sha256(path)
+        .whatever("unable to hash file")
+        .with_info(|_| format!("path: {path:?}"))?;
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    fs::write(checksum_path(path), format!("{digest}  {name}\n"))
+        .whatever("unable to write checksum file")
+        .with_info(|_| format!("path: {path:?}"))
+}
+
+/// Verify the given file against its checksum file, if there is one.
+pub fn verify_checksum(path: &Path) -> BakeryResult<()> {
+    let checksum_path = checksum_path(path);
+    if !checksum_path.exists() {
+        return Ok(());
+    }
+    let contents = fs::read_to_string(&checksum_path)
+        .whatever("unable to read checksum file")
+        .with_info(|_| format!("path: {checksum_path:?}"))?;
+    let Some(expected) = contents.split_whitespace().next() else {
+        bail!("checksum file {checksum_path:?} is empty");
+    };
+    info!("Verifying checksum of {path:?}.");
+    let actual = file_sha256(path)
+        .whatever("unable to hash file")
+        .with_info(|_| format!("path: {path:?}"))?;
+    if !actual.eq_ignore_ascii_case(expected) {
+        bail!("checksum mismatch for {path:?}, expected {expected} but found {actual}");
+    }
+    Ok(())
+}
+
+/// Compute a content-based cache key for applying the given recipes to a layer.
+///
+/// The key covers the contents of the source archive, if any, the keys of the recipes,
+/// which must cover their contents and resolved parameters, in the order they are
+/// applied, and the given settings, which must include everything else influencing the
+/// resulting layer.
+pub fn recipes_cache_key(
+    src: Option<&Path>,
+    recipes: &[String],
+    settings: &[(&str, &[u8])],
+) -> BakeryResult<String> {
+    let mut hasher = Hasher::new();
+    if let Some(src) = src {
+        hasher.push(
+            "src",
+            file_digest(src).whatever("unable to hash source layer")?,
+        );
+    }
+    for recipe in recipes {
+        hasher.push("recipe", recipe);
+    }
+    for (tag, value) in settings {
+        hasher.push(tag, value);
+    }
+    Ok(hasher.finalize())
+}
+
+/// Compute content-based keys for the states of a layer after applying each recipe.
+///
+/// The key of each state covers the contents of the source archive, if any, the given
+/// settings, and the keys of the recipes applied up to and including the respective
+/// recipe (see [`recipes_cache_key`]).
+pub fn checkpoint_keys(
+    src: Option<&Path>,
+    recipes: &[String],
+    settings: &[(&str, &[u8])],
+) -> BakeryResult<Vec<String>> {
+    let mut hasher = Hasher::new();
+    if let Some(src) = src {
+        hasher.push(
+            "src",
+            file_digest(src).whatever("unable to hash source layer")?,
+        );
+    }
+    for (tag, value) in settings {
+        hasher.push(tag, value);
+    }
+    let mut keys = Vec::with_capacity(recipes.len());
+    for recipe in recipes {
+        hasher.push("recipe", recipe);
+        keys.push(hasher.clone().finalize());
+    }
+    Ok(keys)
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct Hasher {
     hasher: Sha1,
 }
@@ -124,3 +340,91 @@ pub fn mtime_recursive(path: &Path) -> Result<ModificationTime, io::Error> {
     }
     Ok(time)
 }
+
+/// Recursively hash the contents of a directory.
+///
+/// Entries are hashed in the order of their paths relative to the given directory. Only
+/// paths, contents, symlink targets, and executable bits are hashed, such that the hash
+/// is stable across checkouts on different machines.
+pub fn hash_recursive(hasher: &mut Hasher, path: &Path) -> Result<(), io::Error> {
+    hash_dir(hasher, path, Path::new(""))
+}
+
+fn hash_dir(hasher: &mut Hasher, root: &Path, dir: &Path) -> Result<(), io::Error> {
+    let mut names = fs::read_dir(root.join(dir))?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<Result<Vec<_>, _>>()?;
+    names.sort();
+    for name in names {
+        let relative = dir.join(name);
+        let path = root.join(&relative);
+        hasher.push("path", relative.as_os_str().as_encoded_bytes());
+        let metadata = fs::symlink_metadata(&path)?;
+        if metadata.is_symlink() {
+            hasher.push(
+                "symlink",
+                fs::read_link(&path)?.as_os_str().as_encoded_bytes(),
+            );
+        } else if metadata.is_dir() {
+            hasher.push("dir", "");
+            hash_dir(hasher, root, &relative)?;
+        } else {
+            let executable = metadata.permissions().mode() & 0o111 != 0;
+            hasher.push("executable", if executable { "1" } else { "0" });
+            hasher.push("file", fs::read(&path)?);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use url::Url;
+
+    use super::{
+        checkpoint_keys, checksum_path, download_cache_name, verify_checksum, write_checksum,
+    };
+
+    #[test]
+    fn test_checkpoint_keys() {
+        let recipes = ["first".to_owned(), "second".to_owned()];
+        let settings = [("arch", b"arm64".as_slice())];
+        let keys = checkpoint_keys(None, &recipes, &settings).unwrap();
+        let prefix = checkpoint_keys(None, &recipes[..1], &settings).unwrap();
+        assert_eq!(keys[..1], prefix);
+        let changed =
+            checkpoint_keys(None, &["first".to_owned(), "third".to_owned()], &settings).unwrap();
+        assert_eq!(keys[0], changed[0]);
+        assert_ne!(keys[1], changed[1]);
+        let other = checkpoint_keys(None, &recipes[..1], &[("arch", b"amd64".as_slice())]).unwrap();
+        assert_ne!(keys[0], other[0]);
+    }
+
+    #[test]
+    fn test_download_cache_name() {
+        let url = Url::parse("https://example.com/images/raspios.img.xz").unwrap();
+        let plain = download_cache_name(&url, None).unwrap();
+        let verified = download_cache_name(&url, Some("ABCD")).unwrap();
+        assert!(plain.ends_with(".img.xz"));
+        assert!(verified.ends_with(".img.xz"));
+        assert_ne!(plain, verified);
+        assert_eq!(verified, download_cache_name(&url, Some("abcd")).unwrap());
+    }
+
+    #[test]
+    fn test_verify_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("system.tar");
+        fs::write(&archive, b"layer contents").unwrap();
+        // Files without checksum file are not verified.
+        verify_checksum(&archive).unwrap();
+        write_checksum(&archive).unwrap();
+        let checksum = fs::read_to_string(checksum_path(&archive)).unwrap();
+        assert!(checksum.ends_with("  system.tar\n"));
+        verify_checksum(&archive).unwrap();
+        fs::write(&archive, b"layer").unwrap();
+        assert!(verify_checksum(&archive).is_err());
+    }
+}
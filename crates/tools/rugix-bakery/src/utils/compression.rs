@@ -0,0 +1,128 @@
+//! Compression of build outputs.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use clap::ValueEnum;
+use reportify::{bail, ResultExt};
+use tracing::info;
+
+use crate::BakeryResult;
+
+/// Compression format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    /// XZ compression (`.xz`).
+    Xz,
+    /// Gzip compression (`.gz`).
+    Gzip,
+    /// Zstandard compression (`.zst`).
+    Zstd,
+}
+
+impl Compression {
+    /// Name of the compressor binary.
+    pub fn program(self) -> &'static str {
+        match self {
+            Compression::Xz => "xz",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    /// File extension of compressed files.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::Xz => "xz",
+            Compression::Gzip => "gz",
+            Compression::Zstd => "zst",
+        }
+    }
+
+    /// Arguments of the compressor for the given compression level.
+    ///
+    /// Zstandard requires `--ultra` for levels above 19.
+    pub fn level_args(self, level: u32) -> Vec<String> {
+        let mut args = Vec::new();
+        if self == Compression::Zstd && level > 19 {
+            args.push("--ultra".to_owned());
+        }
+        args.push(format!("-{level}"));
+        args
+    }
+
+    /// Path of the compressed file for the given file.
+    pub fn compressed_path(self, path: &Path) -> PathBuf {
+        let mut compressed = path.as_os_str().to_owned();
+        compressed.push(".");
+        compressed.push(self.extension());
+        PathBuf::from(compressed)
+    }
+}
+
+/// Compress the given file with the given compression level, if any.
+///
+/// The file is streamed through the compressor and written to *compressed*. The original
+/// file is left untouched.
+pub fn compress_file(
+    path: &Path,
+    compressed: &Path,
+    compression: Compression,
+    level: Option<u32>,
+) -> BakeryResult<()> {
+    info!("Compressing {path:?} with {}.", compression.program());
+    let input = File::open(path)
+        .whatever("unable to open file for compression")
+        .with_info(|_| format!("path: {path:?}"))?;
+    let output = File::create(compressed)
+        .whatever("unable to create compressed file")
+        .with_info(|_| format!("path: {compressed:?}"))?;
+    let mut command = Command::new(compression.program());
+    command.arg("-c");
+    if let Some(level) = level {
+        command.args(compression.level_args(level));
+    }
+    if matches!(compression, Compression::Xz | Compression::Zstd) {
+        // Use all available cores.
+        command.arg("-T0");
+    }
+    let status = command
+        .stdin(input)
+        .stdout(output)
+        .status()
+        .whatever("unable to run compressor")
+        .with_info(|_| format!("compressor: {}", compression.program()))?;
+    if !status.success() {
+        std::fs::remove_file(compressed).ok();
+        bail!("{} failed with {status}", compression.program());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::Compression;
+
+    #[test]
+    fn test_compressed_path() {
+        let path = Path::new("build/customized/system.img");
+        assert_eq!(
+            Compression::Xz.compressed_path(path),
+            Path::new("build/customized/system.img.xz")
+        );
+        assert_eq!(
+            Compression::Zstd.compressed_path(path),
+            Path::new("build/customized/system.img.zst")
+        );
+    }
+
+    #[test]
+    fn test_level_args() {
+        assert_eq!(Compression::Zstd.level_args(19), ["-19"]);
+        assert_eq!(Compression::Zstd.level_args(22), ["--ultra", "-22"]);
+        assert_eq!(Compression::Xz.level_args(9), ["-9"]);
+    }
+}
@@ -0,0 +1,132 @@
+//! Deduplication of identical files via hardlinks.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use sha1::{Digest, Sha1};
+
+/// Statistics of a deduplication pass.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DedupStats {
+    /// Number of files replaced by hardlinks.
+    pub files: u64,
+    /// Number of bytes saved.
+    pub bytes: u64,
+}
+
+/// Replace byte-identical regular files within the given directory with hardlinks.
+///
+/// Only files with the same mode and ownership are linked, as hardlinks share both.
+pub fn dedup_files(root: &Path) -> io::Result<DedupStats> {
+    // Group candidates by size, mode, and ownership first to avoid hashing unique files.
+    let mut candidates = HashMap::<(u64, u32, u32, u32), Vec<PathBuf>>::new();
+    let mut stack = vec![root.to_path_buf()];
+    while let Some(dir) = stack.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                stack.push(entry.path());
+            } else if metadata.is_file() && metadata.len() > 0 {
+                let key = (
+                    metadata.len(),
+                    metadata.mode(),
+                    metadata.uid(),
+                    metadata.gid(),
+                );
+                candidates.entry(key).or_default().push(entry.path());
+            }
+        }
+    }
+    let mut stats = DedupStats::default();
+    for ((size, ..), paths) in candidates {
+        if paths.len() < 2 {
+            continue;
+        }
+        let mut originals = HashMap::<[u8; 20], Vec<PathBuf>>::new();
+        for path in paths {
+            let digest = hash_file(&path)?;
+            let originals = originals.entry(digest).or_default();
+            let mut linked = false;
+            for original in originals.iter() {
+                if is_same_inode(original, &path)? {
+                    linked = true;
+                    break;
+                }
+                if fs::read(original)? == fs::read(&path)? {
+                    replace_with_link(original, &path)?;
+                    stats.files += 1;
+                    stats.bytes += size;
+                    linked = true;
+                    break;
+                }
+            }
+            if !linked {
+                originals.push(path);
+            }
+        }
+    }
+    Ok(stats)
+}
+
+/// Compute the SHA1 digest of the given file.
+fn hash_file(path: &Path) -> io::Result<[u8; 20]> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha1::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// Check whether both paths refer to the same inode.
+fn is_same_inode(a: &Path, b: &Path) -> io::Result<bool> {
+    let a = fs::metadata(a)?;
+    let b = fs::metadata(b)?;
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+/// Atomically replace the `duplicate` with a hardlink to the `original`.
+fn replace_with_link(original: &Path, duplicate: &Path) -> io::Result<()> {
+    let mut temp_name = duplicate.as_os_str().to_owned();
+    temp_name.push(".rugix-dedup");
+    let temp_path = PathBuf::from(temp_name);
+    fs::hard_link(original, &temp_path)?;
+    fs::rename(&temp_path, duplicate)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+
+    use super::dedup_files;
+
+    #[test]
+    fn test_dedup_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::write(root.join("a/x"), "identical").unwrap();
+        fs::write(root.join("a/b/y"), "identical").unwrap();
+        fs::write(root.join("a/b/z"), "different").unwrap();
+        let stats = dedup_files(root).unwrap();
+        assert_eq!(stats.files, 1);
+        assert_eq!(stats.bytes, 9);
+        let x = fs::metadata(root.join("a/x")).unwrap();
+        let y = fs::metadata(root.join("a/b/y")).unwrap();
+        let z = fs::metadata(root.join("a/b/z")).unwrap();
+        assert_eq!(x.ino(), y.ino());
+        assert_ne!(x.ino(), z.ino());
+        // Running the pass again must not link anything.
+        assert_eq!(dedup_files(root).unwrap().files, 0);
+    }
+}
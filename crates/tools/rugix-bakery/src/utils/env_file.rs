@@ -0,0 +1,95 @@
+//! Parsing of dotenv-style environment files.
+
+use std::path::Path;
+
+use reportify::{bail, ResultExt};
+
+use crate::BakeryResult;
+
+/// Load the variables of the given environment file.
+pub fn load_env_file(path: &Path) -> BakeryResult<Vec<(String, String)>> {
+    let contents = std::fs::read_to_string(path)
+        .whatever("unable to read environment file")
+        .with_info(|_| format!("path: {path:?}"))?;
+    parse_env_file(&contents).with_info(|_| format!("path: {path:?}"))
+}
+
+/// Parse the contents of an environment file.
+///
+/// Each non-empty line, which is not a comment starting with `#`, must be of the form
+/// `KEY=value`, optionally prefixed with `export`. Values may be enclosed in single or
+/// double quotes, which are removed. No further escaping or interpolation is performed.
+pub fn parse_env_file(contents: &str) -> BakeryResult<Vec<(String, String)>> {
+    let mut variables = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, value)) = line.split_once('=') else {
+            bail!(
+                "expected `KEY=value` in line {} of environment file",
+                idx + 1
+            );
+        };
+        let key = key.trim();
+        if !is_valid_key(key) {
+            bail!(
+                "invalid variable name `{key}` in line {} of environment file",
+                idx + 1
+            );
+        }
+        let value = value.trim();
+        let value = ['"', '\'']
+            .into_iter()
+            .find_map(|quote| {
+                value
+                    .strip_prefix(quote)
+                    .and_then(|value| value.strip_suffix(quote))
+            })
+            .unwrap_or(value);
+        variables.push((key.to_owned(), value.to_owned()));
+    }
+    Ok(variables)
+}
+
+/// Check whether the given name is a valid name for an environment variable.
+fn is_valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_env_file;
+
+    #[test]
+    fn test_parse_env_file() {
+        let variables = parse_env_file(
+            "# Credentials.\nTOKEN=secret\n\nexport FEATURE = \"on\"\nGREETING='hello world'\nEMPTY=\n",
+        )
+        .unwrap();
+        let expected = [
+            ("TOKEN", "secret"),
+            ("FEATURE", "on"),
+            ("GREETING", "hello world"),
+            ("EMPTY", ""),
+        ];
+        assert_eq!(variables.len(), expected.len());
+        for ((key, value), (expected_key, expected_value)) in variables.iter().zip(expected) {
+            assert_eq!(key, expected_key);
+            assert_eq!(value, expected_value);
+        }
+    }
+
+    #[test]
+    fn test_parse_env_file_invalid() {
+        assert!(parse_env_file("NO_VALUE").is_err());
+        assert!(parse_env_file("1KEY=value").is_err());
+        assert!(parse_env_file("KEY WITH SPACE=value").is_err());
+    }
+}
@@ -140,7 +140,10 @@ type PhantomIdx<I> = PhantomData<fn(&I)>;
 macro_rules! new_idx_type {
     ($(#[$doc:meta])* $vis:vis $name:ident) => {
         $(#[$doc])*
-        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        #[derive(
+            Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash,
+            ::serde::Serialize, ::serde::Deserialize,
+        )]
         $vis struct $name(usize);
 
         impl $crate::utils::idx_vec::Idx for $name {
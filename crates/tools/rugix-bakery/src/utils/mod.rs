@@ -1,6 +1,15 @@
 //! Various utilities.
 
+pub mod archive;
 pub mod caching;
+pub mod compression;
+pub mod dedup;
+pub mod env_file;
 pub mod idx_vec;
 pub mod once_cell_ext;
+pub mod overlay;
 pub mod prelude;
+pub mod setuid;
+pub mod temp;
+pub mod tree_diff;
+pub mod which;
@@ -0,0 +1,223 @@
+//! Utilities for merging the upper directory of an overlay filesystem.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+/// Prepare merging the given upper directory of an overlay filesystem into its lower
+/// directory.
+///
+/// Whiteouts are removed from the upper directory and the files they hide are removed
+/// from the lower directory. Likewise, the lower counterparts of opaque directories and
+/// of entries whose type changed are removed. Finally, the extended attributes used
+/// internally by overlay filesystems are removed from the upper directory, such that they
+/// do not end up in the lower directory. Afterwards, the contents of the upper directory
+/// can simply be copied over the lower directory.
+pub fn prepare_merge(upper: &Path, lower: &Path) -> io::Result<()> {
+    remove_overlay_xattrs(upper)?;
+    for entry in fs::read_dir(upper)? {
+        let entry = entry?;
+        let upper_path = entry.path();
+        let lower_path = lower.join(entry.file_name());
+        let metadata = entry.metadata()?;
+        let lower_metadata = fs::symlink_metadata(&lower_path).ok();
+        if is_whiteout(&metadata) {
+            fs::remove_file(&upper_path)?;
+            if let Some(lower_metadata) = lower_metadata {
+                remove_path(&lower_path, &lower_metadata)?;
+            }
+            continue;
+        }
+        if let Some(lower_metadata) = lower_metadata {
+            let type_changed = metadata.is_dir() != lower_metadata.is_dir();
+            if type_changed || (metadata.is_dir() && is_opaque(&upper_path)?) {
+                remove_path(&lower_path, &lower_metadata)?;
+            }
+        }
+        if metadata.is_dir() {
+            prepare_merge(&upper_path, &lower_path)?;
+        } else {
+            remove_overlay_xattrs(&upper_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Find a path modified in more than one of the given upper directories.
+///
+/// Directories present in multiple upper directories are merged, so only other entries,
+/// including whiteouts, and opaque directories count as modifications. Two upper
+/// directories conflict if they modify the same path or if one of them modifies a path
+/// within a path modified by the other one. Returns the relative path and the indices of
+/// the conflicting upper directories.
+pub fn find_conflict(uppers: &[PathBuf]) -> io::Result<Option<(PathBuf, usize, usize)>> {
+    let mut modified = HashMap::new();
+    for (idx, upper) in uppers.iter().enumerate() {
+        let mut paths = Vec::new();
+        collect_modified(upper, Path::new(""), &mut paths)?;
+        for path in paths {
+            if let Some(other) = modified.insert(path.clone(), idx) {
+                if other != idx {
+                    return Ok(Some((path, other, idx)));
+                }
+            }
+        }
+    }
+    for (path, idx) in &modified {
+        for ancestor in path.ancestors().skip(1) {
+            if let Some(other) = modified.get(ancestor) {
+                if other != idx {
+                    return Ok(Some((path.clone(), *other.min(idx), *other.max(idx))));
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Collect the paths modified in the given upper directory (see [`find_conflict`]).
+fn collect_modified(upper: &Path, relative: &Path, paths: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(upper.join(relative))? {
+        let entry = entry?;
+        let path = relative.join(entry.file_name());
+        if !entry.metadata()?.is_dir() {
+            paths.push(path);
+            continue;
+        }
+        if is_opaque(&upper.join(&path))? {
+            paths.push(path.clone());
+        }
+        collect_modified(upper, &path, paths)?;
+    }
+    Ok(())
+}
+
+/// Check whether the given entry is a whiteout, i.e., a character device `0:0`.
+pub fn is_whiteout(metadata: &fs::Metadata) -> bool {
+    metadata.file_type().is_char_device() && metadata.rdev() == 0
+}
+
+/// Check whether the given directory is marked as opaque.
+pub fn is_opaque(path: &Path) -> io::Result<bool> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let name = c"trusted.overlay.opaque";
+    let mut value = [0u8; 1];
+    // SAFETY: Both strings are NUL-terminated and the buffer size is correct.
+    let size = unsafe {
+        nix::libc::lgetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_mut_ptr().cast(),
+            value.len(),
+        )
+    };
+    if size < 0 {
+        let error = io::Error::last_os_error();
+        return match error.raw_os_error() {
+            Some(nix::libc::ENODATA) | Some(nix::libc::ENOTSUP) => Ok(false),
+            _ => Err(error),
+        };
+    }
+    Ok(size == 1 && value[0] == b'y')
+}
+
+/// Remove the `trusted.overlay.*` extended attributes of the given entry.
+///
+/// Overlay filesystems use these attributes, e.g., to mark directories as opaque.
+fn remove_overlay_xattrs(path: &Path) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: The path is NUL-terminated and a null buffer of size zero queries the size.
+    let size = unsafe { nix::libc::llistxattr(path.as_ptr(), std::ptr::null_mut(), 0) };
+    if size < 0 {
+        let error = io::Error::last_os_error();
+        return match error.raw_os_error() {
+            Some(nix::libc::ENOTSUP) => Ok(()),
+            _ => Err(error),
+        };
+    }
+    let mut names = vec![0u8; size as usize];
+    // SAFETY: The path is NUL-terminated and the buffer size is correct.
+    let size =
+        unsafe { nix::libc::llistxattr(path.as_ptr(), names.as_mut_ptr().cast(), names.len()) };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    names.truncate(size as usize);
+    for name in names
+        .split(|byte| *byte == 0)
+        .filter(|name| name.starts_with(b"trusted.overlay."))
+    {
+        let name = CString::new(name)?;
+        // SAFETY: Both strings are NUL-terminated.
+        if unsafe { nix::libc::lremovexattr(path.as_ptr(), name.as_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Remove the given file or directory.
+fn remove_path(path: &Path, metadata: &fs::Metadata) -> io::Result<()> {
+    if metadata.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{find_conflict, prepare_merge};
+
+    #[test]
+    fn test_prepare_merge_type_changes() {
+        let upper = tempfile::tempdir().unwrap();
+        let lower = tempfile::tempdir().unwrap();
+        fs::create_dir_all(lower.path().join("a/b")).unwrap();
+        fs::write(lower.path().join("a/b/x"), "x").unwrap();
+        fs::write(lower.path().join("c"), "c").unwrap();
+        fs::write(lower.path().join("d"), "d").unwrap();
+        // Replace the directory `a/b` with a file and the file `c` with a directory.
+        fs::create_dir_all(upper.path().join("a")).unwrap();
+        fs::write(upper.path().join("a/b"), "b").unwrap();
+        fs::create_dir_all(upper.path().join("c")).unwrap();
+        prepare_merge(upper.path(), lower.path()).unwrap();
+        assert!(!lower.path().join("a/b").exists());
+        assert!(lower.path().join("a").is_dir());
+        assert!(!lower.path().join("c").exists());
+        assert!(lower.path().join("d").is_file());
+        assert!(upper.path().join("a/b").is_file());
+    }
+
+    #[test]
+    fn test_find_conflict() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let uppers = ["a", "b", "c"].map(|name| temp_dir.path().join(name));
+        for upper in &uppers {
+            fs::create_dir_all(upper.join("etc")).unwrap();
+        }
+        fs::write(uppers[0].join("etc/a.conf"), "a").unwrap();
+        fs::write(uppers[1].join("etc/b.conf"), "b").unwrap();
+        // Directories are merged, so they do not conflict.
+        assert_eq!(find_conflict(&uppers).unwrap(), None);
+        fs::write(uppers[2].join("etc/a.conf"), "c").unwrap();
+        assert_eq!(
+            find_conflict(&uppers).unwrap(),
+            Some(("etc/a.conf".into(), 0, 2))
+        );
+        fs::remove_file(uppers[2].join("etc/a.conf")).unwrap();
+        // Replacing a file with a directory conflicts with the file's contents.
+        fs::create_dir_all(uppers[2].join("etc/b.conf")).unwrap();
+        fs::write(uppers[2].join("etc/b.conf/c.conf"), "c").unwrap();
+        assert_eq!(
+            find_conflict(&uppers).unwrap(),
+            Some(("etc/b.conf/c.conf".into(), 1, 2))
+        );
+    }
+}
@@ -0,0 +1,300 @@
+//! Auditing of setuid and setgid files of layer archives.
+
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use reportify::{bail, ResultExt};
+use tracing::{error, info};
+
+use crate::BakeryResult;
+
+/// Mask of the setuid and setgid bits of a file mode.
+const SETUID_MASK: u32 = 0o6000;
+
+/// Size of a block of a `.tar` archive.
+const BLOCK_SIZE: usize = 512;
+
+/// Directory of the root filesystem within layer archives.
+const SYSTEM_ROOT: &str = "roots/system";
+
+/// Setuid or setgid file of a root filesystem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SetuidFile {
+    /// Absolute path of the file within the root filesystem.
+    pub path: PathBuf,
+    /// Mode of the file.
+    pub mode: u32,
+}
+
+/// Check that all setuid and setgid files of the given layer archive are on the
+/// allowlist stored at the given path.
+///
+/// Fails if the allowlist cannot be read, reporting all files not on the allowlist.
+pub fn audit_layer(archive: &Path, allowlist: &Path) -> BakeryResult<()> {
+    info!("Auditing setuid and setgid files.");
+    let allowlist = std::fs::read_to_string(allowlist)
+        .whatever("unable to read setuid allowlist")
+        .with_info(|_| format!("allowlist: {allowlist:?}"))?;
+    let allowlist = match parse_allowlist(&allowlist) {
+        Ok(allowlist) => allowlist,
+        Err(error) => bail!("invalid setuid allowlist: {error}"),
+    };
+    let files = find_setuid_files(archive)
+        .whatever("unable to audit setuid files")
+        .with_info(|_| format!("layer: {archive:?}"))?;
+    let offenders = files
+        .iter()
+        .filter(|file| !allowlist.contains(&file.path))
+        .collect::<Vec<_>>();
+    if offenders.is_empty() {
+        return Ok(());
+    }
+    for offender in &offenders {
+        error!(
+            "setuid/setgid file not on allowlist: {} ({:04o})",
+            offender.path.display(),
+            offender.mode
+        );
+    }
+    bail!(
+        "found {} setuid/setgid files not on the allowlist",
+        offenders.len()
+    );
+}
+
+/// Find all files with the setuid or setgid bit within the root filesystem of the given
+/// layer archive.
+///
+/// Only the headers of the archive are inspected, the archive is not extracted. Regular
+/// files and hardlinks are considered. Returned paths are absolute with respect to the
+/// root filesystem and sorted.
+pub fn find_setuid_files(archive: &Path) -> io::Result<Vec<SetuidFile>> {
+    let mut reader = BufReader::new(File::open(archive)?);
+    let mut files = Vec::new();
+    let mut long_name = None;
+    let mut header = [0; BLOCK_SIZE];
+    loop {
+        if !read_block(&mut reader, &mut header)? || header.iter().all(|byte| *byte == 0) {
+            break;
+        }
+        let size = parse_number(&header[124..136])?;
+        let entry_type = header[156];
+        match entry_type {
+            // GNU long name and PAX extended header, both apply to the next entry.
+            b'L' => {
+                let data = read_data(&mut reader, size)?;
+                let name = data.split(|byte| *byte == 0).next().unwrap_or_default();
+                long_name = Some(bytes_to_path(name));
+                continue;
+            }
+            b'x' => {
+                let data = read_data(&mut reader, size)?;
+                if let Some(path) = parse_pax_path(&data) {
+                    long_name = Some(path);
+                }
+                continue;
+            }
+            _ => (),
+        }
+        let name = long_name.take().unwrap_or_else(|| header_name(&header));
+        let mode = parse_number(&header[100..108])? as u32 & 0o7777;
+        if matches!(entry_type, b'0' | 0 | b'7' | b'1') && mode & SETUID_MASK != 0 {
+            if let Some(path) = system_path(&name) {
+                files.push(SetuidFile { path, mode });
+            }
+        }
+        // Hardlinks do not have any contents even if they specify a size.
+        if entry_type != b'1' {
+            skip_data(&mut reader, size)?;
+        }
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+/// Parse an allowlist of setuid and setgid files.
+///
+/// The allowlist contains one absolute path per line. Empty lines and lines starting
+/// with `#` are ignored.
+pub fn parse_allowlist(allowlist: &str) -> Result<HashSet<PathBuf>, String> {
+    let mut paths = HashSet::new();
+    for (idx, line) in allowlist.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if !line.starts_with('/') {
+            return Err(format!("line {}: path `{line}` is not absolute", idx + 1));
+        }
+        paths.insert(PathBuf::from(line));
+    }
+    Ok(paths)
+}
+
+/// Read a block, returning `false` at the end of the archive.
+fn read_block(reader: &mut impl Read, block: &mut [u8; BLOCK_SIZE]) -> io::Result<bool> {
+    match reader.read_exact(block) {
+        Ok(()) => Ok(true),
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(error) => Err(error),
+    }
+}
+
+/// Read the data of an entry including its padding.
+fn read_data(reader: &mut impl Read, size: u64) -> io::Result<Vec<u8>> {
+    let mut data = vec![0; padded(size) as usize];
+    reader.read_exact(&mut data)?;
+    data.truncate(size as usize);
+    Ok(data)
+}
+
+/// Skip the data of an entry including its padding.
+fn skip_data(reader: &mut impl Read, size: u64) -> io::Result<()> {
+    let padded = padded(size);
+    let skipped = io::copy(&mut reader.take(padded), &mut io::sink())?;
+    if skipped != padded {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "archive is truncated",
+        ));
+    }
+    Ok(())
+}
+
+/// Size of the given number of bytes padded to full blocks.
+fn padded(size: u64) -> u64 {
+    size.div_ceil(BLOCK_SIZE as u64) * BLOCK_SIZE as u64
+}
+
+/// Parse a numeric header field, either octal or GNU base-256.
+fn parse_number(field: &[u8]) -> io::Result<u64> {
+    if field[0] & 0x80 != 0 {
+        return Ok(field[1..]
+            .iter()
+            .fold(0, |value, byte| (value << 8) | u64::from(*byte)));
+    }
+    let digits = field
+        .iter()
+        .copied()
+        .skip_while(|byte| *byte == b' ')
+        .take_while(|byte| (b'0'..=b'7').contains(byte))
+        .collect::<Vec<_>>();
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(std::str::from_utf8(&digits).unwrap(), 8)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid numeric header field"))
+}
+
+/// Name of the entry of the given header, including the prefix of UStar headers.
+fn header_name(header: &[u8; BLOCK_SIZE]) -> PathBuf {
+    let name = nul_terminated(&header[0..100]);
+    // GNU headers use the space of the prefix for other fields.
+    if &header[257..263] == b"ustar\0" && header[345] != 0 {
+        let mut path = bytes_to_path(nul_terminated(&header[345..500]));
+        path.push(bytes_to_path(name));
+        path
+    } else {
+        bytes_to_path(name)
+    }
+}
+
+/// Extract the `path` record of a PAX extended header.
+fn parse_pax_path(mut data: &[u8]) -> Option<PathBuf> {
+    let mut path = None;
+    while let Some(space) = data.iter().position(|byte| *byte == b' ') {
+        let length = std::str::from_utf8(&data[..space]).ok()?.parse().ok()?;
+        if length <= space || length > data.len() {
+            break;
+        }
+        // Records have the form `<length> <key>=<value>\n`.
+        let record = &data[space + 1..length - 1];
+        if let Some(value) = record.strip_prefix(b"path=") {
+            path = Some(bytes_to_path(value));
+        }
+        data = &data[length..];
+    }
+    path
+}
+
+/// Path of the given archive entry within the root filesystem, if it is part of it.
+fn system_path(name: &Path) -> Option<PathBuf> {
+    let relative = name.strip_prefix(".").unwrap_or(name);
+    let relative = relative.strip_prefix(SYSTEM_ROOT).ok()?;
+    Some(Path::new("/").join(relative))
+}
+
+/// Contents of the given NUL-terminated header field.
+fn nul_terminated(field: &[u8]) -> &[u8] {
+    field.split(|byte| *byte == 0).next().unwrap_or_default()
+}
+
+/// Convert the given raw bytes of a path into a path.
+fn bytes_to_path(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(OsStr::from_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    use xscript::{run, Run};
+
+    use super::{find_setuid_files, parse_allowlist, SetuidFile};
+
+    #[test]
+    fn test_find_setuid_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let bundle_dir = temp_dir.path().join("bundle");
+        let bin_dir = bundle_dir.join("roots/system/usr/bin");
+        let long_dir = bundle_dir.join("roots/system/opt").join("a".repeat(120));
+        fs::create_dir_all(&bin_dir).unwrap();
+        fs::create_dir_all(&long_dir).unwrap();
+        fs::create_dir_all(bundle_dir.join("roots/boot")).unwrap();
+        for (path, mode) in [
+            (bin_dir.join("sudo"), 0o4755),
+            (bin_dir.join("wall"), 0o2755),
+            (bin_dir.join("ls"), 0o755),
+            (long_dir.join("helper"), 0o4750),
+            (bundle_dir.join("roots/boot/setuid"), 0o4755),
+        ] {
+            fs::write(&path, "binary").unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        }
+        std::os::unix::fs::symlink("sudo", bin_dir.join("sudo-link")).unwrap();
+        let archive = temp_dir.path().join("system.tar");
+        run!(["tar", "-c", "-f", &archive, "-C", &bundle_dir, "."]).unwrap();
+        assert_eq!(
+            find_setuid_files(&archive).unwrap(),
+            vec![
+                SetuidFile {
+                    path: PathBuf::from("/opt").join("a".repeat(120)).join("helper"),
+                    mode: 0o4750,
+                },
+                SetuidFile {
+                    path: PathBuf::from("/usr/bin/sudo"),
+                    mode: 0o4755,
+                },
+                SetuidFile {
+                    path: PathBuf::from("/usr/bin/wall"),
+                    mode: 0o2755,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_allowlist() {
+        let allowlist =
+            parse_allowlist("# Allowed\n/usr/bin/sudo\n\n  /usr/bin/passwd  \n").unwrap();
+        assert_eq!(allowlist.len(), 2);
+        assert!(allowlist.contains(&PathBuf::from("/usr/bin/passwd")));
+        assert!(parse_allowlist("usr/bin/sudo").is_err());
+    }
+}
@@ -0,0 +1,87 @@
+//! Temporary directories for extracting and assembling layers and images.
+
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use reportify::ResultExt;
+use tempfile::TempDir;
+
+use crate::BakeryResult;
+
+/// Environment variable with the directory to create temporary directories in.
+pub const WORK_DIR_VAR: &str = "RUGIX_WORK_DIR";
+
+/// Prefix of the temporary directories created in the work directory.
+pub const TEMP_DIR_PREFIX: &str = "rugix-";
+
+/// Work directory configured on the command line.
+static WORK_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Set the directory to create temporary directories in.
+///
+/// Takes precedence over [`WORK_DIR_VAR`]. Has no effect if already set.
+pub fn set_work_dir(work_dir: &Path) {
+    let _ = WORK_DIR.set(work_dir.to_path_buf());
+}
+
+/// Directory to create temporary directories in.
+pub fn work_dir() -> PathBuf {
+    resolve_work_dir(
+        WORK_DIR.get().map(PathBuf::as_path),
+        std::env::var_os(WORK_DIR_VAR),
+    )
+}
+
+/// Resolve the work directory from the configured directory and the environment.
+///
+/// Falls back to the temporary directory of the system, usually `/tmp`.
+fn resolve_work_dir(configured: Option<&Path>, env: Option<OsString>) -> PathBuf {
+    if let Some(configured) = configured {
+        return configured.to_path_buf();
+    }
+    match env {
+        Some(env) if !env.is_empty() => PathBuf::from(env),
+        _ => std::env::temp_dir(),
+    }
+}
+
+/// Create a temporary directory in the work directory.
+///
+/// The directory is removed when the returned [`TempDir`] is dropped.
+pub fn temp_dir() -> BakeryResult<TempDir> {
+    let work_dir = work_dir();
+    fs::create_dir_all(&work_dir)
+        .whatever("unable to create work directory")
+        .with_info(|_| format!("work directory: {work_dir:?}"))?;
+    tempfile::Builder::new()
+        .prefix(TEMP_DIR_PREFIX)
+        .tempdir_in(&work_dir)
+        .whatever("unable to create temporary directory")
+        .with_info(|_| format!("work directory: {work_dir:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::resolve_work_dir;
+
+    #[test]
+    fn test_resolve_work_dir() {
+        assert_eq!(
+            resolve_work_dir(Some(Path::new("/var/tmp/a")), Some("/var/tmp/b".into())),
+            Path::new("/var/tmp/a")
+        );
+        assert_eq!(
+            resolve_work_dir(None, Some("/var/tmp/b".into())),
+            Path::new("/var/tmp/b")
+        );
+        assert_eq!(
+            resolve_work_dir(None, Some("".into())),
+            std::env::temp_dir()
+        );
+        assert_eq!(resolve_work_dir(None, None), std::env::temp_dir());
+    }
+}
@@ -0,0 +1,217 @@
+//! Comparison of directory trees.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::utils::overlay::{is_opaque, is_whiteout};
+
+/// Differences between two directory trees.
+///
+/// All paths are relative to the roots of the trees.
+#[derive(Debug, Clone, Default)]
+pub struct TreeDiff {
+    /// Entries which only exist in the new tree.
+    pub added: Vec<PathBuf>,
+    /// Entries which exist in both trees but differ in type, metadata, or contents.
+    pub modified: Vec<PathBuf>,
+    /// Entries which only exist in the old tree.
+    ///
+    /// For removed directories, only the directory itself is reported.
+    pub removed: Vec<PathBuf>,
+}
+
+impl TreeDiff {
+    /// Sort all paths.
+    fn sort(&mut self) {
+        self.added.sort();
+        self.modified.sort();
+        self.removed.sort();
+    }
+}
+
+/// Compare the old and the new directory tree.
+///
+/// Entries are compared by type, mode, ownership, symlink target, and contents.
+/// Modification times are ignored, as they change with every build.
+pub fn diff_trees(old: &Path, new: &Path) -> io::Result<TreeDiff> {
+    let mut diff = TreeDiff::default();
+    diff_subtree(old, new, PathBuf::new(), &mut diff)?;
+    diff.sort();
+    Ok(diff)
+}
+
+/// Compute the changes recorded in the upper directory of an overlay filesystem.
+///
+/// In contrast to [`diff_trees`], only the entries of the upper directory are compared
+/// with their counterparts in the lower directory. Entries which have merely been copied
+/// up without any changes are not reported.
+pub fn diff_upper(lower: &Path, upper: &Path) -> io::Result<TreeDiff> {
+    let mut diff = TreeDiff::default();
+    let mut stack = vec![PathBuf::new()];
+    while let Some(dir) = stack.pop() {
+        for entry in fs::read_dir(upper.join(&dir))? {
+            let entry = entry?;
+            let path = dir.join(entry.file_name());
+            let upper_metadata = entry.metadata()?;
+            let lower_metadata = fs::symlink_metadata(lower.join(&path)).ok();
+            if is_whiteout(&upper_metadata) {
+                if lower_metadata.is_some() {
+                    diff.removed.push(path);
+                }
+                continue;
+            }
+            let Some(lower_metadata) = lower_metadata else {
+                diff.added.push(path.clone());
+                if upper_metadata.is_dir() {
+                    collect_all(upper, &path, &mut diff.added)?;
+                }
+                continue;
+            };
+            if upper_metadata.is_dir() && lower_metadata.is_dir() {
+                if !same_metadata(&lower_metadata, &upper_metadata) {
+                    diff.modified.push(path.clone());
+                }
+                if is_opaque(&upper.join(&path))? {
+                    // The upper directory replaces the lower directory entirely.
+                    diff_subtree(lower, upper, path, &mut diff)?;
+                } else {
+                    stack.push(path);
+                }
+            } else if !same_entry(lower, upper, &path, &lower_metadata, &upper_metadata)? {
+                diff.modified.push(path.clone());
+                if upper_metadata.is_dir() {
+                    collect_all(upper, &path, &mut diff.added)?;
+                }
+            }
+        }
+    }
+    diff.sort();
+    Ok(diff)
+}
+
+/// Compare the given directory of the old and the new tree.
+fn diff_subtree(old: &Path, new: &Path, dir: PathBuf, diff: &mut TreeDiff) -> io::Result<()> {
+    let mut stack = vec![dir];
+    while let Some(dir) = stack.pop() {
+        let mut old_names = fs::read_dir(old.join(&dir))?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<io::Result<HashSet<_>>>()?;
+        for entry in fs::read_dir(new.join(&dir))? {
+            let entry = entry?;
+            let path = dir.join(entry.file_name());
+            let new_metadata = entry.metadata()?;
+            if !old_names.remove(&entry.file_name()) {
+                diff.added.push(path.clone());
+                if new_metadata.is_dir() {
+                    collect_all(new, &path, &mut diff.added)?;
+                }
+                continue;
+            }
+            let old_metadata = fs::symlink_metadata(old.join(&path))?;
+            if new_metadata.is_dir() && old_metadata.is_dir() {
+                if !same_metadata(&old_metadata, &new_metadata) {
+                    diff.modified.push(path.clone());
+                }
+                stack.push(path);
+            } else if !same_entry(old, new, &path, &old_metadata, &new_metadata)? {
+                diff.modified.push(path.clone());
+                if new_metadata.is_dir() {
+                    collect_all(new, &path, &mut diff.added)?;
+                }
+            }
+        }
+        diff.removed
+            .extend(old_names.into_iter().map(|name| dir.join(name)));
+    }
+    Ok(())
+}
+
+/// Collect all entries below the given directory.
+fn collect_all(root: &Path, dir: &Path, paths: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(root.join(dir))? {
+        let entry = entry?;
+        let path = dir.join(entry.file_name());
+        paths.push(path.clone());
+        if entry.metadata()?.is_dir() {
+            collect_all(root, &path, paths)?;
+        }
+    }
+    Ok(())
+}
+
+/// Check whether the mode and ownership of both entries are the same.
+fn same_metadata(old: &fs::Metadata, new: &fs::Metadata) -> bool {
+    old.mode() == new.mode() && old.uid() == new.uid() && old.gid() == new.gid()
+}
+
+/// Check whether both non-directory entries are the same.
+fn same_entry(
+    old: &Path,
+    new: &Path,
+    path: &Path,
+    old_metadata: &fs::Metadata,
+    new_metadata: &fs::Metadata,
+) -> io::Result<bool> {
+    if !same_metadata(old_metadata, new_metadata) {
+        return Ok(false);
+    }
+    let file_type = new_metadata.file_type();
+    if file_type.is_symlink() {
+        Ok(fs::read_link(old.join(path))? == fs::read_link(new.join(path))?)
+    } else if file_type.is_file() {
+        Ok(old_metadata.len() == new_metadata.len()
+            && same_contents(&old.join(path), &new.join(path))?)
+    } else {
+        // Devices, FIFOs, and sockets.
+        Ok(old_metadata.rdev() == new_metadata.rdev())
+    }
+}
+
+/// Check whether both files have the same contents.
+fn same_contents(old: &Path, new: &Path) -> io::Result<bool> {
+    let mut old = fs::File::open(old)?;
+    let mut new = fs::File::open(new)?;
+    let mut old_buffer = vec![0; 64 * 1024];
+    let mut new_buffer = vec![0; 64 * 1024];
+    loop {
+        let read = old.read(&mut old_buffer)?;
+        if read == 0 {
+            return Ok(true);
+        }
+        new.read_exact(&mut new_buffer[..read])?;
+        if old_buffer[..read] != new_buffer[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::diff_trees;
+
+    #[test]
+    fn test_diff_trees() {
+        let old = tempfile::tempdir().unwrap();
+        let new = tempfile::tempdir().unwrap();
+        for root in [old.path(), new.path()] {
+            fs::create_dir_all(root.join("etc")).unwrap();
+            fs::write(root.join("etc/same"), "same").unwrap();
+        }
+        fs::write(old.path().join("etc/changed"), "old").unwrap();
+        fs::write(new.path().join("etc/changed"), "new").unwrap();
+        fs::create_dir_all(old.path().join("var/lib")).unwrap();
+        fs::create_dir_all(new.path().join("opt/app")).unwrap();
+        fs::write(new.path().join("opt/app/bin"), "bin").unwrap();
+        let diff = diff_trees(old.path(), new.path()).unwrap();
+        let paths = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+        assert_eq!(diff.added, paths(&["opt", "opt/app", "opt/app/bin"]));
+        assert_eq!(diff.modified, paths(&["etc/changed"]));
+        assert_eq!(diff.removed, paths(&["var"]));
+    }
+}
@@ -0,0 +1,12 @@
+//! Lookup of binaries on the host.
+
+use std::path::PathBuf;
+
+/// Find a binary on the `PATH`.
+pub fn find_binary(name: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .chain(["/sbin".into(), "/usr/sbin".into()])
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
@@ -333,7 +333,7 @@ fn tryboot_uboot_post_install(
         bail!("unknown entry");
     };
     let boot_slot = &system.slots()[boot_slot];
-    let _system_slot = &system.slots()[system_slot];
+    let system_slot = &system.slots()[system_slot];
     let SlotKind::Block(boot_raw) = boot_slot.kind() else {
         bail!("boot slot must be of type `block`")
     };
@@ -353,9 +353,30 @@ fn tryboot_uboot_post_install(
             format!("PARTUUID={disk_id}-06")
         }
     } else {
-        todo!("use the GPT partition UUID");
+        let SlotKind::Block(system_raw) = system_slot.kind() else {
+            bail!("system slot must be of type `block`")
+        };
+        let Some(number) = system_raw
+            .device()
+            .is_partition()
+            .whatever("unable to get partition number of system slot")?
+        else {
+            bail!("system slot must be a partition");
+        };
+        let Some(part_uuid) = table
+            .partitions
+            .iter()
+            .find(|partition| u32::from(partition.number) == number)
+            .and_then(|partition| partition.gpt_id)
+        else {
+            bail!("unable to find GPT partition UUID of system partition");
+        };
+        format!(
+            "PARTUUID={}",
+            part_uuid.to_hex_str(ascii_numbers::Case::Lower)
+        )
     };
-    rpi_patch_boot(temp_dir_spare, root).whatever("unable to patch boot partition")?;
+    rpi_patch_boot(temp_dir_spare, root, &[]).whatever("unable to patch boot partition")?;
     Ok(())
 }
 